@@ -0,0 +1,1031 @@
+use std::fmt;
+
+use crate::cpu::{Branch, Endianness, Function, Instruction, REGISTER_NAMES};
+use crate::fpu::{FmtOperation, COMPARE_CONDITIONS, COP1};
+use crate::memory_mapper::{BusError, MemoryMapper};
+use crate::symbol_table::SymbolTable;
+
+/// Where the sections start and how multi-byte values are laid out in the image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AssemblerOptions {
+    pub text_base: u32,
+    pub data_base: u32,
+    pub endianness: Endianness,
+}
+
+impl Default for AssemblerOptions {
+    /// The same memory layout as SPIM and MARS
+    fn default() -> Self {
+        AssemblerOptions { text_base: 0x0040_0000, data_base: 0x1001_0000, endianness: Endianness::Big }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssemblerError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssemblerError {}
+
+/// A contiguous run of bytes of the image starting at `base`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub base: u32,
+    pub bytes: Vec<u8>,
+}
+
+/// The result of assembling a source file, ready to be written into memory
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub segments: Vec<Segment>,
+    pub entry: u32,
+    pub symbols: SymbolTable,
+}
+
+impl Program {
+    pub fn load(&self, memory_mapper: &mut MemoryMapper) -> Result<(), BusError> {
+        for segment in &self.segments {
+            for (i, byte) in segment.bytes.iter().enumerate() {
+                memory_mapper.write_byte(segment.base.wrapping_add(i as u32), [*byte])?;
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// Assembles `source` with the default options
+pub fn assemble(source: &str) -> Result<Program, Vec<AssemblerError>> {
+    return Assembler::new(AssemblerOptions::default()).assemble(source);
+}
+
+/// A two pass assembler, the first pass lays out every statement and collects the labels, the second one encodes
+pub struct Assembler {
+    options: AssemblerOptions,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Text,
+    Data,
+}
+
+enum Item {
+    Instruction { mnemonic: String, operands: Vec<String> },
+    Data { directive: String, operands: Vec<String> },
+}
+
+struct Statement {
+    line: usize,
+    segment: usize,
+    offset: usize,
+    address: u32,
+    item: Item,
+}
+
+/// Keeps track of the segments each section is writing to during the first pass
+struct Layout {
+    segments: Vec<Segment>,
+    current: [Option<usize>; 2],
+    bases: [u32; 2],
+}
+
+impl Layout {
+    fn segment(&mut self, section: Section) -> usize {
+        let index = section as usize;
+        if let Some(segment) = self.current[index] {
+            return segment;
+        }
+        self.segments.push(Segment { base: self.bases[index], bytes: vec![] });
+        self.current[index] = Some(self.segments.len() - 1);
+        return self.segments.len() - 1;
+    }
+
+    fn move_to(&mut self, section: Section, address: u32) {
+        self.bases[section as usize] = address;
+        self.current[section as usize] = None;
+    }
+
+    fn address(&mut self, section: Section) -> u32 {
+        let segment = self.segment(section);
+        let segment = &self.segments[segment];
+        return segment.base.wrapping_add(segment.bytes.len() as u32);
+    }
+
+    fn align(&mut self, section: Section, alignment: u32) {
+        while !self.address(section).is_multiple_of(alignment) {
+            self.reserve(section, 1);
+        }
+    }
+
+    /// Reserves `size` zeroed bytes, returns the segment and the offset they start at
+    fn reserve(&mut self, section: Section, size: usize) -> (usize, usize) {
+        let segment = self.segment(section);
+        let offset = self.segments[segment].bytes.len();
+        self.segments[segment].bytes.resize(offset + size, 0);
+        return (segment, offset);
+    }
+}
+
+impl Assembler {
+    /// Register used by pseudo instructions for intermediate values
+    const AT: u32 = 1;
+
+    pub fn new(options: AssemblerOptions) -> Self {
+        Assembler { options }
+    }
+
+    pub fn assemble(&self, source: &str) -> Result<Program, Vec<AssemblerError>> {
+        let mut errors = vec![];
+        let mut symbols = SymbolTable::new();
+        let mut statements = vec![];
+        let mut layout = Layout { segments: vec![], current: [None, None], bases: [self.options.text_base, self.options.data_base] };
+        let mut section = Section::Text;
+
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index + 1;
+            let mut rest = strip_comment(line).trim();
+            let mut labels = vec![];
+            while let Some((label, after)) = split_label(rest) {
+                labels.push(label);
+                rest = after.trim();
+            }
+
+            // Labels are defined after the statement is aligned so that they point at its first byte
+            let laid_out = self.layout_statement(rest, &mut layout, &mut section);
+            let address = layout.address(section);
+            for label in labels {
+                if !symbols.insert(label, address) {
+                    errors.push(AssemblerError { line: line_number, message: format!("label `{}` is already defined", label) });
+                }
+            }
+            match laid_out {
+                Ok(Some((item, size))) => {
+                    let (segment, offset) = layout.reserve(section, size);
+                    statements.push(Statement { line: line_number, segment, offset, address, item });
+                },
+                Ok(None) => {},
+                Err(message) => errors.push(AssemblerError { line: line_number, message }),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let mut segments = layout.segments;
+        for statement in &statements {
+            let bytes = match &statement.item {
+                Item::Instruction { mnemonic, operands } => self.encode(mnemonic, operands, statement.address, Some(&symbols))
+                    .map(|words| words.iter().flat_map(|word| self.options.endianness.u32_to_bytes(*word)).collect::<Vec<u8>>()),
+                Item::Data { directive, operands } => self.encode_data(directive, operands, &symbols),
+            };
+            match bytes {
+                Ok(bytes) => segments[statement.segment].bytes[statement.offset..statement.offset + bytes.len()].copy_from_slice(&bytes),
+                Err(message) => errors.push(AssemblerError { line: statement.line, message }),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        segments.retain(|segment| !segment.bytes.is_empty());
+        let entry = symbols.get("main").or(symbols.get("__start")).unwrap_or(self.options.text_base);
+        return Ok(Program { segments, entry, symbols });
+    }
+
+    /// First pass handling of a statement, returns what has to be encoded in the second pass and its size
+    fn layout_statement(&self, statement: &str, layout: &mut Layout, section: &mut Section) -> Result<Option<(Item, usize)>, String> {
+        if statement.is_empty() {
+            return Ok(None);
+        }
+        let (head, tail) = match statement.find(char::is_whitespace) {
+            Some(position) => (&statement[..position], statement[position..].trim()),
+            None => (statement, ""),
+        };
+        let head = head.to_lowercase();
+        let operands = split_operands(tail)?;
+
+        if head.starts_with('.') {
+            let size = self.layout_directive(&head, &operands, layout, section)?;
+            return Ok(size.map(|size| (Item::Data { directive: head, operands }, size)));
+        }
+        if *section == Section::Data {
+            return Err(format!("instruction `{}` in the data section", head));
+        }
+        layout.align(*section, 4);
+        // Encoding without symbols gives the final size, pseudo instructions never change size once symbols are known
+        let words = self.encode(&head, &operands, layout.address(*section), None)?;
+        return Ok(Some((Item::Instruction { mnemonic: head, operands }, words.len() * 4)));
+    }
+
+    /// First pass handling of a directive, returns the number of bytes to reserve for the data it emits
+    fn layout_directive(&self, directive: &str, operands: &[String], layout: &mut Layout, section: &mut Section) -> Result<Option<usize>, String> {
+        let items = operands.len();
+        return match directive {
+            ".text" | ".data" => {
+                *section = if directive == ".text" { Section::Text } else { Section::Data };
+                if let Some(address) = operands.first() {
+                    let address = evaluate_constant(address)?;
+                    layout.move_to(*section, address as u32);
+                }
+                Ok(None)
+            },
+            ".align" => {
+                let power = evaluate_constant(single_operand(operands)?)?;
+                if !(0..=16).contains(&power) {
+                    return Err(format!("invalid alignment {}", power));
+                }
+                layout.align(*section, 1 << power);
+                Ok(None)
+            },
+            ".space" => {
+                let size = evaluate_constant(single_operand(operands)?)?;
+                if size < 0 {
+                    return Err(format!("invalid size {}", size));
+                }
+                Ok(Some(size as usize))
+            },
+            ".byte" => Ok(Some(items)),
+            ".half" => {
+                layout.align(*section, 2);
+                Ok(Some(items * 2))
+            },
+            ".word" | ".float" => {
+                layout.align(*section, 4);
+                Ok(Some(items * 4))
+            },
+            ".double" => {
+                layout.align(*section, 8);
+                Ok(Some(items * 8))
+            },
+            ".ascii" | ".asciiz" => {
+                let mut size = 0;
+                for operand in operands {
+                    size += parse_string(operand)?.len();
+                    if directive == ".asciiz" {
+                        size += 1;
+                    }
+                }
+                Ok(Some(size))
+            },
+            ".globl" | ".global" | ".extern" | ".set" | ".ent" | ".end" | ".type" | ".size" => Ok(None),
+            _ => Err(format!("unknown directive `{}`", directive)),
+        };
+    }
+
+    fn encode_data(&self, directive: &str, operands: &[String], symbols: &SymbolTable) -> Result<Vec<u8>, String> {
+        let endianness = self.options.endianness;
+        let mut bytes = vec![];
+        match directive {
+            ".space" => bytes.resize(evaluate_constant(&operands[0])? as usize, 0),
+            ".ascii" | ".asciiz" => {
+                for operand in operands {
+                    bytes.extend(parse_string(operand)?);
+                    if directive == ".asciiz" {
+                        bytes.push(0);
+                    }
+                }
+            },
+            ".float" => {
+                for operand in operands {
+                    let value: f32 = operand.parse().map_err(|_| format!("invalid float `{}`", operand))?;
+                    bytes.extend(endianness.u32_to_bytes(value.to_bits()));
+                }
+            },
+            ".double" => {
+                for operand in operands {
+                    let value: f64 = operand.parse().map_err(|_| format!("invalid double `{}`", operand))?;
+                    let value = value.to_bits();
+                    let (high, low) = (endianness.u32_to_bytes((value >> 32) as u32), endianness.u32_to_bytes(value as u32));
+                    match endianness {
+                        Endianness::Big => bytes.extend(high.iter().chain(low.iter())),
+                        Endianness::Little => bytes.extend(low.iter().chain(high.iter())),
+                    }
+                }
+            },
+            _ => {
+                for operand in operands {
+                    let (value, _) = evaluate(operand, Some(symbols))?;
+                    match directive {
+                        ".byte" => bytes.push(value as u8),
+                        ".half" => bytes.extend(endianness.u16_to_bytes(value as u16)),
+                        _ => bytes.extend(endianness.u32_to_bytes(value as u32)),
+                    }
+                }
+            },
+        }
+        return Ok(bytes);
+    }
+
+    /// Encodes an instruction or the expansion of a pseudo instruction.
+    ///
+    /// Without a symbol table every symbol evaluates to zero and range checks are skipped, that's enough to size the
+    /// statement in the first pass
+    fn encode(&self, mnemonic: &str, operands: &[String], address: u32, symbols: Option<&SymbolTable>) -> Result<Vec<u32>, String> {
+        let encoder = Encoder { operands, address, symbols, endianness: self.options.endianness };
+        if let Some(words) = encoder.floating_point(mnemonic)? {
+            return Ok(words);
+        }
+        let words = match mnemonic {
+            "add" => vec![encoder.r_three(Function::ADD)?],
+            "addu" => vec![encoder.r_three(Function::ADDU)?],
+            "sub" => vec![encoder.r_three(Function::SUB)?],
+            "subu" => vec![encoder.r_three(Function::SUBU)?],
+            "and" => vec![encoder.r_three(Function::AND)?],
+            "or" => vec![encoder.r_three(Function::OR)?],
+            "xor" => vec![encoder.r_three(Function::XOR)?],
+            "nor" => vec![encoder.r_three(Function::NOR)?],
+            "slt" => vec![encoder.r_three(Function::SLT)?],
+            "sltu" => vec![encoder.r_three(Function::SLTU)?],
+            "sll" => vec![encoder.shift(Function::SLL)?],
+            "srl" => vec![encoder.shift(Function::SRL)?],
+            "sra" => vec![encoder.shift(Function::SRA)?],
+            "sllv" => vec![encoder.variable_shift(Function::SLLV)?],
+            "srlv" => vec![encoder.variable_shift(Function::SRLV)?],
+            "srav" => vec![encoder.variable_shift(Function::SRAV)?],
+            "mult" => vec![encoder.r_two(Function::MULT)?],
+            "multu" => vec![encoder.r_two(Function::MULTU)?],
+            "div" | "divu" | "rem" | "remu" => encoder.division(mnemonic)?,
+            "mul" => {
+                encoder.expect(3)?;
+                let (rd, rs, rt) = (encoder.register(0)?, encoder.register(1)?, encoder.register(2)?);
+                vec![r_type(rs, rt, 0, 0, Function::MULT), r_type(0, 0, rd, 0, Function::MFLO)]
+            },
+            "jr" => {
+                encoder.expect(1)?;
+                vec![r_type(encoder.register(0)?, 0, 0, 0, Function::JR)]
+            },
+            "jalr" => {
+                let (rd, rs) = match operands.len() {
+                    1 => (31, encoder.register(0)?),
+                    _ => {
+                        encoder.expect(2)?;
+                        (encoder.register(0)?, encoder.register(1)?)
+                    },
+                };
+                vec![r_type(rs, 0, rd, 0, Function::JALR)]
+            },
+            "mfhi" | "mflo" => {
+                encoder.expect(1)?;
+                let function = if mnemonic == "mfhi" { Function::MFHI } else { Function::MFLO };
+                vec![r_type(0, 0, encoder.register(0)?, 0, function)]
+            },
+            "mthi" | "mtlo" => {
+                encoder.expect(1)?;
+                let function = if mnemonic == "mthi" { Function::MTHI } else { Function::MTLO };
+                vec![r_type(encoder.register(0)?, 0, 0, 0, function)]
+            },
+            "syscall" => {
+                encoder.expect(0)?;
+                vec![r_type(0, 0, 0, 0, Function::SYSCALL)]
+            },
+            "break" => {
+                let code = match operands.len() {
+                    0 => 0,
+                    _ => encoder.unsigned(0, 0xfffff)?,
+                };
+                vec![(code << 6) | Function::BREAK as u32]
+            },
+            "addi" => vec![encoder.i_arithmetic(Instruction::ADDI)?],
+            "addiu" => vec![encoder.i_arithmetic(Instruction::ADDIU)?],
+            "slti" => vec![encoder.i_arithmetic(Instruction::SLTI)?],
+            "sltiu" => vec![encoder.i_arithmetic(Instruction::SLTIU)?],
+            "andi" => vec![encoder.i_arithmetic(Instruction::ANDI)?],
+            "ori" => vec![encoder.i_arithmetic(Instruction::ORI)?],
+            "xori" => vec![encoder.i_arithmetic(Instruction::XORI)?],
+            "lui" => {
+                encoder.expect(2)?;
+                vec![i_type(Instruction::LUI, 0, encoder.register(0)?, encoder.unsigned(1, 0xffff)?)]
+            },
+            "lb" => encoder.memory(Instruction::LB, false)?,
+            "lbu" => encoder.memory(Instruction::LBU, false)?,
+            "lh" => encoder.memory(Instruction::LHW, false)?,
+            "lhu" => encoder.memory(Instruction::LHWU, false)?,
+            "lw" => encoder.memory(Instruction::LW, false)?,
+            "lwl" => encoder.memory(Instruction::LWL, false)?,
+            "lwr" => encoder.memory(Instruction::LWR, false)?,
+            "sb" => encoder.memory(Instruction::SB, false)?,
+            "sh" => encoder.memory(Instruction::SHW, false)?,
+            "sw" => encoder.memory(Instruction::SW, false)?,
+            "swl" => encoder.memory(Instruction::SWL, false)?,
+            "swr" => encoder.memory(Instruction::SWR, false)?,
+            "lwc1" | "l.s" => encoder.memory(Instruction::LWC1, true)?,
+            "swc1" | "s.s" => encoder.memory(Instruction::SWC1, true)?,
+            "l.d" => encoder.double_memory(true)?,
+            "s.d" => encoder.double_memory(false)?,
+            "beq" | "bne" => {
+                encoder.expect(3)?;
+                let op_code = if mnemonic == "beq" { Instruction::BEQ } else { Instruction::BNE };
+                vec![i_type(op_code, encoder.register(0)?, encoder.register(1)?, encoder.branch_offset(2, address)?)]
+            },
+            "blez" | "bgtz" => {
+                encoder.expect(2)?;
+                let op_code = if mnemonic == "blez" { Instruction::BLEZ } else { Instruction::BGTZ };
+                vec![i_type(op_code, encoder.register(0)?, 0, encoder.branch_offset(1, address)?)]
+            },
+            "bltz" | "bgez" | "bltzal" | "bgezal" => {
+                encoder.expect(2)?;
+                let branch = match mnemonic {
+                    "bltz" => Branch::BLTZ,
+                    "bgez" => Branch::BGEZ,
+                    "bltzal" => Branch::BLTZAL,
+                    _ => Branch::BGEZAL,
+                };
+                vec![i_type(Instruction::REGIMM, encoder.register(0)?, branch as u32, encoder.branch_offset(1, address)?)]
+            },
+            "j" | "jal" => {
+                encoder.expect(1)?;
+                let op_code = if mnemonic == "j" { Instruction::J } else { Instruction::JAL };
+                vec![((op_code as u32) << 26) | encoder.jump_target(0, address)?]
+            },
+            "mfc0" | "mtc0" => {
+                encoder.expect(2)?;
+                let operation = if mnemonic == "mfc0" { 0b00000 } else { 0b00100 };
+                vec![((Instruction::COP0 as u32) << 26) | (operation << 21) | (encoder.register(0)? << 16) | (encoder.cop0_register(1)? << 11)]
+            },
+            "rfe" => {
+                encoder.expect(0)?;
+                vec![((Instruction::COP0 as u32) << 26) | (0b10000 << 21) | crate::cop0::RFE_FUNCTION]
+            },
+            "nop" => {
+                encoder.expect(0)?;
+                vec![0]
+            },
+            "move" => {
+                encoder.expect(2)?;
+                vec![r_type(encoder.register(1)?, 0, encoder.register(0)?, 0, Function::ADDU)]
+            },
+            "not" => {
+                encoder.expect(2)?;
+                vec![r_type(encoder.register(1)?, 0, encoder.register(0)?, 0, Function::NOR)]
+            },
+            "neg" | "negu" => {
+                encoder.expect(2)?;
+                let function = if mnemonic == "neg" { Function::SUB } else { Function::SUBU };
+                vec![r_type(0, encoder.register(1)?, encoder.register(0)?, 0, function)]
+            },
+            "li" => encoder.load_immediate()?,
+            "la" => {
+                encoder.expect(2)?;
+                let rt = encoder.register(0)?;
+                let (value, _) = encoder.value(1)?;
+                let value = value as u32;
+                vec![i_type(Instruction::LUI, 0, rt, value >> 16), i_type(Instruction::ORI, rt, rt, value & 0xffff)]
+            },
+            "b" => {
+                encoder.expect(1)?;
+                vec![i_type(Instruction::BEQ, 0, 0, encoder.branch_offset(0, address)?)]
+            },
+            "bal" => {
+                encoder.expect(1)?;
+                vec![i_type(Instruction::REGIMM, 0, Branch::BGEZAL as u32, encoder.branch_offset(0, address)?)]
+            },
+            "beqz" | "bnez" => {
+                encoder.expect(2)?;
+                let op_code = if mnemonic == "beqz" { Instruction::BEQ } else { Instruction::BNE };
+                vec![i_type(op_code, encoder.register(0)?, 0, encoder.branch_offset(1, address)?)]
+            },
+            "blt" | "bgt" | "ble" | "bge" | "bltu" | "bgtu" | "bleu" | "bgeu" => encoder.compare_and_branch(mnemonic, address)?,
+            _ => return Err(format!("unknown instruction `{}`", mnemonic)),
+        };
+        return Ok(words);
+    }
+}
+
+fn r_type(rs: u32, rt: u32, rd: u32, shift_amount: u32, function: Function) -> u32 {
+    return (rs << 21) | (rt << 16) | (rd << 11) | (shift_amount << 6) | function as u32;
+}
+
+fn i_type(op_code: Instruction, rs: u32, rt: u32, immediate: u32) -> u32 {
+    return ((op_code as u32) << 26) | (rs << 21) | (rt << 16) | (immediate & 0xffff);
+}
+
+fn cop1_type(format: COP1, ft: u32, fs: u32, fd: u32, function: u32) -> u32 {
+    return ((Instruction::COP1 as u32) << 26) | ((format as u32) << 21) | (ft << 16) | (fs << 11) | (fd << 6) | function;
+}
+
+/// The operands of one statement together with what is needed to resolve them
+struct Encoder<'a> {
+    operands: &'a [String],
+    address: u32,
+    symbols: Option<&'a SymbolTable>,
+    endianness: Endianness,
+}
+
+impl<'a> Encoder<'a> {
+    fn expect(&self, count: usize) -> Result<(), String> {
+        if self.operands.len() != count {
+            return Err(format!("expected {} operands, found {}", count, self.operands.len()));
+        }
+        return Ok(());
+    }
+
+    fn operand(&self, index: usize) -> Result<&str, String> {
+        return self.operands.get(index).map(|operand| operand.as_str()).ok_or(format!("missing operand {}", index + 1));
+    }
+
+    fn register(&self, index: usize) -> Result<u32, String> {
+        return parse_register(self.operand(index)?);
+    }
+
+    fn float_register(&self, index: usize) -> Result<u32, String> {
+        return parse_float_register(self.operand(index)?);
+    }
+
+    fn cop0_register(&self, index: usize) -> Result<u32, String> {
+        let operand = self.operand(index)?;
+        return match operand.to_lowercase().as_str() {
+            "$badvaddr" => Ok(8),
+            "$status" => Ok(12),
+            "$cause" => Ok(13),
+            "$epc" => Ok(14),
+            "$prid" => Ok(15),
+            _ => parse_numbered_register(operand, "$"),
+        };
+    }
+
+    fn value(&self, index: usize) -> Result<(i64, bool), String> {
+        return evaluate(self.operand(index)?, self.symbols);
+    }
+
+    fn unsigned(&self, index: usize, max: i64) -> Result<u32, String> {
+        let (value, _) = self.value(index)?;
+        if !(0..=max).contains(&value) {
+            return Err(format!("value {} out of range 0..={}", value, max));
+        }
+        return Ok(value as u32);
+    }
+
+    /// 16 bit immediates accept both signed and unsigned values, the instruction decides how they are extended
+    fn immediate(&self, index: usize) -> Result<u32, String> {
+        let (value, _) = self.value(index)?;
+        if !(-0x8000..=0xffff).contains(&value) {
+            return Err(format!("immediate {} doesn't fit in 16 bits", value));
+        }
+        return Ok(value as u32 & 0xffff);
+    }
+
+    fn r_three(&self, function: Function) -> Result<u32, String> {
+        self.expect(3)?;
+        return Ok(r_type(self.register(1)?, self.register(2)?, self.register(0)?, 0, function));
+    }
+
+    fn r_two(&self, function: Function) -> Result<u32, String> {
+        self.expect(2)?;
+        return Ok(r_type(self.register(0)?, self.register(1)?, 0, 0, function));
+    }
+
+    fn shift(&self, function: Function) -> Result<u32, String> {
+        self.expect(3)?;
+        return Ok(r_type(0, self.register(1)?, self.register(0)?, self.unsigned(2, 31)?, function));
+    }
+
+    fn variable_shift(&self, function: Function) -> Result<u32, String> {
+        self.expect(3)?;
+        return Ok(r_type(self.register(2)?, self.register(1)?, self.register(0)?, 0, function));
+    }
+
+    /// `div rs, rt` is the real instruction, `div rd, rs, rt` and `rem` also move the result out of lo or hi
+    fn division(&self, mnemonic: &str) -> Result<Vec<u32>, String> {
+        let function = if mnemonic.ends_with('u') { Function::DIVU } else { Function::DIV };
+        if mnemonic.starts_with("div") && self.operands.len() == 2 {
+            return Ok(vec![self.r_two(function)?]);
+        }
+        self.expect(3)?;
+        let (rd, rs, rt) = (self.register(0)?, self.register(1)?, self.register(2)?);
+        let result = if mnemonic.starts_with("div") { Function::MFLO } else { Function::MFHI };
+        return Ok(vec![r_type(rs, rt, 0, 0, function), r_type(0, 0, rd, 0, result)]);
+    }
+
+    fn i_arithmetic(&self, op_code: Instruction) -> Result<u32, String> {
+        self.expect(3)?;
+        return Ok(i_type(op_code, self.register(1)?, self.register(0)?, self.immediate(2)?));
+    }
+
+    /// `offset(base)`, `(base)` or a plain address that gets built in $at
+    fn memory(&self, op_code: Instruction, float: bool) -> Result<Vec<u32>, String> {
+        self.expect(2)?;
+        let rt = if float { self.float_register(0)? } else { self.register(0)? };
+        let operand = self.operand(1)?;
+        if let Some((offset, base)) = split_memory_operand(operand) {
+            let base = parse_register(base)?;
+            let offset = if offset.is_empty() { 0 } else { self.immediate_expression(offset)? };
+            return Ok(vec![i_type(op_code, base, rt, offset)]);
+        }
+        let (address, _) = evaluate(operand, self.symbols)?;
+        let address = address as u32;
+        // The low half is sign extended by the load, so the high half compensates
+        let high = address.wrapping_add(0x8000) >> 16;
+        return Ok(vec![i_type(Instruction::LUI, 0, Assembler::AT, high), i_type(op_code, Assembler::AT, rt, address & 0xffff)]);
+    }
+
+    /// Loads and stores sign extend their offset, so it has to fit in 16 signed bits
+    fn immediate_expression(&self, expression: &str) -> Result<u32, String> {
+        let (value, _) = evaluate(expression, self.symbols)?;
+        if !(-0x8000..=0x7fff).contains(&value) {
+            return Err(format!("offset {} doesn't fit in 16 bits", value));
+        }
+        return Ok(value as u32 & 0xffff);
+    }
+
+    /// L.D and S.D move the two halves of a double, the even register holds the least significant word
+    fn double_memory(&self, load: bool) -> Result<Vec<u32>, String> {
+        self.expect(2)?;
+        let ft = self.float_register(0)?;
+        if ft % 2 != 0 {
+            return Err("doubles need an even register".to_owned());
+        }
+        let (offset, base) = split_memory_operand(self.operand(1)?).ok_or("expected an offset(base) operand")?;
+        let base = parse_register(base)?;
+        let offset = if offset.is_empty() { 0 } else { evaluate(offset, self.symbols)?.0 };
+        let (low, high) = match self.endianness {
+            Endianness::Big => (offset + 4, offset),
+            Endianness::Little => (offset, offset + 4),
+        };
+        if !(-0x8000..=0x7fff).contains(&low) || !(-0x8000..=0x7fff).contains(&high) {
+            return Err(format!("offset {} doesn't fit in 16 bits", offset));
+        }
+        let op_code = || if load { Instruction::LWC1 } else { Instruction::SWC1 };
+        return Ok(vec![i_type(op_code(), base, ft, low as u32), i_type(op_code(), base, ft + 1, high as u32)]);
+    }
+
+    fn load_immediate(&self) -> Result<Vec<u32>, String> {
+        self.expect(2)?;
+        let rt = self.register(0)?;
+        let (value, symbolic) = self.value(1)?;
+        if !symbolic && (-0x8000..=0x7fff).contains(&value) {
+            return Ok(vec![i_type(Instruction::ADDIU, 0, rt, value as u32)]);
+        }
+        if !symbolic && (0..=0xffff).contains(&value) {
+            return Ok(vec![i_type(Instruction::ORI, 0, rt, value as u32)]);
+        }
+        if !(-0x8000_0000..=0xffff_ffff).contains(&value) {
+            return Err(format!("value {} doesn't fit in 32 bits", value));
+        }
+        let value = value as u32;
+        return Ok(vec![i_type(Instruction::LUI, 0, rt, value >> 16), i_type(Instruction::ORI, rt, rt, value & 0xffff)]);
+    }
+
+    fn branch_offset(&self, index: usize, address: u32) -> Result<u32, String> {
+        let (target, _) = self.value(index)?;
+        if self.symbols.is_none() {
+            return Ok(0);
+        }
+        let offset = target - (address as i64 + 4);
+        if offset % 4 != 0 {
+            return Err(format!("branch target {:#x} is not word aligned", target));
+        }
+        let offset = offset / 4;
+        if !(-0x8000..=0x7fff).contains(&offset) {
+            return Err(format!("branch target {:#x} is out of range", target));
+        }
+        return Ok(offset as u32 & 0xffff);
+    }
+
+    fn jump_target(&self, index: usize, address: u32) -> Result<u32, String> {
+        let (target, _) = self.value(index)?;
+        if self.symbols.is_none() {
+            return Ok(0);
+        }
+        let target = target as u32;
+        if !target.is_multiple_of(4) || (target & 0xf000_0000) != (address.wrapping_add(4) & 0xf000_0000) {
+            return Err(format!("jump target {:#x} is unreachable", target));
+        }
+        return Ok((target >> 2) & 0x03ff_ffff);
+    }
+
+    fn compare_and_branch(&self, mnemonic: &str, address: u32) -> Result<Vec<u32>, String> {
+        self.expect(3)?;
+        let (rs, rt) = (self.register(0)?, self.register(1)?);
+        let function = if mnemonic.ends_with('u') { Function::SLTU } else { Function::SLT };
+        // blt and bge test rs < rt, bgt and ble test rt < rs
+        let compare = match &mnemonic[..3] {
+            "blt" | "bge" => r_type(rs, rt, Assembler::AT, 0, function),
+            _ => r_type(rt, rs, Assembler::AT, 0, function),
+        };
+        let op_code = match &mnemonic[..3] {
+            "blt" | "bgt" => Instruction::BNE,
+            _ => Instruction::BEQ,
+        };
+        let branch_address = address.wrapping_add(4);
+        return Ok(vec![compare, i_type(op_code, Assembler::AT, 0, self.branch_offset(2, branch_address)?)]);
+    }
+
+    /// The COP1 instructions, returns None when `mnemonic` isn't one of them
+    fn floating_point(&self, mnemonic: &str) -> Result<Option<Vec<u32>>, String> {
+        let word = match mnemonic {
+            "mfc1" | "mtc1" | "cfc1" | "ctc1" => {
+                self.expect(2)?;
+                let operation = match mnemonic {
+                    "mfc1" => COP1::MF,
+                    "mtc1" => COP1::MT,
+                    "cfc1" => COP1::CF,
+                    _ => COP1::CT,
+                };
+                let fs = match mnemonic {
+                    "cfc1" | "ctc1" => parse_float_register(self.operand(1)?).or(parse_numbered_register(self.operand(1)?, "$"))?,
+                    _ => self.float_register(1)?,
+                };
+                cop1_type(operation, self.register(0)?, fs, 0, 0)
+            },
+            "bc1f" | "bc1t" => {
+                self.expect(1)?;
+                let condition = if mnemonic == "bc1t" { 1 } else { 0 };
+                cop1_type(COP1::BC, condition, 0, 0, 0) | self.branch_offset(0, self.address)?
+            },
+            _ => {
+                let parts: Vec<&str> = mnemonic.split('.').collect();
+                let (operation, format) = match parts.as_slice() {
+                    [operation, format] => (*operation, *format),
+                    ["c", condition, format] => {
+                        let condition = COMPARE_CONDITIONS.iter().position(|name| name == condition)
+                            .ok_or(format!("unknown compare condition `{}`", condition))?;
+                        self.expect(2)?;
+                        let format = parse_format(format)?;
+                        return Ok(Some(vec![cop1_type(format, self.float_register(1)?, self.float_register(0)?, 0, 0o60 | condition as u32)]));
+                    },
+                    ["cvt", to, format] => {
+                        let function = match *to {
+                            "s" => FmtOperation::CVT_S,
+                            "d" => FmtOperation::CVT_D,
+                            "w" => FmtOperation::CVT_W,
+                            _ => return Err(format!("unknown conversion `{}`", mnemonic)),
+                        };
+                        self.expect(2)?;
+                        let format = parse_format(format)?;
+                        return Ok(Some(vec![cop1_type(format, 0, self.float_register(1)?, self.float_register(0)?, function as u32)]));
+                    },
+                    _ => return Ok(None),
+                };
+                let function = match operation {
+                    "add" => FmtOperation::ADD,
+                    "sub" => FmtOperation::SUB,
+                    "mul" => FmtOperation::MUL,
+                    "div" => FmtOperation::DIV,
+                    "abs" => FmtOperation::ABS,
+                    "neg" => FmtOperation::NEG,
+                    "mov" => FmtOperation::MOV,
+                    // l.s, s.s, l.d and s.d are memory accesses
+                    _ => return Ok(None),
+                };
+                let format = parse_format(format)?;
+                match function {
+                    FmtOperation::ABS | FmtOperation::NEG | FmtOperation::MOV => {
+                        self.expect(2)?;
+                        cop1_type(format, 0, self.float_register(1)?, self.float_register(0)?, function as u32)
+                    },
+                    _ => {
+                        self.expect(3)?;
+                        cop1_type(format, self.float_register(2)?, self.float_register(1)?, self.float_register(0)?, function as u32)
+                    },
+                }
+            },
+        };
+        return Ok(Some(vec![word]));
+    }
+}
+
+fn parse_format(format: &str) -> Result<COP1, String> {
+    return match format {
+        "s" => Ok(COP1::FMTS),
+        "d" => Ok(COP1::FMTD),
+        "w" => Ok(COP1::FMTW),
+        _ => Err(format!("unknown format `{}`", format)),
+    };
+}
+
+fn parse_numbered_register(operand: &str, prefix: &str) -> Result<u32, String> {
+    let number = operand.strip_prefix(prefix).and_then(|number| number.parse::<u32>().ok());
+    return match number {
+        Some(number) if number < 32 => Ok(number),
+        _ => Err(format!("invalid register `{}`", operand)),
+    };
+}
+
+/// A general purpose register, either by number (`$8`) or by name (`$t0`)
+fn parse_register(operand: &str) -> Result<u32, String> {
+    let name = operand.strip_prefix('$').ok_or(format!("expected a register, found `{}`", operand))?;
+    if let Some(index) = REGISTER_NAMES.iter().position(|register| *register == name) {
+        return Ok(index as u32);
+    }
+    return match name {
+        "s8" => Ok(30),
+        _ => parse_numbered_register(operand, "$"),
+    };
+}
+
+fn parse_float_register(operand: &str) -> Result<u32, String> {
+    return parse_numbered_register(operand, "$f");
+}
+
+/// Splits `offset(base)` into its two parts
+fn split_memory_operand(operand: &str) -> Option<(&str, &str)> {
+    if !operand.ends_with(')') || operand.starts_with('%') && operand.matches('(').count() < 2 {
+        return None;
+    }
+    let open = operand.rfind('(')?;
+    return Some((operand[..open].trim(), operand[open + 1..operand.len() - 1].trim()));
+}
+
+fn single_operand(operands: &[String]) -> Result<&str, String> {
+    return match operands {
+        [operand] => Ok(operand),
+        _ => Err(format!("expected 1 operand, found {}", operands.len())),
+    };
+}
+
+/// Removes a `#` comment, ignoring the ones inside string and character literals
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (index, character) in line.char_indices() {
+        match (quote, character) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(open), _) if open == character => quote = None,
+            (None, '"') | (None, '\'') => quote = Some(character),
+            (None, '#') => return &line[..index],
+            _ => {},
+        }
+    }
+    return line;
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut characters = name.chars();
+    return match characters.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' || first == '.' => {
+            characters.all(|character| character.is_ascii_alphanumeric() || character == '_' || character == '.' || character == '$')
+        },
+        _ => false,
+    };
+}
+
+/// Splits a leading `label:` off the statement
+fn split_label(statement: &str) -> Option<(&str, &str)> {
+    let colon = statement.find(':')?;
+    let label = statement[..colon].trim();
+    if !is_identifier(label) {
+        return None;
+    }
+    return Some((label, &statement[colon + 1..]));
+}
+
+/// Splits the operands on the commas that aren't part of a literal
+fn split_operands(operands: &str) -> Result<Vec<String>, String> {
+    let mut result = vec![];
+    let mut current = String::new();
+    let mut quote = None;
+    let mut escaped = false;
+    for character in operands.chars() {
+        match (quote, character) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(open), _) if open == character => quote = None,
+            (None, '"') | (None, '\'') => quote = Some(character),
+            (None, ',') => {
+                result.push(current.trim().to_owned());
+                current.clear();
+                continue;
+            },
+            _ => {},
+        }
+        current.push(character);
+    }
+    if quote.is_some() {
+        return Err("unterminated literal".to_owned());
+    }
+    if !current.trim().is_empty() || !result.is_empty() {
+        result.push(current.trim().to_owned());
+    }
+    if result.iter().any(|operand| operand.is_empty()) {
+        return Err("empty operand".to_owned());
+    }
+    return Ok(result);
+}
+
+/// Decodes the escapes of a literal without its quotes
+fn unescape(literal: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    let mut characters = literal.chars();
+    while let Some(character) = characters.next() {
+        if character != '\\' {
+            let mut buffer = [0; 4];
+            bytes.extend(character.encode_utf8(&mut buffer).bytes());
+            continue;
+        }
+        let escaped = match characters.next() {
+            Some('n') => b'\n',
+            Some('t') => b'\t',
+            Some('r') => b'\r',
+            Some('0') => 0,
+            Some('\\') => b'\\',
+            Some('"') => b'"',
+            Some('\'') => b'\'',
+            other => return Err(format!("invalid escape `\\{}`", other.map(String::from).unwrap_or_default())),
+        };
+        bytes.push(escaped);
+    }
+    return Ok(bytes);
+}
+
+fn parse_string(operand: &str) -> Result<Vec<u8>, String> {
+    let literal = operand.strip_prefix('"').and_then(|operand| operand.strip_suffix('"'))
+        .ok_or(format!("expected a string, found `{}`", operand))?;
+    return unescape(literal);
+}
+
+fn parse_number(term: &str) -> Option<i64> {
+    let (digits, radix) = match term.get(..2) {
+        Some("0x") | Some("0X") => (&term[2..], 16),
+        Some("0b") | Some("0B") => (&term[2..], 2),
+        _ => (term, 10),
+    };
+    return i64::from_str_radix(digits, radix).ok();
+}
+
+fn evaluate_term(term: &str, symbols: Option<&SymbolTable>) -> Result<(i64, bool), String> {
+    if let Some(literal) = term.strip_prefix('\'').and_then(|term| term.strip_suffix('\'')) {
+        return match unescape(literal)?.as_slice() {
+            [byte] => Ok((*byte as i64, false)),
+            _ => Err(format!("invalid character literal `{}`", term)),
+        };
+    }
+    if term.starts_with(|character: char| character.is_ascii_digit()) {
+        return parse_number(term).map(|value| (value, false)).ok_or(format!("invalid number `{}`", term));
+    }
+    if !is_identifier(term) {
+        return Err(format!("invalid expression `{}`", term));
+    }
+    return match symbols {
+        None => Ok((0, true)),
+        Some(symbols) => symbols.get(term).map(|address| (address as i64, true)).ok_or(format!("undefined symbol `{}`", term)),
+    };
+}
+
+/// Evaluates `term (+|- term)*` where terms are numbers, characters or symbols, optionally wrapped in `%hi()` or `%lo()`.
+///
+/// Also tells whether a symbol was involved, so that the first pass can size `li` the same way the second one will
+fn evaluate(expression: &str, symbols: Option<&SymbolTable>) -> Result<(i64, bool), String> {
+    let expression = expression.trim();
+    for (prefix, high) in [("%hi(", true), ("%lo(", false)] {
+        if let Some(inner) = expression.strip_prefix(prefix).and_then(|inner| inner.strip_suffix(')')) {
+            let (value, symbolic) = evaluate(inner, symbols)?;
+            let value = value as u32;
+            // The low half is sign extended like the offsets it's used as, %hi compensates for it
+            let value = if high { (value.wrapping_add(0x8000) >> 16) as i64 } else { value as u16 as i16 as i64 };
+            return Ok((value, symbolic));
+        }
+    }
+
+    let mut total: i64 = 0;
+    let mut symbolic = false;
+    let mut negative = false;
+    let mut rest = expression;
+    loop {
+        rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix('-') {
+            negative = !negative;
+            rest = after;
+            continue;
+        }
+        if let Some(after) = rest.strip_prefix('+') {
+            rest = after;
+            continue;
+        }
+        // A character literal may contain an operator, so it's delimited by its quotes
+        let end = match rest.strip_prefix('\'') {
+            Some(after) if after.starts_with('\\') => 4.min(rest.len()),
+            Some(_) => 3.min(rest.len()),
+            None => rest.find(['+', '-']).unwrap_or(rest.len()),
+        };
+        let (value, is_symbol) = evaluate_term(rest[..end].trim(), symbols)?;
+        let value = if negative { value.checked_neg() } else { Some(value) };
+        total = value.and_then(|value| total.checked_add(value)).ok_or(format!("`{}` overflows", expression))?;
+        symbolic |= is_symbol;
+        negative = false;
+        rest = rest[end..].trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        if !rest.starts_with(['+', '-']) {
+            return Err(format!("invalid expression `{}`", expression));
+        }
+    }
+    return Ok((total, symbolic));
+}
+
+/// An expression that must not depend on any symbol, like the argument of `.space`
+fn evaluate_constant(expression: &str) -> Result<i64, String> {
+    return match evaluate(expression, None)? {
+        (value, false) => Ok(value),
+        (_, true) => Err(format!("`{}` must be a constant", expression)),
+    };
+}
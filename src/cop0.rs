@@ -0,0 +1,164 @@
+use num_derive::FromPrimitive;
+
+/// Coprocessor 0, the system control coprocessor of the MIPS I architecture.
+///
+/// It holds the registers that describe the processor status and the last exception taken
+pub struct Cop0 {
+    status: u32,
+    cause: u32,
+    epc: u32,
+    bad_vaddr: u32,
+}
+
+impl Cop0 {
+    pub const STATUS_IEC: u32 = 1 << 0;
+    pub const STATUS_KUC: u32 = 1 << 1;
+    pub const STATUS_IM_MASK: u32 = 0xff << 8;
+    pub const STATUS_BEV: u32 = 1 << 22;
+    pub const STATUS_CU0: u32 = 1 << 28;
+    pub const STATUS_CU1: u32 = 1 << 29;
+    pub const CAUSE_EXC_CODE_MASK: u32 = 0x1f << 2;
+    pub const CAUSE_IP_MASK: u32 = 0xff << 8;
+    pub const CAUSE_BD: u32 = 1 << 31;
+    /// ExcCode of the exception taken when an enabled interrupt is pending
+    pub const EXC_CODE_INTERRUPT: u32 = 0;
+
+    /// Only the two software interrupt bits of Cause can be written by MTC0
+    const CAUSE_WRITABLE_MASK: u32 = 0b11 << 8;
+    /// IP2 to IP7, they follow the hardware interrupt lines
+    const CAUSE_HARDWARE_IP_MASK: u32 = 0x3f << 10;
+    /// Bits of Status that don't exist on a MIPS I processor read as zero
+    const STATUS_WRITABLE_MASK: u32 = 0xf07f_ff3f;
+    const PRID: u32 = 0x0000_0230;
+
+    const GENERAL_EXCEPTION_VECTOR: u32 = 0x8000_0080;
+    const BOOT_EXCEPTION_VECTOR: u32 = 0xbfc0_0180;
+
+    /// The FPU comes out of reset enabled so that programs which never touch Status can use it
+    pub fn new() -> Self {
+        Cop0 { status: Cop0::STATUS_BEV | Cop0::STATUS_CU1, cause: 0, epc: 0, bad_vaddr: 0 }
+    }
+
+    pub fn read_register(&self, register: u8) -> u32 {
+        let register: Option<Cop0Register> = num::FromPrimitive::from_u8(register);
+        return match register {
+            Some(Cop0Register::BadVAddr) => self.bad_vaddr,
+            Some(Cop0Register::Status) => self.status,
+            Some(Cop0Register::Cause) => self.cause,
+            Some(Cop0Register::EPC) => self.epc,
+            Some(Cop0Register::PRId) => Cop0::PRID,
+            None => 0,
+        };
+    }
+
+    pub fn write_register(&mut self, register: u8, value: u32) {
+        let register: Option<Cop0Register> = num::FromPrimitive::from_u8(register);
+        match register {
+            Some(Cop0Register::Status) => self.status = value & Cop0::STATUS_WRITABLE_MASK,
+            Some(Cop0Register::Cause) => self.cause = (self.cause & !Cop0::CAUSE_WRITABLE_MASK) | (value & Cop0::CAUSE_WRITABLE_MASK),
+            Some(Cop0Register::EPC) => self.epc = value,
+            Some(Cop0Register::BadVAddr) | Some(Cop0Register::PRId) | None => {},
+        }
+    }
+
+    pub fn status(&self) -> u32 {
+        return self.status;
+    }
+
+    pub fn cause(&self) -> u32 {
+        return self.cause;
+    }
+
+    pub fn epc(&self) -> u32 {
+        return self.epc;
+    }
+
+    pub fn bad_vaddr(&self) -> u32 {
+        return self.bad_vaddr;
+    }
+
+    /// Sets the registers as they are, without the masks MTC0 applies, to bring back a snapshot
+    pub fn restore(&mut self, status: u32, cause: u32, epc: u32, bad_vaddr: u32) {
+        self.status = status;
+        self.cause = cause;
+        self.epc = epc;
+        self.bad_vaddr = bad_vaddr;
+    }
+
+    /// Sets IP2 to IP7 to the levels of the hardware interrupt lines, bit 0 of `lines` is IP2
+    pub fn set_hardware_interrupts(&mut self, lines: u8) {
+        self.cause = (self.cause & !Cop0::CAUSE_HARDWARE_IP_MASK) | (((lines as u32) << 10) & Cop0::CAUSE_HARDWARE_IP_MASK);
+    }
+
+    /// Whether an interrupt is pending, enabled by its IM bit and interrupts are enabled
+    pub fn interrupt_pending(&self) -> bool {
+        return self.status & Cop0::STATUS_IEC != 0 && self.cause & self.status & Cop0::STATUS_IM_MASK != 0;
+    }
+
+    pub fn kernel_mode(&self) -> bool {
+        return self.status & Cop0::STATUS_KUC == 0;
+    }
+
+    /// Coprocessor 0 is always usable in kernel mode, the others only when their CU bit is set
+    pub fn coprocessor_usable(&self, coprocessor: u8) -> bool {
+        if coprocessor == 0 && self.kernel_mode() {
+            return true;
+        }
+        return self.status & (Cop0::STATUS_CU0 << coprocessor) != 0;
+    }
+
+    pub fn exception_vector(&self) -> u32 {
+        if self.status & Cop0::STATUS_BEV != 0 {
+            return Cop0::BOOT_EXCEPTION_VECTOR;
+        }
+        return Cop0::GENERAL_EXCEPTION_VECTOR;
+    }
+
+    /// Records an exception and pushes the KU/IE stack, interrupts get disabled and the processor enters kernel mode.
+    ///
+    /// Returns the address the processor has to jump to
+    pub fn enter_exception(&mut self, exc_code: u32, epc: u32, bad_vaddr: Option<u32>, branch_delay: bool) -> u32 {
+        let stack = self.status & 0x3f;
+        self.status = (self.status & !0x3f) | ((stack << 2) & 0x3c);
+        self.cause &= !(Cop0::CAUSE_EXC_CODE_MASK | Cop0::CAUSE_BD);
+        self.cause |= (exc_code << 2) & Cop0::CAUSE_EXC_CODE_MASK;
+        if branch_delay {
+            self.cause |= Cop0::CAUSE_BD;
+        }
+        self.epc = epc;
+        if let Some(bad_vaddr) = bad_vaddr {
+            self.bad_vaddr = bad_vaddr;
+        }
+        return self.exception_vector();
+    }
+
+    /// RFE, pops the KU/IE stack restoring the mode the processor was in before the exception
+    pub fn return_from_exception(&mut self) {
+        let stack = self.status & 0x3f;
+        self.status = (self.status & !0x0f) | (stack >> 2);
+    }
+}
+
+impl Default for Cop0 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(FromPrimitive)]
+pub enum Cop0Register {
+    BadVAddr = 8,
+    Status = 12,
+    Cause = 13,
+    EPC = 14,
+    PRId = 15,
+}
+
+#[derive(FromPrimitive)]
+pub enum Cop0Operation {
+    MF = 0b00000,
+    MT = 0b00100,
+}
+
+/// Function field of the COP0 instructions that have the CO bit set
+pub const RFE_FUNCTION: u32 = 0o20;
@@ -1,75 +1,531 @@
 extern crate num;
 
+use std::collections::BTreeSet;
+use std::io;
+use std::time::{Duration, Instant};
+
 use num_derive::FromPrimitive;
+use serde::Deserialize;
 
-use crate::memory_mapper::MemoryMapper;
+use crate::cop0::{self, Cop0, Cop0Operation};
+use crate::exception::CpuException;
+use crate::fpu::{COP1, FPU};
+use crate::history::{History, HistoryLimits, UndoRecord, WriteTarget};
+use crate::memory_mapper::{BusError, MemoryAccess, MemoryMapper, WatchHit, WatchKind};
+use crate::snapshot::{CpuState, Snapshot};
+use crate::syscall::SyscallHandler;
+use crate::trace::{RegisterChange, TraceRecord, TracedRegister, Tracer};
 
 pub struct CPU<'a> {
     registers: [u32; 32],
     pc: u32,
     hi: u32,
     lo: u32,
+    cop0: Cop0,
+    fpu: FPU,
+    endianness: Endianness,
+    config: CpuConfig,
+    /// Target of a taken branch, applied once the instruction in the delay slot has executed
+    delayed_branch: Option<u32>,
+    /// Register and value of a load that becomes visible after the next instruction
+    pending_load: Option<(u8, u32)>,
+    /// The load that completes at the end of the instruction being executed
+    load_in_flight: Option<(u8, u32)>,
+    /// Bit mask of the registers written by the instruction being executed, a delayed load doesn't land on them
+    written_registers: u32,
+    syscall_handler: Option<Box<dyn SyscallHandler + 'a>>,
+    /// Set when the program asked to terminate through a syscall
+    exit_code: Option<i32>,
+    /// Accesses of the last instruction that hit a watchpoint
+    watch_hits: Vec<WatchHit>,
+    /// Decides whether a watchpoint hit stops `run`, without one every hit does
+    watch_handler: Option<WatchHandler<'a>>,
+    watch_stop: bool,
+    tracer: Option<Tracer<'a>>,
+    history: Option<History>,
     memory_mapper: &'a mut MemoryMapper
 }
 
+/// Told about every watchpoint hit, returns whether `run` should stop
+pub type WatchHandler<'a> = Box<dyn FnMut(&WatchHit) -> bool + 'a>;
+
+/// Selects how faithfully the pipeline of a MIPS I processor is modelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CpuConfig {
+    /// The instruction after a branch or jump always executes before control is transferred
+    pub branch_delay_slots: bool,
+    /// The value of a load can't be used by the instruction that immediately follows it
+    pub load_delay_slots: bool,
+}
+
 impl<'a> CPU<'a> {
     const REGISTER_MASK: u32 = 0b00000011111;
     const FUNCTION_MASK: u32 = 0x0000003f;
     const IMMEDIATE_MASK: u32 = 0x0000ffff;
 
+    /// Creates a CPU without delay slots, the way most teaching material describes MIPS
     pub fn new(memory_mapper:  &'a mut MemoryMapper) -> Self {
-        CPU{ registers: [0; 32], pc: 0, hi: 0, lo: 0, memory_mapper }
+        return CPU::with_config(memory_mapper, CpuConfig::default());
+    }
+
+    pub fn with_config(memory_mapper:  &'a mut MemoryMapper, config: CpuConfig) -> Self {
+        CPU{
+            registers: [0; 32], pc: 0, hi: 0, lo: 0, cop0: Cop0::new(), fpu: FPU::new(), endianness: Endianness::Big, config,
+            delayed_branch: None, pending_load: None, load_in_flight: None, written_registers: 0, syscall_handler: None, exit_code: None,
+            watch_hits: vec![], watch_handler: None, watch_stop: false, tracer: None, history: None,
+            memory_mapper
+        }
+    }
+
+    pub fn config(&self) -> CpuConfig {
+        return self.config;
+    }
+
+    fn fetch(&mut self) -> Result<u32, CpuException> {
+        if !self.pc.is_multiple_of(4) || !self.address_accessible(self.pc) {
+            return Err(CpuException::AddressErrorLoad(self.pc));
+        }
+        let instruction_bytes:[u8; 4] = self.memory_mapper.fetch_word(self.pc).map_err(|e| CpuException::InstructionBusError(e.address))?;
+        let res = self.endianness.u32_from_bytes(instruction_bytes);
+        self.pc = self.pc.wrapping_add(4);
+        return Ok(res);
+    }
+
+    pub fn endianness(&self) -> Endianness {
+        return self.endianness;
+    }
+
+    /// Selects the byte order used for instruction fetches, loads and stores
+    pub fn set_endianness(&mut self, endianness: Endianness) {
+        self.endianness = endianness;
+    }
+
+    pub fn cop0(&self) -> &Cop0 {
+        return &self.cop0;
+    }
+
+    pub fn cop0_mut(&mut self) -> &mut Cop0 {
+        return &mut self.cop0;
+    }
+
+    pub fn fpu(&self) -> &FPU {
+        return &self.fpu;
+    }
+
+    pub fn fpu_mut(&mut self) -> &mut FPU {
+        return &mut self.fpu;
+    }
+
+    pub fn memory_mapper(&self) -> &MemoryMapper {
+        return self.memory_mapper;
+    }
+
+    pub fn memory_mapper_mut(&mut self) -> &mut MemoryMapper {
+        return self.memory_mapper;
+    }
+
+    /// Without a handler SYSCALL raises the Syscall exception
+    pub fn set_syscall_handler(&mut self, handler: Box<dyn SyscallHandler + 'a>) {
+        self.syscall_handler = Some(handler);
+    }
+
+    /// Stops the program, `run` returns once the current instruction completes
+    pub fn exit(&mut self, code: i32) {
+        self.exit_code = Some(code);
+    }
+
+    pub fn exit_code(&self) -> Option<i32> {
+        return self.exit_code;
+    }
+
+    /// Called with every watchpoint hit, returning true stops `run` after the instruction that made the access
+    pub fn set_watch_handler(&mut self, handler: WatchHandler<'a>) {
+        self.watch_handler = Some(handler);
+    }
+
+    /// The accesses of the last instruction executed that hit a watchpoint of the memory mapper
+    pub fn watch_hits(&self) -> &[WatchHit] {
+        return &self.watch_hits;
+    }
+
+    /// Whether a watchpoint hit by the last instruction asked to stop
+    pub fn stopped_by_watchpoint(&self) -> bool {
+        return self.watch_stop;
+    }
+
+    /// Logs every instruction executed from now on, memory accesses included
+    pub fn set_tracer(&mut self, tracer: Tracer<'a>) {
+        self.tracer = Some(tracer);
+        self.update_access_logging();
+    }
+
+    pub fn tracer_mut(&mut self) -> Option<&mut Tracer<'a>> {
+        return self.tracer.as_mut();
+    }
+
+    pub fn take_tracer(&mut self) -> Option<Tracer<'a>> {
+        let tracer = self.tracer.take();
+        self.update_access_logging();
+        return tracer;
+    }
+
+    /// The tracer and the history both need the accesses of every instruction
+    fn update_access_logging(&mut self) {
+        self.memory_mapper.set_access_logging(self.tracer.is_some() || self.history.is_some());
+    }
+
+    /// Starts recording what every instruction does so that `step_back` can take it back, the past starts here
+    pub fn enable_history(&mut self, limits: HistoryLimits) {
+        self.history = Some(History::new(limits));
+        self.update_access_logging();
     }
 
-    fn fetch(&mut self) -> u32 {
-        let instruction_bytes:[u8; 4] = self.memory_mapper.get_word(self.pc as u32);
-        let res = u32::from_be_bytes(instruction_bytes);
-        self.pc += 4;
-        return res;
+    pub fn disable_history(&mut self) {
+        self.history = None;
+        self.update_access_logging();
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        return self.history.as_ref();
+    }
+
+    /// Goes back `count` instructions, or as far as the history goes, and returns how many were taken back.
+    ///
+    /// Past the undo log it lands on the oldest snapshot that isn't before the target. Writes to devices other than
+    /// memory, like the output of the program, can't be taken back
+    pub fn step_back(&mut self, count: u64) -> u64 {
+        let Some(mut history) = self.history.take() else {
+            return 0;
+        };
+        let start = history.position();
+        let target = start.saturating_sub(count);
+        if target < history.first_record() {
+            if let Some((position, snapshot)) = history.snapshot_before_records(target) {
+                // The snapshot was taken on this machine, its memory map can always be restored
+                let position = *position;
+                let _ = self.restore(snapshot);
+                history.rewind_to(position);
+            }
+        }
+        while history.position() > target {
+            let Some(record) = history.pop() else {
+                break;
+            };
+            self.undo(&record);
+        }
+        self.watch_hits.clear();
+        self.watch_stop = false;
+        let undone = start - history.position();
+        self.history = Some(history);
+        return undone;
+    }
+
+    /// Goes back to the last instruction that changed a register or wrote to memory, pc is left on it so that
+    /// stepping runs it again.
+    ///
+    /// Only the undo log is searched, returns how many instructions were taken back or `None` when nothing in it wrote
+    /// to the target
+    pub fn reverse_to_last_write(&mut self, target: WriteTarget) -> Option<u64> {
+        let distance = self.history.as_ref()?.records().rev().position(|record| record.writes_to(target))?;
+        return Some(self.step_back(distance as u64 + 1));
+    }
+
+    fn undo(&mut self, record: &UndoRecord) {
+        for write in record.writes.iter().rev() {
+            self.memory_mapper.undo_write(write);
+        }
+        if let (Some(handler), Some(state)) = (self.syscall_handler.as_mut(), &record.syscalls) {
+            // The state was saved by this handler
+            let _ = handler.restore_state(state);
+        }
+        let mut state = self.cpu_state();
+        record.undo(&mut state);
+        self.restore_cpu_state(&state);
+    }
+
+    /// Saves the state of the machine between two instructions
+    pub fn snapshot(&self) -> Snapshot {
+        let syscalls = self.syscall_handler.as_ref().and_then(|handler| handler.save_state());
+        return Snapshot { cpu: self.cpu_state(), regions: self.memory_mapper.save_regions(), syscalls };
+    }
+
+    fn cpu_state(&self) -> CpuState {
+        let mut fpu_registers = [0; 32];
+        for (i, register) in fpu_registers.iter_mut().enumerate() {
+            *register = self.fpu.read_register(i as u8);
+        }
+        return CpuState {
+            registers: self.registers,
+            pc: self.pc,
+            hi: self.hi,
+            lo: self.lo,
+            status: self.cop0.status(),
+            cause: self.cop0.cause(),
+            epc: self.cop0.epc(),
+            bad_vaddr: self.cop0.bad_vaddr(),
+            fpu_registers,
+            fcsr: self.fpu.fcsr(),
+            endianness: self.endianness,
+            config: self.config,
+            delayed_branch: self.delayed_branch,
+            pending_load: self.pending_load,
+            exit_code: self.exit_code,
+        };
+    }
+
+    /// Puts the machine back in the state of `snapshot`, the memory map must be the one it was taken with.
+    ///
+    /// The syscall handler, the tracer and the watchpoints are kept, only their state is brought back
+    pub fn restore(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        self.memory_mapper.restore_regions(&snapshot.regions)?;
+        if let (Some(handler), Some(state)) = (self.syscall_handler.as_mut(), &snapshot.syscalls) {
+            handler.restore_state(state)?;
+        }
+        self.restore_cpu_state(&snapshot.cpu);
+        self.watch_hits.clear();
+        self.watch_stop = false;
+        return Ok(());
+    }
+
+    fn restore_cpu_state(&mut self, cpu: &CpuState) {
+        self.registers = cpu.registers;
+        self.pc = cpu.pc;
+        self.hi = cpu.hi;
+        self.lo = cpu.lo;
+        self.cop0.restore(cpu.status, cpu.cause, cpu.epc, cpu.bad_vaddr);
+        for (i, register) in cpu.fpu_registers.iter().enumerate() {
+            self.fpu.write_register(i as u8, *register);
+        }
+        self.fpu.restore_fcsr(cpu.fcsr);
+        self.endianness = cpu.endianness;
+        self.config = cpu.config;
+        self.delayed_branch = cpu.delayed_branch;
+        self.pending_load = cpu.pending_load;
+        self.load_in_flight = None;
+        self.exit_code = cpu.exit_code;
+    }
+
+    /// The memory mapper reads values in address order, the CPU sees them in its own byte order
+    fn to_cpu_order(&self, value: u32, size: u32) -> u32 {
+        return match size {
+            2 => self.endianness.u16_from_bytes((value as u16).to_be_bytes()) as u32,
+            4 => self.endianness.u32_from_bytes(value.to_be_bytes()),
+            _ => value,
+        };
+    }
+
+    /// Attributes the hits recorded by the memory mapper to the instruction at `pc`
+    fn collect_watch_hits(&mut self, pc: u32) {
+        self.watch_hits = self.memory_mapper.take_watch_hits().into_iter().map(|hit| WatchHit {
+            pc,
+            old_value: self.to_cpu_order(hit.old_value, hit.size),
+            new_value: self.to_cpu_order(hit.new_value, hit.size),
+            ..hit
+        }).collect();
+        self.watch_stop = false;
+        for hit in &self.watch_hits {
+            let stop = match self.watch_handler.as_mut() {
+                Some(handler) => handler(hit),
+                None => true,
+            };
+            self.watch_stop |= stop;
+        }
+    }
+
+    /// What the instruction at `pc` changed, given the registers it started from
+    fn trace_record(&self, pc: u32, word: Option<u32>, before: &([u32; 32], u32, u32), exception: Option<CpuException>, accesses: &[MemoryAccess]) -> TraceRecord {
+        let (registers, hi, lo) = before;
+        let mut changes: Vec<RegisterChange> = (1..32)
+            .filter(|i| registers[*i] != self.registers[*i])
+            .map(|i| RegisterChange { register: TracedRegister::Gpr(i as u8), old_value: registers[i], new_value: self.registers[i] })
+            .collect();
+        if *hi != self.hi {
+            changes.push(RegisterChange { register: TracedRegister::Hi, old_value: *hi, new_value: self.hi });
+        }
+        if *lo != self.lo {
+            changes.push(RegisterChange { register: TracedRegister::Lo, old_value: *lo, new_value: self.lo });
+        }
+        let memory = accesses.iter().map(|access| MemoryAccess {
+            old_value: self.to_cpu_order(access.old_value, access.size),
+            new_value: self.to_cpu_order(access.new_value, access.size),
+            ..*access
+        }).collect();
+        let index = self.tracer.as_ref().map_or(0, |tracer| tracer.executed());
+        return TraceRecord { index, pc, word, registers: changes, memory, exception };
+    }
+
+    fn syscall(&mut self) -> Result<(), CpuException> {
+        // The handler is taken out for the duration of the call so that it can borrow the CPU
+        let mut handler = self.syscall_handler.take().ok_or(CpuException::Syscall)?;
+        let result = handler.syscall(self);
+        self.syscall_handler = Some(handler);
+        return result;
+    }
+
+    /// In user mode only the lower half of the address space (kuseg) can be accessed
+    fn address_accessible(&self, address: u32) -> bool {
+        return self.cop0.kernel_mode() || address < 0x8000_0000;
     }
 
-    #[cfg(test)]
     pub fn get_register_value(&self, i: usize) -> u32 {
         return self.registers[i];
     }
 
+    /// Writes to $zero are ignored like they are for instructions
+    pub fn set_register_value(&mut self, i: usize, value: u32) {
+        if i != 0 {
+            self.registers[i] = value;
+            self.written_registers |= 1 << i;
+        }
+    }
+
+    pub fn get_hi_lo(&self) -> (u32, u32) {
+        return (self.hi, self.lo);
+    }
+
+    pub fn set_hi_lo(&mut self, hi: u32, lo: u32) {
+        self.hi = hi;
+        self.lo = lo;
+    }
+
+    pub fn get_pc(&self) -> u32 {
+        return self.pc;
+    }
+
+    /// Moves execution to `pc`, discarding any branch that was about to be taken
+    pub fn set_pc(&mut self, pc: u32) {
+        self.pc = pc;
+        self.delayed_branch = None;
+    }
+
     fn immediate_unsigned_op_write_r(&mut self, instruction: u32, op: fn(u32, u32) -> u32) {
         let (rs, rt, immediate) = CPU::get_immediate_instructions_values(instruction);
         let rs_value = self.registers[rs as usize];
-        self.registers[rt as usize] = op(rs_value, immediate);
+        self.write_register(rt, op(rs_value, immediate));
     }
 
-    fn immediate_signed_op_write_r(&mut self, instruction: u32, op: fn(i32, i32) -> i32) {
+    /// Runs an operation on rs and the sign extended immediate, `None` means that the operation overflowed
+    fn immediate_signed_op_write_r(&mut self, instruction: u32, op: fn(i32, i32) -> Option<i32>) -> Result<(), CpuException> {
         let (rs, rt, immediate) = CPU::get_immediate_instructions_values(instruction);
         let rs_value = u32_to_i32_interpreatation(self.registers[rs as usize]);
-        let immediate = u32_to_i32_interpreatation(immediate);
-        let result = op(rs_value, immediate);
-        self.registers[rt as usize] = i32_interpreatation_to_u32(result);
+        let immediate = sign_extend_immediate(immediate);
+        let result = op(rs_value, immediate).ok_or(CpuException::Overflow)?;
+        self.write_register(rt, i32_interpreatation_to_u32(result));
+        return Ok(());
     }
 
 
-    fn load(&mut self, instruction: u32, op: fn(&mut MemoryMapper, u32) -> u32) {
+    fn load(&mut self, instruction: u32, alignment: u32, op: fn(&mut MemoryMapper, u32, Endianness) -> Result<u32, BusError>) -> Result<(), CpuException> {
         let (rs, rt, immediate) = CPU::get_immediate_instructions_values(instruction);
         let index = self.registers[rs as usize];
-        let offset = u32_to_i32_interpreatation(immediate);
-        self.registers[rt as usize] = op(self.memory_mapper, CPU::calculate_address_offset(index, offset))
+        let offset = sign_extend_immediate(immediate);
+        let address = CPU::calculate_address_offset(index, offset);
+        if !address.is_multiple_of(alignment) || !self.address_accessible(address) {
+            return Err(CpuException::AddressErrorLoad(address));
+        }
+        let value = op(self.memory_mapper, address, self.endianness).map_err(|e| CpuException::DataBusError(e.address))?;
+        self.write_loaded_register(rt, value);
+        return Ok(());
     }
 
     fn calculate_address_offset(address: u32, offset: i32) -> u32 {
-        return (address as isize + offset as isize) as u32;
+        return address.wrapping_add(i32_interpreatation_to_u32(offset));
     }
 
-    fn store(&mut self, instruction: u32, op: fn(&mut MemoryMapper, u32, u32)) {
+    fn store(&mut self, instruction: u32, alignment: u32, op: fn(&mut MemoryMapper, u32, u32, Endianness) -> Result<(), BusError>) -> Result<(), CpuException> {
         let (rs, rt, immediate) = CPU::get_immediate_instructions_values(instruction);
         let address = self.registers[rs as usize];
-        let offset = u32_to_i32_interpreatation(immediate);
+        let offset = sign_extend_immediate(immediate);
+        let address = CPU::calculate_address_offset(address, offset);
+        if !address.is_multiple_of(alignment) || !self.address_accessible(address) {
+            return Err(CpuException::AddressErrorStore(address));
+        }
         let signed_content = self.registers[rt as usize];
-        op(self.memory_mapper, signed_content, CPU::calculate_address_offset(address, offset));
+        return op(self.memory_mapper, signed_content, address, self.endianness).map_err(|e| CpuException::DataBusError(e.address));
+    }
+
+    /// Returns rt and the effective address of a load or store
+    fn effective_address(&self, instruction: u32) -> (u8, u32) {
+        let (rs, rt, immediate) = CPU::get_immediate_instructions_values(instruction);
+        let address = CPU::calculate_address_offset(self.registers[rs as usize], sign_extend_immediate(immediate));
+        return (rt, address);
+    }
+
+    /// LWL (`left`) and LWR, merge the part of the aligned word that contains the address into rt.
+    ///
+    /// LWL loads from the addressed byte to the least significant end of the word into the most significant bytes of
+    /// rt, LWR from the most significant end of the word to the addressed byte into the least significant bytes of rt
+    fn load_unaligned(&mut self, instruction: u32, left: bool) -> Result<(), CpuException> {
+        let (rt, address) = self.effective_address(instruction);
+        if !self.address_accessible(address) {
+            return Err(CpuException::AddressErrorLoad(address));
+        }
+        let word = self.memory_mapper.get_word(address & !3).map_err(|e| CpuException::DataBusError(e.address))?;
+        let word = self.endianness.u32_from_bytes(word);
+        // Byte offset of the addressed byte counted from the most significant end of the word
+        let offset = match self.endianness {
+            Endianness::Big => address & 3,
+            Endianness::Little => 3 - (address & 3),
+        };
+        // A LWL/LWR pair merges into the value the previous load is still delivering
+        let rt_value = match self.load_in_flight {
+            Some((register, value)) if register == rt => value,
+            _ => self.registers[rt as usize],
+        };
+        let value = if left {
+            let shift = 8 * offset;
+            (word << shift) | (rt_value & ((1 << shift) - 1))
+        } else {
+            let shift = 8 * (3 - offset);
+            (word >> shift) | (rt_value & !(u32::MAX >> shift))
+        };
+        self.write_loaded_register(rt, value);
+        return Ok(());
+    }
+
+    /// Instructions write the general purpose registers through here so that a delayed load knows it was overwritten
+    fn write_register(&mut self, register: u8, value: u32) {
+        self.registers[register as usize] = value;
+        self.written_registers |= 1 << register;
+    }
+
+    /// Loads and coprocessor moves go through here so that the load delay model can postpone the write
+    fn write_loaded_register(&mut self, register: u8, value: u32) {
+        if self.config.load_delay_slots {
+            self.pending_load = Some((register, value));
+        } else {
+            self.write_register(register, value);
+        }
+    }
+
+    /// SWL (`left`) and SWR, the counterparts of LWL and LWR, only the bytes of the word that belong to the access are written
+    fn store_unaligned(&mut self, instruction: u32, left: bool) -> Result<(), CpuException> {
+        let (rt, address) = self.effective_address(instruction);
+        if !self.address_accessible(address) {
+            return Err(CpuException::AddressErrorStore(address));
+        }
+        let aligned_address = address & !3;
+        let byte = address & 3;
+        let offset = match self.endianness {
+            Endianness::Big => byte,
+            Endianness::Little => 3 - byte,
+        };
+        let rt_value = self.registers[rt as usize];
+        let value = if left { rt_value >> (8 * offset) } else { rt_value << (8 * (3 - offset)) };
+        let lanes = match (left, self.endianness) {
+            (true, Endianness::Big) | (false, Endianness::Little) => byte..=3,
+            (true, Endianness::Little) | (false, Endianness::Big) => 0..=byte,
+        };
+        let bytes = self.endianness.u32_to_bytes(value);
+        for lane in lanes {
+            self.memory_mapper.write_byte(aligned_address + lane, [bytes[lane as usize]]).map_err(|e| CpuException::DataBusError(e.address))?;
+        }
+        return Ok(());
     }
 
     fn branch_instruction(&mut self, instruction: u32, condition: fn(u32, u32) -> bool) {
         let (rs, rt, immediate) = CPU::get_immediate_instructions_values(instruction);
-        let offset = u32_to_i32_interpreatation(immediate);
+        let offset = sign_extend_immediate(immediate);
         if condition(self.registers[rs as usize], self.registers[rt as usize]) {
             self.branch(offset)
         }
@@ -77,16 +533,16 @@ impl<'a> CPU<'a> {
 
     fn branch_instruction_signed_values(&mut self, instruction: u32, condition: fn(i32) -> bool) {
         let (rs, _, immediate) = CPU::get_immediate_instructions_values(instruction);
-        let offset = u32_to_i32_interpreatation(immediate);
+        let offset = sign_extend_immediate(immediate);
         let rs_value = u32_to_i32_interpreatation(self.registers[rs as usize]);
         if condition(rs_value) {
             self.branch(offset)
         }
     }
 
-    fn execute(&mut self, instruction: u32) -> bool {
+    fn execute(&mut self, instruction: u32) -> Result<(), CpuException> {
         let op_code: u8 = (instruction >> 26) as u8;
-        let op_code: Instruction = num::FromPrimitive::from_u8(op_code).unwrap();
+        let op_code: Instruction = num::FromPrimitive::from_u8(op_code).ok_or(CpuException::ReservedInstruction(instruction))?;
         match op_code {
             Instruction::R => {
                 let rs:u8 = ((instruction >> 21) & CPU::REGISTER_MASK) as u8;
@@ -94,95 +550,200 @@ impl<'a> CPU<'a> {
                 let rd:u8 = ((instruction >> 11) & CPU::REGISTER_MASK) as u8;
                 let shift_amount: u8 = ((instruction >> 6) & CPU::REGISTER_MASK) as u8;
                 let function: u8 = (instruction & CPU::FUNCTION_MASK) as u8;
-                return self.alu_operation(rs, rt, rd, shift_amount, function);
+                return self.alu_operation(instruction, rs, rt, rd, shift_amount, function);
             },
-            Instruction::ADDI => self.immediate_signed_op_write_r(instruction, |rs, immediate| rs + immediate),
-            Instruction::ADDIU => self.immediate_unsigned_op_write_r(instruction, |rs, immediate| rs + immediate),
-            Instruction::LB => self.load(instruction, |mm, address| i32_interpreatation_to_u32(i8::from_be_bytes(mm.get_byte(address)) as i32)),
-            Instruction::LBU => self.load(instruction, |mm, address| u8::from_be_bytes(mm.get_byte(address)) as u32),
-            Instruction::LHW => self.load(instruction, |mm, address| i32_interpreatation_to_u32(i16::from_be_bytes(mm.get_half_word(address)) as i32)),
-            Instruction::LHWU => self.load(instruction, |mm, address| u16::from_be_bytes(mm.get_half_word(address)) as u32),
-            Instruction::LW => self.load(instruction, |mm, address| u32::from_be_bytes(mm.get_word(address))),
+            Instruction::ADDI => self.immediate_signed_op_write_r(instruction, |rs, immediate| rs.checked_add(immediate))?,
+            Instruction::ADDIU => self.immediate_signed_op_write_r(instruction, |rs, immediate| Some(rs.wrapping_add(immediate)))?,
+            Instruction::LB => self.load(instruction, 1, |mm, address, _| Ok(i32_interpreatation_to_u32(i8::from_be_bytes(mm.get_byte(address)?) as i32)))?,
+            Instruction::LBU => self.load(instruction, 1, |mm, address, _| Ok(u8::from_be_bytes(mm.get_byte(address)?) as u32))?,
+            Instruction::LHW => self.load(instruction, 2, |mm, address, endianness| Ok(i32_interpreatation_to_u32(endianness.u16_from_bytes(mm.get_half_word(address)?) as i16 as i32)))?,
+            Instruction::LHWU => self.load(instruction, 2, |mm, address, endianness| Ok(endianness.u16_from_bytes(mm.get_half_word(address)?) as u32))?,
+            Instruction::LW => self.load(instruction, 4, |mm, address, endianness| Ok(endianness.u32_from_bytes(mm.get_word(address)?)))?,
             Instruction::LUI => {
                 let (_, rt, immediate) = CPU::get_immediate_instructions_values(instruction);
-                self.registers[rt as usize] = immediate << 16;
+                self.write_register(rt, immediate << 16);
             },
-            Instruction::LWC1 => todo!(),
-            Instruction::LWL => todo!(),
-            Instruction::LWR => todo!(),             // let (rs, rt, immediate) = CPU::get_r_immediate_instructions_values(instruction);
-            Instruction::SB => self.store(instruction, |mm, address, value| mm.write_byte(address, (value as u8).to_be_bytes())),
-            Instruction::SHW => self.store(instruction, |mm, address, value| mm.write_half_word(address, (value as u16).to_be_bytes())),
-            Instruction::SW => self.store(instruction, |mm, address, value| mm.write_word(address, value.to_be_bytes())),
-            Instruction::SWR => todo!(),
-            Instruction::SWL => todo!(),
-            Instruction::SWC1 => todo!(),
+            Instruction::LWC1 => self.load_cop1(instruction)?,
+            Instruction::LWL => self.load_unaligned(instruction, true)?,
+            Instruction::LWR => self.load_unaligned(instruction, false)?,
+            Instruction::SB => self.store(instruction, 1, |mm, value, address, _| mm.write_byte(address, (value as u8).to_be_bytes()))?,
+            Instruction::SHW => self.store(instruction, 2, |mm, value, address, endianness| mm.write_half_word(address, endianness.u16_to_bytes(value as u16)))?,
+            Instruction::SW => self.store(instruction, 4, |mm, value, address, endianness| mm.write_word(address, endianness.u32_to_bytes(value)))?,
+            Instruction::SWR => self.store_unaligned(instruction, false)?,
+            Instruction::SWL => self.store_unaligned(instruction, true)?,
+            Instruction::SWC1 => self.store_cop1(instruction)?,
             Instruction::ANDI => self.immediate_unsigned_op_write_r(instruction, |rs, immediate| rs & immediate),
             Instruction::ORI => self.immediate_unsigned_op_write_r(instruction, |rs, immediate| rs | immediate),
             Instruction::XORI => self.immediate_unsigned_op_write_r(instruction, |rs, immediate| rs ^ immediate),
-            Instruction::SLTI => self.immediate_signed_op_write_r(instruction, |rs, immediate| (rs < immediate) as i32),
-            Instruction::SLTIU => self.immediate_unsigned_op_write_r(instruction, |rs, immediate| (rs < immediate) as u32),
+            Instruction::SLTI => self.immediate_signed_op_write_r(instruction, |rs, immediate| Some((rs < immediate) as i32))?,
+            Instruction::SLTIU => self.immediate_signed_op_write_r(instruction, |rs, immediate| Some((i32_interpreatation_to_u32(rs) < i32_interpreatation_to_u32(immediate)) as i32))?,
             Instruction::BEQ => self.branch_instruction(instruction, |rs, rt| rs == rt),
             Instruction::BNE => self.branch_instruction(instruction, |rs, rt| rs != rt),
             Instruction::BLEZ => self.branch_instruction_signed_values(instruction, |rs| rs <= 0),
-            Instruction::BGTZ => self.branch_instruction_signed_values(instruction, |rs| rs >= 0),
-            Instruction::REGIMM => self.regimm_branching(instruction),
-            Instruction::J => self.pc = self.get_jump_address(instruction),
+            Instruction::BGTZ => self.branch_instruction_signed_values(instruction, |rs| rs > 0),
+            Instruction::REGIMM => self.regimm_branching(instruction)?,
+            Instruction::J => self.jump(self.get_jump_address(instruction)),
             Instruction::JAL => {
-                self.registers[31] = (self.pc + 4) as u32;
-                self.pc = self.get_jump_address(instruction);
+                self.write_register(31, self.return_address());
+                self.jump(self.get_jump_address(instruction));
+            },
+            Instruction::COP0 => self.cop0_operation(instruction)?,
+            Instruction::COP1 => self.cop1_operation(instruction)?,
+
+
+        }
+        return Ok(());
+    }
+
+    fn cop0_operation(&mut self, instruction: u32) -> Result<(), CpuException> {
+        if !self.cop0.coprocessor_usable(0) {
+            return Err(CpuException::CoprocessorUnusable(0));
+        }
+        let rs = ((instruction >> 21) & CPU::REGISTER_MASK) as u8;
+        let rt = ((instruction >> 16) & CPU::REGISTER_MASK) as u8;
+        let rd = ((instruction >> 11) & CPU::REGISTER_MASK) as u8;
+        // With the CO bit set the function field selects the operation
+        if rs & 0b10000 != 0 {
+            if instruction & CPU::FUNCTION_MASK == cop0::RFE_FUNCTION {
+                self.cop0.return_from_exception();
+                return Ok(());
+            }
+            return Err(CpuException::ReservedInstruction(instruction));
+        }
+        let operation: Cop0Operation = num::FromPrimitive::from_u8(rs).ok_or(CpuException::ReservedInstruction(instruction))?;
+        match operation {
+            Cop0Operation::MF => self.write_loaded_register(rt, self.cop0.read_register(rd)),
+            Cop0Operation::MT => self.cop0.write_register(rd, self.registers[rt as usize]),
+        }
+        return Ok(());
+    }
+
+    fn cop1_operation(&mut self, instruction: u32) -> Result<(), CpuException> {
+        if !self.cop0.coprocessor_usable(1) {
+            return Err(CpuException::CoprocessorUnusable(1));
+        }
+        let (selector, rt, immediate) = CPU::get_immediate_instructions_values(instruction);
+        let fs = ((instruction >> 11) & CPU::REGISTER_MASK) as u8;
+        let selector: COP1 = num::FromPrimitive::from_u8(selector).ok_or(CpuException::ReservedInstruction(instruction))?;
+        match selector {
+            COP1::MF => self.write_loaded_register(rt, self.fpu.read_register(fs)),
+            COP1::MT => self.fpu.write_register(fs, self.registers[rt as usize]),
+            COP1::CF => self.write_loaded_register(rt, self.fpu.read_control(fs)),
+            COP1::CT => self.fpu.write_control(fs, self.registers[rt as usize])?,
+            COP1::BC => {
+                // The lowest bit of rt selects between BC1T and BC1F
+                let branch_on_true = rt & 1 != 0;
+                if self.fpu.condition() == branch_on_true {
+                    self.branch(sign_extend_immediate(immediate));
+                }
             },
-            Instruction::COP1 => todo!(),
+            COP1::FMTS | COP1::FMTD | COP1::FMTW => self.fpu.execute(instruction)?,
+        }
+        return Ok(());
+    }
 
+    fn load_cop1(&mut self, instruction: u32) -> Result<(), CpuException> {
+        if !self.cop0.coprocessor_usable(1) {
+            return Err(CpuException::CoprocessorUnusable(1));
+        }
+        let (ft, address) = self.effective_address(instruction);
+        if !address.is_multiple_of(4) || !self.address_accessible(address) {
+            return Err(CpuException::AddressErrorLoad(address));
+        }
+        let word = self.memory_mapper.get_word(address).map_err(|e| CpuException::DataBusError(e.address))?;
+        self.fpu.write_register(ft, self.endianness.u32_from_bytes(word));
+        return Ok(());
+    }
 
+    fn store_cop1(&mut self, instruction: u32) -> Result<(), CpuException> {
+        if !self.cop0.coprocessor_usable(1) {
+            return Err(CpuException::CoprocessorUnusable(1));
+        }
+        let (ft, address) = self.effective_address(instruction);
+        if !address.is_multiple_of(4) || !self.address_accessible(address) {
+            return Err(CpuException::AddressErrorStore(address));
         }
-        return false;
+        let word = self.endianness.u32_to_bytes(self.fpu.read_register(ft));
+        return self.memory_mapper.write_word(address, word).map_err(|e| CpuException::DataBusError(e.address));
     }
 
+    /// The link register always gets written, even when the branch isn't taken. The condition tests rs as it was
+    /// before the link, `bltzal $ra` tests the old return address
     fn branch_al_instruction(&mut self, instruction: u32, condition: fn(i32) -> bool) {
-        self.registers[31] = self.pc + 4;
-        self.branch_instruction_signed_values(instruction, condition);
+        let (rs, _, immediate) = CPU::get_immediate_instructions_values(instruction);
+        let rs_value = u32_to_i32_interpreatation(self.registers[rs as usize]);
+        self.write_register(31, self.return_address());
+        if condition(rs_value) {
+            self.branch(sign_extend_immediate(immediate));
+        }
     }
 
-    fn regimm_branching(&mut self, instruction: u32) {
+    fn regimm_branching(&mut self, instruction: u32) -> Result<(), CpuException> {
         let branch: u8 = ((instruction >> 16) & CPU::REGISTER_MASK) as u8;
-        let branch: Branch = num::FromPrimitive::from_u8(branch).unwrap();
+        let branch: Branch = num::FromPrimitive::from_u8(branch).ok_or(CpuException::ReservedInstruction(instruction))?;
         match branch {
             Branch::BLTZ => self.branch_instruction_signed_values(instruction, |rs| rs < 0),
             Branch::BLTZAL => self.branch_al_instruction(instruction, |rs| rs < 0),
-            Branch::BGEZ => self.branch_instruction_signed_values(instruction, |rs| rs > 0),
-            Branch::BGEZAL => self.branch_al_instruction(instruction, |rs| rs > 0),
+            Branch::BGEZ => self.branch_instruction_signed_values(instruction, |rs| rs >= 0),
+            Branch::BGEZAL => self.branch_al_instruction(instruction, |rs| rs >= 0),
         }
+        return Ok(());
     }
 
 
     fn get_immediate_instructions_values(instruction: u32) -> (u8, u8, u32) {
         let rs = ((instruction >> 21) & CPU::REGISTER_MASK) as u8;
         let rd = ((instruction >> 16) & CPU::REGISTER_MASK) as u8;
-        let immediate = (instruction & CPU::IMMEDIATE_MASK) as u32;
+        let immediate = instruction & CPU::IMMEDIATE_MASK;
         return (rs, rd, immediate);
     }
 
     fn get_jump_address(&self, instruction: u32) -> u32 {
         let pseudo_address = instruction & 0b0000_0011_1111_1111_1111_1111_1111_1111;
-        return pseudo_address << 2 + (self.pc & 0xf0000000);
+        return (pseudo_address << 2) | (self.pc & 0xf0000000);
     }
 
+    /// Branch offsets count instructions from the one that follows the branch, `pc` already points there
     fn branch(&mut self, offset: i32) {
-        let mut pc_content = self.pc as i32;
-        pc_content += offset;
-        self.pc = pc_content as u32;
+        let target = self.pc.wrapping_add(i32_interpreatation_to_u32(offset << 2));
+        self.jump(target);
+    }
+
+    fn jump(&mut self, target: u32) {
+        if self.config.branch_delay_slots {
+            self.delayed_branch = Some(target);
+        } else {
+            self.pc = target;
+        }
+    }
+
+    /// Where a call returns to, past the delay slot when there is one
+    fn return_address(&self) -> u32 {
+        if self.config.branch_delay_slots {
+            return self.pc.wrapping_add(4);
+        }
+        return self.pc;
     }
 
     fn alu_instruction(&mut self, rs:u8, rt:u8, rd:u8, shift_amount: u8, op: fn(i32, i32, u8) -> i32) {
         let signed_rs_content = u32_to_i32_interpreatation(self.registers[rs as usize]);
         let signed_rt_content = u32_to_i32_interpreatation(self.registers[rt as usize]);
-        self.registers[rd as usize] = i32_interpreatation_to_u32(op(signed_rs_content, signed_rt_content, shift_amount));
+        self.write_register(rd, i32_interpreatation_to_u32(op(signed_rs_content, signed_rt_content, shift_amount)));
+    }
+
+    /// Like `alu_instruction` but the operation can trap, `None` means that the operation overflowed
+    fn alu_trapping_instruction(&mut self, rs:u8, rt:u8, rd:u8, op: fn(i32, i32) -> Option<i32>) -> Result<(), CpuException> {
+        let signed_rs_content = u32_to_i32_interpreatation(self.registers[rs as usize]);
+        let signed_rt_content = u32_to_i32_interpreatation(self.registers[rt as usize]);
+        let result = op(signed_rs_content, signed_rt_content).ok_or(CpuException::Overflow)?;
+        self.write_register(rd, i32_interpreatation_to_u32(result));
+        return Ok(());
     }
 
     fn alu_unsigned_instruction(&mut self, rs:u8, rt:u8, rd:u8, shift_amount: u8, op: fn(u32, u32, u8) -> u32) {
         let rs_content = self.registers[rs as usize];
         let rt_content = self.registers[rt as usize];
-        self.registers[rd as usize] = op(rs_content, rt_content, shift_amount);
+        self.write_register(rd, op(rs_content, rt_content, shift_amount));
     }
 
 
@@ -197,79 +758,312 @@ impl<'a> CPU<'a> {
     fn alu_instruction_hi_lo(&mut self, rs:u8, rt:u8, op: fn(i64, i64) -> i64) {
         let rs_value = u32_to_i32_interpreatation(self.registers[rs as usize]) as i64;
         let rt_value = u32_to_i32_interpreatation(self.registers[rt as usize]) as i64;
-        let result = op(rs_value, rt_value) as i64;
+        let result = op(rs_value, rt_value);
         self.lo = i32_interpreatation_to_u32(result as i32);
         self.hi = i32_interpreatation_to_u32((result >> 32) as i32);
     }
 
-    fn alu_operation(&mut self, rs:u8, rt:u8, rd:u8, shift_amount: u8, function: u8) -> bool {
-
-        let function: Function = num::FromPrimitive::from_u8(function).unwrap();
-        if rd == 0 && !matches!(function, Function::SYSCALL)  {
-            panic!("You cannot write on the zero register");
+    /// Division leaves the quotient in lo and the remainder in hi, dividing by zero leaves them untouched
+    fn division(&mut self, rs:u8, rt:u8, signed: bool) {
+        let rs_value = self.registers[rs as usize];
+        let rt_value = self.registers[rt as usize];
+        if rt_value == 0 {
+            return;
+        }
+        if signed {
+            let rs_value = u32_to_i32_interpreatation(rs_value);
+            let rt_value = u32_to_i32_interpreatation(rt_value);
+            self.lo = i32_interpreatation_to_u32(rs_value.wrapping_div(rt_value));
+            self.hi = i32_interpreatation_to_u32(rs_value.wrapping_rem(rt_value));
+        } else {
+            self.lo = rs_value / rt_value;
+            self.hi = rs_value % rt_value;
         }
+    }
+
+    fn alu_operation(&mut self, instruction: u32, rs:u8, rt:u8, rd:u8, shift_amount: u8, function: u8) -> Result<(), CpuException> {
+
+        let function: Function = num::FromPrimitive::from_u8(function).ok_or(CpuException::ReservedInstruction(instruction))?;
         match function {
-            Function::ADD => self.alu_instruction(rs, rt, rd, shift_amount, |rs, rt, _| rs + rt),
-            Function::ADDU => self.alu_unsigned_instruction(rs, rt, rd, shift_amount, |rs, rt, _| rs + rt),
-            Function::SUB => self.alu_instruction(rs, rt, rd, shift_amount, |rs, rt, _| rs - rt),
-            Function::SUBU => self.alu_unsigned_instruction(rs, rt, rd, shift_amount, |rs, rt, _| rs - rt),
+            Function::ADD => self.alu_trapping_instruction(rs, rt, rd, |rs, rt| rs.checked_add(rt))?,
+            Function::ADDU => self.alu_unsigned_instruction(rs, rt, rd, shift_amount, |rs, rt, _| rs.wrapping_add(rt)),
+            Function::SUB => self.alu_trapping_instruction(rs, rt, rd, |rs, rt| rs.checked_sub(rt))?,
+            Function::SUBU => self.alu_unsigned_instruction(rs, rt, rd, shift_amount, |rs, rt, _| rs.wrapping_sub(rt)),
             Function::MULT => self.alu_instruction_hi_lo(rs, rt, |rs, rt| rs * rt),
-            Function::MULTU => self.alu_instruction_hi_lo(rs, rt, |rs, rt| rs * rt),
-            Function::DIV => self.alu_instruction_hi_lo(rs, rt, |rs, rt| rs / rt),
-            Function::DIVU => self.alu_instruction_hi_lo_unsigned(rs, rt, |rs, rt| rs / rt),
+            Function::MULTU => self.alu_instruction_hi_lo_unsigned(rs, rt, |rs, rt| rs * rt),
+            Function::DIV => self.division(rs, rt, true),
+            Function::DIVU => self.division(rs, rt, false),
             Function::AND => self.alu_instruction(rs, rt, rd, shift_amount, |rs, rt, _| rs & rt),
             Function::OR => self.alu_instruction(rs, rt, rd, shift_amount, |rs, rt, _| rs | rt),
             Function::XOR => self.alu_instruction(rs, rt, rd, shift_amount, |rs, rt, _| rs ^ rt),
             Function::NOR => self.alu_instruction(rs, rt, rd, shift_amount, |rs, rt, _| !(rs | rt)),
-            Function::SLL => self.alu_instruction(rs, rt, rd, shift_amount, |_, rt, shift_amount| rt << shift_amount),
-            Function::SRL => self.alu_instruction(rs, rt, rd, shift_amount, |_, rt, shift_amount| rt >> shift_amount),
-            Function::SLLV => self.alu_instruction(rs, rt, rd, shift_amount, |rs, rt, _| rs << rt),
-            Function::SRLV => self.alu_instruction(rs, rt, rd, shift_amount, |rs, rt, _| rs >> rt),
+            Function::SLL => self.alu_unsigned_instruction(rs, rt, rd, shift_amount, |_, rt, shift_amount| rt << shift_amount),
+            Function::SRL => self.alu_unsigned_instruction(rs, rt, rd, shift_amount, |_, rt, shift_amount| rt >> shift_amount),
+            Function::SLLV => self.alu_unsigned_instruction(rs, rt, rd, shift_amount, |rs, rt, _| rt << (rs & 0x1f)),
+            Function::SRLV => self.alu_unsigned_instruction(rs, rt, rd, shift_amount, |rs, rt, _| rt >> (rs & 0x1f)),
             Function::SLT => self.alu_instruction(rs, rt, rd, shift_amount, |rs, rt, _| (rs < rt) as i32),
             Function::SLTU => self.alu_unsigned_instruction(rs, rt, rd, shift_amount, |rs, rt, _| (rs < rt) as u32),
-            Function::SRA => self.alu_unsigned_instruction(rs, rt, rd, shift_amount, |_, rt, shift_amount| rt * 2_u32.pow(shift_amount as u32)),
-            Function::SRAV => self.alu_unsigned_instruction(rs, rt, rd, shift_amount, |rs, rt, _| rs * 2_u32.pow(rt)),
-            Function::BREAK => todo!(),
+            Function::SRA => self.alu_instruction(rs, rt, rd, shift_amount, |_, rt, shift_amount| rt >> shift_amount),
+            Function::SRAV => self.alu_instruction(rs, rt, rd, shift_amount, |rs, rt, _| rt >> (rs & 0x1f)),
+            Function::BREAK => return Err(CpuException::Breakpoint),
             Function::JALR => {
-                self.registers[rd as usize];
-                self.pc = self.registers[rs as usize];
+                let rs_value = self.registers[rs as usize];
+                self.write_register(rd, self.return_address());
+                self.jump(rs_value);
             },
             Function::JR => {
                 let rs_value = self.registers[rs as usize];
-                self.pc = rs_value;
+                self.jump(rs_value);
             },
-            Function::MFHI => self.registers[rd as usize] = self.hi,
-            Function::MFLO => self.registers[rd as usize] = self.lo,
-            Function::MTHI => self.hi = self.registers[rd as usize],
-            Function::MTLO => self.hi = self.registers[rd as usize],
-            Function::SYSCALL => {
-                let code = ((rs as u32) << 10) | ((rt as u32) << 15) | (shift_amount as u32);
-                return match code {
-                    10_u32 => true,
-                    _ => false
+            Function::MFHI => self.write_register(rd, self.hi),
+            Function::MFLO => self.write_register(rd, self.lo),
+            Function::MTHI => self.hi = self.registers[rs as usize],
+            Function::MTLO => self.lo = self.registers[rs as usize],
+            Function::SYSCALL => return self.syscall(),
+            Function::MOVCI => return Err(CpuException::ReservedInstruction(instruction)),
+
+        }
+
+        return Ok(())
+    }
+
+
+    /// Executes a single instruction.
+    ///
+    /// When the instruction faults the exception is returned. If a handler is mapped at the exception vector the
+    /// exception is also delivered through COP0 and `pc` jumps to the handler, otherwise `pc` is left pointing at the
+    /// faulting instruction
+    ///
+    /// An exception in a branch delay slot is reported against the branch, which is where execution restarts
+    pub fn step(&mut self) -> Result<(), CpuException> {
+        // Reads made between two instructions, by a debugger for example, belong to no instruction
+        self.memory_mapper.take_watch_hits();
+        self.memory_mapper.take_accesses();
+        let undo_before = self.prepare_undo();
+        self.sample_interrupts();
+        let pc = self.pc;
+        let traced = self.tracer.as_ref().is_some_and(|tracer| tracer.wants(pc)).then_some((self.registers, self.hi, self.lo));
+        let undo_syscalls = undo_before.is_some().then(|| self.syscall_state_before(pc)).flatten();
+        let branch_target = self.delayed_branch.take();
+        self.load_in_flight = self.pending_load.take();
+        self.written_registers = 0;
+
+        let fetched = self.fetch();
+        let result = fetched.and_then(|instruction| self.execute(instruction));
+        self.collect_watch_hits(pc);
+
+        // The delayed load lands unless the instruction in its delay slot overwrote the register itself
+        if let Some((register, value)) = self.load_in_flight.take() {
+            if self.written_registers & (1 << register) == 0 {
+                self.registers[register as usize] = value;
+            }
+        }
+        // $zero is hardwired, whatever an instruction wrote there is discarded
+        self.registers[0] = 0;
+        let accesses = self.memory_mapper.take_accesses();
+        if self.tracer.is_some() {
+            let record = traced.map(|before| self.trace_record(pc, fetched.ok(), &before, result.err(), &accesses));
+            if let Some(tracer) = self.tracer.as_mut() {
+                tracer.trace(record.as_ref());
+            }
+        }
+        match result {
+            Ok(()) => {
+                if let Some(target) = branch_target {
+                    self.pc = target;
                 }
             },
-            Function::MOVCI => todo!(),
+            Err(exception) => {
+                let in_delay_slot = branch_target.is_some();
+                let restart_pc = if in_delay_slot { pc.wrapping_sub(4) } else { pc };
+                self.pc = restart_pc;
+                self.delayed_branch = None;
+                if self.exception_handler_installed() {
+                    self.pc = self.cop0.enter_exception(exception.exc_code(), restart_pc, exception.bad_address(), in_delay_slot);
+                }
+            },
+        }
+        self.memory_mapper.tick_devices(1);
+        if let Some(before) = undo_before {
+            // The DMA transfers of the devices ticked after the instruction are taken back with it
+            let dma = self.memory_mapper.take_accesses();
+            let writes = accesses.into_iter().chain(dma).filter(|access| access.kind == WatchKind::Write).collect();
+            let record = UndoRecord::new(&before, &self.cpu_state(), undo_syscalls, writes);
+            if let Some(history) = self.history.as_mut() {
+                history.push(record);
+            }
+        }
+        return result;
+    }
 
+    /// Takes the snapshot that is due and saves the state the undo record of the next instruction starts from
+    fn prepare_undo(&mut self) -> Option<CpuState> {
+        if self.history.as_ref()?.wants_snapshot() {
+            let snapshot = self.snapshot();
+            self.history.as_mut()?.push_snapshot(snapshot);
         }
+        return Some(self.cpu_state());
+    }
 
-        return false
+    /// The state of the syscall handler when the instruction at `pc` is a SYSCALL, the only one that changes it
+    fn syscall_state_before(&self, pc: u32) -> Option<Vec<u8>> {
+        let word = self.memory_mapper.fetch_word(pc).map(|bytes| self.endianness.u32_from_bytes(bytes));
+        let syscall = word.is_ok_and(|word| word >> 26 == 0 && word & CPU::FUNCTION_MASK == Function::SYSCALL as u32);
+        return match syscall {
+            true => self.syscall_handler.as_ref().and_then(|handler| handler.save_state()),
+            false => None,
+        };
     }
 
+    /// Latches the hardware interrupt lines into Cause and takes an interrupt exception when one is enabled.
+    ///
+    /// The instruction at pc is the one the handler returns to, when it's in a delay slot the branch executes again.
+    /// A load still in flight lands before the handler runs
+    fn sample_interrupts(&mut self) {
+        self.cop0.set_hardware_interrupts(self.memory_mapper.interrupt_lines());
+        if !self.cop0.interrupt_pending() || !self.exception_handler_installed() {
+            return;
+        }
+        if let Some((register, value)) = self.pending_load.take() {
+            self.registers[register as usize] = value;
+            self.registers[0] = 0;
+        }
+        let in_delay_slot = self.delayed_branch.take().is_some();
+        let epc = if in_delay_slot { self.pc.wrapping_sub(4) } else { self.pc };
+        self.pc = self.cop0.enter_exception(Cop0::EXC_CODE_INTERRUPT, epc, None, in_delay_slot);
+    }
 
-    fn step(&mut self) -> bool {
-        let instruction = self.fetch();
-        return self.execute(instruction);
+    /// An exception handler is considered installed when something is mapped at the current exception vector
+    pub fn exception_handler_installed(&self) -> bool {
+        return self.memory_mapper.find_region(self.cop0.exception_vector()).is_ok();
     }
 
-    pub fn run(&mut self) {
-        let halt = self.step();
-        if !halt {
-            self.run();
+    /// Runs until the program exits, a watchpoint stops it or it raises an exception that no handler can take
+    pub fn run(&mut self) -> Result<(), CpuException> {
+        return match self.run_with_limits(&RunLimits::default()) {
+            StopReason::Exception(exception) => Err(exception),
+            _ => Ok(()),
+        };
+    }
+
+    /// Runs until the program exits or one of the limits is reached.
+    ///
+    /// The instruction at pc always executes, so running again after stopping at a breakpoint makes progress.
+    /// Exceptions that no handler can take, watchpoints and the exit syscall stop it regardless of the limits
+    pub fn run_with_limits(&mut self, limits: &RunLimits) -> StopReason {
+        let started = Instant::now();
+        let mut executed: u64 = 0;
+        loop {
+            if let Some(code) = self.exit_code {
+                return StopReason::Exited(code);
+            }
+            if limits.max_instructions.is_some_and(|limit| executed >= limit) {
+                return StopReason::InstructionLimit;
+            }
+            // Reading the clock costs more than an instruction, only look at it every so often
+            if executed.is_multiple_of(RunLimits::CLOCK_INTERVAL) && limits.max_duration.is_some_and(|limit| started.elapsed() >= limit) {
+                return StopReason::TimeLimit;
+            }
+
+            let result = self.step();
+            executed += 1;
+            if let Some(code) = self.exit_code {
+                return StopReason::Exited(code);
+            }
+            if let Err(exception) = result {
+                let requested = limits.stop_on_exception || (limits.stop_on_break && exception == CpuException::Breakpoint);
+                if requested || !self.exception_handler_installed() {
+                    return StopReason::Exception(exception);
+                }
+            }
+            if self.watch_stop {
+                return StopReason::Watchpoint(self.watch_hits[0]);
+            }
+            if limits.breakpoints.contains(&self.pc) {
+                return StopReason::Breakpoint(self.pc);
+            }
         }
     }
 }
 
+/// When `CPU::run_with_limits` gives control back, by default it only stops for the program
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunLimits {
+    pub max_instructions: Option<u64>,
+    /// Checked every few thousand instructions, the run can go a little over it
+    pub max_duration: Option<Duration>,
+    /// Execution stops when it reaches one of these addresses, before the instruction there runs
+    pub breakpoints: BTreeSet<u32>,
+    /// Stop on BREAK even when the guest has a handler for it
+    pub stop_on_break: bool,
+    /// Stop on every exception, not only the ones no handler can take
+    pub stop_on_exception: bool,
+}
+
+impl RunLimits {
+    const CLOCK_INTERVAL: u64 = 1024;
+}
+
+/// Why `CPU::run_with_limits` returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The program asked to terminate with this code
+    Exited(i32),
+    InstructionLimit,
+    TimeLimit,
+    Breakpoint(u32),
+    /// The first hit of the instruction, `CPU::watch_hits` has all of them
+    Watchpoint(WatchHit),
+    /// pc is at the faulting instruction, or at the guest's handler when it has one
+    Exception(CpuException),
+}
+
+
+/// ABI names of the general purpose registers, indexed by register number
+pub const REGISTER_NAMES: [&str; 32] = [
+    "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3",
+    "t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7",
+    "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7",
+    "t8", "t9", "k0", "k1", "gp", "sp", "fp", "ra",
+];
+
+/// Byte order the CPU uses to read and write multi-byte values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Endianness {
+    Big,
+    Little,
+}
+
+impl Endianness {
+    pub fn u16_from_bytes(&self, bytes: [u8; 2]) -> u16 {
+        return match self {
+            Endianness::Big => u16::from_be_bytes(bytes),
+            Endianness::Little => u16::from_le_bytes(bytes),
+        };
+    }
+
+    pub fn u32_from_bytes(&self, bytes: [u8; 4]) -> u32 {
+        return match self {
+            Endianness::Big => u32::from_be_bytes(bytes),
+            Endianness::Little => u32::from_le_bytes(bytes),
+        };
+    }
+
+    pub fn u16_to_bytes(&self, value: u16) -> [u8; 2] {
+        return match self {
+            Endianness::Big => value.to_be_bytes(),
+            Endianness::Little => value.to_le_bytes(),
+        };
+    }
+
+    pub fn u32_to_bytes(&self, value: u32) -> [u8; 4] {
+        return match self {
+            Endianness::Big => value.to_be_bytes(),
+            Endianness::Little => value.to_le_bytes(),
+        };
+    }
+}
 
 #[derive(FromPrimitive)]
 pub enum Instruction {
@@ -307,6 +1101,8 @@ pub enum Instruction {
     J = 0o02,
     JAL = 0o03,
     BGTZ = 0o07,
+    // Coprocessor instructions
+    COP0 = 0o20,
     COP1 = 0o21,
 }
 
@@ -358,3 +1154,8 @@ pub fn u32_to_i32_interpreatation(value: u32) -> i32 {
 pub fn i32_interpreatation_to_u32(value: i32) -> u32 {
     return u32::from_be_bytes(value.to_be_bytes());
 }
+
+/// Sign extends the 16 bits immediate field of an I instruction
+pub fn sign_extend_immediate(immediate: u32) -> i32 {
+    return immediate as u16 as i16 as i32;
+}
@@ -0,0 +1,361 @@
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::io::{BufRead, Write};
+
+use crate::cpu::{Branch, Function, Instruction, RunLimits, StopReason, CPU, REGISTER_NAMES};
+use crate::disassembler::disassemble_with_symbols;
+use crate::exception::CpuException;
+use crate::history::WriteTarget;
+use crate::machine::parse_number;
+use crate::memory_mapper::{WatchHit, WatchKind, Watchpoint};
+use crate::symbol_table::SymbolTable;
+use crate::trace::TracedRegister;
+
+/// Why the debugger gave control back to the user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugStop {
+    /// The requested instructions have been executed
+    Done,
+    Breakpoint(u32),
+    Watchpoint(WatchHit),
+    Exited(i32),
+    /// An exception that no handler installed in the guest can take
+    Exception(CpuException),
+}
+
+const HELP: &str = "\
+break <location>       stop when execution reaches an address or a symbol (alias b)
+delete <location>      remove a breakpoint (alias d)
+breakpoints            list the breakpoints
+watch <location> [n]   stop after an instruction writes any of n bytes, 4 by default
+rwatch <location> [n]  stop after an instruction reads any of n bytes
+awatch <location> [n]  stop after an instruction reads or writes any of n bytes
+unwatch <location>     remove the watchpoints starting at a location
+step [n]               execute n instructions, 1 by default (alias s)
+next                   like step, but calls run until they return (alias n)
+continue               run until a breakpoint, an exception or the end of the program (alias c)
+finish                 run until the current function returns
+back [n]               take back n instructions, 1 by default (alias bs)
+rcontinue <target> [n] go back to the last write to a register ($t0, $hi...) or to n bytes
+                       at a location, 4 by default (alias rc)
+regs                   show the registers, hi, lo and pc
+x <location> [length]  dump memory, 64 bytes by default and 64k at most
+disas [location] [n]   disassemble n instructions, around pc by default and 4096 at most
+help                   show this message
+quit                   leave the debugger (alias q)
+An empty line repeats the last command, locations are numbers, symbols or symbol+offset";
+
+/// Drives a CPU for the `debug` subcommand, it can also be used directly as a library
+pub struct Debugger<'a> {
+    cpu: CPU<'a>,
+    symbols: SymbolTable,
+    breakpoints: BTreeSet<u32>,
+    last_command: String,
+}
+
+impl<'a> Debugger<'a> {
+    const RA: usize = 31;
+    /// Longest memory dump and disassembly, a typo shouldn't print gigabytes
+    const MAX_DUMP: u32 = 0x1_0000;
+    const MAX_INSTRUCTIONS: u32 = 4096;
+
+    pub fn new(cpu: CPU<'a>, symbols: SymbolTable) -> Self {
+        Debugger { cpu, symbols, breakpoints: BTreeSet::new(), last_command: String::new() }
+    }
+
+    pub fn cpu(&self) -> &CPU<'a> {
+        return &self.cpu;
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU<'a> {
+        return &mut self.cpu;
+    }
+
+    pub fn into_cpu(self) -> CPU<'a> {
+        return self.cpu;
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        return &self.symbols;
+    }
+
+    /// Resolves a number, a symbol or `symbol+offset`
+    pub fn resolve(&self, location: &str) -> Result<u32, String> {
+        let (base, offset) = match location.split_once('+') {
+            Some((base, offset)) => (base.trim(), parse_number(offset.trim())?),
+            None => (location.trim(), 0),
+        };
+        let base = match self.symbols.get(base) {
+            Some(address) => address,
+            None => parse_number(base).map_err(|_| format!("no symbol or address `{}`", base))?,
+        };
+        return Ok(base.wrapping_add(offset));
+    }
+
+    pub fn add_breakpoint(&mut self, address: u32) -> bool {
+        return self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u32) -> bool {
+        return self.breakpoints.remove(&address);
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u32> + '_ {
+        return self.breakpoints.iter().copied();
+    }
+
+    pub fn add_watchpoint(&mut self, kind: WatchKind, address: u32, length: u32) {
+        let watchpoint = Watchpoint { kind, start: address, end: address.saturating_add(length.max(1) - 1) };
+        self.cpu.memory_mapper_mut().add_watchpoint(watchpoint);
+    }
+
+    /// Removes every watchpoint that starts at `address` and returns how many there were
+    pub fn remove_watchpoints(&mut self, address: u32) -> usize {
+        let watchpoints: Vec<Watchpoint> = self.cpu.memory_mapper().watchpoints().iter().filter(|w| w.start == address).copied().collect();
+        for watchpoint in &watchpoints {
+            self.cpu.memory_mapper_mut().remove_watchpoint(watchpoint);
+        }
+        return watchpoints.len();
+    }
+
+    /// Runs with the user's breakpoints and stops quietly at `target`, the instruction at pc always executes
+    fn run(&mut self, mut limits: RunLimits, target: Option<u32>) -> DebugStop {
+        limits.breakpoints.extend(&self.breakpoints);
+        limits.breakpoints.extend(target);
+        return match self.cpu.run_with_limits(&limits) {
+            StopReason::Exited(code) => DebugStop::Exited(code),
+            StopReason::Breakpoint(pc) if self.breakpoints.contains(&pc) => DebugStop::Breakpoint(pc),
+            StopReason::Breakpoint(_) | StopReason::InstructionLimit | StopReason::TimeLimit => DebugStop::Done,
+            StopReason::Watchpoint(hit) => DebugStop::Watchpoint(hit),
+            StopReason::Exception(exception) => DebugStop::Exception(exception),
+        };
+    }
+
+    pub fn step(&mut self, count: u32) -> DebugStop {
+        return self.run(RunLimits { max_instructions: Some(count.max(1) as u64), ..RunLimits::default() }, None);
+    }
+
+    /// Steps over JAL, JALR, BLTZAL and BGEZAL, the call runs until it returns past its delay slot
+    pub fn step_over(&mut self) -> DebugStop {
+        let pc = self.cpu.get_pc();
+        let word = self.cpu.memory_mapper().peek_word(pc).map(|word| self.cpu.endianness().u32_from_bytes(word));
+        if !word.is_ok_and(is_call) {
+            return self.step(1);
+        }
+        let return_address = if self.cpu.config().branch_delay_slots { pc.wrapping_add(8) } else { pc.wrapping_add(4) };
+        return self.run(RunLimits::default(), Some(return_address));
+    }
+
+    pub fn continue_execution(&mut self) -> DebugStop {
+        return self.run(RunLimits::default(), None);
+    }
+
+    /// Runs until the return address of the current function, taken from $ra
+    pub fn finish(&mut self) -> DebugStop {
+        let return_address = self.cpu.get_register_value(Debugger::RA);
+        return self.run(RunLimits::default(), Some(return_address));
+    }
+
+    /// Takes back up to `count` instructions, the CPU has to keep a history
+    pub fn step_back(&mut self, count: u32) -> Result<u64, String> {
+        if self.cpu.history().is_none() {
+            return Err("the history is off".to_owned());
+        }
+        return Ok(self.cpu.step_back(count.max(1) as u64));
+    }
+
+    /// `$t0`, `$8`, `$hi` and `$lo` are registers, anything else is a location
+    fn write_target(&self, target: &str, length: u32) -> Result<WriteTarget, String> {
+        let Some(name) = target.strip_prefix('$') else {
+            return self.resolve(target).map(|address| WriteTarget::Memory { address, size: length });
+        };
+        let register = match name {
+            "hi" => TracedRegister::Hi,
+            "lo" => TracedRegister::Lo,
+            name => {
+                let number = REGISTER_NAMES.iter().position(|register| *register == name).or(name.parse().ok().filter(|number| *number < 32));
+                TracedRegister::Gpr(number.ok_or(format!("no register `{}`", target))? as u8)
+            },
+        };
+        return Ok(WriteTarget::Register(register));
+    }
+
+    fn report_back(&self, count: u64) -> String {
+        return match count {
+            0 => "nothing left to take back".to_owned(),
+            count => format!("took back {} instruction(s)\n{}", count, self.report(DebugStop::Done)),
+        };
+    }
+
+    /// `address <symbol+offset>`
+    fn describe(&self, address: u32) -> String {
+        return match self.symbols.lookup(address) {
+            Some((name, 0)) => format!("{:#010x} <{}>", address, name),
+            Some((name, offset)) => format!("{:#010x} <{}+{:#x}>", address, name, offset),
+            None => format!("{:#010x}", address),
+        };
+    }
+
+    fn disassemble_at(&self, address: u32) -> String {
+        return match self.cpu.memory_mapper().peek_word(address) {
+            Ok(word) => disassemble_with_symbols(self.cpu.endianness().u32_from_bytes(word), address, &self.symbols),
+            Err(_) => "<unmapped>".to_owned(),
+        };
+    }
+
+    pub fn registers(&self) -> String {
+        let mut dump = String::new();
+        for (i, name) in REGISTER_NAMES.iter().enumerate() {
+            let separator = if i % 4 == 3 { "\n" } else { "  " };
+            let _ = write!(dump, "{:>4}={:08x}{}", name, self.cpu.get_register_value(i), separator);
+        }
+        let (hi, lo) = self.cpu.get_hi_lo();
+        let _ = write!(dump, "  hi={:08x}    lo={:08x}    pc={:08x}", hi, lo, self.cpu.get_pc());
+        return dump;
+    }
+
+    /// Hex dump with 16 bytes per line, bytes that aren't mapped are shown as `??`
+    pub fn memory(&self, address: u32, length: u32) -> String {
+        let mut lines = vec![];
+        let end = address.saturating_add(length.min(Debugger::MAX_DUMP));
+        let mut line_start = address;
+        while line_start < end {
+            let line_end = line_start.saturating_add(16).min(end);
+            let bytes: Vec<Option<u8>> = (line_start..line_end).map(|address| self.cpu.memory_mapper().peek_byte(address).ok()).collect();
+            let hex: Vec<String> = bytes.iter().map(|byte| byte.map(|byte| format!("{:02x}", byte)).unwrap_or("??".to_owned())).collect();
+            let text: String = bytes.iter().map(|byte| match byte {
+                Some(byte) if byte.is_ascii_graphic() || *byte == b' ' => *byte as char,
+                _ => '.',
+            }).collect();
+            lines.push(format!("{:08x}: {:<47}  |{}|", line_start, hex.join(" "), text));
+            line_start = line_end;
+        }
+        return lines.join("\n");
+    }
+
+    /// `count` instructions starting at `address`, the one at pc is marked with an arrow
+    pub fn disassemble(&self, address: u32, count: u32) -> String {
+        let pc = self.cpu.get_pc();
+        return (0..count.min(Debugger::MAX_INSTRUCTIONS)).map(|i| {
+            let address = address.wrapping_add(i.wrapping_mul(4));
+            let marker = if address == pc { "=>" } else { "  " };
+            format!("{} {}: {}", marker, self.describe(address), self.disassemble_at(address))
+        }).collect::<Vec<String>>().join("\n");
+    }
+
+    fn report(&self, stop: DebugStop) -> String {
+        let pc = self.cpu.get_pc();
+        let location = format!("{}: {}", self.describe(pc), self.disassemble_at(pc));
+        return match stop {
+            DebugStop::Done => location,
+            DebugStop::Breakpoint(_) => format!("breakpoint at {}", location),
+            DebugStop::Watchpoint(hit) => {
+                let access = match hit.kind {
+                    WatchKind::Write => format!("wrote {:#x} over {:#x}", hit.new_value, hit.old_value),
+                    _ => format!("read {:#x}", hit.old_value),
+                };
+                format!("watchpoint at {}: {} {} bytes at {}, {}\nstopped at {}", self.describe(hit.pc), self.disassemble_at(hit.pc), hit.size, self.describe(hit.address), access, location)
+            },
+            DebugStop::Exited(code) => format!("program exited with code {}", code),
+            DebugStop::Exception(exception) => format!("{} at {}", exception, location),
+        };
+    }
+
+    /// Runs one command of the REPL and returns what to show, `None` when the user wants to leave
+    pub fn execute(&mut self, command: &str) -> Option<String> {
+        let command = match command.trim() {
+            "" => self.last_command.clone(),
+            command => command.to_owned(),
+        };
+        self.last_command = command.clone();
+        let arguments: Vec<&str> = command.split_whitespace().collect();
+        let output = match arguments.as_slice() {
+            [] => Ok(String::new()),
+            ["quit"] | ["q"] => return None,
+            ["help"] | ["h"] => Ok(HELP.to_owned()),
+            ["break" | "b", location] => self.resolve(location).map(|address| match self.add_breakpoint(address) {
+                true => format!("breakpoint at {}", self.describe(address)),
+                false => format!("there is already a breakpoint at {}", self.describe(address)),
+            }),
+            ["delete" | "d", location] => self.resolve(location).map(|address| match self.remove_breakpoint(address) {
+                true => format!("deleted the breakpoint at {}", self.describe(address)),
+                false => format!("no breakpoint at {}", self.describe(address)),
+            }),
+            [command @ ("watch" | "rwatch" | "awatch"), location, ..] if arguments.len() <= 3 => {
+                let kind = match *command {
+                    "watch" => WatchKind::Write,
+                    "rwatch" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                let length = arguments.get(2).map(|length| parse_number(length)).unwrap_or(Ok(4));
+                self.resolve(location).and_then(|address| {
+                    let length = length?;
+                    self.add_watchpoint(kind, address, length);
+                    Ok(format!("watching {} bytes at {}", length, self.describe(address)))
+                })
+            },
+            ["unwatch", location] => self.resolve(location).map(|address| match self.remove_watchpoints(address) {
+                0 => format!("no watchpoint at {}", self.describe(address)),
+                count => format!("deleted {} watchpoint(s) at {}", count, self.describe(address)),
+            }),
+            ["breakpoints"] => Ok(self.breakpoints().map(|address| self.describe(address)).collect::<Vec<String>>().join("\n")),
+            ["step" | "s"] => Ok(self.step(1)).map(|stop| self.report(stop)),
+            ["step" | "s", count] => parse_number(count).map(|count| {
+                let stop = self.step(count);
+                self.report(stop)
+            }),
+            ["next" | "n"] => Ok(self.step_over()).map(|stop| self.report(stop)),
+            ["continue" | "c"] => Ok(self.continue_execution()).map(|stop| self.report(stop)),
+            ["finish"] => Ok(self.finish()).map(|stop| self.report(stop)),
+            ["back" | "bs"] => self.step_back(1).map(|count| self.report_back(count)),
+            ["back" | "bs", count] => parse_number(count).and_then(|count| self.step_back(count)).map(|count| self.report_back(count)),
+            ["rcontinue" | "rc", target, ..] if arguments.len() <= 3 => {
+                let length = arguments.get(2).map(|length| parse_number(length)).unwrap_or(Ok(4));
+                length.and_then(|length| self.write_target(target, length)).and_then(|write_target| {
+                    if self.cpu.history().is_none() {
+                        return Err("the history is off".to_owned());
+                    }
+                    match self.cpu.reverse_to_last_write(write_target) {
+                        Some(count) => Ok(self.report_back(count)),
+                        None => Err(format!("no write to {} in the history", target)),
+                    }
+                })
+            },
+            ["regs"] => Ok(self.registers()),
+            ["x", location] => self.resolve(location).map(|address| self.memory(address, 64)),
+            ["x", location, length] => self.resolve(location).and_then(|address| Ok(self.memory(address, parse_number(length)?))),
+            ["disas"] => Ok(self.disassemble(self.cpu.get_pc().wrapping_sub(8), 6)),
+            ["disas", location] => self.resolve(location).map(|address| self.disassemble(address, 6)),
+            ["disas", location, count] => self.resolve(location).and_then(|address| Ok(self.disassemble(address, parse_number(count)?))),
+            _ => Err(format!("unknown command `{}`, try help", command)),
+        };
+        return Some(output.unwrap_or_else(|error| format!("error: {}", error)));
+    }
+
+    /// Reads commands from `input` until `quit` or the end of the input
+    pub fn repl(&mut self, mut input: impl BufRead, mut output: impl Write) -> std::io::Result<()> {
+        writeln!(output, "{}", self.report(DebugStop::Done))?;
+        loop {
+            write!(output, "(vm32bits) ")?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            match self.execute(&line) {
+                Some(text) if text.is_empty() => {},
+                Some(text) => writeln!(output, "{}", text)?,
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Instructions that save a return address in a register
+fn is_call(word: u32) -> bool {
+    let op_code = word >> 26;
+    let branch = (word >> 16) & 0x1f;
+    return op_code == Instruction::JAL as u32
+        || (op_code == Instruction::R as u32 && word & 0x3f == Function::JALR as u32)
+        || (op_code == Instruction::REGIMM as u32 && (branch == Branch::BLTZAL as u32 || branch == Branch::BGEZAL as u32));
+}
@@ -0,0 +1,229 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::disk::DiskDevice;
+use crate::framebuffer::{FrameDumps, FramebufferDevice};
+use crate::interrupt_controller::InterruptController;
+use crate::keyboard::KeyboardDevice;
+use crate::machine::{self, MachineError};
+use crate::memory::Memory;
+use crate::memory_mapper::{BusError, MemoryMappable};
+use crate::screen_device::ScreenDevice;
+use crate::timer::TimerDevice;
+#[cfg(unix)]
+use crate::uart::{PtySerial, UnixSocketSerial};
+use crate::uart::{MemorySerial, SerialBackend, StdioSerial, UartDevice};
+
+/// A peripheral mapped in the address space of the CPU.
+///
+/// Devices are ticked after every instruction and reset with the machine. Their snapshot hooks are the `save_state`
+/// and `restore_state` methods of `MemoryMappable`.
+///
+/// Registers are big endian words. A byte or half word write changes the bytes it covers and the register is written
+/// as a whole with the other bytes as they read back (see `merge_register`), bits that are cleared by writing 1 read
+/// back as 0 so that only a write covering them clears them. Devices whose registers are bytes say so
+pub trait Device: MemoryMappable {
+    /// The type of the device, as machine descriptions spell it
+    fn name(&self) -> &str;
+
+    /// Bytes of address space the device answers to
+    fn size(&self) -> u32;
+
+    /// Puts the device back in its power-on state
+    fn reset(&mut self) {}
+
+    /// Called after every instruction with the cycles it took
+    fn tick(&mut self, _cycles: u64) {}
+
+    /// Level of the interrupt output, machine descriptions wire it to one of the interrupt lines of the CPU
+    fn interrupt(&self) -> bool {
+        return false;
+    }
+
+    /// A transfer between the device and guest memory, asked for after every tick until it returns `None`
+    fn dma_request(&mut self) -> Option<DmaRequest> {
+        return None;
+    }
+
+    /// How the last transfer went, with the bytes read for `DmaRequest::FromMemory`
+    fn dma_complete(&mut self, _result: Result<Vec<u8>, BusError>) {}
+
+    /// Interrupt controllers get the levels of the interrupt lines before the CPU samples them and return the lines
+    /// that reach the CPU, the other devices return `None`
+    fn route_interrupts(&self, _lines: u8) -> Option<u8> {
+        return None;
+    }
+}
+
+/// The word a byte or half word write at `address` makes of a register reading back `register`
+pub fn merge_register(register: u32, address: u32, bytes: &[u8]) -> u32 {
+    let mut word = register.to_be_bytes();
+    let offset = (address & 3) as usize;
+    word[offset..offset + bytes.len()].copy_from_slice(bytes);
+    return u32::from_be_bytes(word);
+}
+
+/// A DMA transfer, the memory mapper carries it out through the regions that are mapped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DmaRequest {
+    /// Writes `bytes` from `address` on
+    ToMemory { address: u32, bytes: Vec<u8> },
+    /// Reads `length` bytes from `address` on
+    FromMemory { address: u32, length: u32 },
+}
+
+/// One `[[device]]` of a machine description, what isn't the type, base or interrupt line is left to the device
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DeviceConfig {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(deserialize_with = "machine::deserialize_number")]
+    pub base: u32,
+    /// Hardware interrupt line, 0 to 5 for IP2 to IP7
+    #[serde(default)]
+    pub irq: Option<u8>,
+    #[serde(flatten)]
+    pub options: BTreeMap<String, serde_json::Value>,
+}
+
+impl DeviceConfig {
+    pub fn new(kind: &str, base: u32) -> Self {
+        DeviceConfig { kind: kind.to_owned(), base, irq: None, options: BTreeMap::new() }
+    }
+
+    /// A number option, written as an integer or as a string such as "0x1000" or "64k"
+    pub fn number(&self, option: &str) -> Result<Option<u32>, String> {
+        return match self.options.get(option) {
+            None => Ok(None),
+            Some(serde_json::Value::Number(number)) => {
+                number.as_u64().and_then(|number| u32::try_from(number).ok()).map(Some).ok_or(format!("`{}` is out of range", option))
+            },
+            Some(serde_json::Value::String(text)) => machine::parse_number(text).map(Some),
+            Some(_) => Err(format!("`{}` must be a number", option)),
+        };
+    }
+
+    pub fn string(&self, option: &str) -> Result<Option<&str>, String> {
+        return match self.options.get(option) {
+            None => Ok(None),
+            Some(serde_json::Value::String(text)) => Ok(Some(text)),
+            Some(_) => Err(format!("`{}` must be a string", option)),
+        };
+    }
+}
+
+/// Builds a device from its configuration
+pub type DeviceFactory = Box<dyn Fn(&DeviceConfig) -> Result<Box<dyn Device>, String>>;
+
+/// The device types a machine description can use
+pub struct DeviceRegistry {
+    factories: BTreeMap<String, DeviceFactory>,
+}
+
+impl DeviceRegistry {
+    /// A registry with the devices of this crate: ram (with a `size`, a multiple of 4), screen (`columns` by `rows`,
+    /// 32x8 by default, drawn on the terminal unless the `renderer` is none), keyboard (typing an optional `input`
+    /// string instead of reading the host's standard input), timer (counting every `divider` cycles, 1 by default),
+    /// intc, the interrupt controller, disk (backed by the `image` file or by `size` bytes of memory), uart, whose
+    /// `backend` is stdio (the default), pty, socket (listening on `path`) or memory (receiving an optional `input`),
+    /// and framebuffer (`width` by `height`, 320x200 by default, dumping frames to the `dump` pattern every
+    /// `dump_every` frames)
+    pub fn new() -> Self {
+        let mut registry = DeviceRegistry::empty();
+        registry.register("ram", |config| {
+            let size = config.number("size")?.ok_or("ram needs a size")?;
+            if size == 0 {
+                return Err("ram needs a size".to_owned());
+            }
+            if !size.is_multiple_of(4) {
+                return Err(format!("the size of ram must be a multiple of 4, not {}", size));
+            }
+            return Ok(Box::new(Memory::new(size as usize)));
+        });
+        registry.register("screen", |config| {
+            let columns = config.number("columns")?.unwrap_or(ScreenDevice::DEFAULT_COLUMNS);
+            let mut screen = ScreenDevice::new(columns, config.number("rows")?.unwrap_or(ScreenDevice::DEFAULT_ROWS))?;
+            match config.string("renderer")?.unwrap_or("ansi") {
+                "ansi" => {},
+                "none" => screen.set_renderer(None),
+                renderer => return Err(format!("unknown renderer `{}`", renderer)),
+            }
+            return Ok(Box::new(screen));
+        });
+        registry.register("keyboard", |config| {
+            return Ok(match config.string("input")? {
+                Some(input) => Box::new(KeyboardDevice::scripted(input.as_bytes())),
+                None => Box::new(KeyboardDevice::stdin()),
+            });
+        });
+        registry.register("framebuffer", |config| {
+            let (width, height) = (config.number("width")?.unwrap_or(320), config.number("height")?.unwrap_or(200));
+            let mut framebuffer = FramebufferDevice::new(width, height)?;
+            if let Some(pattern) = config.string("dump")? {
+                let every = config.number("dump_every")?.unwrap_or(1);
+                framebuffer.set_dumps(Some(FrameDumps { pattern: pattern.to_owned(), every }));
+            }
+            return Ok(Box::new(framebuffer));
+        });
+        registry.register("intc", |_| Ok(Box::new(InterruptController::new())));
+        registry.register("disk", |config| {
+            if let Some(path) = config.string("image")? {
+                let disk = DiskDevice::open(Path::new(path)).map_err(|error| format!("can't open {}: {}", path, error))?;
+                return Ok(Box::new(disk));
+            }
+            let size = config.number("size")?.ok_or("disk needs an image or a size")?;
+            return Ok(Box::new(DiskDevice::in_memory(vec![0; size as usize])));
+        });
+        registry.register("uart", |config| {
+            let backend: Box<dyn SerialBackend> = match config.string("backend")?.unwrap_or("stdio") {
+                "stdio" => Box::new(StdioSerial::new()),
+                #[cfg(unix)]
+                "pty" => {
+                    let pty = PtySerial::open().map_err(|error| format!("can't open a pseudo terminal: {}", error))?;
+                    eprintln!("uart: connect to {}", pty.path().display());
+                    Box::new(pty)
+                },
+                #[cfg(unix)]
+                "socket" => {
+                    let path = config.string("path")?.ok_or("a socket backend needs a path")?;
+                    Box::new(UnixSocketSerial::bind(Path::new(path)).map_err(|error| format!("can't listen on {}: {}", path, error))?)
+                },
+                "memory" => {
+                    let memory = MemorySerial::new();
+                    memory.send(config.string("input")?.unwrap_or("").as_bytes());
+                    Box::new(memory)
+                },
+                backend => return Err(format!("unknown backend `{}`", backend)),
+            };
+            return Ok(Box::new(UartDevice::new(backend)));
+        });
+        registry.register("timer", |config| Ok(Box::new(TimerDevice::with_divider(config.number("divider")?.unwrap_or(1)))));
+        return registry;
+    }
+
+    pub fn empty() -> Self {
+        DeviceRegistry { factories: BTreeMap::new() }
+    }
+
+    /// Makes `kind` available to machine descriptions, replacing any device registered under the same name
+    pub fn register(&mut self, kind: &str, factory: impl Fn(&DeviceConfig) -> Result<Box<dyn Device>, String> + 'static) {
+        self.factories.insert(kind.to_owned(), Box::new(factory));
+    }
+
+    pub fn kinds(&self) -> impl Iterator<Item = &str> + '_ {
+        return self.factories.keys().map(|kind| kind.as_str());
+    }
+
+    pub fn create(&self, config: &DeviceConfig) -> Result<Box<dyn Device>, MachineError> {
+        let factory = self.factories.get(&config.kind).ok_or_else(|| MachineError::UnknownDevice(config.kind.clone()))?;
+        return factory(config).map_err(|message| MachineError::InvalidDevice { kind: config.kind.clone(), message });
+    }
+}
+
+impl Default for DeviceRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
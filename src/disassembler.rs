@@ -0,0 +1,247 @@
+use crate::cop0::{self, Cop0Operation};
+use crate::cpu::{sign_extend_immediate, Branch, Function, Instruction, REGISTER_NAMES};
+use crate::fpu::{FmtOperation, COMPARE_CONDITIONS, COP1};
+use crate::symbol_table::SymbolTable;
+
+const REGISTER_MASK: u32 = 0b00000011111;
+const FUNCTION_MASK: u32 = 0x0000003f;
+const IMMEDIATE_MASK: u32 = 0x0000ffff;
+const JUMP_INDEX_MASK: u32 = 0x03ff_ffff;
+
+/// Turns an instruction word back into assembly, `pc` is the address of the instruction and is used to resolve
+/// branch and jump targets.
+///
+/// Words that don't decode to a known instruction are shown as a `.word` directive
+pub fn disassemble(word: u32, pc: u32) -> String {
+    return decode(word, pc, &|target| format!("{:#010x}", target));
+}
+
+/// Like `disassemble`, with branch and jump targets shown relative to the closest symbol
+pub fn disassemble_with_symbols(word: u32, pc: u32, symbols: &SymbolTable) -> String {
+    return decode(word, pc, &|target| match symbols.lookup(target) {
+        Some((name, 0)) => name.to_owned(),
+        Some((name, offset)) => format!("{}+{:#x}", name, offset),
+        None => format!("{:#010x}", target),
+    });
+}
+
+/// The fields of an instruction word, named as in the MIPS manuals
+struct Fields {
+    word: u32,
+    rs: u32,
+    rt: u32,
+    rd: u32,
+    shift_amount: u32,
+    function: u32,
+    immediate: u32,
+}
+
+impl Fields {
+    fn new(word: u32) -> Self {
+        Fields {
+            word,
+            rs: (word >> 21) & REGISTER_MASK,
+            rt: (word >> 16) & REGISTER_MASK,
+            rd: (word >> 11) & REGISTER_MASK,
+            shift_amount: (word >> 6) & REGISTER_MASK,
+            function: word & FUNCTION_MASK,
+            immediate: word & IMMEDIATE_MASK,
+        }
+    }
+
+    fn signed_immediate(&self) -> i32 {
+        return sign_extend_immediate(self.immediate);
+    }
+
+    fn branch_target(&self, pc: u32) -> u32 {
+        return pc.wrapping_add(4).wrapping_add((self.signed_immediate() << 2) as u32);
+    }
+}
+
+fn register(register: u32) -> String {
+    return format!("${}", REGISTER_NAMES[register as usize]);
+}
+
+fn float_register(register: u32) -> String {
+    return format!("$f{}", register);
+}
+
+fn unknown(word: u32) -> String {
+    return format!(".word {:#010x}", word);
+}
+
+fn decode(word: u32, pc: u32, target: &dyn Fn(u32) -> String) -> String {
+    let fields = Fields::new(word);
+    let op_code: Option<Instruction> = num::FromPrimitive::from_u32(word >> 26);
+    let op_code = match op_code {
+        Some(op_code) => op_code,
+        None => return unknown(word),
+    };
+    let (rs, rt) = (register(fields.rs), register(fields.rt));
+    return match op_code {
+        Instruction::R => decode_function(&fields),
+        Instruction::ADDI => format!("addi {}, {}, {}", rt, rs, fields.signed_immediate()),
+        Instruction::ADDIU => format!("addiu {}, {}, {}", rt, rs, fields.signed_immediate()),
+        Instruction::SLTI => format!("slti {}, {}, {}", rt, rs, fields.signed_immediate()),
+        Instruction::SLTIU => format!("sltiu {}, {}, {}", rt, rs, fields.signed_immediate()),
+        Instruction::ANDI => format!("andi {}, {}, {:#x}", rt, rs, fields.immediate),
+        Instruction::ORI => format!("ori {}, {}, {:#x}", rt, rs, fields.immediate),
+        Instruction::XORI => format!("xori {}, {}, {:#x}", rt, rs, fields.immediate),
+        Instruction::LUI => format!("lui {}, {:#x}", rt, fields.immediate),
+        Instruction::LB => memory("lb", &rt, &fields),
+        Instruction::LBU => memory("lbu", &rt, &fields),
+        Instruction::LHW => memory("lh", &rt, &fields),
+        Instruction::LHWU => memory("lhu", &rt, &fields),
+        Instruction::LW => memory("lw", &rt, &fields),
+        Instruction::LWL => memory("lwl", &rt, &fields),
+        Instruction::LWR => memory("lwr", &rt, &fields),
+        Instruction::SB => memory("sb", &rt, &fields),
+        Instruction::SHW => memory("sh", &rt, &fields),
+        Instruction::SW => memory("sw", &rt, &fields),
+        Instruction::SWL => memory("swl", &rt, &fields),
+        Instruction::SWR => memory("swr", &rt, &fields),
+        Instruction::LWC1 => memory("lwc1", &float_register(fields.rt), &fields),
+        Instruction::SWC1 => memory("swc1", &float_register(fields.rt), &fields),
+        Instruction::BEQ => format!("beq {}, {}, {}", rs, rt, target(fields.branch_target(pc))),
+        Instruction::BNE => format!("bne {}, {}, {}", rs, rt, target(fields.branch_target(pc))),
+        Instruction::BLEZ => format!("blez {}, {}", rs, target(fields.branch_target(pc))),
+        Instruction::BGTZ => format!("bgtz {}, {}", rs, target(fields.branch_target(pc))),
+        Instruction::REGIMM => {
+            let branch: Option<Branch> = num::FromPrimitive::from_u32(fields.rt);
+            let mnemonic = match branch {
+                Some(Branch::BLTZ) => "bltz",
+                Some(Branch::BGEZ) => "bgez",
+                Some(Branch::BLTZAL) => "bltzal",
+                Some(Branch::BGEZAL) => "bgezal",
+                None => return unknown(word),
+            };
+            format!("{} {}, {}", mnemonic, rs, target(fields.branch_target(pc)))
+        },
+        Instruction::J | Instruction::JAL => {
+            let mnemonic = if word >> 26 == Instruction::J as u32 { "j" } else { "jal" };
+            let address = (pc.wrapping_add(4) & 0xf000_0000) | ((word & JUMP_INDEX_MASK) << 2);
+            format!("{} {}", mnemonic, target(address))
+        },
+        Instruction::COP0 => decode_cop0(&fields),
+        Instruction::COP1 => decode_cop1(&fields, pc, target),
+    };
+}
+
+fn memory(mnemonic: &str, rt: &str, fields: &Fields) -> String {
+    return format!("{} {}, {}({})", mnemonic, rt, fields.signed_immediate(), register(fields.rs));
+}
+
+fn decode_function(fields: &Fields) -> String {
+    if fields.word == 0 {
+        return "nop".to_owned();
+    }
+    let function: Option<Function> = num::FromPrimitive::from_u32(fields.function);
+    let function = match function {
+        Some(function) => function,
+        None => return unknown(fields.word),
+    };
+    let (rs, rt, rd) = (register(fields.rs), register(fields.rt), register(fields.rd));
+    let three = |mnemonic: &str| format!("{} {}, {}, {}", mnemonic, rd, rs, rt);
+    let shift = |mnemonic: &str| format!("{} {}, {}, {}", mnemonic, rd, rt, fields.shift_amount);
+    let variable_shift = |mnemonic: &str| format!("{} {}, {}, {}", mnemonic, rd, rt, rs);
+    return match function {
+        Function::ADD => three("add"),
+        Function::ADDU => three("addu"),
+        Function::SUB => three("sub"),
+        Function::SUBU => three("subu"),
+        Function::AND => three("and"),
+        Function::OR => three("or"),
+        Function::XOR => three("xor"),
+        Function::NOR => three("nor"),
+        Function::SLT => three("slt"),
+        Function::SLTU => three("sltu"),
+        Function::SLL => shift("sll"),
+        Function::SRL => shift("srl"),
+        Function::SRA => shift("sra"),
+        Function::SLLV => variable_shift("sllv"),
+        Function::SRLV => variable_shift("srlv"),
+        Function::SRAV => variable_shift("srav"),
+        Function::MULT => format!("mult {}, {}", rs, rt),
+        Function::MULTU => format!("multu {}, {}", rs, rt),
+        Function::DIV => format!("div {}, {}", rs, rt),
+        Function::DIVU => format!("divu {}, {}", rs, rt),
+        Function::MFHI => format!("mfhi {}", rd),
+        Function::MFLO => format!("mflo {}", rd),
+        Function::MTHI => format!("mthi {}", rs),
+        Function::MTLO => format!("mtlo {}", rs),
+        Function::JR => format!("jr {}", rs),
+        Function::JALR if fields.rd == 31 => format!("jalr {}", rs),
+        Function::JALR => format!("jalr {}, {}", rd, rs),
+        Function::SYSCALL => "syscall".to_owned(),
+        Function::BREAK => match (fields.word >> 6) & 0xfffff {
+            0 => "break".to_owned(),
+            code => format!("break {}", code),
+        },
+        // MOVF/MOVT are MIPS IV, the CPU treats them as reserved
+        Function::MOVCI => unknown(fields.word),
+    };
+}
+
+fn decode_cop0(fields: &Fields) -> String {
+    if fields.rs & 0b10000 != 0 {
+        return match fields.function {
+            cop0::RFE_FUNCTION => "rfe".to_owned(),
+            _ => unknown(fields.word),
+        };
+    }
+    let operation: Option<Cop0Operation> = num::FromPrimitive::from_u32(fields.rs);
+    return match operation {
+        Some(Cop0Operation::MF) => format!("mfc0 {}, ${}", register(fields.rt), fields.rd),
+        Some(Cop0Operation::MT) => format!("mtc0 {}, ${}", register(fields.rt), fields.rd),
+        None => unknown(fields.word),
+    };
+}
+
+fn decode_cop1(fields: &Fields, pc: u32, target: &dyn Fn(u32) -> String) -> String {
+    let format: Option<COP1> = num::FromPrimitive::from_u32(fields.rs);
+    let format = match format {
+        Some(COP1::MF) => return format!("mfc1 {}, {}", register(fields.rt), float_register(fields.rd)),
+        Some(COP1::MT) => return format!("mtc1 {}, {}", register(fields.rt), float_register(fields.rd)),
+        Some(COP1::CF) => return format!("cfc1 {}, ${}", register(fields.rt), fields.rd),
+        Some(COP1::CT) => return format!("ctc1 {}, ${}", register(fields.rt), fields.rd),
+        Some(COP1::BC) => {
+            let mnemonic = if fields.rt & 1 != 0 { "bc1t" } else { "bc1f" };
+            return format!("{} {}", mnemonic, target(fields.branch_target(pc)));
+        },
+        Some(COP1::FMTS) => "s",
+        Some(COP1::FMTD) => "d",
+        Some(COP1::FMTW) => "w",
+        None => return unknown(fields.word),
+    };
+    // In the format instructions fs sits in the rd field and fd in the shift amount field
+    let (ft, fs, fd) = (float_register(fields.rt), float_register(fields.rd), float_register(fields.shift_amount));
+    if fields.function >= 0o60 {
+        return format!("c.{}.{} {}, {}", COMPARE_CONDITIONS[(fields.function & 0xf) as usize], format, fs, ft);
+    }
+    let operation: Option<FmtOperation> = num::FromPrimitive::from_u32(fields.function);
+    let operation = match operation {
+        Some(operation) => operation,
+        None => return unknown(fields.word),
+    };
+    let two = |mnemonic: &str| format!("{}.{} {}, {}", mnemonic, format, fd, fs);
+    let three = |mnemonic: &str| format!("{}.{} {}, {}, {}", mnemonic, format, fd, fs, ft);
+    return match operation {
+        FmtOperation::ADD => three("add"),
+        FmtOperation::SUB => three("sub"),
+        FmtOperation::MUL => three("mul"),
+        FmtOperation::DIV => three("div"),
+        FmtOperation::ABS => two("abs"),
+        FmtOperation::NEG => two("neg"),
+        FmtOperation::MOV => two("mov"),
+        FmtOperation::CVT_S => two("cvt.s"),
+        FmtOperation::CVT_D => two("cvt.d"),
+        FmtOperation::CVT_W => two("cvt.w"),
+        FmtOperation::CEIL_W => two("ceil.w"),
+        FmtOperation::CEIL_L => two("ceil.l"),
+        FmtOperation::MOVN => format!("movn.{} {}, {}, {}", format, fd, fs, register(fields.rt)),
+        FmtOperation::MOVCF => {
+            let mnemonic = if fields.rt & 1 != 0 { "movt" } else { "movf" };
+            format!("{}.{} {}, {}, {}", mnemonic, format, fd, fs, fields.rt >> 2)
+        },
+    };
+}
@@ -0,0 +1,273 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::device::{self, Device, DmaRequest};
+use crate::memory_mapper::{BusError, MemoryMappable};
+use crate::snapshot;
+
+/// A disk controller moving whole sectors between a disk image and guest memory.
+///
+/// The registers are big endian words: the first sector (LBA) at offset 0x00, the guest address of the buffer at 0x04,
+/// the number of sectors at 0x08, the command at 0x0c, the status at 0x10 and the size of the disk in sectors at
+/// 0x14. Writing `COMMAND_READ` or `COMMAND_WRITE` starts a transfer that is carried out by DMA after the instruction,
+/// then the done bit of the status is set along with the error bit when the sectors are out of the disk, the buffer
+/// isn't mapped or the image can't be accessed. Writing 1 to the done bit clears it, the error bit and the interrupt,
+/// which is raised while done is set and interrupts are enabled. The command register reads back as 0, so a byte
+/// written to its last byte is a whole command
+pub struct DiskDevice {
+    image: DiskImage,
+    sectors: u32,
+    sector: u32,
+    buffer: u32,
+    count: u32,
+    status: u32,
+    /// The command waiting for its DMA transfer
+    pending: Option<u32>,
+}
+
+enum DiskImage {
+    Memory(Vec<u8>),
+    File(File),
+}
+
+impl DiskDevice {
+    pub const SECTOR_SIZE: u32 = 512;
+
+    pub const COMMAND_READ: u32 = 1;
+    pub const COMMAND_WRITE: u32 = 2;
+
+    pub const STATUS_BUSY: u32 = 1 << 0;
+    pub const STATUS_DONE: u32 = 1 << 1;
+    pub const STATUS_ERROR: u32 = 1 << 2;
+    pub const STATUS_INTERRUPT_ENABLE: u32 = 1 << 3;
+
+    const SECTOR: u32 = 0x00;
+    const BUFFER: u32 = 0x04;
+    const COUNT: u32 = 0x08;
+    const COMMAND: u32 = 0x0c;
+    const STATUS: u32 = 0x10;
+    const SECTORS: u32 = 0x14;
+
+    /// A disk kept in memory, for tests and scratch disks. The image is padded to a whole number of sectors
+    pub fn in_memory(mut image: Vec<u8>) -> Self {
+        image.resize(image.len().next_multiple_of(DiskDevice::SECTOR_SIZE as usize), 0);
+        let sectors = (image.len() / DiskDevice::SECTOR_SIZE as usize) as u32;
+        return DiskDevice::with_image(DiskImage::Memory(image), sectors);
+    }
+
+    /// A disk backed by an image file on the host, writes go straight to the file. A partial sector at the end of the
+    /// file is out of the disk
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let sectors = u32::try_from(file.metadata()?.len() / DiskDevice::SECTOR_SIZE as u64).unwrap_or(u32::MAX);
+        return Ok(DiskDevice::with_image(DiskImage::File(file), sectors));
+    }
+
+    fn with_image(image: DiskImage, sectors: u32) -> Self {
+        DiskDevice { image, sectors, sector: 0, buffer: 0, count: 0, status: 0, pending: None }
+    }
+
+    pub fn sectors(&self) -> u32 {
+        return self.sectors;
+    }
+
+    /// The content of an in-memory disk
+    pub fn image(&self) -> Option<&[u8]> {
+        return match &self.image {
+            DiskImage::Memory(image) => Some(image),
+            DiskImage::File(_) => None,
+        };
+    }
+
+    fn read_register(&self, address: u32) -> u32 {
+        return match address & !3 {
+            DiskDevice::SECTOR => self.sector,
+            DiskDevice::BUFFER => self.buffer,
+            DiskDevice::COUNT => self.count,
+            DiskDevice::STATUS => self.status,
+            DiskDevice::SECTORS => self.sectors,
+            _ => 0,
+        };
+    }
+
+    fn write_register(&mut self, address: u32, value: u32) {
+        // The registers of a transfer can't change while it's in progress
+        let busy = self.status & DiskDevice::STATUS_BUSY != 0;
+        match address & !3 {
+            DiskDevice::SECTOR if !busy => self.sector = value,
+            DiskDevice::BUFFER if !busy => self.buffer = value,
+            DiskDevice::COUNT if !busy => self.count = value,
+            DiskDevice::COMMAND if !busy => self.start(value),
+            DiskDevice::STATUS => {
+                let mut status = self.status & !DiskDevice::STATUS_INTERRUPT_ENABLE;
+                if value & DiskDevice::STATUS_DONE != 0 {
+                    status &= !(DiskDevice::STATUS_DONE | DiskDevice::STATUS_ERROR);
+                }
+                self.status = status | (value & DiskDevice::STATUS_INTERRUPT_ENABLE);
+            },
+            _ => {},
+        }
+    }
+
+    /// Merges a byte or half word write into the register it belongs to
+    fn write_bytes(&mut self, address: u32, bytes: &[u8]) {
+        let mut word = self.read_register(address);
+        if address & !3 == DiskDevice::STATUS {
+            // Only a write to the byte with the done bit acknowledges the transfer
+            word &= !DiskDevice::STATUS_DONE;
+        }
+        self.write_register(address, device::merge_register(word, address, bytes));
+    }
+
+    fn start(&mut self, command: u32) {
+        self.status &= !(DiskDevice::STATUS_DONE | DiskDevice::STATUS_ERROR);
+        let in_disk = self.sector.checked_add(self.count).is_some_and(|end| end <= self.sectors)
+            && self.count.checked_mul(DiskDevice::SECTOR_SIZE).is_some();
+        let known = command == DiskDevice::COMMAND_READ || command == DiskDevice::COMMAND_WRITE;
+        if !known || !in_disk || self.count == 0 {
+            self.status |= DiskDevice::STATUS_DONE | DiskDevice::STATUS_ERROR;
+            return;
+        }
+        self.status |= DiskDevice::STATUS_BUSY;
+        self.pending = Some(command);
+    }
+
+    fn finish(&mut self, succeeded: bool) {
+        self.status &= !DiskDevice::STATUS_BUSY;
+        self.status |= DiskDevice::STATUS_DONE;
+        if !succeeded {
+            self.status |= DiskDevice::STATUS_ERROR;
+        }
+    }
+
+    fn read_sectors(&mut self) -> io::Result<Vec<u8>> {
+        let (offset, length) = self.transfer_range();
+        return match &mut self.image {
+            DiskImage::Memory(image) => Ok(image[offset as usize..(offset + length) as usize].to_vec()),
+            DiskImage::File(file) => {
+                let mut bytes = vec![0; length as usize];
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_exact(&mut bytes)?;
+                Ok(bytes)
+            },
+        };
+    }
+
+    fn write_sectors(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let (offset, _) = self.transfer_range();
+        return match &mut self.image {
+            DiskImage::Memory(image) => {
+                image[offset as usize..offset as usize + bytes.len()].copy_from_slice(bytes);
+                Ok(())
+            },
+            DiskImage::File(file) => {
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_all(bytes)?;
+                file.flush()
+            },
+        };
+    }
+
+    /// Offset and length in the image of the sectors of the current transfer
+    fn transfer_range(&self) -> (u64, u64) {
+        let sector_size = DiskDevice::SECTOR_SIZE as u64;
+        return (self.sector as u64 * sector_size, self.count as u64 * sector_size);
+    }
+}
+
+impl MemoryMappable for DiskDevice {
+    fn get_byte(&self, address: u32) -> [u8; 1] {
+        return [self.read_register(address).to_be_bytes()[(address & 3) as usize]];
+    }
+
+    fn get_half_word(&self, address: u32) -> [u8; 2] {
+        let word = self.read_register(address).to_be_bytes();
+        let offset = (address & 2) as usize;
+        return [word[offset], word[offset + 1]];
+    }
+
+    fn get_word(&self, address: u32) -> [u8; 4] {
+        return self.read_register(address).to_be_bytes();
+    }
+
+    fn write_byte(&mut self, address: u32, value: [u8; 1]) {
+        self.write_bytes(address, &value);
+    }
+
+    fn write_half_word(&mut self, address: u32, value: [u8; 2]) {
+        self.write_bytes(address, &value);
+    }
+
+    fn write_word(&mut self, address: u32, value: [u8; 4]) {
+        self.write_register(address, u32::from_be_bytes(value));
+    }
+
+    /// The registers as little endian words, the content of the disk isn't part of the machine. Transfers are carried
+    /// out after the instruction that starts them so there is never one pending between two instructions
+    fn save_state(&self) -> Option<Vec<u8>> {
+        let registers = [self.sector, self.buffer, self.count, self.status];
+        return Some(registers.iter().flat_map(|register| register.to_le_bytes()).collect());
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> io::Result<()> {
+        if state.len() != 16 {
+            return Err(snapshot::invalid_snapshot("invalid disk state"));
+        }
+        let word = |i: usize| u32::from_le_bytes(state[i * 4..i * 4 + 4].try_into().unwrap());
+        self.sector = word(0);
+        self.buffer = word(1);
+        self.count = word(2);
+        self.status = word(3) & !DiskDevice::STATUS_BUSY;
+        self.pending = None;
+        return Ok(());
+    }
+}
+
+impl Device for DiskDevice {
+    fn name(&self) -> &str {
+        return "disk";
+    }
+
+    fn size(&self) -> u32 {
+        return 0x18;
+    }
+
+    fn reset(&mut self) {
+        self.sector = 0;
+        self.buffer = 0;
+        self.count = 0;
+        self.status = 0;
+        self.pending = None;
+    }
+
+    fn dma_request(&mut self) -> Option<DmaRequest> {
+        return match self.pending? {
+            DiskDevice::COMMAND_READ => match self.read_sectors() {
+                Ok(bytes) => Some(DmaRequest::ToMemory { address: self.buffer, bytes }),
+                Err(_) => {
+                    self.pending = None;
+                    self.finish(false);
+                    None
+                },
+            },
+            _ => Some(DmaRequest::FromMemory { address: self.buffer, length: self.count * DiskDevice::SECTOR_SIZE }),
+        };
+    }
+
+    fn dma_complete(&mut self, result: Result<Vec<u8>, BusError>) {
+        let Some(command) = self.pending.take() else {
+            return;
+        };
+        let succeeded = match (command, result) {
+            (DiskDevice::COMMAND_WRITE, Ok(bytes)) => self.write_sectors(&bytes).is_ok(),
+            (_, result) => result.is_ok(),
+        };
+        self.finish(succeeded);
+    }
+
+    fn interrupt(&self) -> bool {
+        let done = DiskDevice::STATUS_DONE | DiskDevice::STATUS_INTERRUPT_ENABLE;
+        return self.status & done == done;
+    }
+}
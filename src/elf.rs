@@ -0,0 +1,222 @@
+use std::fmt;
+
+use crate::cpu::{Endianness, CPU};
+use crate::memory_mapper::{BusError, MemoryMapper};
+use crate::symbol_table::SymbolTable;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    Truncated,
+    NotElf,
+    /// Only ELFCLASS32 files can run on a 32 bits CPU
+    Not32Bit,
+    InvalidEndianness(u8),
+    NotMips(u16),
+    NotExecutable(u16),
+    /// A segment whose file size is bigger than its memory size or that wraps around the address space
+    InvalidSegment(u32),
+    Bus(BusError),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::Truncated => write!(f, "the file is truncated"),
+            ElfError::NotElf => write!(f, "not an ELF file"),
+            ElfError::Not32Bit => write!(f, "only 32 bit ELF files are supported"),
+            ElfError::InvalidEndianness(data) => write!(f, "invalid data encoding {}", data),
+            ElfError::NotMips(machine) => write!(f, "not a MIPS executable (machine {})", machine),
+            ElfError::NotExecutable(kind) => write!(f, "not an executable (type {})", kind),
+            ElfError::InvalidSegment(address) => write!(f, "invalid segment at {:#010x}", address),
+            ElfError::Bus(error) => write!(f, "segment overlaps a device at {:#010x}", error.address),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+/// Where a `PT_LOAD` segment was placed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadedSegment {
+    pub address: u32,
+    pub file_size: u32,
+    pub memory_size: u32,
+}
+
+/// What the loader learnt from the file, used to set up the CPU and for debugging
+#[derive(Debug, Clone)]
+pub struct ElfProgram {
+    pub entry: u32,
+    pub endianness: Endianness,
+    pub segments: Vec<LoadedSegment>,
+    pub symbols: SymbolTable,
+    pub stack_pointer: u32,
+    pub global_pointer: u32,
+}
+
+impl ElfProgram {
+    /// Initial stack pointer, the same one SPIM uses
+    pub const STACK_POINTER: u32 = 0x7fff_effc;
+    /// Used when the file doesn't define `_gp`, the middle of the first 64KiB of data
+    pub const DEFAULT_GLOBAL_POINTER: u32 = 0x1000_8000;
+
+    const STACK_SIZE: u32 = 0x10_0000;
+    /// The lowest address of the stack `map_stack` maps
+    pub const STACK_BOTTOM: u32 = (ElfProgram::STACK_POINTER | (MemoryMapper::PAGE_SIZE - 1)) + 1 - ElfProgram::STACK_SIZE;
+
+    /// Maps the memory below `STACK_POINTER`, programs that don't come from an ELF file can use it too
+    pub fn map_stack(memory_mapper: &mut MemoryMapper) {
+        memory_mapper.map_ram(ElfProgram::STACK_BOTTOM, ElfProgram::STACK_SIZE);
+    }
+
+    /// Points the CPU at the entry point with $sp and $gp set up for the program
+    pub fn prepare_cpu(&self, cpu: &mut CPU) {
+        cpu.set_endianness(self.endianness);
+        cpu.set_pc(self.entry);
+        cpu.set_register_value(29, self.stack_pointer);
+        cpu.set_register_value(28, self.global_pointer);
+    }
+}
+
+const EM_MIPS: u16 = 8;
+const EM_MIPS_RS3_LE: u16 = 10;
+const ET_EXEC: u16 = 2;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+
+/// Reads the fields of the file with the endianness it declares
+struct Reader<'a> {
+    bytes: &'a [u8],
+    endianness: Endianness,
+}
+
+impl<'a> Reader<'a> {
+    fn slice(&self, offset: u32, size: u32) -> Result<&'a [u8], ElfError> {
+        let end = offset.checked_add(size).ok_or(ElfError::Truncated)?;
+        return self.bytes.get(offset as usize..end as usize).ok_or(ElfError::Truncated);
+    }
+
+    fn u16(&self, offset: u32) -> Result<u16, ElfError> {
+        let bytes = self.slice(offset, 2)?;
+        return Ok(self.endianness.u16_from_bytes([bytes[0], bytes[1]]));
+    }
+
+    fn u32(&self, offset: u32) -> Result<u32, ElfError> {
+        let bytes = self.slice(offset, 4)?;
+        return Ok(self.endianness.u32_from_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+    }
+
+    /// A NUL terminated string of a string table
+    fn string(&self, offset: u32) -> Result<String, ElfError> {
+        let bytes = self.bytes.get(offset as usize..).ok_or(ElfError::Truncated)?;
+        let end = bytes.iter().position(|byte| *byte == 0).ok_or(ElfError::Truncated)?;
+        return Ok(String::from_utf8_lossy(&bytes[..end]).into_owned());
+    }
+}
+
+/// The file offset of a field, offsets that wrap around can only come from a broken file
+fn field(base: u32, offset: u32) -> Result<u32, ElfError> {
+    return base.checked_add(offset).ok_or(ElfError::Truncated);
+}
+
+/// The file offset of entry `index` of a table of headers
+fn table_entry(table: u32, index: u32, size: u32) -> Result<u32, ElfError> {
+    return field(table, index.checked_mul(size).ok_or(ElfError::Truncated)?);
+}
+
+/// Loads the `PT_LOAD` segments of an ELF32 MIPS executable, mapping memory where nothing is mapped yet
+pub fn load(bytes: &[u8], memory_mapper: &mut MemoryMapper) -> Result<ElfProgram, ElfError> {
+    let identification = bytes.get(..16).ok_or(ElfError::Truncated)?;
+    if identification[..4] != [0x7f, b'E', b'L', b'F'] {
+        return Err(ElfError::NotElf);
+    }
+    if identification[4] != 1 {
+        return Err(ElfError::Not32Bit);
+    }
+    let endianness = match identification[5] {
+        1 => Endianness::Little,
+        2 => Endianness::Big,
+        data => return Err(ElfError::InvalidEndianness(data)),
+    };
+    let reader = Reader { bytes, endianness };
+
+    let kind = reader.u16(16)?;
+    let machine = reader.u16(18)?;
+    if machine != EM_MIPS && machine != EM_MIPS_RS3_LE {
+        return Err(ElfError::NotMips(machine));
+    }
+    if kind != ET_EXEC {
+        return Err(ElfError::NotExecutable(kind));
+    }
+    let entry = reader.u32(24)?;
+
+    let program_headers = reader.u32(28)?;
+    let program_header_size = reader.u16(42)? as u32;
+    let program_header_count = reader.u16(44)? as u32;
+    let mut segments = vec![];
+    for i in 0..program_header_count {
+        let header = table_entry(program_headers, i, program_header_size)?;
+        if reader.u32(header)? != PT_LOAD {
+            continue;
+        }
+        let offset = reader.u32(field(header, 4)?)?;
+        let segment = LoadedSegment {
+            address: reader.u32(field(header, 8)?)?,
+            file_size: reader.u32(field(header, 16)?)?,
+            memory_size: reader.u32(field(header, 20)?)?,
+        };
+        if segment.file_size > segment.memory_size || segment.address.checked_add(segment.memory_size).is_none() {
+            return Err(ElfError::InvalidSegment(segment.address));
+        }
+        let contents = reader.slice(offset, segment.file_size)?;
+        load_segment(memory_mapper, &segment, contents).map_err(ElfError::Bus)?;
+        segments.push(segment);
+    }
+
+    let symbols = read_symbols(&reader)?;
+    let global_pointer = symbols.get("_gp").unwrap_or(ElfProgram::DEFAULT_GLOBAL_POINTER);
+    ElfProgram::map_stack(memory_mapper);
+    return Ok(ElfProgram { entry, endianness, segments, symbols, stack_pointer: ElfProgram::STACK_POINTER, global_pointer });
+}
+
+/// Copies the file contents of the segment and zeroes the rest of it, that's where `.bss` lives
+fn load_segment(memory_mapper: &mut MemoryMapper, segment: &LoadedSegment, contents: &[u8]) -> Result<(), BusError> {
+    memory_mapper.map_ram(segment.address, segment.memory_size);
+    for offset in 0..segment.memory_size {
+        let byte = contents.get(offset as usize).copied().unwrap_or(0);
+        memory_mapper.write_byte(segment.address + offset, [byte])?;
+    }
+    return Ok(());
+}
+
+/// Collects the named symbols defined in `.symtab`, files that have been stripped give an empty table
+fn read_symbols(reader: &Reader) -> Result<SymbolTable, ElfError> {
+    let mut symbols = SymbolTable::new();
+    let section_headers = reader.u32(32)?;
+    let section_header_size = reader.u16(46)? as u32;
+    let section_header_count = reader.u16(48)? as u32;
+    for i in 0..section_header_count {
+        let header = table_entry(section_headers, i, section_header_size)?;
+        if reader.u32(field(header, 4)?)? != SHT_SYMTAB {
+            continue;
+        }
+        let offset = reader.u32(field(header, 16)?)?;
+        let size = reader.u32(field(header, 20)?)?;
+        let link = reader.u32(field(header, 24)?)?;
+        let entry_size = reader.u32(field(header, 36)?)?.max(16);
+        let strings = reader.u32(field(table_entry(section_headers, link, section_header_size)?, 16)?)?;
+        // Entry 0 is always the undefined symbol
+        for entry in (field(offset, entry_size)?..field(offset, size)?).step_by(entry_size as usize) {
+            let name = reader.u32(entry)?;
+            let value = reader.u32(field(entry, 4)?)?;
+            let info = reader.slice(field(entry, 12)?, 1)?[0];
+            let section = reader.u16(field(entry, 14)?)?;
+            // Undefined symbols and the names of sections and files aren't interesting for debugging
+            if name == 0 || section == 0 || !matches!(info & 0xf, 0..=2) {
+                continue;
+            }
+            symbols.insert(&reader.string(field(strings, name)?)?, value);
+        }
+    }
+    return Ok(symbols);
+}
@@ -0,0 +1,64 @@
+use std::fmt;
+
+/// The synchronous exceptions a MIPS I CPU can raise while executing an instruction.
+///
+/// Each variant carries whatever the exception handler needs to know about the fault
+/// (the offending address or instruction word).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuException {
+    AddressErrorLoad(u32),
+    AddressErrorStore(u32),
+    InstructionBusError(u32),
+    DataBusError(u32),
+    Syscall,
+    Breakpoint,
+    ReservedInstruction(u32),
+    CoprocessorUnusable(u8),
+    Overflow,
+    FloatingPoint,
+}
+
+impl CpuException {
+    /// The value that the exception stores in the ExcCode field of the Cause register
+    pub fn exc_code(&self) -> u32 {
+        return match self {
+            CpuException::AddressErrorLoad(_) => 4,
+            CpuException::AddressErrorStore(_) => 5,
+            CpuException::InstructionBusError(_) => 6,
+            CpuException::DataBusError(_) => 7,
+            CpuException::Syscall => 8,
+            CpuException::Breakpoint => 9,
+            CpuException::ReservedInstruction(_) => 10,
+            CpuException::CoprocessorUnusable(_) => 11,
+            CpuException::Overflow => 12,
+            CpuException::FloatingPoint => 15,
+        };
+    }
+
+    /// The address that goes to BadVAddr, only address errors set it
+    pub fn bad_address(&self) -> Option<u32> {
+        return match self {
+            CpuException::AddressErrorLoad(address) | CpuException::AddressErrorStore(address) => Some(*address),
+            _ => None,
+        };
+    }
+}
+
+impl fmt::Display for CpuException {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpuException::AddressErrorLoad(address) => write!(f, "address error on load/fetch at {:#010x}", address),
+            CpuException::AddressErrorStore(address) => write!(f, "address error on store at {:#010x}", address),
+            CpuException::InstructionBusError(address) => write!(f, "bus error on instruction fetch at {:#010x}", address),
+            CpuException::DataBusError(address) => write!(f, "bus error on data access at {:#010x}", address),
+            CpuException::Syscall => write!(f, "syscall"),
+            CpuException::Breakpoint => write!(f, "breakpoint"),
+            CpuException::ReservedInstruction(instruction) => write!(f, "reserved instruction {:#010x}", instruction),
+            CpuException::CoprocessorUnusable(coprocessor) => write!(f, "coprocessor {} unusable", coprocessor),
+            CpuException::Overflow => write!(f, "arithmetic overflow"),
+            CpuException::FloatingPoint => write!(f, "floating point exception"),
+        }
+    }
+}
+
+impl std::error::Error for CpuException {}
@@ -1,29 +1,474 @@
 use num_derive::FromPrimitive;
+use num_traits::Float;
 
+use crate::exception::CpuException;
+
+/// Coprocessor 1, the floating point unit.
+///
+/// The 32 registers hold raw bits, a double precision value lives in an even/odd pair of registers with the least
+/// significant word in the even register
 pub struct FPU {
-    registers: [f32; 32]
+    registers: [u32; 32],
+    fcsr: u32
 }
 
 impl FPU {
-    fn execute(&mut self, instruction: u32) {
-        let cop1_selector = (instruction & 0b11111_00000_00000_00000_00000_000000) >> 21;
-        let selector: COP1 = num::FromPrimitive::from_u32(instruction).unwrap();
-        match selector {
-            COP1::FMTS => todo!(),
-            COP1::FMTW => todo!(),
-            COP1::BC => todo!(),
-            COP1::MF => todo!(),
-            COP1::MT => todo!(),
-            COP1::CF => todo!(),
-            COP1::CT => todo!(),
+    const FUNCTION_MASK: u32 = 0x0000003f;
+    const REGISTER_MASK: u32 = 0b00000011111;
+
+    const ROUNDING_MODE_MASK: u32 = 0b11;
+    const FLAGS_SHIFT: u32 = 2;
+    const ENABLES_SHIFT: u32 = 7;
+    const CAUSE_SHIFT: u32 = 12;
+    const CONDITION: u32 = 1 << 23;
+    const FCSR_WRITABLE_MASK: u32 = 0x0183_ffff;
+    /// Implementation/revision register, reports an R3010 compatible unit
+    const FIR: u32 = 0x0000_0300;
+
+    /// Default NaNs produced by invalid operations, MIPS uses the legacy encoding where the quiet bit is clear
+    const DEFAULT_NAN_SINGLE: u32 = 0x7fbf_ffff;
+    const DEFAULT_NAN_DOUBLE: u64 = 0x7ff7_ffff_ffff_ffff;
+
+    pub fn new() -> Self {
+        FPU { registers: [0; 32], fcsr: 0 }
+    }
+
+    pub fn read_register(&self, register: u8) -> u32 {
+        return self.registers[register as usize];
+    }
+
+    pub fn write_register(&mut self, register: u8, value: u32) {
+        self.registers[register as usize] = value;
+    }
+
+    pub fn read_single(&self, register: u8) -> f32 {
+        return f32::from_bits(self.registers[register as usize]);
+    }
+
+    pub fn write_single(&mut self, register: u8, value: f32) {
+        self.registers[register as usize] = value.to_bits();
+    }
+
+    /// Reads the double held by the pair starting at the even `register`
+    pub fn read_double(&self, register: u8) -> f64 {
+        return f64::from_bits(self.read_pair(register));
+    }
+
+    pub fn write_double(&mut self, register: u8, value: f64) {
+        self.write_pair(register, value.to_bits());
+    }
+
+    fn read_pair(&self, register: u8) -> u64 {
+        let register = (register & !1) as usize;
+        return ((self.registers[register + 1] as u64) << 32) | self.registers[register] as u64;
+    }
+
+    fn write_pair(&mut self, register: u8, value: u64) {
+        let register = (register & !1) as usize;
+        self.registers[register] = value as u32;
+        self.registers[register + 1] = (value >> 32) as u32;
+    }
+
+    /// CFC1, only the implementation register (0) and the control/status register (31) exist
+    pub fn read_control(&self, register: u8) -> u32 {
+        return match register {
+            0 => FPU::FIR,
+            31 => self.fcsr,
+            _ => 0,
+        };
+    }
+
+    /// CTC1, writing cause bits that are enabled raises a floating point exception straight away
+    pub fn write_control(&mut self, register: u8, value: u32) -> Result<(), CpuException> {
+        if register != 31 {
+            return Ok(());
+        }
+        self.fcsr = value & FPU::FCSR_WRITABLE_MASK;
+        let cause = (self.fcsr >> FPU::CAUSE_SHIFT) & 0x3f;
+        if cause & (self.enables() | FpuFlag::UNIMPLEMENTED) != 0 {
+            return Err(CpuException::FloatingPoint);
+        }
+        return Ok(());
+    }
+
+    pub fn fcsr(&self) -> u32 {
+        return self.fcsr;
+    }
+
+    /// Sets FCSR as it is, unlike CTC1 it never raises an exception
+    pub fn restore_fcsr(&mut self, fcsr: u32) {
+        self.fcsr = fcsr;
+    }
+
+    /// The condition bit set by the last compare, tested by BC1T and BC1F
+    pub fn condition(&self) -> bool {
+        return self.fcsr & FPU::CONDITION != 0;
+    }
+
+    pub fn rounding_mode(&self) -> RoundingMode {
+        return num::FromPrimitive::from_u32(self.fcsr & FPU::ROUNDING_MODE_MASK).unwrap();
+    }
+
+    fn enables(&self) -> u32 {
+        return (self.fcsr >> FPU::ENABLES_SHIFT) & 0x1f;
+    }
+
+    /// Executes the computational instructions of the S, D and W formats
+    pub fn execute(&mut self, instruction: u32) -> Result<(), CpuException> {
+        let format = (instruction >> 21) & FPU::REGISTER_MASK;
+        let ft = ((instruction >> 16) & FPU::REGISTER_MASK) as u8;
+        let fs = ((instruction >> 11) & FPU::REGISTER_MASK) as u8;
+        let fd = ((instruction >> 6) & FPU::REGISTER_MASK) as u8;
+        let function = instruction & FPU::FUNCTION_MASK;
+
+        let format: Option<COP1> = num::FromPrimitive::from_u32(format);
+        let format = match format {
+            Some(COP1::FMTS) => Format::Single,
+            Some(COP1::FMTD) => Format::Double,
+            Some(COP1::FMTW) => Format::Word,
+            _ => return Err(CpuException::ReservedInstruction(instruction)),
+        };
+        // Doubles can only live in even registers, the sources are read in the instruction's format
+        if format == Format::Double && (ft | fs) & 1 != 0 {
+            return self.set_cause(FpuFlag::UNIMPLEMENTED);
+        }
+
+        if function >= FPU::COMPARE_FUNCTION {
+            return self.compare(format, fs, ft, function & 0xf);
+        }
+        let operation: Option<FmtOperation> = num::FromPrimitive::from_u32(function);
+        let operation = match operation {
+            Some(operation) => operation,
+            None => return self.set_cause(FpuFlag::UNIMPLEMENTED),
+        };
+        let rounding_mode = self.rounding_mode();
+        let outcome = match (format, operation) {
+            (Format::Single, FmtOperation::ADD) => single(arithmetic(self.read_single(fs), self.read_single(ft), rounding_mode, add)),
+            (Format::Single, FmtOperation::SUB) => single(arithmetic(self.read_single(fs), -self.read_single(ft), rounding_mode, add)),
+            (Format::Single, FmtOperation::MUL) => single(arithmetic(self.read_single(fs), self.read_single(ft), rounding_mode, mul)),
+            (Format::Single, FmtOperation::DIV) => single(division(self.read_single(fs), self.read_single(ft), rounding_mode)),
+            (Format::Double, FmtOperation::ADD) => double(arithmetic(self.read_double(fs), self.read_double(ft), rounding_mode, add)),
+            (Format::Double, FmtOperation::SUB) => double(arithmetic(self.read_double(fs), -self.read_double(ft), rounding_mode, add)),
+            (Format::Double, FmtOperation::MUL) => double(arithmetic(self.read_double(fs), self.read_double(ft), rounding_mode, mul)),
+            (Format::Double, FmtOperation::DIV) => double(division(self.read_double(fs), self.read_double(ft), rounding_mode)),
+            (Format::Single, FmtOperation::ABS) => Outcome::single(self.read_register(fs) & 0x7fff_ffff, 0),
+            (Format::Single, FmtOperation::NEG) => Outcome::single(self.read_register(fs) ^ 0x8000_0000, 0),
+            (Format::Single, FmtOperation::MOV) => Outcome::single(self.read_register(fs), 0),
+            (Format::Double, FmtOperation::ABS) => Outcome::double(self.read_pair(fs) & 0x7fff_ffff_ffff_ffff, 0),
+            (Format::Double, FmtOperation::NEG) => Outcome::double(self.read_pair(fs) ^ 0x8000_0000_0000_0000, 0),
+            (Format::Double, FmtOperation::MOV) => Outcome::double(self.read_pair(fs), 0),
+            (Format::Double, FmtOperation::CVT_S) => single(convert(self.read_double(fs), rounding_mode)),
+            (Format::Word, FmtOperation::CVT_S) => single(convert_word(self.read_register(fs) as i32, rounding_mode)),
+            (Format::Single, FmtOperation::CVT_D) => double((self.read_single(fs) as f64, signaling_nan(self.read_single(fs)))),
+            (Format::Word, FmtOperation::CVT_D) => Outcome::double((self.read_register(fs) as i32 as f64).to_bits(), 0),
+            (Format::Single, FmtOperation::CVT_W) => to_word(self.read_single(fs) as f64, rounding_mode),
+            (Format::Double, FmtOperation::CVT_W) => to_word(self.read_double(fs), rounding_mode),
+            _ => return self.set_cause(FpuFlag::UNIMPLEMENTED),
+        };
+        // and the destination is written in the result's format
+        if matches!(outcome.value, Value::Double(_)) && fd & 1 != 0 {
+            return self.set_cause(FpuFlag::UNIMPLEMENTED);
         }
+
+        self.set_cause(outcome.flags)?;
+        match outcome.value {
+            Value::Single(bits) => self.write_register(fd, bits),
+            Value::Double(bits) => self.write_pair(fd, bits),
+        }
+        return Ok(());
+    }
+
+    const COMPARE_FUNCTION: u32 = 0o60;
+
+    /// C.cond.fmt, the low bits of the function select which relations make the condition true, the fourth bit
+    /// makes unordered operands signal an invalid operation
+    fn compare(&mut self, format: Format, fs: u8, ft: u8, condition: u32) -> Result<(), CpuException> {
+        let (less, equal, unordered, signaling) = match format {
+            Format::Single => {
+                let (a, b) = (self.read_single(fs), self.read_single(ft));
+                (a < b, a == b, a.is_nan() || b.is_nan(), signaling_nan(a) | signaling_nan(b) != 0)
+            },
+            Format::Double => {
+                let (a, b) = (self.read_double(fs), self.read_double(ft));
+                (a < b, a == b, a.is_nan() || b.is_nan(), signaling_nan(a) | signaling_nan(b) != 0)
+            },
+            Format::Word => return self.set_cause(FpuFlag::UNIMPLEMENTED),
+        };
+        let invalid = signaling || (unordered && condition & 0b1000 != 0);
+        self.set_cause(if invalid { FpuFlag::INVALID } else { 0 })?;
+        let result = (condition & 0b100 != 0 && less) || (condition & 0b10 != 0 && equal) || (condition & 0b1 != 0 && unordered);
+        if result {
+            self.fcsr |= FPU::CONDITION;
+        } else {
+            self.fcsr &= !FPU::CONDITION;
+        }
+        return Ok(());
     }
 
+    /// Records the exceptions raised by an operation, traps if any of them is enabled.
+    ///
+    /// When the operation traps the sticky flags are left untouched and the destination must not be written
+    fn set_cause(&mut self, flags: u32) -> Result<(), CpuException> {
+        self.fcsr &= !(0x3f << FPU::CAUSE_SHIFT);
+        self.fcsr |= flags << FPU::CAUSE_SHIFT;
+        if flags & (self.enables() | FpuFlag::UNIMPLEMENTED) != 0 {
+            return Err(CpuException::FloatingPoint);
+        }
+        self.fcsr |= (flags & 0x1f) << FPU::FLAGS_SHIFT;
+        return Ok(());
+    }
 }
 
+impl Default for FPU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum Format {
+    Single,
+    Double,
+    Word,
+}
+
+enum Value {
+    Single(u32),
+    Double(u64),
+}
+
+struct Outcome {
+    value: Value,
+    flags: u32,
+}
+
+impl Outcome {
+    fn single(bits: u32, flags: u32) -> Self {
+        Outcome { value: Value::Single(bits), flags }
+    }
+
+    fn double(bits: u64, flags: u32) -> Self {
+        Outcome { value: Value::Double(bits), flags }
+    }
+}
+
+fn single((value, flags): (f32, u32)) -> Outcome {
+    if value.is_nan() {
+        return Outcome::single(FPU::DEFAULT_NAN_SINGLE, flags);
+    }
+    return Outcome::single(value.to_bits(), flags);
+}
+
+fn double((value, flags): (f64, u32)) -> Outcome {
+    if value.is_nan() {
+        return Outcome::double(FPU::DEFAULT_NAN_DOUBLE, flags);
+    }
+    return Outcome::double(value.to_bits(), flags);
+}
+
+/// The IEEE 754 exceptions, in the order they appear in the flags, enables and cause fields of the FCSR
+pub struct FpuFlag;
+
+impl FpuFlag {
+    pub const INEXACT: u32 = 1 << 0;
+    pub const UNDERFLOW: u32 = 1 << 1;
+    pub const OVERFLOW: u32 = 1 << 2;
+    pub const DIVISION_BY_ZERO: u32 = 1 << 3;
+    pub const INVALID: u32 = 1 << 4;
+    /// Only exists in the cause field and can't be disabled
+    pub const UNIMPLEMENTED: u32 = 1 << 5;
+}
+
+#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+    Nearest = 0,
+    TowardZero = 1,
+    TowardPositive = 2,
+    TowardNegative = 3,
+}
+
+/// The float operations the FPU needs on top of `num_traits::Float`
+trait FpuFloat: Float {
+    fn next_toward_positive(self) -> Self;
+    fn next_toward_negative(self) -> Self;
+    /// A NaN is signaling when its quiet bit, the most significant bit of the mantissa, is set
+    fn is_signaling_nan(self) -> bool;
+}
+
+impl FpuFloat for f32 {
+    fn next_toward_positive(self) -> Self {
+        return self.next_up();
+    }
+
+    fn next_toward_negative(self) -> Self {
+        return self.next_down();
+    }
+
+    fn is_signaling_nan(self) -> bool {
+        return self.is_nan() && self.to_bits() & 0x0040_0000 != 0;
+    }
+}
+
+impl FpuFloat for f64 {
+    fn next_toward_positive(self) -> Self {
+        return self.next_up();
+    }
+
+    fn next_toward_negative(self) -> Self {
+        return self.next_down();
+    }
+
+    fn is_signaling_nan(self) -> bool {
+        return self.is_nan() && self.to_bits() & 0x0008_0000_0000_0000 != 0;
+    }
+}
+
+fn signaling_nan<F: FpuFloat>(value: F) -> u32 {
+    if value.is_signaling_nan() {
+        return FpuFlag::INVALID;
+    }
+    return 0;
+}
+
+/// Sum and its exact rounding error (TwoSum)
+fn add<F: FpuFloat>(a: F, b: F) -> (F, F) {
+    let sum = a + b;
+    let b_virtual = sum - a;
+    let error = (a - (sum - b_virtual)) + (b - b_virtual);
+    return (sum, error);
+}
+
+/// Product and its exact rounding error, recovered through a fused multiply add
+fn mul<F: FpuFloat>(a: F, b: F) -> (F, F) {
+    let product = a * b;
+    return (product, a.mul_add(b, -product));
+}
+
+/// Quotient and a value with the sign of the rounding error, the remainder a - q * b is exact
+fn div<F: FpuFloat>(a: F, b: F) -> (F, F) {
+    let quotient = a / b;
+    let remainder = (-quotient).mul_add(b, a);
+    return (quotient, remainder / b);
+}
+
+/// Dividing a finite value by zero is exact and only raises the division by zero exception
+fn division<F: FpuFloat>(a: F, b: F, rounding_mode: RoundingMode) -> (F, u32) {
+    if b.is_zero() && a.is_finite() && !a.is_zero() {
+        return (a / b, FpuFlag::DIVISION_BY_ZERO);
+    }
+    return arithmetic(a, b, rounding_mode, div);
+}
+
+/// Runs an operation rounding to nearest and moves the result to the neighbouring float when the selected
+/// rounding mode and the sign of the rounding error require it
+fn arithmetic<F: FpuFloat>(a: F, b: F, rounding_mode: RoundingMode, op: fn(F, F) -> (F, F)) -> (F, u32) {
+    let mut flags = signaling_nan(a) | signaling_nan(b);
+    if a.is_nan() || b.is_nan() {
+        return (F::nan(), flags);
+    }
+    let (result, error) = op(a, b);
+    if result.is_nan() {
+        return (result, flags | FpuFlag::INVALID);
+    }
+    if result.is_infinite() {
+        if a.is_finite() && b.is_finite() {
+            return (overflow(result.is_sign_negative(), rounding_mode), flags | FpuFlag::OVERFLOW | FpuFlag::INEXACT);
+        }
+        return (result, flags);
+    }
+    if error.is_zero() || error.is_nan() {
+        return (result, flags);
+    }
+    flags |= FpuFlag::INEXACT;
+    let result = round(result, error, rounding_mode);
+    if result.abs() < F::min_positive_value() {
+        flags |= FpuFlag::UNDERFLOW;
+    }
+    return (result, flags);
+}
+
+/// The result of an overflow depends on the direction of the rounding
+fn overflow<F: FpuFloat>(negative: bool, rounding_mode: RoundingMode) -> F {
+    let to_infinity = match rounding_mode {
+        RoundingMode::Nearest => true,
+        RoundingMode::TowardZero => false,
+        RoundingMode::TowardPositive => !negative,
+        RoundingMode::TowardNegative => negative,
+    };
+    let magnitude = if to_infinity { F::infinity() } else { F::max_value() };
+    if negative {
+        return -magnitude;
+    }
+    return magnitude;
+}
+
+/// `result` is the exact value rounded to nearest, `error` has the sign of exact - result
+fn round<F: FpuFloat>(result: F, error: F, rounding_mode: RoundingMode) -> F {
+    let positive_error = error > F::zero();
+    return match rounding_mode {
+        RoundingMode::Nearest => result,
+        RoundingMode::TowardPositive if positive_error => result.next_toward_positive(),
+        RoundingMode::TowardNegative if !positive_error => result.next_toward_negative(),
+        RoundingMode::TowardZero if result > F::zero() && !positive_error => result.next_toward_negative(),
+        RoundingMode::TowardZero if result < F::zero() && positive_error => result.next_toward_positive(),
+        _ => result,
+    };
+}
+
+/// CVT.S.D
+fn convert(value: f64, rounding_mode: RoundingMode) -> (f32, u32) {
+    let flags = signaling_nan(value);
+    if value.is_nan() {
+        return (f32::NAN, flags);
+    }
+    let result = value as f32;
+    if result.is_infinite() && value.is_finite() {
+        return (overflow(value.is_sign_negative(), rounding_mode), flags | FpuFlag::OVERFLOW | FpuFlag::INEXACT);
+    }
+    let error = value - result as f64;
+    if error == 0.0 {
+        return (result, flags);
+    }
+    let result = round(result, error as f32, rounding_mode);
+    let underflow = if result.abs() < f32::MIN_POSITIVE { FpuFlag::UNDERFLOW } else { 0 };
+    return (result, flags | FpuFlag::INEXACT | underflow);
+}
+
+/// CVT.S.W
+fn convert_word(value: i32, rounding_mode: RoundingMode) -> (f32, u32) {
+    let result = value as f32;
+    let error = value as f64 - result as f64;
+    if error == 0.0 {
+        return (result, 0);
+    }
+    return (round(result, error as f32, rounding_mode), FpuFlag::INEXACT);
+}
+
+/// CVT.W.S and CVT.W.D, values that don't fit in a word are invalid and produce the largest positive word
+fn to_word(value: f64, rounding_mode: RoundingMode) -> Outcome {
+    let rounded = match rounding_mode {
+        RoundingMode::Nearest => value.round_ties_even(),
+        RoundingMode::TowardZero => value.trunc(),
+        RoundingMode::TowardPositive => value.ceil(),
+        RoundingMode::TowardNegative => value.floor(),
+    };
+    if value.is_nan() || rounded < i32::MIN as f64 || rounded > i32::MAX as f64 {
+        return Outcome::single(i32::MAX as u32, FpuFlag::INVALID);
+    }
+    let flags = if rounded != value { FpuFlag::INEXACT } else { 0 };
+    return Outcome::single(rounded as i32 as u32, flags);
+}
+
+/// Names of the C.cond.fmt conditions, indexed by the low four bits of the function field
+pub const COMPARE_CONDITIONS: [&str; 16] = [
+    "f", "un", "eq", "ueq", "olt", "ult", "ole", "ule",
+    "sf", "ngle", "seq", "ngl", "lt", "nge", "le", "ngt",
+];
+
 #[derive(FromPrimitive)]
 pub enum COP1 {
     FMTS = 16,
+    FMTD = 17,
     FMTW = 20,
     BC = 0b01000,
     MF = 0b00000,
@@ -32,19 +477,20 @@ pub enum COP1 {
     CT = 0b00110
 }
 
+#[derive(FromPrimitive)]
 pub enum FmtOperation {
     ABS = 0o05,
     CEIL_L = 0o12,
     CEIL_W = 0o16,
     CVT_D = 0o41,
     CVT_S = 0o40,
+    CVT_W = 0o44,
     DIV = 0o03,
     MOV = 0o06,
     MOVCF = 0o21,
     MOVN = 0o23,
     MUL = 0o02,
     NEG = 0o07,
-    SUM = 0o01
+    ADD = 0o00,
+    SUB = 0o01
 }
-
-
@@ -0,0 +1,308 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use num_derive::FromPrimitive;
+
+use crate::device::{self, Device};
+use crate::memory_mapper::MemoryMappable;
+use crate::snapshot;
+
+/// A linear framebuffer with two pages of pixels, the page on display is switched by writing the control register.
+///
+/// The registers are big endian words from offset 0: the width, height, pixel mode, pitch (bytes per line), control,
+/// frame count, offset of the first page and size of a page. The 256 entries of the palette follow at 0x100 as
+/// 0x00RRGGBB words, the pages start at 0x1000. Pixels are a palette index in `PixelMode::INDEXED8`, a big endian
+/// half word in `PixelMode::RGB565` and the bytes R, G, B then A in `PixelMode::RGBA8888`. Writing the control
+/// register presents a frame: bit 0 picks the page on display and bit 1 dumps it right away. Frames can also be
+/// dumped every few presents to numbered PNG or PPM files
+pub struct FramebufferDevice {
+    width: u32,
+    height: u32,
+    mode: PixelMode,
+    control: u32,
+    frames: u32,
+    palette: [u32; 256],
+    pixels: Vec<u8>,
+    dumps: Option<FrameDumps>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
+pub enum PixelMode {
+    INDEXED8 = 0,
+    RGB565 = 1,
+    RGBA8888 = 2,
+}
+
+impl PixelMode {
+    pub fn bytes_per_pixel(self) -> u32 {
+        return match self {
+            PixelMode::INDEXED8 => 1,
+            PixelMode::RGB565 => 2,
+            PixelMode::RGBA8888 => 4,
+        };
+    }
+}
+
+/// Where frames are dumped: `{frame}` in the pattern is replaced by the frame number, a .ppm extension writes PPM and
+/// anything else PNG
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameDumps {
+    pub pattern: String,
+    /// Dump every `every` frames, 0 only dumps when the guest asks for it
+    pub every: u32,
+}
+
+impl FrameDumps {
+    fn path(&self, frame: u32) -> PathBuf {
+        return PathBuf::from(self.pattern.replace("{frame}", &format!("{:06}", frame)));
+    }
+}
+
+impl FramebufferDevice {
+    pub const CONTROL_PAGE: u32 = 1 << 0;
+    pub const CONTROL_DUMP: u32 = 1 << 1;
+
+    pub const WIDTH: u32 = 0x00;
+    pub const HEIGHT: u32 = 0x04;
+    pub const MODE: u32 = 0x08;
+    pub const PITCH: u32 = 0x0c;
+    pub const CONTROL: u32 = 0x10;
+    pub const FRAMES: u32 = 0x14;
+    pub const PAGE_OFFSET: u32 = 0x18;
+    pub const PAGE_SIZE: u32 = 0x1c;
+    pub const PALETTE: u32 = 0x100;
+    pub const PAGES: u32 = 0x1000;
+
+    /// Frames larger than this don't fit the address space with two pages
+    pub const MAX_PIXELS: u32 = 4096 * 4096;
+
+    /// A framebuffer in `PixelMode::INDEXED8` with a grey ramp palette
+    pub fn new(width: u32, height: u32) -> Result<Self, String> {
+        if width == 0 || height == 0 || width.checked_mul(height).is_none_or(|pixels| pixels > FramebufferDevice::MAX_PIXELS) {
+            return Err(format!("a {}x{} framebuffer is out of range", width, height));
+        }
+        let mut palette = [0; 256];
+        for (i, color) in palette.iter_mut().enumerate() {
+            *color = (i as u32) * 0x010101;
+        }
+        let mut framebuffer = FramebufferDevice {
+            width, height, mode: PixelMode::INDEXED8, control: 0, frames: 0, palette, pixels: vec![], dumps: None,
+        };
+        framebuffer.pixels = vec![0; 2 * framebuffer.page_size() as usize];
+        return Ok(framebuffer);
+    }
+
+    pub fn set_dumps(&mut self, dumps: Option<FrameDumps>) {
+        self.dumps = dumps;
+    }
+
+    pub fn width(&self) -> u32 {
+        return self.width;
+    }
+
+    pub fn height(&self) -> u32 {
+        return self.height;
+    }
+
+    pub fn frames(&self) -> u32 {
+        return self.frames;
+    }
+
+    /// Room for a page in the mode with the largest pixels, rounded to 4k
+    fn page_size(&self) -> u32 {
+        return (self.width * self.height * PixelMode::RGBA8888.bytes_per_pixel()).next_multiple_of(0x1000);
+    }
+
+    fn pitch(&self) -> u32 {
+        return self.width * self.mode.bytes_per_pixel();
+    }
+
+    /// The page on display as RGB triples, line by line
+    pub fn render(&self) -> Vec<u8> {
+        let page = (self.control & FramebufferDevice::CONTROL_PAGE) * self.page_size();
+        let bytes_per_pixel = self.mode.bytes_per_pixel();
+        let mut rgb = Vec::with_capacity((self.width * self.height * 3) as usize);
+        for i in 0..self.width * self.height {
+            let offset = (page + i * bytes_per_pixel) as usize;
+            let pixel = &self.pixels[offset..offset + bytes_per_pixel as usize];
+            match self.mode {
+                PixelMode::INDEXED8 => rgb.extend(&self.palette[pixel[0] as usize].to_be_bytes()[1..]),
+                PixelMode::RGB565 => {
+                    let color = u16::from_be_bytes([pixel[0], pixel[1]]);
+                    // Scales the 5 and 6 bit channels to 8 bits, white stays white
+                    let (red, green, blue) = ((color >> 11) as u8, (color >> 5 & 0x3f) as u8, (color & 0x1f) as u8);
+                    rgb.extend([red << 3 | red >> 2, green << 2 | green >> 4, blue << 3 | blue >> 2]);
+                },
+                PixelMode::RGBA8888 => rgb.extend(&pixel[..3]),
+            }
+        }
+        return rgb;
+    }
+
+    pub fn write_png(&self, output: impl Write) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(output, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(io::Error::other)?;
+        return writer.write_image_data(&self.render()).map_err(io::Error::other);
+    }
+
+    /// Binary PPM (P6)
+    pub fn write_ppm(&self, mut output: impl Write) -> io::Result<()> {
+        write!(output, "P6\n{} {}\n255\n", self.width, self.height)?;
+        output.write_all(&self.render())?;
+        return output.flush();
+    }
+
+    /// Writes the page on display as PPM when `path` ends in .ppm and as PNG otherwise
+    pub fn dump(&self, path: &Path) -> io::Result<()> {
+        let output = BufWriter::new(File::create(path)?);
+        return match path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("ppm")) {
+            true => self.write_ppm(output),
+            false => self.write_png(output),
+        };
+    }
+
+    /// Counts the frame and dumps it when it's due or asked for
+    fn present(&mut self, control: u32) {
+        self.control = control & FramebufferDevice::CONTROL_PAGE;
+        self.frames = self.frames.wrapping_add(1);
+        let Some(dumps) = &self.dumps else {
+            return;
+        };
+        let due = dumps.every != 0 && self.frames.is_multiple_of(dumps.every);
+        if due || control & FramebufferDevice::CONTROL_DUMP != 0 {
+            let path = dumps.path(self.frames);
+            if let Err(error) = self.dump(&path) {
+                eprintln!("framebuffer: can't write {}: {}", path.display(), error);
+            }
+        }
+    }
+
+    fn read_register(&self, address: u32) -> u32 {
+        return match address & !3 {
+            FramebufferDevice::WIDTH => self.width,
+            FramebufferDevice::HEIGHT => self.height,
+            FramebufferDevice::MODE => self.mode as u32,
+            FramebufferDevice::PITCH => self.pitch(),
+            FramebufferDevice::CONTROL => self.control,
+            FramebufferDevice::FRAMES => self.frames,
+            FramebufferDevice::PAGE_OFFSET => FramebufferDevice::PAGES,
+            FramebufferDevice::PAGE_SIZE => self.page_size(),
+            address if (FramebufferDevice::PALETTE..FramebufferDevice::PALETTE + 0x400).contains(&address) => self.palette[((address - FramebufferDevice::PALETTE) / 4) as usize],
+            _ => 0,
+        };
+    }
+
+    fn write_register(&mut self, address: u32, value: u32) {
+        match address & !3 {
+            FramebufferDevice::MODE => {
+                if let Some(mode) = num::FromPrimitive::from_u32(value) {
+                    self.mode = mode;
+                }
+            },
+            FramebufferDevice::CONTROL => self.present(value),
+            address if (FramebufferDevice::PALETTE..FramebufferDevice::PALETTE + 0x400).contains(&address) => {
+                self.palette[((address - FramebufferDevice::PALETTE) / 4) as usize] = value & 0x00ff_ffff;
+            },
+            _ => {},
+        }
+    }
+
+    /// Pixels take the bytes as they are, a register gets them merged into the word it reads back so that a byte can
+    /// change one channel of a palette entry and any write to the control register presents a frame
+    fn write_bytes(&mut self, address: u32, bytes: &[u8]) {
+        match self.pixel_offset(address, bytes.len()) {
+            Some(offset) => self.pixels[offset..offset + bytes.len()].copy_from_slice(bytes),
+            None => self.write_register(address, device::merge_register(self.read_register(address), address, bytes)),
+        }
+    }
+
+    /// The pixel memory behind `address` when there is some
+    fn pixel_offset(&self, address: u32, size: usize) -> Option<usize> {
+        let offset = address.checked_sub(FramebufferDevice::PAGES)? as usize;
+        return (offset + size <= self.pixels.len()).then_some(offset);
+    }
+}
+
+impl MemoryMappable for FramebufferDevice {
+    fn get_byte(&self, address: u32) -> [u8; 1] {
+        if let Some(offset) = self.pixel_offset(address, 1) {
+            return [self.pixels[offset]];
+        }
+        return [self.read_register(address).to_be_bytes()[(address & 3) as usize]];
+    }
+
+    fn get_half_word(&self, address: u32) -> [u8; 2] {
+        if let Some(offset) = self.pixel_offset(address, 2) {
+            return [self.pixels[offset], self.pixels[offset + 1]];
+        }
+        let word = self.read_register(address).to_be_bytes();
+        let offset = (address & 2) as usize;
+        return [word[offset], word[offset + 1]];
+    }
+
+    fn get_word(&self, address: u32) -> [u8; 4] {
+        if let Some(offset) = self.pixel_offset(address, 4) {
+            return self.pixels[offset..offset + 4].try_into().unwrap();
+        }
+        return self.read_register(address).to_be_bytes();
+    }
+
+    fn write_byte(&mut self, address: u32, value: [u8; 1]) {
+        self.write_bytes(address, &value);
+    }
+
+    fn write_half_word(&mut self, address: u32, value: [u8; 2]) {
+        self.write_bytes(address, &value);
+    }
+
+    fn write_word(&mut self, address: u32, value: [u8; 4]) {
+        match self.pixel_offset(address, 4) {
+            Some(offset) => self.pixels[offset..offset + 4].copy_from_slice(&value),
+            None => self.write_register(address, u32::from_be_bytes(value)),
+        }
+    }
+
+    /// The mode, control and frame count as little endian words, then the palette and the pixels of both pages
+    fn save_state(&self) -> Option<Vec<u8>> {
+        let mut state = vec![];
+        for word in [self.mode as u32, self.control, self.frames].iter().chain(self.palette.iter()) {
+            state.extend(word.to_le_bytes());
+        }
+        state.extend(&self.pixels);
+        return Some(state);
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> io::Result<()> {
+        if state.len() != (3 + 256) * 4 + self.pixels.len() {
+            return Err(snapshot::invalid_snapshot("invalid framebuffer state"));
+        }
+        let word = |i: usize| u32::from_le_bytes(state[i * 4..i * 4 + 4].try_into().unwrap());
+        self.mode = num::FromPrimitive::from_u32(word(0)).ok_or_else(|| snapshot::invalid_snapshot("invalid framebuffer mode"))?;
+        self.control = word(1);
+        self.frames = word(2);
+        for (i, color) in self.palette.iter_mut().enumerate() {
+            *color = word(3 + i);
+        }
+        self.pixels.copy_from_slice(&state[(3 + 256) * 4..]);
+        return Ok(());
+    }
+}
+
+impl Device for FramebufferDevice {
+    fn name(&self) -> &str {
+        return "framebuffer";
+    }
+
+    fn size(&self) -> u32 {
+        return FramebufferDevice::PAGES + 2 * self.page_size();
+    }
+
+    fn reset(&mut self) {
+        let dumps = self.dumps.take();
+        *self = FramebufferDevice::new(self.width, self.height).unwrap();
+        self.dumps = dumps;
+    }
+}
@@ -0,0 +1,525 @@
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use crate::cop0::Cop0Register;
+use crate::cpu::{sign_extend_immediate, Instruction, CPU};
+use crate::exception::CpuException;
+use crate::memory_mapper::{WatchKind, Watchpoint};
+
+/// The byte gdb sends when the user presses Ctrl-C while the target runs
+const INTERRUPT: u8 = 0x03;
+/// How many instructions run between two checks for an interrupt from the client
+const POLL_INTERVAL: u64 = 4096;
+
+/// Registers in the order gdb numbers them for MIPS: the GPRs, sr, lo, hi, badvaddr, cause, pc, the FPRs, fcsr and fir
+const REGISTER_COUNT: usize = 72;
+const SR: usize = 32;
+const LO: usize = 33;
+const HI: usize = 34;
+const BAD_VADDR: usize = 35;
+const CAUSE: usize = 36;
+const PC: usize = 37;
+const FIRST_FPR: usize = 38;
+const FCSR: usize = 70;
+const FIR: usize = 71;
+
+/// Signals reported in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGBUS: u8 = 7;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+const SIGSYS: u8 = 31;
+
+const ERROR_INVALID: &str = "E01";
+/// EFAULT, for memory that isn't mapped
+const ERROR_FAULT: &str = "E0e";
+
+/// A stream gdb is connected through
+pub trait Connection: Read + Write {
+    /// Whether the client sent Ctrl-C since the target was resumed, it must not block
+    fn interrupt_requested(&mut self) -> io::Result<bool>;
+}
+
+/// Reads whatever is pending on a non-blocking stream, while the target runs gdb sends nothing but interrupts
+fn read_interrupt(stream: &mut impl Read) -> io::Result<bool> {
+    let mut byte = [0];
+    return match stream.read(&mut byte) {
+        // A client that went away can't resume the target again, stop so that the session ends
+        Ok(0) => Ok(true),
+        Ok(_) => Ok(byte[0] == INTERRUPT),
+        Err(error) if error.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(error) => Err(error),
+    };
+}
+
+impl Connection for TcpStream {
+    fn interrupt_requested(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let interrupted = read_interrupt(self);
+        self.set_nonblocking(false)?;
+        return interrupted;
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn interrupt_requested(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let interrupted = read_interrupt(self);
+        self.set_nonblocking(false)?;
+        return interrupted;
+    }
+}
+
+/// Serves the GDB Remote Serial Protocol for a CPU, so that `gdb-multiarch` can debug the guest with `target remote`
+pub struct GdbStub<'a> {
+    cpu: CPU<'a>,
+    breakpoints: BTreeSet<u32>,
+    /// Answer to `?`, the reason the target last stopped
+    last_stop: String,
+    /// Set once the client asked for QStartNoAckMode
+    no_ack: bool,
+}
+
+impl<'a> GdbStub<'a> {
+    pub fn new(cpu: CPU<'a>) -> Self {
+        GdbStub { cpu, breakpoints: BTreeSet::new(), last_stop: format!("S{:02x}", SIGTRAP), no_ack: false }
+    }
+
+    pub fn cpu(&self) -> &CPU<'a> {
+        return &self.cpu;
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU<'a> {
+        return &mut self.cpu;
+    }
+
+    pub fn into_cpu(self) -> CPU<'a> {
+        return self.cpu;
+    }
+
+    /// Handles the packets of one client until it detaches, kills the target or disconnects
+    pub fn serve(&mut self, connection: &mut impl Connection) -> io::Result<()> {
+        self.no_ack = false;
+        loop {
+            let packet = match self.read_packet(connection)? {
+                Some(packet) => packet,
+                None => return Ok(()),
+            };
+            if packet == "k" {
+                return Ok(());
+            }
+            if packet.starts_with('D') {
+                return write_packet(connection, "OK");
+            }
+            let reply = self.handle(&packet, connection)?;
+            write_packet(connection, &reply)?;
+            if packet == "QStartNoAckMode" {
+                self.no_ack = true;
+            }
+        }
+    }
+
+    /// Returns the payload of the next packet, `None` at the end of the stream.
+    ///
+    /// Acknowledgements from the client are skipped, the stream is reliable so there is nothing to retransmit
+    fn read_packet(&mut self, connection: &mut impl Connection) -> io::Result<Option<String>> {
+        loop {
+            let mut byte = [0];
+            if connection.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] != b'$' {
+                continue;
+            }
+            let mut payload = vec![];
+            loop {
+                if connection.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'#' {
+                    break;
+                }
+                payload.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            connection.read_exact(&mut checksum)?;
+            if self.no_ack {
+                return Ok(Some(String::from_utf8_lossy(&payload).into_owned()));
+            }
+            let expected = payload.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+            if parse_hex(&String::from_utf8_lossy(&checksum)) != Some(expected as u32) {
+                connection.write_all(b"-")?;
+                continue;
+            }
+            connection.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&payload).into_owned()));
+        }
+    }
+
+    /// The reply to a packet, empty for the packets that aren't supported
+    fn handle(&mut self, packet: &str, connection: &mut impl Connection) -> io::Result<String> {
+        let (command, arguments) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => self.last_stop.clone(),
+            "g" => (0..REGISTER_COUNT).map(|register| self.encoded_register(register)).collect(),
+            "G" => self.write_registers(arguments),
+            "p" => match parse_hex(arguments) {
+                Some(register) => self.encoded_register(register as usize),
+                None => ERROR_INVALID.to_owned(),
+            },
+            "P" => self.write_register_packet(arguments),
+            "m" => self.read_memory(arguments),
+            "M" => self.write_memory(arguments),
+            "c" | "s" => {
+                if !arguments.is_empty() {
+                    match parse_hex(arguments) {
+                        Some(address) => self.cpu.set_pc(address),
+                        None => return Ok(ERROR_INVALID.to_owned()),
+                    }
+                }
+                let stop = self.resume(command == "s", connection)?;
+                self.last_stop = stop.clone();
+                stop
+            },
+            "Z" => self.insert_point(arguments),
+            "z" => self.remove_point(arguments),
+            "H" | "T" => "OK".to_owned(),
+            "q" => self.query(arguments),
+            "Q" if arguments == "StartNoAckMode" => "OK".to_owned(),
+            _ => String::new(),
+        };
+        return Ok(reply);
+    }
+
+    fn query(&self, query: &str) -> String {
+        if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            return match parse_range(range) {
+                Some((offset, length)) => read_chunk(&target_description(), offset, length),
+                None => ERROR_INVALID.to_owned(),
+            };
+        }
+        return match query.split(':').next().unwrap_or("") {
+            "Supported" => "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+".to_owned(),
+            "Attached" => "1".to_owned(),
+            "C" => "QC1".to_owned(),
+            "fThreadInfo" => "m1".to_owned(),
+            "sThreadInfo" => "l".to_owned(),
+            _ => String::new(),
+        };
+    }
+
+    fn read_register(&self, register: usize) -> Option<u32> {
+        let (hi, lo) = self.cpu.get_hi_lo();
+        return match register {
+            0..=31 => Some(self.cpu.get_register_value(register)),
+            SR => Some(self.cpu.cop0().status()),
+            LO => Some(lo),
+            HI => Some(hi),
+            BAD_VADDR => Some(self.cpu.cop0().bad_vaddr()),
+            CAUSE => Some(self.cpu.cop0().cause()),
+            PC => Some(self.cpu.get_pc()),
+            FIRST_FPR..FCSR => Some(self.cpu.fpu().read_register((register - FIRST_FPR) as u8)),
+            FCSR => Some(self.cpu.fpu().fcsr()),
+            FIR => Some(self.cpu.fpu().read_control(0)),
+            _ => None,
+        };
+    }
+
+    /// Writes go through the same masks MTC0 and CTC1 apply, read-only registers ignore them
+    fn write_register(&mut self, register: usize, value: u32) -> bool {
+        let (hi, lo) = self.cpu.get_hi_lo();
+        match register {
+            0..=31 => self.cpu.set_register_value(register, value),
+            SR => self.cpu.cop0_mut().write_register(Cop0Register::Status as u8, value),
+            LO => self.cpu.set_hi_lo(hi, value),
+            HI => self.cpu.set_hi_lo(value, lo),
+            BAD_VADDR => self.cpu.cop0_mut().write_register(Cop0Register::BadVAddr as u8, value),
+            CAUSE => self.cpu.cop0_mut().write_register(Cop0Register::Cause as u8, value),
+            PC => self.cpu.set_pc(value),
+            FIRST_FPR..FCSR => self.cpu.fpu_mut().write_register((register - FIRST_FPR) as u8, value),
+            // The value is stored even when it enables a pending cause, the exception is raised by the next FPU instruction
+            FCSR => {
+                let _ = self.cpu.fpu_mut().write_control(31, value);
+            },
+            FIR => {},
+            _ => return false,
+        }
+        return true;
+    }
+
+    /// Registers travel in the byte order of the target, `x` marks the ones that don't exist
+    fn encoded_register(&self, register: usize) -> String {
+        return match self.read_register(register) {
+            Some(value) => hex(&self.cpu.endianness().u32_to_bytes(value)),
+            None => "xxxxxxxx".to_owned(),
+        };
+    }
+
+    fn decode_register(&self, text: &str) -> Option<u32> {
+        let bytes: [u8; 4] = parse_hex_bytes(text)?.try_into().ok()?;
+        return Some(self.cpu.endianness().u32_from_bytes(bytes));
+    }
+
+    fn write_registers(&mut self, arguments: &str) -> String {
+        if !arguments.len().is_multiple_of(8) || !arguments.is_ascii() {
+            return ERROR_INVALID.to_owned();
+        }
+        for (register, start) in (0..arguments.len()).step_by(8).enumerate() {
+            let text = &arguments[start..start + 8];
+            // Registers gdb doesn't know the value of are sent back as x
+            if text.starts_with('x') {
+                continue;
+            }
+            match self.decode_register(text) {
+                Some(value) => {
+                    self.write_register(register, value);
+                },
+                None => return ERROR_INVALID.to_owned(),
+            }
+        }
+        return "OK".to_owned();
+    }
+
+    /// `P n=value`
+    fn write_register_packet(&mut self, arguments: &str) -> String {
+        let parsed = arguments.split_once('=').and_then(|(register, value)| Some((parse_hex(register)?, self.decode_register(value)?)));
+        return match parsed {
+            Some((register, value)) if self.write_register(register as usize, value) => "OK".to_owned(),
+            _ => ERROR_INVALID.to_owned(),
+        };
+    }
+
+    /// `m address,length`, a read that runs into unmapped memory returns the bytes before it
+    fn read_memory(&self, arguments: &str) -> String {
+        let (address, length) = match parse_range(arguments) {
+            Some(range) => range,
+            None => return ERROR_INVALID.to_owned(),
+        };
+        let bytes: Vec<u8> = (0..length)
+            .map_while(|i| self.cpu.memory_mapper().peek_byte(address.wrapping_add(i)).ok())
+            .collect();
+        if bytes.is_empty() && length > 0 {
+            return ERROR_FAULT.to_owned();
+        }
+        return hex(&bytes);
+    }
+
+    /// `M address,length:bytes`
+    fn write_memory(&mut self, arguments: &str) -> String {
+        let parsed = arguments.split_once(':').and_then(|(range, data)| Some((parse_range(range)?, parse_hex_bytes(data)?)));
+        let ((address, length), bytes) = match parsed {
+            Some(parsed) => parsed,
+            None => return ERROR_INVALID.to_owned(),
+        };
+        if bytes.len() != length as usize {
+            return ERROR_INVALID.to_owned();
+        }
+        for (i, byte) in bytes.iter().enumerate() {
+            if self.cpu.memory_mapper_mut().write_byte(address.wrapping_add(i as u32), [*byte]).is_err() {
+                return ERROR_FAULT.to_owned();
+            }
+        }
+        return "OK".to_owned();
+    }
+
+    /// `type,address,kind`, software and hardware breakpoints are the same thing for an emulator
+    fn parse_point(arguments: &str) -> Option<(char, u32, u32)> {
+        let mut fields = arguments.split(',');
+        let kind = fields.next()?.chars().next()?;
+        let address = parse_hex(fields.next()?)?;
+        // Conditions and commands may follow the length after a semicolon
+        let length = parse_hex(fields.next()?.split(';').next()?)?;
+        return Some((kind, address, length));
+    }
+
+    /// The watchpoint of a Z2, Z3 or Z4 packet, they live in the memory mapper
+    fn watchpoint(kind: char, address: u32, length: u32) -> Option<Watchpoint> {
+        let kind = match kind {
+            '2' => WatchKind::Write,
+            '3' => WatchKind::Read,
+            '4' => WatchKind::Access,
+            _ => return None,
+        };
+        return Some(Watchpoint { kind, start: address, end: address.saturating_add(length.max(1) - 1) });
+    }
+
+    fn insert_point(&mut self, arguments: &str) -> String {
+        let (kind, address, length) = match GdbStub::parse_point(arguments) {
+            Some(point) => point,
+            None => return ERROR_INVALID.to_owned(),
+        };
+        if kind == '0' || kind == '1' {
+            self.breakpoints.insert(address);
+            return "OK".to_owned();
+        }
+        return match GdbStub::watchpoint(kind, address, length) {
+            Some(watchpoint) => {
+                self.cpu.memory_mapper_mut().add_watchpoint(watchpoint);
+                "OK".to_owned()
+            },
+            None => String::new(),
+        };
+    }
+
+    fn remove_point(&mut self, arguments: &str) -> String {
+        let (kind, address, length) = match GdbStub::parse_point(arguments) {
+            Some(point) => point,
+            None => return ERROR_INVALID.to_owned(),
+        };
+        if kind == '0' || kind == '1' {
+            self.breakpoints.remove(&address);
+            return "OK".to_owned();
+        }
+        return match GdbStub::watchpoint(kind, address, length) {
+            Some(watchpoint) => {
+                self.cpu.memory_mapper_mut().remove_watchpoint(&watchpoint);
+                "OK".to_owned()
+            },
+            None => String::new(),
+        };
+    }
+
+    /// The data access the instruction at pc is about to make: its kind, address and size
+    fn pending_access(&self) -> Option<(WatchKind, u32, u32)> {
+        let pc = self.cpu.get_pc();
+        let word = self.cpu.endianness().u32_from_bytes(self.cpu.memory_mapper().peek_word(pc).ok()?);
+        let instruction: Instruction = num::FromPrimitive::from_u32(word >> 26)?;
+        let (kind, size) = match instruction {
+            Instruction::LB | Instruction::LBU => (WatchKind::Read, 1),
+            Instruction::LHW | Instruction::LHWU => (WatchKind::Read, 2),
+            Instruction::LW | Instruction::LWC1 | Instruction::LWL | Instruction::LWR => (WatchKind::Read, 4),
+            Instruction::SB => (WatchKind::Write, 1),
+            Instruction::SHW => (WatchKind::Write, 2),
+            Instruction::SW | Instruction::SWC1 | Instruction::SWL | Instruction::SWR => (WatchKind::Write, 4),
+            _ => return None,
+        };
+        let base = self.cpu.get_register_value(((word >> 21) & 0x1f) as usize);
+        let address = base.wrapping_add(sign_extend_immediate(word & 0xffff) as u32);
+        // The unaligned accesses touch the aligned word around the address
+        return match instruction {
+            Instruction::LWL | Instruction::LWR | Instruction::SWL | Instruction::SWR => Some((kind, address & !3, size)),
+            _ => Some((kind, address, size)),
+        };
+    }
+
+    /// Like on MIPS hardware a watchpoint triggers before the access, gdb steps over the instruction itself.
+    ///
+    /// The memory mapper only sees accesses as they happen, so the instruction at pc is decoded to predict its access
+    fn watchpoint_hit(&self) -> Option<String> {
+        let (kind, address, size) = self.pending_access()?;
+        let watchpoint = self.cpu.memory_mapper().find_watchpoint(kind, address, size)?;
+        let name = match watchpoint.kind {
+            WatchKind::Write => "watch",
+            WatchKind::Read => "rwatch",
+            WatchKind::Access => "awatch",
+        };
+        return Some(format!("T{:02x}{}:{:x};", SIGTRAP, name, address));
+    }
+
+    /// Runs the CPU and returns the stop reply, the instruction at pc always executes first
+    fn resume(&mut self, single_step: bool, connection: &mut impl Connection) -> io::Result<String> {
+        let mut executed: u64 = 0;
+        loop {
+            if let Some(code) = self.cpu.exit_code() {
+                return Ok(format!("W{:02x}", code as u8));
+            }
+            let result = self.cpu.step();
+            executed += 1;
+            if let Some(code) = self.cpu.exit_code() {
+                return Ok(format!("W{:02x}", code as u8));
+            }
+            if let Err(exception) = result {
+                if !self.cpu.exception_handler_installed() {
+                    return Ok(format!("S{:02x}", signal(exception)));
+                }
+            }
+            if single_step || self.breakpoints.contains(&self.cpu.get_pc()) {
+                return Ok(format!("S{:02x}", SIGTRAP));
+            }
+            if let Some(reply) = self.watchpoint_hit() {
+                return Ok(reply);
+            }
+            if executed.is_multiple_of(POLL_INTERVAL) && connection.interrupt_requested()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+    }
+}
+
+/// The signal a Unix kernel on MIPS delivers for an exception
+fn signal(exception: CpuException) -> u8 {
+    return match exception {
+        CpuException::AddressErrorLoad(_) | CpuException::AddressErrorStore(_) => SIGBUS,
+        CpuException::InstructionBusError(_) | CpuException::DataBusError(_) => SIGSEGV,
+        CpuException::Syscall => SIGSYS,
+        CpuException::Breakpoint => SIGTRAP,
+        CpuException::ReservedInstruction(_) | CpuException::CoprocessorUnusable(_) => SIGILL,
+        CpuException::Overflow | CpuException::FloatingPoint => SIGFPE,
+    };
+}
+
+/// Describes the registers so that gdb uses the numbering of the `g` packet
+fn target_description() -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n<architecture>mips</architecture>\n");
+    xml.push_str("<feature name=\"org.gnu.gdb.mips.cpu\">\n");
+    for register in 0..32 {
+        let _ = writeln!(xml, "<reg name=\"r{}\" bitsize=\"32\" regnum=\"{}\"/>", register, register);
+    }
+    let _ = writeln!(xml, "<reg name=\"lo\" bitsize=\"32\" regnum=\"{}\"/>", LO);
+    let _ = writeln!(xml, "<reg name=\"hi\" bitsize=\"32\" regnum=\"{}\"/>", HI);
+    let _ = writeln!(xml, "<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{}\"/>", PC);
+    xml.push_str("</feature>\n<feature name=\"org.gnu.gdb.mips.cp0\">\n");
+    let _ = writeln!(xml, "<reg name=\"status\" bitsize=\"32\" regnum=\"{}\"/>", SR);
+    let _ = writeln!(xml, "<reg name=\"badvaddr\" bitsize=\"32\" regnum=\"{}\"/>", BAD_VADDR);
+    let _ = writeln!(xml, "<reg name=\"cause\" bitsize=\"32\" regnum=\"{}\"/>", CAUSE);
+    xml.push_str("</feature>\n<feature name=\"org.gnu.gdb.mips.fpu\">\n");
+    for register in 0..32 {
+        let _ = writeln!(xml, "<reg name=\"f{}\" bitsize=\"32\" type=\"ieee_single\" regnum=\"{}\"/>", register, FIRST_FPR + register);
+    }
+    let _ = writeln!(xml, "<reg name=\"fcsr\" bitsize=\"32\" group=\"float\" regnum=\"{}\"/>", FCSR);
+    let _ = writeln!(xml, "<reg name=\"fir\" bitsize=\"32\" group=\"float\" regnum=\"{}\"/>", FIR);
+    xml.push_str("</feature>\n</target>\n");
+    return xml;
+}
+
+/// A qXfer reply, `l` marks the last chunk
+fn read_chunk(document: &str, offset: u32, length: u32) -> String {
+    let start = (offset as usize).min(document.len());
+    let end = start.saturating_add(length as usize).min(document.len());
+    let marker = if end == document.len() { 'l' } else { 'm' };
+    return format!("{}{}", marker, &document[start..end]);
+}
+
+fn write_packet(connection: &mut impl Connection, payload: &str) -> io::Result<()> {
+    let checksum = payload.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    connection.write_all(format!("${}#{:02x}", payload, checksum).as_bytes())?;
+    return connection.flush();
+}
+
+fn hex(bytes: &[u8]) -> String {
+    return bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    return u32::from_str_radix(text, 16).ok();
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    return (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok()).collect();
+}
+
+/// `address,length`
+fn parse_range(text: &str) -> Option<(u32, u32)> {
+    let (address, length) = text.split_once(',')?;
+    return Some((parse_hex(address)?, parse_hex(length)?));
+}
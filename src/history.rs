@@ -0,0 +1,222 @@
+use std::collections::VecDeque;
+use std::mem;
+
+use crate::memory_mapper::MemoryAccess;
+use crate::snapshot::{CpuState, Snapshot};
+use crate::trace::TracedRegister;
+
+/// How much of the past `CPU::step_back` can go back to, the oldest instructions are forgotten first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryLimits {
+    /// Rough size of the undo log, which can go back one instruction at a time
+    pub max_bytes: usize,
+    /// Instructions between two snapshots, snapshots are kept after their instructions have left the undo log
+    pub snapshot_interval: u64,
+    /// Each snapshot holds a copy of every memory region
+    pub max_snapshots: usize,
+}
+
+impl Default for HistoryLimits {
+    fn default() -> Self {
+        HistoryLimits { max_bytes: 64 << 20, snapshot_interval: 1 << 20, max_snapshots: 4 }
+    }
+}
+
+/// What `CPU::reverse_to_last_write` looks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteTarget {
+    Register(TracedRegister),
+    /// Any of `size` bytes from `address`
+    Memory { address: u32, size: u32 },
+}
+
+/// Takes back one instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndoRecord {
+    /// The words of the CPU state the instruction changed with the value they had before, numbered like `state_words`
+    pub words: Vec<(u8, u32)>,
+    pub delayed_branch: Option<u32>,
+    pub pending_load: Option<(u8, u32)>,
+    pub exit_code: Option<i32>,
+    /// The state of the syscall handler before a SYSCALL
+    pub syscalls: Option<Vec<u8>>,
+    /// The writes of the instruction in the order it made them, with the values they overwrote
+    pub writes: Vec<MemoryAccess>,
+}
+
+const WORD_PC: usize = 32;
+const WORD_HI: usize = 33;
+const WORD_LO: usize = 34;
+const STATE_WORDS: usize = 72;
+
+/// The registers of a CPU state as words: the GPRs, pc, hi, lo, Status, Cause, EPC, BadVAddr, the FPU registers
+/// and FCSR
+fn state_words(state: &CpuState) -> [u32; STATE_WORDS] {
+    let mut words = [0; STATE_WORDS];
+    words[..32].copy_from_slice(&state.registers);
+    words[32..39].copy_from_slice(&[state.pc, state.hi, state.lo, state.status, state.cause, state.epc, state.bad_vaddr]);
+    words[39..71].copy_from_slice(&state.fpu_registers);
+    words[71] = state.fcsr;
+    return words;
+}
+
+fn set_state_word(state: &mut CpuState, index: usize, value: u32) {
+    match index {
+        0..=31 => state.registers[index] = value,
+        WORD_PC => state.pc = value,
+        WORD_HI => state.hi = value,
+        WORD_LO => state.lo = value,
+        35 => state.status = value,
+        36 => state.cause = value,
+        37 => state.epc = value,
+        38 => state.bad_vaddr = value,
+        39..=70 => state.fpu_registers[index - 39] = value,
+        _ => state.fcsr = value,
+    }
+}
+
+impl UndoRecord {
+    /// What an instruction did, given the state before and after it
+    pub fn new(before: &CpuState, after: &CpuState, syscalls: Option<Vec<u8>>, writes: Vec<MemoryAccess>) -> Self {
+        let (old, new) = (state_words(before), state_words(after));
+        let words = (0..STATE_WORDS).filter(|i| old[*i] != new[*i]).map(|i| (i as u8, old[i])).collect();
+        UndoRecord {
+            words,
+            delayed_branch: before.delayed_branch,
+            pending_load: before.pending_load,
+            exit_code: before.exit_code,
+            syscalls,
+            writes,
+        }
+    }
+
+    /// Takes the registers back to what they were before the instruction, memory is left to the caller
+    pub fn undo(&self, state: &mut CpuState) {
+        for (index, value) in &self.words {
+            set_state_word(state, *index as usize, *value);
+        }
+        state.delayed_branch = self.delayed_branch;
+        state.pending_load = self.pending_load;
+        state.exit_code = self.exit_code;
+    }
+
+    /// Whether the instruction changed the register or wrote any of the bytes
+    pub fn writes_to(&self, target: WriteTarget) -> bool {
+        return match target {
+            WriteTarget::Register(register) => {
+                let index = match register {
+                    TracedRegister::Gpr(register) => register as usize,
+                    TracedRegister::Hi => WORD_HI,
+                    TracedRegister::Lo => WORD_LO,
+                };
+                self.words.iter().any(|(i, _)| *i as usize == index)
+            },
+            WriteTarget::Memory { address, size } => {
+                let last = address.saturating_add(size.max(1) - 1);
+                self.writes.iter().any(|write| write.address <= last && address <= write.address.saturating_add(write.size - 1))
+            },
+        };
+    }
+
+    fn size(&self) -> usize {
+        return mem::size_of::<UndoRecord>()
+            + self.words.len() * mem::size_of::<(u8, u32)>()
+            + self.writes.len() * mem::size_of::<MemoryAccess>()
+            + self.syscalls.as_ref().map_or(0, |state| state.len());
+    }
+}
+
+/// The undo log and the snapshots of a CPU.
+///
+/// Positions count the instructions executed since the history started, stepping back lowers the position and
+/// forgets the future
+pub struct History {
+    limits: HistoryLimits,
+    position: u64,
+    /// The last record takes the CPU from `position` back to `position - 1`
+    records: VecDeque<UndoRecord>,
+    bytes: usize,
+    snapshots: VecDeque<(u64, Snapshot)>,
+}
+
+impl History {
+    pub fn new(limits: HistoryLimits) -> Self {
+        History { limits, position: 0, records: VecDeque::new(), bytes: 0, snapshots: VecDeque::new() }
+    }
+
+    pub fn limits(&self) -> HistoryLimits {
+        return self.limits;
+    }
+
+    pub fn position(&self) -> u64 {
+        return self.position;
+    }
+
+    /// The oldest position the undo log can go back to one instruction at a time
+    pub fn first_record(&self) -> u64 {
+        return self.position - self.records.len() as u64;
+    }
+
+    /// The oldest position that can be gone back to
+    pub fn oldest(&self) -> u64 {
+        return self.snapshots.front().map_or(self.first_record(), |(position, _)| (*position).min(self.first_record()));
+    }
+
+    /// Whether a snapshot is due before the next instruction
+    pub fn wants_snapshot(&self) -> bool {
+        let taken = self.snapshots.back().is_some_and(|(position, _)| *position == self.position);
+        return self.limits.max_snapshots > 0 && !taken && self.position.is_multiple_of(self.limits.snapshot_interval.max(1));
+    }
+
+    pub fn push_snapshot(&mut self, snapshot: Snapshot) {
+        self.snapshots.push_back((self.position, snapshot));
+        if self.snapshots.len() > self.limits.max_snapshots {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// Records the instruction that just executed
+    pub fn push(&mut self, record: UndoRecord) {
+        self.position += 1;
+        self.bytes += record.size();
+        self.records.push_back(record);
+        while self.bytes > self.limits.max_bytes {
+            match self.records.pop_front() {
+                Some(record) => self.bytes -= record.size(),
+                None => break,
+            }
+        }
+    }
+
+    /// Takes the record of the last instruction
+    pub fn pop(&mut self) -> Option<UndoRecord> {
+        let record = self.records.pop_back()?;
+        self.bytes -= record.size();
+        self.position -= 1;
+        self.forget_future();
+        return Some(record);
+    }
+
+    pub fn records(&self) -> impl DoubleEndedIterator<Item = &UndoRecord> + '_ {
+        return self.records.iter();
+    }
+
+    /// The oldest snapshot taken at or after `position`, older than anything the undo log can reach
+    pub fn snapshot_before_records(&self, position: u64) -> Option<&(u64, Snapshot)> {
+        return self.snapshots.iter().find(|(taken, _)| *taken >= position && *taken < self.first_record());
+    }
+
+    /// Moves back to a snapshot, the undo log doesn't reach that far so it is emptied
+    pub fn rewind_to(&mut self, position: u64) {
+        self.records.clear();
+        self.bytes = 0;
+        self.position = position;
+        self.forget_future();
+    }
+
+    fn forget_future(&mut self) {
+        while self.snapshots.back().is_some_and(|(taken, _)| *taken > self.position) {
+            self.snapshots.pop_back();
+        }
+    }
+}
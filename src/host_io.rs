@@ -0,0 +1,108 @@
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::thread;
+
+/// The host's standard input, read by a single thread and shared by everything that reads the console: the devices
+/// poll it without blocking while the console syscalls and the debugger wait for it
+struct SharedStdin {
+    state: Mutex<StdinState>,
+    arrived: Condvar,
+}
+
+#[derive(Default)]
+struct StdinState {
+    bytes: VecDeque<u8>,
+    closed: bool,
+}
+
+/// Starts the reader thread the first time standard input is needed, so nothing is read before someone asks
+fn shared_stdin() -> &'static SharedStdin {
+    static STDIN: OnceLock<SharedStdin> = OnceLock::new();
+    return STDIN.get_or_init(|| {
+        thread::spawn(|| {
+            let stdin = shared_stdin();
+            let mut buffer = [0; 4096];
+            loop {
+                let read = io::stdin().lock().read(&mut buffer);
+                let mut state = stdin.state.lock().unwrap();
+                match read {
+                    Ok(0) | Err(_) => state.closed = true,
+                    Ok(length) => state.bytes.extend(&buffer[..length]),
+                }
+                stdin.arrived.notify_all();
+                if state.closed {
+                    return;
+                }
+            }
+        });
+        SharedStdin { state: Mutex::new(StdinState::default()), arrived: Condvar::new() }
+    });
+}
+
+/// The next byte of the host's standard input when one has arrived, without blocking
+pub fn poll_stdin() -> Option<u8> {
+    return shared_stdin().state.lock().unwrap().bytes.pop_front();
+}
+
+/// Blocking reads of the host's standard input that leave what they don't need to the devices, wrap it in a
+/// `BufReader` of capacity 1 to read lines
+pub struct Stdin;
+
+impl Read for Stdin {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let stdin = shared_stdin();
+        let mut state = stdin.state.lock().unwrap();
+        while state.bytes.is_empty() && !state.closed {
+            state = stdin.arrived.wait(state).unwrap();
+        }
+        let length = buffer.len().min(state.bytes.len());
+        for (byte, read) in buffer.iter_mut().zip(state.bytes.drain(..length)) {
+            *byte = read;
+        }
+        return Ok(length);
+    }
+}
+
+/// Puts the terminal on standard input in raw mode, the settings it had are restored on drop.
+///
+/// Ctrl-C still stops the virtual machine and the line endings of the output are left alone
+#[cfg(unix)]
+pub struct RawMode {
+    original: libc::termios,
+}
+
+#[cfg(unix)]
+impl RawMode {
+    /// `None` when standard input isn't a terminal
+    pub fn enable() -> Option<RawMode> {
+        // SAFETY: termios is plain data that tcgetattr fills in, and the calls only touch the terminal settings
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) == 0 {
+                return None;
+            }
+            let mut original: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return None;
+            }
+            let mut raw = original;
+            libc::cfmakeraw(&mut raw);
+            raw.c_lflag |= libc::ISIG;
+            raw.c_oflag |= libc::OPOST;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return None;
+            }
+            return Some(RawMode { original });
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        // SAFETY: puts back the settings read by `enable`
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::cell::Cell;
+use std::io;
+
+use crate::device::Device;
+use crate::memory_mapper::{MemoryMappable, MemoryMapper};
+use crate::snapshot;
+
+/// Sits between the interrupt lines the devices are wired to and the hardware interrupt inputs of the CPU.
+///
+/// The registers are big endian words: the lines asserted when the CPU last sampled them at offset 0, the mask of the
+/// lines let through at offset 4 and the highest priority line that is both pending and enabled at offset 8, or
+/// 0xffffffff when there is none. Lines are numbered like the `irq` of the devices, bit 0 is IP2 and the higher lines
+/// have priority as they do on the CPU. All the lines are masked after a reset
+pub struct InterruptController {
+    /// Inputs are levels, they are sampled again before every instruction
+    pending: Cell<u8>,
+    mask: u8,
+}
+
+impl InterruptController {
+    const PENDING: u32 = 0;
+    const MASK: u32 = 4;
+    const ACTIVE: u32 = 8;
+
+    const LINES_MASK: u8 = (1 << MemoryMapper::INTERRUPT_LINES) - 1;
+
+    pub fn new() -> Self {
+        InterruptController { pending: Cell::new(0), mask: 0 }
+    }
+
+    pub fn pending(&self) -> u8 {
+        return self.pending.get();
+    }
+
+    pub fn mask(&self) -> u8 {
+        return self.mask;
+    }
+
+    pub fn set_mask(&mut self, mask: u8) {
+        self.mask = mask & InterruptController::LINES_MASK;
+    }
+
+    /// The pending and enabled line with the highest priority
+    pub fn active_line(&self) -> Option<u8> {
+        let lines = self.pending.get() & self.mask;
+        return (lines != 0).then(|| 7 - lines.leading_zeros() as u8);
+    }
+
+    fn read_register(&self, address: u32) -> u32 {
+        return match address & !3 {
+            InterruptController::PENDING => self.pending.get() as u32,
+            InterruptController::MASK => self.mask as u32,
+            InterruptController::ACTIVE => self.active_line().map_or(u32::MAX, |line| line as u32),
+            _ => 0,
+        };
+    }
+}
+
+impl Default for InterruptController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryMappable for InterruptController {
+    fn get_byte(&self, address: u32) -> [u8; 1] {
+        return [self.read_register(address).to_be_bytes()[(address & 3) as usize]];
+    }
+
+    fn get_half_word(&self, address: u32) -> [u8; 2] {
+        let word = self.read_register(address).to_be_bytes();
+        let offset = (address & 2) as usize;
+        return [word[offset], word[offset + 1]];
+    }
+
+    fn get_word(&self, address: u32) -> [u8; 4] {
+        return self.read_register(address).to_be_bytes();
+    }
+
+    /// The mask fits in the least significant byte of its register
+    fn write_byte(&mut self, address: u32, value: [u8; 1]) {
+        if address == InterruptController::MASK + 3 {
+            self.set_mask(value[0]);
+        }
+    }
+
+    fn write_half_word(&mut self, address: u32, value: [u8; 2]) {
+        if address == InterruptController::MASK + 2 {
+            self.set_mask(value[1]);
+        }
+    }
+
+    fn write_word(&mut self, address: u32, value: [u8; 4]) {
+        if address & !3 == InterruptController::MASK {
+            self.set_mask(value[3]);
+        }
+    }
+
+    fn save_state(&self) -> Option<Vec<u8>> {
+        return Some(vec![self.pending.get(), self.mask]);
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> io::Result<()> {
+        let [pending, mask] = state else {
+            return Err(snapshot::invalid_snapshot("invalid interrupt controller state"));
+        };
+        self.pending.set(*pending);
+        self.mask = *mask;
+        return Ok(());
+    }
+}
+
+impl Device for InterruptController {
+    fn name(&self) -> &str {
+        return "intc";
+    }
+
+    fn size(&self) -> u32 {
+        return 12;
+    }
+
+    fn reset(&mut self) {
+        *self = InterruptController::new();
+    }
+
+    fn route_interrupts(&self, lines: u8) -> Option<u8> {
+        self.pending.set(lines & InterruptController::LINES_MASK);
+        return Some(self.pending.get() & self.mask);
+    }
+}
@@ -0,0 +1,201 @@
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io;
+
+use crate::device::Device;
+use crate::host_io;
+#[cfg(unix)]
+use crate::host_io::RawMode;
+use crate::memory_mapper::MemoryMappable;
+use crate::snapshot;
+
+/// A console receiver with the registers of the SPIM and MARS simulators.
+///
+/// The control register at offset 0 has the ready bit, set when a byte has been received, and the interrupt enable
+/// bit, the only one the guest can write. Reading the data register at offset 4 returns the byte and clears the ready
+/// bit, the next byte is received on the following instruction. Registers are big endian words like the other devices
+pub struct KeyboardDevice {
+    input: KeyboardInput,
+    ready: Cell<bool>,
+    data: Cell<u8>,
+    interrupt_enable: bool,
+    /// Set by the first access of the guest, the host's input is left to the syscalls before
+    accessed: Cell<bool>,
+    /// Restores the terminal when the device goes away
+    #[cfg(unix)]
+    _raw_mode: Option<RawMode>,
+}
+
+enum KeyboardInput {
+    Script(VecDeque<u8>),
+    /// The host's standard input, shared with the console syscalls and the debugger
+    Host {
+        /// The terminal has been put in raw mode
+        open: bool,
+    },
+}
+
+impl KeyboardDevice {
+    pub const CONTROL_READY: u32 = 1 << 0;
+    pub const CONTROL_INTERRUPT_ENABLE: u32 = 1 << 1;
+
+    const CONTROL: u32 = 0;
+    const DATA: u32 = 4;
+
+    /// Types `input` once the guest starts reading, for tests and scripted sessions
+    pub fn scripted(input: &[u8]) -> Self {
+        return KeyboardDevice::with_input(KeyboardInput::Script(input.iter().copied().collect()));
+    }
+
+    /// Reads the host's standard input a key at a time once the guest has accessed the keyboard, a terminal is then put
+    /// in raw mode without echo until the device is dropped. Ctrl-C still stops the virtual machine
+    pub fn stdin() -> Self {
+        return KeyboardDevice::with_input(KeyboardInput::Host { open: false });
+    }
+
+    fn with_input(input: KeyboardInput) -> Self {
+        KeyboardDevice {
+            input,
+            ready: Cell::new(false),
+            data: Cell::new(0),
+            interrupt_enable: false,
+            accessed: Cell::new(false),
+            #[cfg(unix)]
+            _raw_mode: None,
+        }
+    }
+
+    /// Adds to the scripted input, does nothing for the host's input
+    pub fn type_bytes(&mut self, bytes: &[u8]) {
+        if let KeyboardInput::Script(script) = &mut self.input {
+            script.extend(bytes);
+        }
+    }
+
+    fn control(&self) -> u32 {
+        let ready = if self.ready.get() { KeyboardDevice::CONTROL_READY } else { 0 };
+        let enable = if self.interrupt_enable { KeyboardDevice::CONTROL_INTERRUPT_ENABLE } else { 0 };
+        return ready | enable;
+    }
+
+    /// The value of a register, without the side effects of reading it
+    fn register(&self, address: u32) -> u32 {
+        return match address & !3 {
+            KeyboardDevice::CONTROL => self.control(),
+            KeyboardDevice::DATA => self.data.get() as u32,
+            _ => 0,
+        };
+    }
+
+    fn read_register(&self, address: u32) -> u32 {
+        self.accessed.set(true);
+        if address & !3 == KeyboardDevice::DATA {
+            self.ready.set(false);
+        }
+        return self.register(address);
+    }
+
+    fn write_register(&mut self, address: u32, value: u32) {
+        self.accessed.set(true);
+        if address & !3 == KeyboardDevice::CONTROL {
+            self.interrupt_enable = value & KeyboardDevice::CONTROL_INTERRUPT_ENABLE != 0;
+        }
+    }
+}
+
+impl MemoryMappable for KeyboardDevice {
+    fn get_byte(&self, address: u32) -> [u8; 1] {
+        return [self.read_register(address).to_be_bytes()[(address & 3) as usize]];
+    }
+
+    fn get_half_word(&self, address: u32) -> [u8; 2] {
+        let word = self.read_register(address).to_be_bytes();
+        let offset = (address & 2) as usize;
+        return [word[offset], word[offset + 1]];
+    }
+
+    fn get_word(&self, address: u32) -> [u8; 4] {
+        return self.read_register(address).to_be_bytes();
+    }
+
+    fn peek(&self, address: u32) -> u8 {
+        return self.register(address).to_be_bytes()[(address & 3) as usize];
+    }
+
+    /// The control register is written a byte at a time as its least significant byte
+    fn write_byte(&mut self, address: u32, value: [u8; 1]) {
+        if address & 3 == 3 {
+            self.write_register(address, value[0] as u32);
+        }
+    }
+
+    fn write_half_word(&mut self, address: u32, value: [u8; 2]) {
+        if address & 2 == 2 {
+            self.write_register(address, u16::from_be_bytes(value) as u32);
+        }
+    }
+
+    fn write_word(&mut self, address: u32, value: [u8; 4]) {
+        self.write_register(address, u32::from_be_bytes(value));
+    }
+
+    /// The registers, input that hasn't been received yet isn't part of the machine
+    fn save_state(&self) -> Option<Vec<u8>> {
+        return Some(vec![self.ready.get() as u8, self.data.get(), self.interrupt_enable as u8]);
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> io::Result<()> {
+        let [ready, data, interrupt_enable] = state else {
+            return Err(snapshot::invalid_snapshot("invalid keyboard state"));
+        };
+        self.ready.set(*ready != 0);
+        self.data.set(*data);
+        self.interrupt_enable = *interrupt_enable != 0;
+        return Ok(());
+    }
+}
+
+impl Device for KeyboardDevice {
+    fn name(&self) -> &str {
+        return "keyboard";
+    }
+
+    fn size(&self) -> u32 {
+        return 8;
+    }
+
+    fn reset(&mut self) {
+        self.ready.set(false);
+        self.data.set(0);
+        self.interrupt_enable = false;
+    }
+
+    /// Receives the next byte once the guest has read the previous one
+    fn tick(&mut self, _cycles: u64) {
+        if self.ready.get() {
+            return;
+        }
+        let byte = match &mut self.input {
+            KeyboardInput::Script(script) => script.pop_front(),
+            KeyboardInput::Host { .. } if !self.accessed.get() => None,
+            KeyboardInput::Host { open } => {
+                if !*open {
+                    #[cfg(unix)]
+                    {
+                        self._raw_mode = RawMode::enable();
+                    }
+                    *open = true;
+                }
+                host_io::poll_stdin()
+            },
+        };
+        if let Some(byte) = byte {
+            self.data.set(byte);
+            self.ready.set(true);
+        }
+    }
+
+    fn interrupt(&self) -> bool {
+        return self.interrupt_enable && self.ready.get();
+    }
+}
@@ -1,12 +1,57 @@
+#![allow(clippy::needless_return)]
+#![allow(non_camel_case_types)]
+
+pub mod assembler;
+pub mod cop0;
 pub mod cpu;
+pub mod debugger;
+pub mod device;
+pub mod disassembler;
+pub mod disk;
+pub mod elf;
+pub mod exception;
+pub mod fpu;
+pub mod framebuffer;
+pub mod gdb;
+pub mod history;
+pub mod host_io;
+pub mod interrupt_controller;
+pub mod keyboard;
+pub mod machine;
 pub mod memory;
 pub mod memory_mapper;
 pub mod screen_device;
+pub mod snapshot;
+pub mod symbol_table;
+pub mod syscall;
+pub mod timer;
+pub mod trace;
+pub mod uart;
 
 #[cfg(test)]
 mod tests {
+    use crate::assembler::{assemble, Assembler, AssemblerOptions};
     use crate::cpu::CPU;
+    use crate::debugger::{DebugStop, Debugger};
+    use crate::device::{Device, DeviceRegistry};
+    use crate::disk::DiskDevice;
+    use crate::disassembler::{disassemble, disassemble_with_symbols};
+    use crate::elf::{self, ElfError, ElfProgram};
+    use crate::cop0::Cop0;
+    use crate::cpu::{CpuConfig, Endianness, Function, Instruction, RunLimits, StopReason};
+    use crate::exception::CpuException;
+    use crate::fpu::{FpuFlag, FPU};
+    use crate::framebuffer::{FrameDumps, FramebufferDevice, PixelMode};
+    use crate::history::{HistoryLimits, WriteTarget};
+    use crate::machine::{MachineDescription, MachineError};
     use crate::memory::Memory;
+    use crate::memory_mapper::{BusError, MapError, MemoryAccess, MemoryMappable, MemoryMapper, WatchKind, Watchpoint};
+    use crate::screen_device::{AnsiRenderer, ScreenDevice, TextScreen, TextSnapshot};
+    use crate::snapshot::Snapshot;
+    use crate::syscall::SpimSyscalls;
+    use crate::timer::TimerDevice;
+    use crate::trace::{self, BinarySink, JsonLinesSink, RegisterChange, TraceFilter, TracedRegister, Tracer};
+    use crate::uart::{MemorySerial, UartDevice};
 
 
     // #[test]
@@ -588,5 +633,1855 @@ mod tests {
     
     
 
-}
+    fn form_i_instruction(op_code: u32, rs: u32, rt: u32, immediate: u32) -> u32 {
+        return (op_code << 26) | (rs << 21) | (rt << 16) | (immediate & 0xffff);
+    }
+
+    fn form_r_instruction(rs: u32, rt: u32, rd: u32, shift_amount: u32, function: u32) -> u32 {
+        return (rs << 21) | (rt << 16) | (rd << 11) | (shift_amount << 6) | function;
+    }
+
+    fn memory_mapper_with_program(program: &[u32]) -> MemoryMapper {
+        let mut memory_mapper = MemoryMapper::new();
+        memory_mapper.map(Box::new(Memory::new(0x1000)), 0, 0xfff, false);
+        for (i, instruction) in program.iter().enumerate() {
+            memory_mapper.write_word(i as u32 * 4, instruction.to_be_bytes()).unwrap();
+        }
+        return memory_mapper;
+    }
+
+    #[test]
+    fn unknown_opcode_raises_reserved_instruction() {
+        let instruction = 0o77_u32 << 26;
+        let mut memory_mapper = memory_mapper_with_program(&[instruction]);
+        let mut cpu = CPU::new(&mut memory_mapper);
+
+        assert_eq!(cpu.step(), Err(CpuException::ReservedInstruction(instruction)));
+        assert_eq!(cpu.get_pc(), 0);
+    }
+
+    #[test]
+    fn add_overflow_raises_overflow_and_leaves_rd_untouched() {
+        let mut memory_mapper = memory_mapper_with_program(&[
+            form_i_instruction(Instruction::LUI as u32, 0, 1, 0x7fff),
+            form_i_instruction(Instruction::ADDI as u32, 0, 3, 7),
+            form_r_instruction(1, 1, 3, 0, Function::ADD as u32),
+        ]);
+        let mut cpu = CPU::new(&mut memory_mapper);
+
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.step(), Err(CpuException::Overflow));
+        assert_eq!(cpu.get_register_value(3), 7);
+        assert_eq!(cpu.get_pc(), 8);
+    }
+
+    #[test]
+    fn writes_to_the_zero_register_are_discarded() {
+        let mut memory_mapper = memory_mapper_with_program(&[
+            form_i_instruction(Instruction::ADDIU as u32, 0, 1, 5),
+            form_r_instruction(1, 1, 0, 0, Function::ADDU as u32),
+        ]);
+        let mut cpu = CPU::new(&mut memory_mapper);
+
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.get_register_value(0), 0);
+    }
+
+    #[test]
+    fn unaligned_and_unmapped_accesses_raise_address_and_bus_errors() {
+        let mut memory_mapper = memory_mapper_with_program(&[form_i_instruction(Instruction::LW as u32, 0, 1, 2)]);
+        let mut cpu = CPU::new(&mut memory_mapper);
+        assert_eq!(cpu.step(), Err(CpuException::AddressErrorLoad(2)));
+
+        let mut memory_mapper = memory_mapper_with_program(&[form_i_instruction(Instruction::SHW as u32, 0, 1, 3)]);
+        let mut cpu = CPU::new(&mut memory_mapper);
+        assert_eq!(cpu.step(), Err(CpuException::AddressErrorStore(3)));
+
+        let mut memory_mapper = memory_mapper_with_program(&[
+            form_i_instruction(Instruction::LUI as u32, 0, 2, 0x10),
+            form_i_instruction(Instruction::LW as u32, 2, 1, 0xfffc),
+        ]);
+        let mut cpu = CPU::new(&mut memory_mapper);
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.step(), Err(CpuException::DataBusError(0x000f_fffc)));
+    }
+
+    #[test]
+    fn ram_mapped_past_the_end_of_the_address_space_stops_there() {
+        let mut memory_mapper = MemoryMapper::new();
+        memory_mapper.map_ram(0xffff_f800, 0x1000);
+        assert_eq!(memory_mapper.get_word(0xffff_effc), Err(BusError { address: 0xffff_effc }));
+        assert!(memory_mapper.write_word(0xffff_fffc, [1; 4]).is_ok());
+        assert_eq!(memory_mapper.get_word(0xffff_f000).unwrap(), [0; 4]);
+    }
+
+    #[test]
+    fn syscall_and_break_raise_their_exceptions() {
+        let mut memory_mapper = memory_mapper_with_program(&[form_r_instruction(0, 0, 0, 0, Function::BREAK as u32)]);
+        let mut cpu = CPU::new(&mut memory_mapper);
+        assert_eq!(cpu.step(), Err(CpuException::Breakpoint));
+
+        let mut memory_mapper = memory_mapper_with_program(&[form_r_instruction(0, 0, 0, 0, Function::SYSCALL as u32)]);
+        let mut cpu = CPU::new(&mut memory_mapper);
+        assert_eq!(cpu.run(), Err(CpuException::Syscall));
+    }
+
+    #[test]
+    fn fetching_from_an_unmapped_address_raises_a_bus_error() {
+        let mut memory_mapper = memory_mapper_with_program(&[((Instruction::J as u32) << 26) | (0x2000 >> 2)]);
+        let mut cpu = CPU::new(&mut memory_mapper);
+
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.step(), Err(CpuException::InstructionBusError(0x2000)));
+    }
+
+    fn form_cop0_instruction(operation: u32, rt: u32, rd: u32) -> u32 {
+        return ((Instruction::COP0 as u32) << 26) | (operation << 21) | (rt << 16) | (rd << 11);
+    }
+
+    const RFE: u32 = ((Instruction::COP0 as u32) << 26) | (0b10000 << 21) | 0o20;
+
+    #[test]
+    fn exceptions_vector_through_cop0_and_rfe_restores_the_mode() {
+        let mut memory_mapper = memory_mapper_with_program(&[
+            form_i_instruction(Instruction::ORI as u32, 0, 1, 0b000001),
+            form_cop0_instruction(0b00100, 1, 12),
+            form_r_instruction(0, 0, 0, 0, Function::SYSCALL as u32),
+        ]);
+        let mut handler = Memory::new(0x100);
+        for (i, instruction) in [form_cop0_instruction(0, 2, 13), form_cop0_instruction(0, 3, 14), RFE].iter().enumerate() {
+            handler.write_word(0x80 + i as u32 * 4, instruction.to_be_bytes());
+        }
+        memory_mapper.map(Box::new(handler), 0x8000_0000, 0x8000_00ff, true);
+        let mut cpu = CPU::new(&mut memory_mapper);
+
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.step(), Err(CpuException::Syscall));
+        assert_eq!(cpu.get_pc(), 0x8000_0080);
+        assert_eq!(cpu.cop0().status() & 0x3f, 0b000100);
+
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.get_register_value(2) & Cop0::CAUSE_EXC_CODE_MASK, 8 << 2);
+        assert_eq!(cpu.get_register_value(3), 8);
+        assert_eq!(cpu.cop0().status() & 0x3f, 0b000001);
+    }
+
+    #[test]
+    fn address_errors_record_bad_vaddr_and_use_the_boot_vector_when_bev_is_set() {
+        let mut memory_mapper = memory_mapper_with_program(&[form_i_instruction(Instruction::LW as u32, 0, 1, 6)]);
+        memory_mapper.map(Box::new(Memory::new(0x200)), 0xbfc0_0000, 0xbfc0_01ff, true);
+        let mut cpu = CPU::new(&mut memory_mapper);
+
+        assert_eq!(cpu.step(), Err(CpuException::AddressErrorLoad(6)));
+        assert_eq!(cpu.get_pc(), 0xbfc0_0180);
+        assert_eq!(cpu.cop0().bad_vaddr(), 6);
+        assert_eq!(cpu.cop0().epc(), 0);
+        assert_eq!(cpu.cop0().cause() & Cop0::CAUSE_EXC_CODE_MASK, 4 << 2);
+
+        // Bus errors leave BadVAddr alone
+        let mut memory_mapper = memory_mapper_with_program(&[
+            form_i_instruction(Instruction::LUI as u32, 0, 2, 0x10),
+            form_i_instruction(Instruction::LW as u32, 2, 1, 0xfffc),
+        ]);
+        memory_mapper.map(Box::new(Memory::new(0x200)), 0xbfc0_0000, 0xbfc0_01ff, true);
+        let mut cpu = CPU::new(&mut memory_mapper);
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.step(), Err(CpuException::DataBusError(0x000f_fffc)));
+        assert_eq!(cpu.get_pc(), 0xbfc0_0180);
+        assert_eq!(cpu.cop0().bad_vaddr(), 0);
+        assert_eq!(cpu.cop0().cause() & Cop0::CAUSE_EXC_CODE_MASK, 7 << 2);
+    }
+
+    #[test]
+    fn user_mode_cannot_touch_kernel_space_or_cop0() {
+        let mut memory_mapper = memory_mapper_with_program(&[
+            form_i_instruction(Instruction::ORI as u32, 0, 1, 0b000010),
+            form_cop0_instruction(0b00100, 1, 12),
+            form_i_instruction(Instruction::LUI as u32, 0, 2, 0x8000),
+            form_i_instruction(Instruction::LW as u32, 2, 3, 0),
+        ]);
+        let mut cpu = CPU::new(&mut memory_mapper);
+
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.step(), Err(CpuException::AddressErrorLoad(0x8000_0000)));
+
+        let mut memory_mapper = memory_mapper_with_program(&[
+            form_i_instruction(Instruction::ORI as u32, 0, 1, 0b000010),
+            form_cop0_instruction(0b00100, 1, 12),
+            form_cop0_instruction(0b00100, 0, 12),
+        ]);
+        let mut cpu = CPU::new(&mut memory_mapper);
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.step(), Err(CpuException::CoprocessorUnusable(0)));
+    }
+
+    /// Runs `LUI/ORI $1, 0xaabbccdd` followed by `instruction` on memory holding 11 22 33 44 at 0x100,
+    /// returns $1 and the four bytes at 0x100
+    fn run_unaligned_access(endianness: Endianness, instruction: u32) -> (u32, [u8; 4]) {
+        let program = [
+            form_i_instruction(Instruction::LUI as u32, 0, 1, 0xaabb),
+            form_i_instruction(Instruction::ORI as u32, 1, 1, 0xccdd),
+            instruction,
+        ];
+        let mut memory_mapper = MemoryMapper::new();
+        memory_mapper.map(Box::new(Memory::new(0x200)), 0, 0x1ff, false);
+        for (i, instruction) in program.iter().enumerate() {
+            memory_mapper.write_word(i as u32 * 4, endianness.u32_to_bytes(*instruction)).unwrap();
+        }
+        memory_mapper.write_word(0x100, [0x11, 0x22, 0x33, 0x44]).unwrap();
+        let mut cpu = CPU::new(&mut memory_mapper);
+        cpu.set_endianness(endianness);
+        for _ in 0..program.len() {
+            assert_eq!(cpu.step(), Ok(()));
+        }
+        let register = cpu.get_register_value(1);
+        return (register, cpu.memory_mapper().get_word(0x100).unwrap());
+    }
+
+    #[test]
+    fn lwl_and_lwr_merge_every_byte_offset() {
+        let cases = [
+            (Endianness::Big, Instruction::LWL, [0x11223344, 0x223344dd, 0x3344ccdd, 0x44bbccdd]),
+            (Endianness::Big, Instruction::LWR, [0xaabbcc11, 0xaabb1122, 0xaa112233, 0x11223344]),
+            (Endianness::Little, Instruction::LWL, [0x11bbccdd, 0x2211ccdd, 0x332211dd, 0x44332211]),
+            (Endianness::Little, Instruction::LWR, [0x44332211, 0xaa443322, 0xaabb4433, 0xaabbcc44]),
+        ];
+        for (endianness, op_code, expected) in cases {
+            let op_code = op_code as u32;
+            for (offset, expected) in expected.iter().enumerate() {
+                let (register, _) = run_unaligned_access(endianness, form_i_instruction(op_code, 0, 1, 0x100 + offset as u32));
+                assert_eq!(register, *expected, "{:?} opcode {:o} offset {}", endianness, op_code, offset);
+            }
+        }
+    }
+
+    #[test]
+    fn swl_and_swr_store_every_byte_offset() {
+        let cases = [
+            (Endianness::Big, Instruction::SWL, [[0xaa, 0xbb, 0xcc, 0xdd], [0x11, 0xaa, 0xbb, 0xcc], [0x11, 0x22, 0xaa, 0xbb], [0x11, 0x22, 0x33, 0xaa]]),
+            (Endianness::Big, Instruction::SWR, [[0xdd, 0x22, 0x33, 0x44], [0xcc, 0xdd, 0x33, 0x44], [0xbb, 0xcc, 0xdd, 0x44], [0xaa, 0xbb, 0xcc, 0xdd]]),
+            (Endianness::Little, Instruction::SWL, [[0xaa, 0x22, 0x33, 0x44], [0xbb, 0xaa, 0x33, 0x44], [0xcc, 0xbb, 0xaa, 0x44], [0xdd, 0xcc, 0xbb, 0xaa]]),
+            (Endianness::Little, Instruction::SWR, [[0xdd, 0xcc, 0xbb, 0xaa], [0x11, 0xdd, 0xcc, 0xbb], [0x11, 0x22, 0xdd, 0xcc], [0x11, 0x22, 0x33, 0xdd]]),
+        ];
+        for (endianness, op_code, expected) in cases {
+            let op_code = op_code as u32;
+            for (offset, expected) in expected.iter().enumerate() {
+                let (_, memory) = run_unaligned_access(endianness, form_i_instruction(op_code, 0, 1, 0x100 + offset as u32));
+                assert_eq!(memory, *expected, "{:?} opcode {:o} offset {}", endianness, op_code, offset);
+            }
+        }
+    }
+
+    #[test]
+    fn lwl_lwr_pair_loads_an_unaligned_word() {
+        let mut memory_mapper = memory_mapper_with_program(&[
+            form_i_instruction(Instruction::LWL as u32, 0, 1, 0x101),
+            form_i_instruction(Instruction::LWR as u32, 0, 1, 0x104),
+        ]);
+        memory_mapper.write_word(0x100, [0x11, 0x22, 0x33, 0x44]).unwrap();
+        memory_mapper.write_word(0x104, [0x55, 0x66, 0x77, 0x88]).unwrap();
+        let mut cpu = CPU::new(&mut memory_mapper);
+
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.get_register_value(1), 0x22334455);
+    }
+
+    fn form_cop1_instruction(format: u32, ft: u32, fs: u32, fd: u32, function: u32) -> u32 {
+        return ((Instruction::COP1 as u32) << 26) | (format << 21) | (ft << 16) | (fs << 11) | (fd << 6) | function;
+    }
+
+    const SINGLE: u32 = 16;
+    const DOUBLE: u32 = 17;
+    const WORD: u32 = 20;
+
+    #[test]
+    fn fpu_single_and_double_arithmetic() {
+        let mut fpu = FPU::new();
+        fpu.write_single(2, 1.5);
+        fpu.write_single(4, 2.25);
+        fpu.execute(form_cop1_instruction(SINGLE, 4, 2, 6, 0o00)).unwrap();
+        fpu.execute(form_cop1_instruction(SINGLE, 4, 2, 7, 0o02)).unwrap();
+        fpu.execute(form_cop1_instruction(SINGLE, 4, 2, 8, 0o01)).unwrap();
+        fpu.execute(form_cop1_instruction(SINGLE, 0, 8, 9, 0o05)).unwrap();
+        assert_eq!(fpu.read_single(6), 3.75);
+        assert_eq!(fpu.read_single(7), 3.375);
+        assert_eq!(fpu.read_single(8), -0.75);
+        assert_eq!(fpu.read_single(9), 0.75);
+
+        fpu.write_double(10, 1e300);
+        fpu.write_double(12, -2.5);
+        fpu.execute(form_cop1_instruction(DOUBLE, 12, 10, 14, 0o03)).unwrap();
+        fpu.execute(form_cop1_instruction(DOUBLE, 0, 12, 16, 0o07)).unwrap();
+        assert_eq!(fpu.read_double(14), -4e299);
+        assert_eq!(fpu.read_double(16), 2.5);
+        assert_eq!(fpu.read_register(16), 0);
+        assert_eq!(fpu.read_register(17), 0x4004_0000);
+
+        // Doubles in odd registers are an unimplemented operation
+        assert_eq!(fpu.execute(form_cop1_instruction(DOUBLE, 12, 11, 14, 0o00)), Err(CpuException::FloatingPoint));
+        assert_ne!(fpu.fcsr() & (FpuFlag::UNIMPLEMENTED << 12), 0);
+    }
+
+    #[test]
+    fn fpu_conversions_follow_the_rounding_mode() {
+        let mut fpu = FPU::new();
+        fpu.write_single(2, 2.5);
+        fpu.execute(form_cop1_instruction(SINGLE, 0, 2, 4, 0o44)).unwrap();
+        assert_eq!(fpu.read_register(4), 2);
+        assert_ne!(fpu.fcsr() & (FpuFlag::INEXACT << 2), 0);
+
+        fpu.write_control(31, 2).unwrap();
+        fpu.execute(form_cop1_instruction(SINGLE, 0, 2, 4, 0o44)).unwrap();
+        assert_eq!(fpu.read_register(4), 3);
+
+        fpu.write_register(6, (-7_i32) as u32);
+        fpu.execute(form_cop1_instruction(WORD, 0, 6, 8, 0o41)).unwrap();
+        assert_eq!(fpu.read_double(8), -7.0);
+        fpu.execute(form_cop1_instruction(DOUBLE, 0, 8, 10, 0o40)).unwrap();
+        assert_eq!(fpu.read_single(10), -7.0);
+
+        // Each operand has to be even only when it holds a double
+        fpu.execute(form_cop1_instruction(DOUBLE, 0, 8, 11, 0o40)).unwrap();
+        assert_eq!(fpu.read_single(11), -7.0);
+        fpu.execute(form_cop1_instruction(DOUBLE, 0, 8, 13, 0o44)).unwrap();
+        assert_eq!(fpu.read_register(13), (-7_i32) as u32);
+        assert_eq!(fpu.execute(form_cop1_instruction(SINGLE, 0, 11, 3, 0o41)), Err(CpuException::FloatingPoint));
+        assert_eq!(fpu.execute(form_cop1_instruction(WORD, 0, 13, 5, 0o41)), Err(CpuException::FloatingPoint));
+        assert_ne!(fpu.fcsr() & (FpuFlag::UNIMPLEMENTED << 12), 0);
+    }
+
+    #[test]
+    fn fpu_directed_rounding_brackets_the_exact_result() {
+        let mut results = vec![];
+        for rounding_mode in 0..4 {
+            let mut fpu = FPU::new();
+            fpu.write_control(31, rounding_mode).unwrap();
+            fpu.write_single(2, 1.0);
+            fpu.write_single(4, 3.0);
+            fpu.execute(form_cop1_instruction(SINGLE, 4, 2, 6, 0o03)).unwrap();
+            results.push(fpu.read_single(6));
+        }
+        assert_eq!(results[0], 1.0_f32 / 3.0);
+        assert_eq!(results[1], results[3]);
+        assert_eq!(results[2], results[3].next_up());
+        assert!((results[3] as f64) < 1.0 / 3.0 && (results[2] as f64) > 1.0 / 3.0);
+    }
+
+    #[test]
+    fn fpu_exceptions_set_flags_and_trap_when_enabled() {
+        let mut fpu = FPU::new();
+        fpu.write_single(2, 1.0);
+        fpu.write_single(4, 0.0);
+        fpu.execute(form_cop1_instruction(SINGLE, 4, 2, 6, 0o03)).unwrap();
+        assert_eq!(fpu.read_single(6), f32::INFINITY);
+        assert_eq!((fpu.fcsr() >> 2) & 0x1f, FpuFlag::DIVISION_BY_ZERO);
+
+        fpu.write_single(8, f32::MAX);
+        fpu.execute(form_cop1_instruction(SINGLE, 8, 8, 10, 0o00)).unwrap();
+        assert_eq!(fpu.read_single(10), f32::INFINITY);
+        assert_ne!(fpu.fcsr() & (FpuFlag::OVERFLOW << 2), 0);
+
+        fpu.write_control(31, FpuFlag::DIVISION_BY_ZERO << 7).unwrap();
+        fpu.write_single(6, 5.0);
+        assert_eq!(fpu.execute(form_cop1_instruction(SINGLE, 4, 2, 6, 0o03)), Err(CpuException::FloatingPoint));
+        assert_eq!(fpu.read_single(6), 5.0);
+    }
+
+    #[test]
+    fn cop1_moves_compares_and_branches_from_the_cpu() {
+        let mut memory_mapper = memory_mapper_with_program(&[
+            form_i_instruction(Instruction::LUI as u32, 0, 1, 0x3fc0),
+            form_cop1_instruction(0b00100, 1, 2, 0, 0),
+            form_i_instruction(Instruction::LWC1 as u32, 0, 4, 0x100),
+            form_cop1_instruction(SINGLE, 4, 2, 0, 0o74),
+            form_cop1_instruction(0b01000, 1, 0, 0, 1),
+            form_i_instruction(Instruction::ORI as u32, 0, 5, 1),
+            form_i_instruction(Instruction::SWC1 as u32, 0, 2, 0x104),
+            form_cop1_instruction(0b00000, 6, 4, 0, 0),
+            form_cop1_instruction(0b00010, 7, 31, 0, 0),
+        ]);
+        memory_mapper.write_word(0x100, 2.0_f32.to_bits().to_be_bytes()).unwrap();
+        {
+            let mut cpu = CPU::new(&mut memory_mapper);
+            for _ in 0..8 {
+                assert_eq!(cpu.step(), Ok(()));
+            }
+
+            assert_eq!(cpu.get_register_value(5), 0);
+            assert_eq!(cpu.get_register_value(6), 2.0_f32.to_bits());
+            assert_ne!(cpu.get_register_value(7) & (1 << 23), 0);
+        }
+        assert_eq!(memory_mapper.get_word(0x104).unwrap(), 1.5_f32.to_bits().to_be_bytes());
+    }
+
+    fn run_steps(memory_mapper: &mut MemoryMapper, config: CpuConfig, steps: usize) -> [u32; 32] {
+        let mut cpu = CPU::with_config(memory_mapper, config);
+        for _ in 0..steps {
+            assert_eq!(cpu.step(), Ok(()));
+        }
+        let mut registers = [0; 32];
+        for (i, register) in registers.iter_mut().enumerate() {
+            *register = cpu.get_register_value(i);
+        }
+        return registers;
+    }
+
+    #[test]
+    fn branch_delay_slot_executes_before_the_branch_is_taken() {
+        let program = [
+            form_i_instruction(Instruction::BEQ as u32, 0, 0, 2),
+            form_i_instruction(Instruction::ADDIU as u32, 0, 1, 1),
+            form_i_instruction(Instruction::ADDIU as u32, 0, 2, 1),
+            form_i_instruction(Instruction::ADDIU as u32, 0, 3, 1),
+        ];
+
+        let with_delay_slots = CpuConfig { branch_delay_slots: true, load_delay_slots: false };
+        let registers = run_steps(&mut memory_mapper_with_program(&program), with_delay_slots, 3);
+        assert_eq!((registers[1], registers[2], registers[3]), (1, 0, 1));
+
+        let registers = run_steps(&mut memory_mapper_with_program(&program), CpuConfig::default(), 2);
+        assert_eq!((registers[1], registers[2], registers[3]), (0, 0, 1));
+    }
+
+    #[test]
+    fn calls_link_past_the_delay_slot_only_when_it_exists() {
+        let program = [
+            ((Instruction::JAL as u32) << 26) | (0x40 >> 2),
+            0,
+        ];
+        let with_delay_slots = CpuConfig { branch_delay_slots: true, load_delay_slots: false };
+        assert_eq!(run_steps(&mut memory_mapper_with_program(&program), with_delay_slots, 1)[31], 8);
+        assert_eq!(run_steps(&mut memory_mapper_with_program(&program), CpuConfig::default(), 1)[31], 4);
+
+        let program = [
+            form_i_instruction(Instruction::REGIMM as u32, 0, 0b10001, 2),
+            0,
+            form_i_instruction(Instruction::ADDIU as u32, 0, 1, 1),
+            form_i_instruction(Instruction::ADDIU as u32, 0, 2, 1),
+        ];
+        let registers = run_steps(&mut memory_mapper_with_program(&program), CpuConfig::default(), 2);
+        assert_eq!((registers[31], registers[1], registers[2]), (4, 0, 1));
+
+        // bgezal $ra tests the return address it had before linking, here -1
+        let program = [
+            form_i_instruction(Instruction::ADDIU as u32, 0, 31, 0xffff),
+            form_i_instruction(Instruction::REGIMM as u32, 31, 0b10001, 2),
+            form_i_instruction(Instruction::ADDIU as u32, 0, 1, 1),
+            form_i_instruction(Instruction::ADDIU as u32, 0, 2, 1),
+        ];
+        let registers = run_steps(&mut memory_mapper_with_program(&program), CpuConfig::default(), 3);
+        assert_eq!((registers[31], registers[1], registers[2]), (8, 1, 0));
+    }
+
+    #[test]
+    fn load_delay_slot_hides_the_loaded_value_from_the_next_instruction() {
+        let program = [
+            form_i_instruction(Instruction::LW as u32, 0, 1, 0x100),
+            form_r_instruction(1, 0, 2, 0, Function::ADDU as u32),
+            form_r_instruction(1, 0, 3, 0, Function::ADDU as u32),
+        ];
+        let mut memory_mapper = memory_mapper_with_program(&program);
+        memory_mapper.write_word(0x100, 42_u32.to_be_bytes()).unwrap();
+        let with_load_delay = CpuConfig { branch_delay_slots: false, load_delay_slots: true };
+        let registers = run_steps(&mut memory_mapper, with_load_delay, 3);
+        assert_eq!((registers[1], registers[2], registers[3]), (42, 0, 42));
+
+        let mut memory_mapper = memory_mapper_with_program(&program);
+        memory_mapper.write_word(0x100, 42_u32.to_be_bytes()).unwrap();
+        let registers = run_steps(&mut memory_mapper, CpuConfig::default(), 3);
+        assert_eq!((registers[1], registers[2], registers[3]), (42, 42, 42));
+
+        // The instruction in the delay slot writes the target last, even with the value it already held
+        let program = [
+            form_i_instruction(Instruction::ADDIU as u32, 0, 1, 7),
+            form_i_instruction(Instruction::LW as u32, 0, 1, 0x100),
+            form_i_instruction(Instruction::ADDIU as u32, 0, 1, 7),
+        ];
+        let mut memory_mapper = memory_mapper_with_program(&program);
+        memory_mapper.write_word(0x100, 42_u32.to_be_bytes()).unwrap();
+        assert_eq!(run_steps(&mut memory_mapper, with_load_delay, 3)[1], 7);
+    }
+
+    #[test]
+    fn exceptions_in_a_delay_slot_are_reported_against_the_branch() {
+        let mut memory_mapper = memory_mapper_with_program(&[
+            form_i_instruction(Instruction::BEQ as u32, 0, 0, 4),
+            form_r_instruction(0, 0, 0, 0, Function::BREAK as u32),
+        ]);
+        memory_mapper.map(Box::new(Memory::new(0x200)), 0xbfc0_0000, 0xbfc0_01ff, true);
+        let mut cpu = CPU::with_config(&mut memory_mapper, CpuConfig { branch_delay_slots: true, load_delay_slots: false });
+
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.step(), Err(CpuException::Breakpoint));
+        assert_eq!(cpu.cop0().epc(), 0);
+        assert_ne!(cpu.cop0().cause() & Cop0::CAUSE_BD, 0);
+        assert_eq!(cpu.get_pc(), 0xbfc0_0180);
+    }
+
+    fn assemble_at_zero(source: &str) -> crate::assembler::Program {
+        let options = AssemblerOptions { text_base: 0, data_base: 0x800, endianness: Endianness::Big };
+        return Assembler::new(options).assemble(source).unwrap();
+    }
+
+    #[test]
+    fn assembled_program_runs() {
+        let program = assemble_at_zero("
+            .data
+            result: .word 0
+            .text
+            main:
+                li $t0, 10          # counter
+                move $t1, $zero
+            loop:
+                addu $t1, $t1, $t0
+                addiu $t0, $t0, -1
+                bgt $t0, $zero, loop
+                sw $t1, result
+                break
+        ");
+        let mut memory_mapper = MemoryMapper::new();
+        memory_mapper.map(Box::new(Memory::new(0x1000)), 0, 0xfff, false);
+        program.load(&mut memory_mapper).unwrap();
+        {
+            let mut cpu = CPU::new(&mut memory_mapper);
+            let mut steps = 0;
+            while cpu.step().is_ok() {
+                steps += 1;
+                assert!(steps < 100);
+            }
+        }
+        assert_eq!(memory_mapper.get_word(0x800).unwrap(), 55_u32.to_be_bytes());
+        assert_eq!(program.symbols.get("loop"), Some(8));
+    }
+
+    #[test]
+    fn pseudo_instructions_expand_to_real_instructions() {
+        let program = assemble_at_zero("li $t0, 0x12345678\nli $t1, -1\nmove $t2, $t0\nl.d $f2, 8($sp)");
+        let words: Vec<u32> = program.segments[0].bytes.chunks(4).map(|word| u32::from_be_bytes(word.try_into().unwrap())).collect();
+        assert_eq!(words, vec![
+            form_i_instruction(Instruction::LUI as u32, 0, 8, 0x1234),
+            form_i_instruction(Instruction::ORI as u32, 8, 8, 0x5678),
+            form_i_instruction(Instruction::ADDIU as u32, 0, 9, 0xffff),
+            form_r_instruction(8, 0, 10, 0, Function::ADDU as u32),
+            form_i_instruction(Instruction::LWC1 as u32, 29, 2, 12),
+            form_i_instruction(Instruction::LWC1 as u32, 29, 3, 8),
+        ]);
+    }
+
+    #[test]
+    fn data_directives_are_aligned_and_labelled() {
+        let program = assemble("
+            .data
+            flag: .byte 1
+            value: .word flag, 'A'
+            text: .asciiz \"hi\\n\"
+            .align 2
+            end:
+        ").unwrap();
+        assert_eq!(program.symbols.get("value"), Some(0x1001_0004));
+        assert_eq!(program.symbols.get("end"), Some(0x1001_0010));
+        let data = &program.segments[0];
+        assert_eq!(data.base, 0x1001_0000);
+        assert_eq!(&data.bytes[..16], &[1, 0, 0, 0, 0x10, 0x01, 0x00, 0x00, 0, 0, 0, 0x41, b'h', b'i', b'\n', 0]);
+    }
+
+    #[test]
+    fn assembler_errors_carry_line_numbers() {
+        let errors = assemble("nop\n  frob $t0\nj missing\naddi $t0, $t1, 0x10000").unwrap_err();
+        let lines: Vec<usize> = errors.iter().map(|error| error.line).collect();
+        assert_eq!(lines, vec![2, 4]);
+        let errors = assemble("j missing\nbeq $t0, $t1, nowhere").unwrap_err();
+        let lines: Vec<usize> = errors.iter().map(|error| error.line).collect();
+        assert_eq!(lines, vec![1, 2]);
+        assert!(errors[0].message.contains("missing"));
+    }
+
+    #[test]
+    fn memory_offsets_are_signed_16_bit() {
+        let errors = assemble("lw $t1, 0xffff($t0)\nsw $t1, 0x8000($t0)\nlw $t1, 0x7fffffffffffffff+1($t0)").unwrap_err();
+        let lines: Vec<usize> = errors.iter().map(|error| error.line).collect();
+        assert_eq!(lines, vec![1, 2, 3]);
+        let program = assemble_at_zero("
+            .data
+            .space 0x8004
+            value: .word 0
+            .text
+            lw $t1, -1($t0)
+            lui $at, %hi(value)
+            lw $t1, %lo(value)($at)
+        ");
+        let words: Vec<u32> = program.segments[0].bytes.chunks(4).map(|word| u32::from_be_bytes(word.try_into().unwrap())).collect();
+        assert_eq!(words, vec![
+            form_i_instruction(Instruction::LW as u32, 8, 9, 0xffff),
+            form_i_instruction(Instruction::LUI as u32, 0, 1, 0x0001),
+            form_i_instruction(Instruction::LW as u32, 1, 9, 0x8804),
+        ]);
+    }
+
+    #[test]
+    fn disassembly_reassembles_to_the_same_words() {
+        let source = [
+            "addu $t1, $t1, $t0", "sra $v0, $a0, 3", "srlv $s0, $s1, $s2", "mult $a0, $a1", "jalr $t9",
+            "jalr $t0, $t9", "break 7", "addiu $sp, $sp, -16", "ori $at, $zero, 0x9000", "lui $gp, 0x1001",
+            "lw $ra, 12($sp)", "swl $t0, -3($a0)", "lwc1 $f4, 0($gp)", "mfc0 $k0, $13", "rfe",
+            "mtc1 $t0, $f2", "cfc1 $t1, $31", "add.d $f0, $f2, $f4", "cvt.s.w $f1, $f3", "c.ole.s $f0, $f1",
+            "neg.s $f5, $f6", "syscall", "nop",
+        ];
+        let program = assemble_at_zero(&source.join("\n"));
+        let words = program.segments[0].bytes.chunks(4).map(|word| u32::from_be_bytes(word.try_into().unwrap()));
+        for (i, (line, word)) in source.iter().zip(words).enumerate() {
+            assert_eq!(disassemble(word, i as u32 * 4), *line);
+        }
+    }
+
+    #[test]
+    fn disassembly_resolves_branch_and_jump_targets() {
+        let program = assemble("main: beq $t0, $zero, main\nloop: bc1t loop\njal main\nbgez $a0, loop+8").unwrap();
+        let word = |i: usize| u32::from_be_bytes(program.segments[0].bytes[i * 4..i * 4 + 4].try_into().unwrap());
+        assert_eq!(disassemble(word(0), 0x0040_0000), "beq $t0, $zero, 0x00400000");
+        assert_eq!(disassemble(word(2), 0x0040_0008), "jal 0x00400000");
+        assert_eq!(disassemble_with_symbols(word(1), 0x0040_0004, &program.symbols), "bc1t loop");
+        assert_eq!(disassemble_with_symbols(word(3), 0x0040_000c, &program.symbols), "bgez $a0, loop+0x8");
+        assert_eq!(disassemble(0xffff_ffff, 0), ".word 0xffffffff");
+    }
+
+    /// A little endian executable with an 8 byte segment of code followed by 8 bytes of .bss and a `main` symbol
+    fn elf_file(class: u8, machine: u16) -> Vec<u8> {
+        let mut file = vec![0x7f, b'E', b'L', b'F', class, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let half = |file: &mut Vec<u8>, value: u16| file.extend(value.to_le_bytes());
+        let word = |file: &mut Vec<u8>, value: u32| file.extend(value.to_le_bytes());
+        half(&mut file, 2);
+        half(&mut file, machine);
+        word(&mut file, 1);
+        word(&mut file, 0x0040_0004);
+        word(&mut file, 52);
+        word(&mut file, 132);
+        word(&mut file, 0);
+        for value in [52, 32, 1, 40, 3, 2] {
+            half(&mut file, value);
+        }
+        for value in [1, 84, 0x0040_0000, 0x0040_0000, 8, 16, 5, 0x1000] {
+            word(&mut file, value);
+        }
+        word(&mut file, 0x2402_0011);
+        word(&mut file, 0x0000_000c);
+        file.extend([0; 16]);
+        for value in [1, 0x0040_0004, 0] {
+            word(&mut file, value);
+        }
+        file.extend([0x12, 0, 1, 0]);
+        file.extend(b"\0main\0\0\0");
+        file.extend([0; 40]);
+        for value in [0, 2, 0, 0, 92, 32, 2, 1, 4, 16] {
+            word(&mut file, value);
+        }
+        for value in [0, 3, 0, 0, 124, 8, 0, 0, 1, 0] {
+            word(&mut file, value);
+        }
+        return file;
+    }
+
+    #[test]
+    fn elf_loader_maps_segments_and_prepares_the_cpu() {
+        let mut memory_mapper = MemoryMapper::new();
+        memory_mapper.map(Box::new(Memory::new(0x1000)), 0x0040_0000, 0x0040_0fff, true);
+        memory_mapper.write_word(0x0040_000c, [0xff; 4]).unwrap();
+        let program = elf::load(&elf_file(1, 8), &mut memory_mapper).unwrap();
+        assert_eq!(program.symbols.get("main"), Some(0x0040_0004));
+        assert_eq!(memory_mapper.get_word(0x0040_0004).unwrap(), [0x0c, 0, 0, 0]);
+        assert_eq!(memory_mapper.get_word(0x0040_000c).unwrap(), [0; 4]);
+
+        let mut cpu = CPU::new(&mut memory_mapper);
+        program.prepare_cpu(&mut cpu);
+        assert_eq!(cpu.endianness(), Endianness::Little);
+        assert_eq!((cpu.get_pc(), cpu.get_register_value(29)), (0x0040_0004, ElfProgram::STACK_POINTER));
+        assert_eq!(cpu.get_register_value(28), ElfProgram::DEFAULT_GLOBAL_POINTER);
+        assert_eq!(cpu.step(), Err(CpuException::Syscall));
+        assert!(cpu.memory_mapper_mut().write_word(ElfProgram::STACK_POINTER, [1; 4]).is_ok());
+    }
+
+    #[test]
+    fn elf_loader_rejects_foreign_files() {
+        let mut memory_mapper = MemoryMapper::new();
+        assert_eq!(elf::load(&elf_file(2, 8), &mut memory_mapper).unwrap_err(), ElfError::Not32Bit);
+        assert_eq!(elf::load(&elf_file(1, 62), &mut memory_mapper).unwrap_err(), ElfError::NotMips(62));
+        assert_eq!(elf::load(b"#!/bin/sh", &mut memory_mapper).unwrap_err(), ElfError::Truncated);
+        assert_eq!(elf::load(&[0; 64], &mut memory_mapper).unwrap_err(), ElfError::NotElf);
+    }
+
+    #[test]
+    fn elf_loader_rejects_offsets_past_the_end_of_the_address_space() {
+        // The size and the string table link of `.symtab`, then the name of its symbol
+        for (offset, value) in [(192, u32::MAX), (196, u32::MAX), (108, u32::MAX - 8)] {
+            let mut file = elf_file(1, 8);
+            file[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            assert_eq!(elf::load(&file, &mut MemoryMapper::new()).unwrap_err(), ElfError::Truncated);
+        }
+        let mut file = elf_file(1, 8);
+        file[28..32].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(elf::load(&file, &mut MemoryMapper::new()).unwrap_err(), ElfError::Truncated);
+    }
+
+    #[test]
+    fn spim_syscalls_print_read_allocate_and_exit() {
+        let program = assemble_at_zero("
+            .data
+            greeting: .asciiz \"sum=\"
+            .text
+                li $v0, 5
+                syscall
+                move $t0, $v0
+                li $v0, 4
+                la $a0, greeting
+                syscall
+                addiu $a0, $t0, 1
+                li $v0, 1
+                syscall
+                li $v0, 11
+                li $a0, '\\n'
+                syscall
+                li $v0, 9
+                li $a0, 16
+                syscall
+                sw $t0, 12($v0)
+                move $s0, $v0
+                li $v0, 17
+                li $a0, 3
+                syscall
+        ");
+        let mut output = vec![];
+        let mut memory_mapper = MemoryMapper::new();
+        memory_mapper.map(Box::new(Memory::new(0x1000)), 0, 0xfff, false);
+        program.load(&mut memory_mapper).unwrap();
+        {
+            let mut cpu = CPU::new(&mut memory_mapper);
+            cpu.set_syscall_handler(Box::new(SpimSyscalls::with_io(&b"41\n"[..], &mut output)));
+            assert_eq!(cpu.run(), Ok(()));
+            assert_eq!(cpu.exit_code(), Some(3));
+            assert_eq!(cpu.get_register_value(16), SpimSyscalls::HEAP_START);
+        }
+        assert_eq!(String::from_utf8(output).unwrap(), "sum=42\n");
+        assert_eq!(memory_mapper.get_word(SpimSyscalls::HEAP_START + 12).unwrap(), 41_u32.to_be_bytes());
+
+        // The length of a read is only a limit and the heap is capped, the guest can't make the host allocate either
+        let program = assemble_at_zero("
+                li $v0, 14
+                li $a0, 0
+                li $a1, 0x900
+                li $a2, -1
+                syscall
+                move $s0, $v0
+                li $v0, 9
+                li $a0, 0x7fffffff
+                syscall
+                move $s1, $v0
+                li $v0, 9
+                li $a0, -4
+                syscall
+                move $s2, $v0
+                li $v0, 10
+                syscall
+        ");
+        program.load(&mut memory_mapper).unwrap();
+        {
+            let mut cpu = CPU::new(&mut memory_mapper);
+            cpu.set_syscall_handler(Box::new(SpimSyscalls::with_io(&b"abc"[..], std::io::sink())));
+            assert_eq!(cpu.run(), Ok(()));
+            assert_eq!(cpu.get_register_value(16), 3);
+            // The heap can neither run into the stack nor go below its start
+            assert_eq!((cpu.get_register_value(17), cpu.get_register_value(18)), (u32::MAX, u32::MAX));
+        }
+        assert_eq!(memory_mapper.get_word(0x900).unwrap(), *b"abc\0");
+
+        // Services it doesn't know are left to the guest
+        let mut memory_mapper = memory_mapper_with_program(&[
+            form_i_instruction(Instruction::ADDIU as u32, 0, 2, 99),
+            form_r_instruction(0, 0, 0, 0, Function::SYSCALL as u32),
+        ]);
+        let mut cpu = CPU::new(&mut memory_mapper);
+        cpu.set_syscall_handler(Box::new(SpimSyscalls::with_io(std::io::empty(), std::io::sink())));
+        assert_eq!(cpu.run(), Err(CpuException::Syscall));
+    }
 
+    #[test]
+    fn debugger_breakpoints_finish_and_step_over_calls() {
+        let program = assemble_at_zero("
+            main:
+                li $a0, 5
+                jal double
+                move $s0, $v0
+                li $v0, 10
+                syscall
+            double:
+                addu $v0, $a0, $a0
+                jr $ra
+        ");
+        let mut memory_mapper = MemoryMapper::new();
+        memory_mapper.map(Box::new(Memory::new(0x1000)), 0, 0xfff, false);
+        program.load(&mut memory_mapper).unwrap();
+        let mut cpu = CPU::new(&mut memory_mapper);
+        cpu.set_syscall_handler(Box::new(SpimSyscalls::with_io(std::io::empty(), std::io::sink())));
+        let mut debugger = Debugger::new(cpu, program.symbols.clone());
+
+        let double = debugger.resolve("double").unwrap();
+        assert_eq!(double, 20);
+        assert!(debugger.add_breakpoint(double));
+        assert_eq!(debugger.continue_execution(), DebugStop::Breakpoint(double));
+        assert!(debugger.registers().contains("pc=00000014"));
+        assert_eq!(debugger.finish(), DebugStop::Done);
+        assert_eq!((debugger.cpu().get_pc(), debugger.cpu().get_register_value(2)), (8, 10));
+
+        debugger.cpu_mut().set_pc(4);
+        assert_eq!(debugger.step_over(), DebugStop::Breakpoint(double));
+        debugger.cpu_mut().set_pc(4);
+        assert!(debugger.remove_breakpoint(double));
+        assert_eq!(debugger.step_over(), DebugStop::Done);
+        assert_eq!(debugger.cpu().get_pc(), 8);
+
+        assert!(debugger.execute("x main 8").unwrap().starts_with("00000000: 24 04 00 05 0c 00 00 05"));
+        assert!(debugger.execute("disas double 2").unwrap().contains("addu $v0, $a0, $a0"));
+        assert_eq!(debugger.execute("x 0 0xffffffff").unwrap().lines().count(), 0x1000);
+        assert_eq!(debugger.execute("disas 0xfffffffc 0xffffffff").unwrap().lines().count(), 4096);
+        assert_eq!(debugger.execute("c").unwrap(), "program exited with code 0");
+        assert!(debugger.execute("frobnicate").unwrap().starts_with("error:"));
+        assert_eq!(debugger.execute("quit"), None);
+    }
+
+    #[test]
+    fn watchpoints_report_the_pc_size_and_values_of_accesses() {
+        let program = assemble_at_zero("
+                li $t0, 5
+                sw $t0, value
+                lw $t1, value
+                sb $t0, flag
+                li $v0, 10
+                syscall
+                .data
+            value: .word 9
+            flag: .word 0
+        ");
+        let mut memory_mapper = MemoryMapper::new();
+        memory_mapper.map(Box::new(Memory::new(0x1000)), 0, 0xfff, false);
+        program.load(&mut memory_mapper).unwrap();
+        memory_mapper.add_watchpoint(Watchpoint { kind: WatchKind::Write, start: 0x800, end: 0x803 });
+        let hits = std::cell::RefCell::new(vec![]);
+        {
+            let mut cpu = CPU::new(&mut memory_mapper);
+            cpu.set_syscall_handler(Box::new(SpimSyscalls::with_io(std::io::empty(), std::io::sink())));
+            assert_eq!(cpu.run(), Ok(()));
+            assert_eq!(cpu.exit_code(), None);
+            let hit = cpu.watch_hits()[0];
+            assert_eq!((hit.kind, hit.pc, hit.address, hit.size, hit.old_value, hit.new_value), (WatchKind::Write, 8, 0x800, 4, 9, 5));
+
+            // Instruction fetches and stores elsewhere don't trigger read watchpoints
+            cpu.memory_mapper_mut().add_watchpoint(Watchpoint { kind: WatchKind::Read, start: 0, end: 0x803 });
+            assert_eq!(cpu.run(), Ok(()));
+            let hit = cpu.watch_hits()[0];
+            assert_eq!((hit.kind, hit.pc, hit.address, hit.old_value, hit.new_value), (WatchKind::Read, 16, 0x800, 5, 5));
+
+            cpu.memory_mapper_mut().add_watchpoint(Watchpoint { kind: WatchKind::Access, start: 0x804, end: 0x807 });
+            cpu.set_watch_handler(Box::new(|hit| {
+                hits.borrow_mut().push(*hit);
+                false
+            }));
+            assert_eq!(cpu.run(), Ok(()));
+            assert_eq!(cpu.exit_code(), Some(0));
+        }
+        let hits = hits.into_inner();
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].pc, hits[0].address, hits[0].size, hits[0].old_value, hits[0].new_value), (24, 0x804, 1, 0, 5));
+    }
+
+    #[test]
+    fn run_with_limits_stops_at_limits_breakpoints_and_exceptions() {
+        let program = assemble_at_zero("
+            spin:
+                addiu $t0, $t0, 1
+                b spin
+        ");
+        let mut memory_mapper = MemoryMapper::new();
+        memory_mapper.map(Box::new(Memory::new(0x1000)), 0, 0xfff, false);
+        program.load(&mut memory_mapper).unwrap();
+        let mut cpu = CPU::new(&mut memory_mapper);
+
+        // Far more instructions than a recursive run loop could survive
+        let limits = RunLimits { max_instructions: Some(1_000_000), ..RunLimits::default() };
+        assert_eq!(cpu.run_with_limits(&limits), StopReason::InstructionLimit);
+        assert_eq!(cpu.get_register_value(8), 500_000);
+        let limits = RunLimits { max_duration: Some(std::time::Duration::from_millis(10)), ..RunLimits::default() };
+        assert_eq!(cpu.run_with_limits(&limits), StopReason::TimeLimit);
+
+        let limits = RunLimits { breakpoints: [4].into(), ..RunLimits::default() };
+        cpu.set_pc(0);
+        assert_eq!(cpu.run_with_limits(&limits), StopReason::Breakpoint(4));
+        // Resuming from the breakpoint executes the instruction there first
+        assert_eq!(cpu.run_with_limits(&limits), StopReason::Breakpoint(4));
+
+        // With a handler mapped at the boot exception vector BREAK only stops when asked to
+        let mut memory_mapper = memory_mapper_with_program(&[form_r_instruction(0, 0, 0, 0, Function::BREAK as u32)]);
+        memory_mapper.map_ram(0xbfc0_0000, 0x1000);
+        let mut cpu = CPU::new(&mut memory_mapper);
+        let limits = RunLimits { max_instructions: Some(3), ..RunLimits::default() };
+        assert_eq!(cpu.run_with_limits(&limits), StopReason::InstructionLimit);
+        cpu.set_pc(0);
+        let limits = RunLimits { stop_on_break: true, ..RunLimits::default() };
+        assert_eq!(cpu.run_with_limits(&limits), StopReason::Exception(CpuException::Breakpoint));
+        assert_eq!(cpu.get_pc(), 0xbfc0_0180);
+    }
+
+    #[test]
+    fn tracer_records_register_and_memory_effects_through_its_filters() {
+        let program = assemble_at_zero("
+                li $t0, -3
+                sw $t0, value
+                lw $t1, 0x800($at)
+                mult $t0, $t1
+                .data
+            value: .word 9
+        ");
+        let mut memory_mapper = MemoryMapper::new();
+        memory_mapper.map(Box::new(Memory::new(0x1000)), 0, 0xfff, false);
+        program.load(&mut memory_mapper).unwrap();
+
+        let mut binary = vec![];
+        let mut json = vec![];
+        {
+            let mut cpu = CPU::new(&mut memory_mapper);
+            let limits = RunLimits { max_instructions: Some(5), ..RunLimits::default() };
+            cpu.set_tracer(Tracer::new(BinarySink::new(&mut binary)));
+            assert_eq!(cpu.run_with_limits(&limits), StopReason::InstructionLimit);
+            cpu.take_tracer().unwrap().finish().unwrap();
+
+            cpu.set_pc(0);
+            let filter = TraceFilter { ranges: vec![4..=0xc], first: 1, count: Some(2) };
+            cpu.set_tracer(Tracer::with_filter(JsonLinesSink::new(&mut json), filter));
+            assert_eq!(cpu.run_with_limits(&limits), StopReason::InstructionLimit);
+            cpu.take_tracer().unwrap().finish().unwrap();
+        }
+
+        let records = trace::read_binary_trace(&binary[..]).unwrap();
+        assert_eq!(records.iter().map(|record| record.pc).collect::<Vec<u32>>(), [0, 4, 8, 0xc, 0x10]);
+        assert_eq!(records[0].registers, [RegisterChange { register: TracedRegister::Gpr(8), old_value: 0, new_value: 0xffff_fffd }]);
+        assert_eq!(records[2].memory, [MemoryAccess { kind: WatchKind::Write, address: 0x800, size: 4, old_value: 9, new_value: 0xffff_fffd }]);
+        assert_eq!(records[3].memory[0].kind, WatchKind::Read);
+        assert_eq!(records[4].registers, [RegisterChange { register: TracedRegister::Lo, old_value: 0, new_value: 9 }]);
+
+        let json = String::from_utf8(json).unwrap();
+        let lines: Vec<&str> = json.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("{\"index\":1,\"pc\":4,"));
+        assert!(lines[1].contains("\"disassembly\":\"sw $t0, 2048($at)\""));
+    }
+
+    #[test]
+    fn binary_traces_keep_the_accesses_of_long_syscalls() {
+        // Fills 300 bytes with 'a' and prints them, the syscall reads 301 bytes
+        let program = assemble_at_zero("
+                la $a0, text
+                li $t0, 300
+                li $t1, 0x61
+            fill:
+                sb $t1, 0($a0)
+                addiu $a0, $a0, 1
+                addiu $t0, $t0, -1
+                bnez $t0, fill
+                la $a0, text
+                li $v0, 4
+                syscall
+                li $v0, 10
+                syscall
+                .data
+            text: .space 304
+        ");
+        let mut memory_mapper = MemoryMapper::new();
+        memory_mapper.map_ram(0, 0x1000);
+        program.load(&mut memory_mapper).unwrap();
+        let mut binary = vec![];
+        let mut output = vec![];
+        {
+            let mut cpu = CPU::new(&mut memory_mapper);
+            cpu.set_syscall_handler(Box::new(SpimSyscalls::with_io(std::io::empty(), &mut output)));
+            cpu.set_tracer(Tracer::new(BinarySink::new(&mut binary)));
+            assert_eq!(cpu.run_with_limits(&RunLimits::default()), StopReason::Exited(0));
+            cpu.take_tracer().unwrap().finish().unwrap();
+        }
+        assert_eq!(output, [b'a'; 300]);
+        let records = trace::read_binary_trace(&binary[..]).unwrap();
+        let print = records.iter().find(|record| record.pc == 0x2c).unwrap();
+        assert_eq!(print.memory.len(), 301);
+        assert_eq!(print.memory[300], MemoryAccess { kind: WatchKind::Read, address: 0x800 + 300, size: 1, old_value: 0, new_value: 0 });
+        assert_eq!(records.last().unwrap().pc, 0x34);
+    }
+
+    #[test]
+    fn snapshots_resume_a_run_bit_exactly() {
+        let program = assemble_at_zero("
+                li $t0, 5
+            loop:
+                li $v0, 9           # sbrk maps the heap on the way
+                li $a0, 16
+                syscall
+                sw $t0, 0($v0)
+                li $v0, 41          # random int
+                syscall
+                mult $t0, $a0
+                lw $t1, value
+                addu $t2, $t2, $t1  # reads $t1 from before the load
+                addiu $t0, $t0, -1
+                bnez $t0, loop
+                nop
+                li $v0, 10
+                syscall
+                .data
+            value: .word 9
+        ");
+        let machine = || {
+            let mut memory_mapper = MemoryMapper::new();
+            memory_mapper.map_ram(0, 0x1000);
+            program.load(&mut memory_mapper).unwrap();
+            return memory_mapper;
+        };
+        let config = CpuConfig { branch_delay_slots: true, load_delay_slots: true };
+        let syscalls = || {
+            let mut syscalls = SpimSyscalls::with_io(std::io::empty(), std::io::sink());
+            syscalls.seed(42);
+            return Box::new(syscalls);
+        };
+
+        let mut memory_mapper = machine();
+        let mut cpu = CPU::with_config(&mut memory_mapper, config);
+        cpu.set_syscall_handler(syscalls());
+        assert_eq!(cpu.run_with_limits(&RunLimits::default()), StopReason::Exited(0));
+        let expected = cpu.snapshot();
+
+        for limit in 1..60 {
+            let mut saved = vec![];
+            let mut memory_mapper = machine();
+            let mut cpu = CPU::with_config(&mut memory_mapper, config);
+            cpu.set_syscall_handler(syscalls());
+            assert_eq!(cpu.run_with_limits(&RunLimits { max_instructions: Some(limit), ..RunLimits::default() }), StopReason::InstructionLimit);
+            cpu.snapshot().write(&mut saved).unwrap();
+            let snapshot = Snapshot::read(saved.as_slice()).unwrap();
+            assert_eq!(snapshot, cpu.snapshot());
+
+            let mut memory_mapper = machine();
+            let mut cpu = CPU::new(&mut memory_mapper);
+            cpu.set_syscall_handler(syscalls());
+            cpu.restore(&snapshot).unwrap();
+            assert_eq!(cpu.run_with_limits(&RunLimits::default()), StopReason::Exited(0));
+            assert_eq!(cpu.snapshot(), expected);
+        }
+
+        let mut memory_mapper = MemoryMapper::new();
+        memory_mapper.map_ram(0, 0x2000);
+        let error = CPU::new(&mut memory_mapper).restore(&expected).unwrap_err();
+        assert_eq!(error.to_string(), "the memory map doesn't match the snapshot");
+        let error = Snapshot::read(&b"VM32SNP\x02"[..]).unwrap_err();
+        assert_eq!(error.to_string(), "unsupported snapshot version");
+    }
+
+    #[test]
+    fn step_back_undoes_registers_memory_and_syscalls() {
+        let program = assemble_at_zero("
+                li $t0, 4
+            loop:
+                li $v0, 41          # random int
+                syscall
+                multu $a0, $t0
+                sb $t0, value($t0)
+                lw $t1, value
+                mflo $t2
+                addiu $t0, $t0, -1
+                bnez $t0, loop
+                nop
+                li $v0, 10
+                syscall
+                .data
+            value: .word 9
+        ");
+        let mut memory_mapper = MemoryMapper::new();
+        memory_mapper.map_ram(0, 0x1000);
+        program.load(&mut memory_mapper).unwrap();
+        let mut cpu = CPU::with_config(&mut memory_mapper, CpuConfig { branch_delay_slots: true, load_delay_slots: true });
+        let mut syscalls = SpimSyscalls::with_io(std::io::empty(), std::io::sink());
+        syscalls.seed(42);
+        cpu.set_syscall_handler(Box::new(syscalls));
+        cpu.enable_history(HistoryLimits { max_bytes: 1 << 20, snapshot_interval: 16, max_snapshots: 2 });
+
+        let mut states = vec![cpu.snapshot()];
+        while cpu.exit_code().is_none() {
+            cpu.step().unwrap();
+            states.push(cpu.snapshot());
+        }
+        assert_eq!(cpu.history().unwrap().position(), states.len() as u64 - 1);
+        for expected in states.iter().rev().skip(1) {
+            assert_eq!(cpu.step_back(1), 1);
+            assert_eq!(&cpu.snapshot(), expected);
+        }
+        assert_eq!(cpu.step_back(1), 0);
+
+        // The history forgets the undone instructions and records them again
+        assert_eq!(cpu.run_with_limits(&RunLimits::default()), StopReason::Exited(0));
+        assert_eq!(cpu.snapshot(), states[states.len() - 1]);
+        let end = states.len() - 1;
+        let last_change = |changed: &dyn Fn(&Snapshot, &Snapshot) -> bool| (1..=end).rev().find(|i| changed(&states[i - 1], &states[*i])).unwrap() - 1;
+        let last_mflo = last_change(&|before, after| before.cpu.registers[10] != after.cpu.registers[10]);
+        assert_eq!(cpu.reverse_to_last_write(WriteTarget::Register(TracedRegister::Gpr(10))), Some((end - last_mflo) as u64));
+        assert_eq!(cpu.snapshot(), states[last_mflo]);
+        let last_sb = last_change(&|before, after| before.regions[0].state.as_ref().unwrap()[0x801] != after.regions[0].state.as_ref().unwrap()[0x801]);
+        assert_eq!(cpu.reverse_to_last_write(WriteTarget::Memory { address: 0x801, size: 1 }), Some((last_mflo - last_sb) as u64));
+        assert_eq!(cpu.snapshot(), states[last_sb]);
+        assert_eq!(cpu.get_register_value(8), 1);
+        assert_eq!(cpu.reverse_to_last_write(WriteTarget::Memory { address: 0x808, size: 4 }), None);
+
+        // Once the undo log is full only the snapshots are left, they are every 16 instructions and only 2 are kept
+        cpu.enable_history(HistoryLimits { max_bytes: 600, snapshot_interval: 16, max_snapshots: 2 });
+        cpu.restore(&states[0]).unwrap();
+        assert_eq!(cpu.run_with_limits(&RunLimits::default()), StopReason::Exited(0));
+        let end = end as u64;
+        let history = cpu.history().unwrap();
+        assert!(history.first_record() > 32 && history.oldest() == 16, "{} {}", history.first_record(), history.oldest());
+        assert_eq!(cpu.step_back(end - 20), end - 32);
+        assert_eq!(cpu.snapshot(), states[32]);
+        assert_eq!(cpu.step_back(end), 16);
+        assert_eq!(cpu.snapshot(), states[16]);
+        assert_eq!(cpu.step_back(1), 0);
+    }
+
+    /// Counts the instructions executed and interrupts once it reaches its limit, the count is read at offset 0
+    struct TickCounter {
+        ticks: u32,
+        limit: u32,
+    }
+
+    impl MemoryMappable for TickCounter {
+        fn get_byte(&self, address: u32) -> [u8; 1] {
+            return [self.get_word(address & !3)[(address & 3) as usize]];
+        }
+
+        fn get_half_word(&self, address: u32) -> [u8; 2] {
+            let word = self.get_word(address & !3);
+            return [word[(address & 2) as usize], word[(address & 2) as usize + 1]];
+        }
+
+        fn get_word(&self, _: u32) -> [u8; 4] {
+            return self.ticks.to_be_bytes();
+        }
+
+        fn write_byte(&mut self, _: u32, _: [u8; 1]) {}
+
+        fn write_half_word(&mut self, _: u32, _: [u8; 2]) {}
+
+        fn write_word(&mut self, _: u32, _: [u8; 4]) {
+            self.ticks = 0;
+        }
+    }
+
+    impl Device for TickCounter {
+        fn name(&self) -> &str {
+            return "counter";
+        }
+
+        fn size(&self) -> u32 {
+            return 4;
+        }
+
+        fn reset(&mut self) {
+            self.ticks = 0;
+        }
+
+        fn tick(&mut self, cycles: u64) {
+            self.ticks += cycles as u32;
+        }
+
+        fn interrupt(&self) -> bool {
+            return self.ticks >= self.limit;
+        }
+    }
+
+    #[test]
+    fn machine_descriptions_build_devices_from_the_registry() {
+        let mut registry = DeviceRegistry::new();
+        registry.register("counter", |config| Ok(Box::new(TickCounter { ticks: 0, limit: config.number("limit")?.unwrap_or(10) })));
+        assert_eq!(registry.kinds().collect::<Vec<&str>>(), ["counter", "disk", "framebuffer", "intc", "keyboard", "ram", "screen", "timer", "uart"]);
+
+        let toml = MachineDescription::from_toml(r#"
+            [cpu]
+            endianness = "little"
+            delay_slots = true
+
+            [[device]]
+            type = "ram"
+            base = 0
+            size = "4k"
+
+            [[device]]
+            type = "counter"
+            base = 0xffff0000
+            irq = 3
+            limit = 3
+        "#).unwrap();
+        let json = MachineDescription::from_json(r#"{
+            "cpu": {"endianness": "little", "delay_slots": true},
+            "devices": [
+                {"type": "ram", "base": 0, "size": 4096},
+                {"type": "counter", "base": "0xffff0000", "irq": 3, "limit": "3"}
+            ]
+        }"#).unwrap();
+        assert_eq!(toml.devices[1].number("limit"), Ok(Some(3)));
+        assert_eq!(json.devices[1].number("limit"), Ok(Some(3)));
+        assert_eq!(toml.cpu, json.cpu);
+
+        let mut machine = json.build(&registry).unwrap();
+        assert_eq!(machine.endianness, Some(Endianness::Little));
+        assert_eq!(machine.config, CpuConfig { branch_delay_slots: true, load_delay_slots: true });
+        let program = [form_i_instruction(Instruction::LW as u32, 0, 8, 0)];
+        for (i, instruction) in program.iter().enumerate() {
+            machine.memory_mapper.write_word(i as u32 * 4, instruction.to_le_bytes()).unwrap();
+        }
+        {
+            let mut cpu = CPU::with_config(&mut machine.memory_mapper, machine.config);
+            cpu.set_endianness(Endianness::Little);
+            cpu.step().unwrap();
+            cpu.step().unwrap();
+            assert_eq!(cpu.memory_mapper().interrupt_lines(), 0);
+            cpu.step().unwrap();
+            assert_eq!(cpu.memory_mapper().interrupt_lines(), 1 << 3);
+        }
+        assert_eq!(machine.memory_mapper.get_word(0xffff_0000), Ok(3_u32.to_be_bytes()));
+        assert_eq!(machine.memory_mapper.device("counter").map(|counter| counter.size()), Some(4));
+        machine.memory_mapper.reset_devices();
+        assert_eq!(machine.memory_mapper.interrupt_lines(), 0);
+
+        let overlapping = MachineDescription::from_toml(r#"
+            [[device]]
+            type = "ram"
+            base = 0
+            size = 0x1000
+            [[device]]
+            type = "screen"
+            base = 0xf00
+        "#).unwrap();
+        assert_eq!(overlapping.build(&registry).err(), Some(MachineError::Map { kind: "screen".to_owned(), error: MapError::Overlap { start: 0, end: 0xfff } }));
+        let errors = [
+            ("[[device]]\ntype = \"tape\"\nbase = 0", MachineError::UnknownDevice("tape".to_owned())),
+            ("[[device]]\ntype = \"ram\"\nbase = 0", MachineError::InvalidDevice { kind: "ram".to_owned(), message: "ram needs a size".to_owned() }),
+            ("[[device]]\ntype = \"ram\"\nbase = 0\nsize = 1001", MachineError::InvalidDevice { kind: "ram".to_owned(), message: "the size of ram must be a multiple of 4, not 1001".to_owned() }),
+            ("[[device]]\ntype = \"screen\"\nbase = 0\nirq = 6", MachineError::Map { kind: "screen".to_owned(), error: MapError::InvalidIrq(6) }),
+        ];
+        for (description, error) in errors {
+            assert_eq!(MachineDescription::from_toml(description).unwrap().build(&registry).err(), Some(error));
+        }
+        assert!(matches!(MachineDescription::from_toml("[cpu]\nendianness = \"middle\""), Err(MachineError::Parse(_))));
+    }
+
+
+    #[test]
+    fn keyboard_receives_scripted_input() {
+        let description = MachineDescription::from_toml(r#"
+            [[device]]
+            type = "ram"
+            base = 0
+            size = "4k"
+
+            [[device]]
+            type = "keyboard"
+            base = 0xffff0000
+            irq = 1
+            input = "ok!"
+        "#).unwrap();
+        let mut machine = description.build(&DeviceRegistry::new()).unwrap();
+        let program = assemble_at_zero("
+                lui $s0, 0xffff
+                move $t2, $zero
+            poll:
+                lw $t0, 0($s0)
+                andi $t0, $t0, 1
+                beqz $t0, poll
+                lw $t1, 4($s0)
+                sb $t1, buffer($t2)
+                addiu $t2, $t2, 1
+                li $t3, 3
+                bne $t2, $t3, poll
+                li $t0, 2           # interrupt enable
+                sw $t0, 0($s0)
+                li $v0, 10
+                syscall
+                .data
+            buffer: .space 4
+        ");
+        program.load(&mut machine.memory_mapper).unwrap();
+        {
+            let mut cpu = CPU::new(&mut machine.memory_mapper);
+            cpu.set_syscall_handler(Box::new(SpimSyscalls::with_io(std::io::empty(), std::io::sink())));
+            assert_eq!(cpu.run_with_limits(&RunLimits::default()), StopReason::Exited(0));
+        }
+        assert_eq!(machine.memory_mapper.get_word(0x800), Ok(*b"ok!\0"));
+        assert_eq!(machine.memory_mapper.get_word(0xffff_0000), Ok(2_u32.to_be_bytes()));
+        assert_eq!(machine.memory_mapper.interrupt_lines(), 0);
+
+        let keyboard = machine.memory_mapper.device_mut("keyboard").unwrap();
+        let state = keyboard.save_state().unwrap();
+        keyboard.restore_state(&[1, b'x', 1]).unwrap();
+        assert_eq!(machine.memory_mapper.interrupt_lines(), 1 << 1);
+        assert_eq!(machine.memory_mapper.get_word(0xffff_0004), Ok((b'x' as u32).to_be_bytes()));
+        assert_eq!(machine.memory_mapper.get_word(0xffff_0000), Ok(2_u32.to_be_bytes()));
+        assert_eq!(machine.memory_mapper.interrupt_lines(), 0);
+        assert_eq!(state, [0, b'!', 1]);
+        machine.memory_mapper.reset_devices();
+        assert_eq!(machine.memory_mapper.get_word(0xffff_0000), Ok([0; 4]));
+    }
+
+    #[test]
+    fn timer_counts_cycles_in_one_shot_and_periodic_modes() {
+        let description = MachineDescription::from_toml(r#"
+            [[device]]
+            type = "ram"
+            base = 0
+            size = "4k"
+
+            [[device]]
+            type = "timer"
+            base = 0xffff0000
+            irq = 5
+        "#).unwrap();
+        let mut machine = description.build(&DeviceRegistry::new()).unwrap();
+        let program = assemble_at_zero("
+                lui $s0, 0xffff
+                li $t0, 10
+                sw $t0, 4($s0)      # compare
+                li $t0, 7           # enable, periodic and interrupt enable
+                sw $t0, 8($s0)
+                move $t2, $zero
+            wait:
+                lw $t0, 8($s0)
+                andi $t1, $t0, 8
+                beqz $t1, wait
+                sw $t0, 8($s0)      # acknowledge
+                addiu $t2, $t2, 1
+                li $t3, 3
+                bne $t2, $t3, wait
+                li $v0, 10
+                syscall
+        ");
+        program.load(&mut machine.memory_mapper).unwrap();
+        let mut interrupts = 0;
+        {
+            let mut cpu = CPU::new(&mut machine.memory_mapper);
+            cpu.set_syscall_handler(Box::new(SpimSyscalls::with_io(std::io::empty(), std::io::sink())));
+            let mut instructions = 0;
+            while cpu.exit_code().is_none() {
+                cpu.step().unwrap();
+                instructions += 1;
+                if cpu.memory_mapper().interrupt_lines() == 1 << 5 {
+                    interrupts += 1;
+                }
+            }
+            assert_eq!(cpu.get_register_value(10), 3);
+            // The store that enabled the timer is the first instruction it counted
+            let count = u32::from_be_bytes(cpu.memory_mapper().get_word(0xffff_0000).unwrap());
+            assert_eq!(count, (instructions - 4) % 10);
+        }
+        assert!(interrupts > 3);
+
+        let mut timer = TimerDevice::with_divider(2);
+        timer.write_word(4, 3_u32.to_be_bytes());
+        timer.write_word(8, TimerDevice::CONTROL_ENABLE.to_be_bytes());
+        timer.tick(5);
+        assert_eq!(timer.get_word(0), 2_u32.to_be_bytes());
+        timer.tick(100);
+        assert_eq!(timer.get_word(0), 3_u32.to_be_bytes());
+        assert_eq!(timer.get_word(8), TimerDevice::CONTROL_PENDING.to_be_bytes());
+        assert!(!timer.interrupt());
+        let state = timer.save_state().unwrap();
+        timer.write_byte(10, [TimerDevice::CONTROL_INTERRUPT_ENABLE as u8]);
+        assert!(!timer.interrupt());
+        timer.write_byte(11, [(TimerDevice::CONTROL_INTERRUPT_ENABLE | TimerDevice::CONTROL_PENDING) as u8]);
+        assert_eq!(timer.get_word(8), TimerDevice::CONTROL_INTERRUPT_ENABLE.to_be_bytes());
+        timer.restore_state(&state).unwrap();
+        assert_eq!(timer.get_word(8), TimerDevice::CONTROL_PENDING.to_be_bytes());
+
+        // Periodic timers catch up on long ticks and keep the remainder
+        timer.reset();
+        timer.write_word(4, 4_u32.to_be_bytes());
+        timer.write_word(8, (TimerDevice::CONTROL_ENABLE | TimerDevice::CONTROL_PERIODIC | TimerDevice::CONTROL_INTERRUPT_ENABLE).to_be_bytes());
+        timer.tick(21);
+        assert_eq!(timer.get_word(0), 2_u32.to_be_bytes());
+        assert!(timer.interrupt());
+    }
+
+    #[test]
+    fn device_interrupts_reach_the_cpu_through_the_controller() {
+        // The counter interrupts before the fourth instruction, the delay slot of the jump
+        let program = assemble_at_zero("
+                li $t0, 0x401       # IM2 and IEc
+                mtc0 $t0, $12
+                j target
+                addiu $s1, $s1, 1
+            target:
+                nop
+        ");
+        let mut memory_mapper = MemoryMapper::new();
+        memory_mapper.map_ram(0, 0x1000);
+        memory_mapper.map_ram(0x8000_0000, 0x1000);
+        let error = memory_mapper.map_device(Box::new(TickCounter { ticks: 0, limit: 3 }), 0xffff_0000, Some(8));
+        assert_eq!(error, Err(MapError::InvalidIrq(8)));
+        memory_mapper.map_device(Box::new(TickCounter { ticks: 0, limit: 3 }), 0xffff_0000, Some(0)).unwrap();
+        program.load(&mut memory_mapper).unwrap();
+        {
+            let mut cpu = CPU::with_config(&mut memory_mapper, CpuConfig { branch_delay_slots: true, load_delay_slots: false });
+            for _ in 0..3 {
+                cpu.step().unwrap();
+            }
+            assert_eq!(cpu.cop0().cause() & Cop0::CAUSE_IP_MASK, 0);
+            cpu.step().unwrap();
+            assert_eq!(cpu.get_pc(), 0x8000_0084);
+            assert_eq!(cpu.cop0().epc(), 8);
+            assert_eq!(cpu.cop0().cause(), Cop0::CAUSE_BD | 1 << 10 | Cop0::EXC_CODE_INTERRUPT << 2);
+            assert_eq!(cpu.cop0().status() & 0x3f, 0b000100);
+            assert_eq!(cpu.get_register_value(17), 0);
+        }
+
+        // A periodic timer on line 2 behind the interrupt controller, the handler counts the interrupts and records
+        // which line was active
+        let description = MachineDescription::from_toml(r#"
+            [[device]]
+            type = "ram"
+            base = 0
+            size = "4k"
+
+            [[device]]
+            type = "ram"
+            base = 0x80000000
+            size = "4k"
+
+            [[device]]
+            type = "timer"
+            base = 0xffff0000
+            irq = 2
+
+            [[device]]
+            type = "intc"
+            base = 0xffff0100
+        "#).unwrap();
+        let mut machine = description.build(&DeviceRegistry::new()).unwrap();
+        let program = assemble_at_zero("
+                lui $s0, 0xffff
+                li $t0, 20
+                sw $t0, 4($s0)      # compare
+                li $t0, 7           # enable, periodic and interrupt enable
+                sw $t0, 8($s0)
+                li $t0, 0x1001      # IM4 and IEc
+                mtc0 $t0, $12
+                li $t3, 3
+            wait:
+                addiu $s1, $s1, 1
+                bne $s2, $t3, wait
+                nop
+            done:
+                b done
+                nop
+        ");
+        program.load(&mut machine.memory_mapper).unwrap();
+        let options = AssemblerOptions { text_base: 0x8000_0080, data_base: 0x8000_0800, endianness: Endianness::Big };
+        let handler = Assembler::new(options).assemble("
+                lui $k1, 0xffff
+                lw $k0, 0x108($k1)  # active line
+                sb $k0, 0x900($s2)
+                lw $k0, 8($k1)
+                sw $k0, 8($k1)      # acknowledge the timer
+                addiu $s2, $s2, 1
+                mfc0 $k0, $14
+                jr $k0
+                rfe
+        ").unwrap();
+        handler.load(&mut machine.memory_mapper).unwrap();
+
+        let mut cpu = CPU::with_config(&mut machine.memory_mapper, CpuConfig { branch_delay_slots: true, load_delay_slots: false });
+        for _ in 0..200 {
+            cpu.step().unwrap();
+        }
+        // Until the controller lets line 2 through nothing happens
+        assert_eq!(cpu.get_register_value(18), 0);
+        assert_eq!(cpu.cop0().cause() & Cop0::CAUSE_IP_MASK, 0);
+        cpu.memory_mapper_mut().write_word(0xffff_0104, 4_u32.to_be_bytes()).unwrap();
+        while cpu.get_register_value(18) < 3 {
+            cpu.step().unwrap();
+        }
+        while cpu.get_pc() >= 0x8000_0000 {
+            cpu.step().unwrap();
+        }
+        let done = program.symbols.get("done").unwrap();
+        for _ in 0..10 {
+            cpu.step().unwrap();
+        }
+        assert!(cpu.get_pc() == done || cpu.get_pc() == done + 4, "{:#x}", cpu.get_pc());
+        assert_eq!(cpu.memory_mapper().get_word(0x900), Ok([2, 2, 2, 0]));
+        assert_eq!(u32::from_be_bytes(cpu.memory_mapper().get_word(0xffff_0108).unwrap()), u32::MAX);
+    }
+
+    #[test]
+    fn disk_transfers_sectors_by_dma() {
+        let description = MachineDescription::from_toml(r#"
+            [[device]]
+            type = "ram"
+            base = 0
+            size = "4k"
+
+            [[device]]
+            type = "disk"
+            base = 0xffff0200
+            irq = 3
+            size = "2k"
+        "#).unwrap();
+        let mut machine = description.build(&DeviceRegistry::new()).unwrap();
+        // Writes the message to sector 2, reads it back in the second buffer then reads past the end of the disk
+        let program = assemble_at_zero("
+                lui $s0, 0xffff
+                ori $s0, $s0, 0x200
+                li $t0, 2
+                sw $t0, 0x00($s0)   # sector
+                li $t0, 0x800
+                sw $t0, 0x04($s0)   # buffer
+                li $t0, 1
+                sw $t0, 0x08($s0)   # count
+                li $t0, 2
+                sw $t0, 0x0c($s0)   # write
+                lw $s1, 0x10($s0)
+                li $t0, 10          # acknowledge and enable the interrupt
+                sw $t0, 0x10($s0)
+                li $t0, 0xa00
+                sw $t0, 0x04($s0)
+                li $t0, 1
+                sw $t0, 0x0c($s0)   # read
+                lw $s2, 0x10($s0)
+                li $t0, 4
+                sw $t0, 0x00($s0)
+                li $t0, 1
+                sw $t0, 0x0c($s0)
+                lw $s3, 0x10($s0)
+                lw $s4, 0x14($s0)
+                li $v0, 10
+                syscall
+                .data
+            message: .asciiz \"hello, disk\"
+        ");
+        program.load(&mut machine.memory_mapper).unwrap();
+        {
+            let mut cpu = CPU::new(&mut machine.memory_mapper);
+            cpu.set_syscall_handler(Box::new(SpimSyscalls::with_io(std::io::empty(), std::io::sink())));
+            cpu.enable_history(HistoryLimits::default());
+            assert_eq!(cpu.run_with_limits(&RunLimits::default()), StopReason::Exited(0));
+            assert_eq!(cpu.get_register_value(17), DiskDevice::STATUS_DONE);
+            assert_eq!(cpu.get_register_value(18), DiskDevice::STATUS_DONE | DiskDevice::STATUS_INTERRUPT_ENABLE);
+            assert_eq!(cpu.get_register_value(19), DiskDevice::STATUS_DONE | DiskDevice::STATUS_ERROR | DiskDevice::STATUS_INTERRUPT_ENABLE);
+            assert_eq!(cpu.get_register_value(20), 4);
+            assert_eq!(cpu.memory_mapper().interrupt_lines(), 1 << 3);
+            assert_eq!(cpu.memory_mapper().get_word(0xa00), Ok(*b"hell"));
+            assert_eq!(cpu.memory_mapper().get_word(0xa08), Ok(*b"isk\0"));
+
+            // Taking back the read takes back what it copied to memory
+            let position = cpu.history().unwrap().position();
+            cpu.step_back(position - 16);
+            assert_eq!(cpu.memory_mapper().get_word(0xa00), Ok([0; 4]));
+        }
+
+        // Disks backed by a file write through to it
+        let path = std::env::temp_dir().join(format!("vm32bits-disk-{}.img", std::process::id()));
+        std::fs::write(&path, [[7; 512], [9; 512]].concat()).unwrap();
+        let mut memory_mapper = MemoryMapper::new();
+        memory_mapper.map_ram(0, 0x1000);
+        memory_mapper.map_device(Box::new(DiskDevice::open(&path).unwrap()), 0xffff_0000, None).unwrap();
+        let registers = [(0x00, 1), (0x04, 0x100), (0x08, 1), (0x0c, DiskDevice::COMMAND_READ)];
+        for (offset, value) in registers {
+            memory_mapper.write_word(0xffff_0000 + offset, value.to_be_bytes()).unwrap();
+        }
+        assert_eq!(memory_mapper.get_word(0xffff_0010), Ok(DiskDevice::STATUS_BUSY.to_be_bytes()));
+        memory_mapper.tick_devices(1);
+        assert_eq!(memory_mapper.get_word(0xffff_0010), Ok(DiskDevice::STATUS_DONE.to_be_bytes()));
+        assert_eq!(memory_mapper.get_word(0x2fc), Ok([9; 4]));
+        memory_mapper.write_word(0x100, *b"disk").unwrap();
+        // Byte and half word writes land in the bytes of the registers they cover
+        memory_mapper.write_half_word(0xffff_0002, 0_u16.to_be_bytes()).unwrap();
+        memory_mapper.write_byte(0xffff_000f, [DiskDevice::COMMAND_WRITE as u8]).unwrap();
+        assert_eq!(memory_mapper.get_word(0xffff_0004), Ok(0x100_u32.to_be_bytes()));
+        memory_mapper.tick_devices(1);
+        memory_mapper.write_byte(0xffff_0010, [0]).unwrap();
+        assert_eq!(memory_mapper.get_word(0xffff_0010), Ok(DiskDevice::STATUS_DONE.to_be_bytes()));
+        memory_mapper.write_byte(0xffff_0013, [DiskDevice::STATUS_DONE as u8]).unwrap();
+        assert_eq!(memory_mapper.get_word(0xffff_0010), Ok(0_u32.to_be_bytes()));
+        let image = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&image[..4], b"disk");
+        assert_eq!(image[4..512], [9; 508]);
+        assert_eq!(image[512..], [9; 512]);
+    }
+
+    #[test]
+    fn uart_moves_bytes_through_its_fifos() {
+        let serial = MemorySerial::new();
+        let mut memory_mapper = MemoryMapper::new();
+        memory_mapper.map_ram(0, 0x1000);
+        memory_mapper.map_device(Box::new(UartDevice::new(Box::new(serial.clone()))), 0xffff_0300, Some(1)).unwrap();
+        // Sends the message then echoes three bytes, polling LSR
+        let program = assemble_at_zero("
+                lui $s0, 0xffff
+                ori $s0, $s0, 0x300
+                li $t0, 7           # enable and clear the FIFOs
+                sb $t0, 2($s0)
+                la $s1, message
+            send:
+                lbu $t1, 0($s1)
+                beqz $t1, echo
+            wait_thr:
+                lbu $t0, 5($s0)
+                andi $t0, $t0, 0x20
+                beqz $t0, wait_thr
+                sb $t1, 0($s0)
+                addiu $s1, $s1, 1
+                b send
+            echo:
+                li $t2, 3
+            receive:
+                lbu $t0, 5($s0)
+                andi $t0, $t0, 1
+                beqz $t0, receive
+                lbu $t1, 0($s0)
+                sb $t1, 0($s0)
+                addiu $t2, $t2, -1
+                bnez $t2, receive
+                li $v0, 10
+                syscall
+                .data
+            message: .asciiz \"hi\\n\"
+        ");
+        program.load(&mut memory_mapper).unwrap();
+        {
+            let mut cpu = CPU::new(&mut memory_mapper);
+            cpu.set_syscall_handler(Box::new(SpimSyscalls::with_io(std::io::empty(), std::io::sink())));
+            let echo = program.symbols.get("echo").unwrap();
+            let limits = RunLimits { breakpoints: std::collections::BTreeSet::from([echo]), ..RunLimits::default() };
+            assert_eq!(cpu.run_with_limits(&limits), StopReason::Breakpoint(echo));
+            assert_eq!(serial.take_output(), b"hi\n");
+            serial.send(b"abc");
+            assert_eq!(cpu.run_with_limits(&RunLimits::default()), StopReason::Exited(0));
+        }
+        assert_eq!(serial.take_output(), b"abc");
+        assert_eq!(memory_mapper.interrupt_lines(), 0);
+
+        // Debuggers peek at the registers without taking the received byte
+        serial.send(b"q");
+        memory_mapper.tick_devices(1);
+        assert_eq!(memory_mapper.peek_byte(0xffff_0300), Ok(b'q'));
+        assert_eq!(memory_mapper.peek_byte(0xffff_0300), Ok(b'q'));
+        assert_eq!(memory_mapper.get_byte(0xffff_0300), Ok([b'q']));
+        assert_eq!(memory_mapper.peek_byte(0xffff_0305).map(|status| status & UartDevice::LSR_DATA_READY), Ok(0));
+
+        // Nothing is received before the guest first accesses the UART, the console is left to the syscalls
+        let serial = MemorySerial::new();
+        let mut uart = UartDevice::new(Box::new(serial.clone()));
+        serial.send(b"z");
+        uart.tick(1);
+        assert_eq!(uart.save_state().unwrap().len(), 10);
+        uart.write_byte(UartDevice::FCR, [UartDevice::FCR_CLEAR_RECEIVE]);
+        uart.tick(1);
+        assert_eq!(uart.get_byte(UartDevice::RBR), [b'z']);
+        uart.write_byte(UartDevice::IER, [UartDevice::IER_TRANSMIT_EMPTY]);
+        assert!(uart.interrupt());
+        assert_eq!(uart.get_byte(UartDevice::IIR), [UartDevice::IIR_TRANSMIT_EMPTY]);
+        assert_eq!(uart.get_byte(UartDevice::IIR), [UartDevice::IIR_NONE]);
+
+        // Data under the trigger level of 4 bytes only interrupts once it has waited for an instruction
+        uart.write_byte(UartDevice::FCR, [0x40 | UartDevice::FCR_ENABLE]);
+        uart.write_byte(UartDevice::IER, [UartDevice::IER_RECEIVED_DATA]);
+        serial.send(b"ab");
+        uart.tick(1);
+        assert_eq!(uart.get_byte(UartDevice::IIR), [UartDevice::IIR_NONE | UartDevice::IIR_FIFO_ENABLED]);
+        uart.tick(1);
+        assert_eq!(uart.get_byte(UartDevice::IIR), [UartDevice::IIR_TIMEOUT | UartDevice::IIR_FIFO_ENABLED]);
+        serial.send(b"cdef");
+        uart.tick(1);
+        assert_eq!(uart.get_byte(UartDevice::IIR), [UartDevice::IIR_RECEIVED_DATA | UartDevice::IIR_FIFO_ENABLED]);
+        assert_eq!(uart.get_byte(UartDevice::RBR), [b'a']);
+
+        let state = uart.save_state().unwrap();
+        let mut restored = UartDevice::new(Box::new(MemorySerial::new()));
+        restored.restore_state(&state).unwrap();
+        assert_eq!(restored.save_state(), Some(state));
+        assert_eq!(restored.get_byte(UartDevice::LSR), [UartDevice::LSR_DATA_READY | UartDevice::LSR_THR_EMPTY | UartDevice::LSR_TRANSMITTER_EMPTY]);
+
+        // In loopback transmitted bytes come back to the receiver, past 16 bytes they overrun the FIFO
+        uart.write_byte(UartDevice::MCR, [UartDevice::MCR_LOOPBACK]);
+        uart.write_byte(UartDevice::IER, [UartDevice::IER_LINE_STATUS]);
+        for byte in b"0123456789abcdef" {
+            uart.write_byte(UartDevice::THR, [*byte]);
+            uart.tick(1);
+        }
+        assert_eq!(uart.get_byte(UartDevice::IIR), [UartDevice::IIR_LINE_STATUS | UartDevice::IIR_FIFO_ENABLED]);
+        assert_eq!(uart.get_byte(UartDevice::LSR)[0] & UartDevice::LSR_OVERRUN, UartDevice::LSR_OVERRUN);
+        assert!(!uart.interrupt());
+        let received: Vec<u8> = (0..16).map(|_| uart.get_byte(UartDevice::RBR)[0]).collect();
+        assert_eq!(received, b"bcdef0123456789a");
+        assert_eq!(serial.output(), b"");
+
+        // The divisor latch hides RBR and IER
+        uart.write_byte(UartDevice::LCR, [UartDevice::LCR_DLAB | 3]);
+        uart.write_byte(UartDevice::THR, [12]);
+        uart.write_byte(UartDevice::IER, [0]);
+        assert_eq!(uart.get_byte(UartDevice::RBR), [12]);
+        uart.write_byte(UartDevice::LCR, [3]);
+        assert_eq!(uart.get_byte(UartDevice::IER), [UartDevice::IER_LINE_STATUS]);
+    }
+
+    #[test]
+    fn framebuffer_renders_its_pixel_modes_and_dumps_frames() {
+        let directory = std::env::temp_dir().join(format!("vm32bits-frames-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let pattern = directory.join("frame-{frame}.png").to_str().unwrap().to_owned();
+        let mut framebuffer = FramebufferDevice::new(4, 2).unwrap();
+        framebuffer.set_dumps(Some(FrameDumps { pattern, every: 2 }));
+        assert!(FramebufferDevice::new(8192, 8192).is_err());
+        let mut memory_mapper = MemoryMapper::new();
+        memory_mapper.map_device(Box::new(framebuffer), 0x2000_0000, None).unwrap();
+        let register = |offset: u32| 0x2000_0000 + offset;
+        let pages = u32::from_be_bytes(memory_mapper.get_word(register(FramebufferDevice::PAGE_OFFSET)).unwrap());
+        let page_size = u32::from_be_bytes(memory_mapper.get_word(register(FramebufferDevice::PAGE_SIZE)).unwrap());
+        assert_eq!((pages, page_size), (0x1000, 0x1000));
+
+        // An indexed frame on page 0, then RGB565 on page 1
+        memory_mapper.write_word(register(pages), [1, 0, 0xff, 1]).unwrap();
+        memory_mapper.write_word(register(FramebufferDevice::CONTROL), 0_u32.to_be_bytes()).unwrap();
+        assert_eq!(memory_mapper.get_word(register(FramebufferDevice::WIDTH)), Ok(4_u32.to_be_bytes()));
+        assert_eq!(memory_mapper.get_word(register(pages)), Ok([1, 0, 0xff, 1]));
+        memory_mapper.write_word(register(FramebufferDevice::MODE), (PixelMode::RGB565 as u32).to_be_bytes()).unwrap();
+        assert_eq!(memory_mapper.get_word(register(FramebufferDevice::PITCH)), Ok(8_u32.to_be_bytes()));
+        memory_mapper.write_half_word(register(pages + page_size), 0xf800_u16.to_be_bytes()).unwrap();
+        memory_mapper.write_half_word(register(pages + page_size + 14), 0xffff_u16.to_be_bytes()).unwrap();
+        memory_mapper.write_word(register(FramebufferDevice::CONTROL), FramebufferDevice::CONTROL_PAGE.to_be_bytes()).unwrap();
+        assert_eq!(memory_mapper.get_word(register(FramebufferDevice::FRAMES)), Ok(2_u32.to_be_bytes()));
+
+        let png = std::fs::File::open(directory.join("frame-000002.png")).unwrap();
+        let mut reader = png::Decoder::new(png).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut pixels).unwrap();
+        let mut expected = vec![0xff, 0, 0];
+        expected.extend([0; 18]);
+        expected.extend([0xff; 3]);
+        assert_eq!(pixels, expected);
+        assert!(!directory.join("frame-000001.png").exists());
+
+        // RGBA pixels are dumped as they are, here on demand to a PPM file
+        let mut framebuffer = FramebufferDevice::new(2, 1).unwrap();
+        let ppm = directory.join("frame.ppm");
+        framebuffer.set_dumps(Some(FrameDumps { pattern: ppm.to_str().unwrap().to_owned(), every: 0 }));
+        framebuffer.write_word(FramebufferDevice::PALETTE + 4, 0x00ff_0000_u32.to_be_bytes());
+        framebuffer.write_byte(FramebufferDevice::PALETTE + 6, [0x80]);
+        framebuffer.write_byte(FramebufferDevice::PAGES + 1, [1]);
+        assert_eq!(framebuffer.render(), [0, 0, 0, 0xff, 0x80, 0]);
+        framebuffer.write_word(FramebufferDevice::MODE, (PixelMode::RGBA8888 as u32).to_be_bytes());
+        framebuffer.write_word(FramebufferDevice::PAGES + 4, [10, 20, 30, 40]);
+        framebuffer.write_word(FramebufferDevice::CONTROL, 0_u32.to_be_bytes());
+        assert!(!ppm.exists());
+        let state = framebuffer.save_state().unwrap();
+        framebuffer.reset();
+        assert_eq!(framebuffer.frames(), 0);
+        framebuffer.restore_state(&state).unwrap();
+        framebuffer.write_word(FramebufferDevice::CONTROL, FramebufferDevice::CONTROL_DUMP.to_be_bytes());
+        let ppm = std::fs::read(&ppm).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(ppm, b"P6\n2 1\n255\n\0\x01\0\x0a\x14\x1e");
+        assert_eq!(framebuffer.frames(), 2);
+    }
+
+    #[test]
+    fn screen_keeps_a_buffer_of_cells_for_its_renderers() {
+        let snapshot = TextSnapshot::new();
+        let mut screen = ScreenDevice::new(8, 3).unwrap();
+        screen.set_renderer(Some(Box::new(snapshot.clone())));
+        assert!(ScreenDevice::new(0, 3).is_err());
+        let mut memory_mapper = MemoryMapper::new();
+        memory_mapper.map_ram(0, 0x1000);
+        memory_mapper.map_device(Box::new(screen), 0xffff_0000, None).unwrap();
+        // Prints the message in red and underlined, then reads the first cell back
+        let program = assemble_at_zero("
+                lui $s0, 0xffff
+                li $t0, 0x0105      # red foreground
+                sw $t0, 0x18($s0)
+                li $t0, 3           # underline
+                sb $t0, 0x1b($s0)
+                la $s1, message
+            print:
+                lbu $t1, 0($s1)
+                beqz $t1, done
+                sb $t1, 0x17($s0)
+                addiu $s1, $s1, 1
+                b print
+            done:
+                lw $s2, 0x100($s0)
+                lw $s3, 0x08($s0)
+                lw $s4, 0x0c($s0)
+                li $v0, 10
+                syscall
+                .data
+            message: .asciiz \"hello\\nvirtual\\tworld\\n>\"
+        ");
+        program.load(&mut memory_mapper).unwrap();
+        {
+            let mut cpu = CPU::new(&mut memory_mapper);
+            cpu.set_syscall_handler(Box::new(SpimSyscalls::with_io(std::io::empty(), std::io::sink())));
+            assert_eq!(cpu.run_with_limits(&RunLimits::default()), StopReason::Exited(0));
+            // The tab stops at the last column, "world" wraps and the last newline scrolls the screen up a row
+            assert_eq!(cpu.get_register_value(18), ((TextScreen::UNDERLINE | 0x9) as u32) << 16 | b'v' as u32);
+            assert_eq!((cpu.get_register_value(19), cpu.get_register_value(20)), (1, 2));
+        }
+        assert_eq!(snapshot.text(), "virtualw\norld\n>\n");
+        let drawn = snapshot.screen().unwrap();
+        assert_eq!((drawn.character(7, 0), drawn.attribute(7, 0)), (b'w', TextScreen::UNDERLINE | 0x9));
+        assert_eq!(TextScreen::colour(drawn.attribute(7, 0), TextScreen::FOREGROUND_SHIFT), Some(1));
+
+        // Cells are written directly, commands out of range are ignored and erasing homes the cursor
+        let mut screen = ScreenDevice::new(4, 2).unwrap();
+        screen.set_renderer(None);
+        screen.write_byte(ScreenDevice::CELLS + 4 * 5 + 3, [b'x']);
+        screen.write_word(ScreenDevice::COMMAND, 0x42_u32.to_be_bytes());
+        assert_eq!(screen.screen().text(), "\n x\n");
+        assert_eq!(screen.get_half_word(ScreenDevice::CELLS + 4 * 5 + 2), [0, b'x']);
+        assert_eq!(screen.get_word(ScreenDevice::COLUMNS), 4_u32.to_be_bytes());
+        let state = screen.save_state().unwrap();
+        screen.write_word(ScreenDevice::CURSOR_X, 9_u32.to_be_bytes());
+        assert_eq!(screen.screen().cursor(), (3, 0));
+        screen.write_word(ScreenDevice::COMMAND, 0xff_u32.to_be_bytes());
+        assert_eq!((screen.screen().text(), screen.screen().cursor()), ("\n\n".to_owned(), (0, 0)));
+        screen.restore_state(&state).unwrap();
+        assert_eq!(screen.screen().text(), "\n x\n");
+        assert!(screen.restore_state(&state[4..]).is_err());
+
+        // The ANSI renderer positions the cursor and sets the rendition of every cell it draws
+        #[derive(Clone, Default)]
+        struct Terminal(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+        impl std::io::Write for Terminal {
+            fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+                return self.0.borrow_mut().write(bytes);
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                return Ok(());
+            }
+        }
+        let terminal = Terminal::default();
+        screen.set_renderer(Some(Box::new(AnsiRenderer::new(Box::new(terminal.clone())))));
+        screen.write_word(ScreenDevice::COMMAND, 0x01_u32.to_be_bytes());
+        screen.write_word(ScreenDevice::COMMAND, 0x0206_u32.to_be_bytes());
+        screen.write_word(ScreenDevice::OUTPUT, (b'!' as u32).to_be_bytes());
+        assert_eq!(String::from_utf8(terminal.0.take()).unwrap(), "\x1b[1;1H\x1b[0;1;42m!\x1b[0m\x1b[1;2H\x1b[?25h");
+    }
+}
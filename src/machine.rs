@@ -0,0 +1,141 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::cpu::{CpuConfig, Endianness};
+use crate::device::{DeviceConfig, DeviceRegistry};
+use crate::memory_mapper::{MapError, MemoryMapper};
+
+/// A board configuration: the CPU options and the devices mapped in its address space.
+///
+/// In TOML:
+///
+/// ```toml
+/// [cpu]
+/// endianness = "little"
+/// delay_slots = true
+///
+/// [[device]]
+/// type = "ram"
+/// base = 0
+/// size = "1M"
+///
+/// [[device]]
+/// type = "screen"
+/// base = 0xffff0000
+/// ```
+///
+/// JSON descriptions have the same fields, with the devices in a `device` or `devices` array. Numbers can also be
+/// written as strings, JSON has no hexadecimal literals
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MachineDescription {
+    #[serde(default)]
+    pub cpu: CpuDescription,
+    #[serde(default, rename = "device", alias = "devices")]
+    pub devices: Vec<DeviceConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CpuDescription {
+    #[serde(default)]
+    pub endianness: Option<Endianness>,
+    /// Both the branch and the load delay slots
+    #[serde(default)]
+    pub delay_slots: bool,
+}
+
+/// A machine built from a description, ready for a program to be loaded
+pub struct Machine {
+    pub memory_mapper: MemoryMapper,
+    pub config: CpuConfig,
+    /// `None` when the description leaves it to the program
+    pub endianness: Option<Endianness>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MachineError {
+    /// The file can't be read
+    Io(String),
+    /// The description isn't valid TOML or JSON, or has fields that don't belong there
+    Parse(String),
+    UnknownDevice(String),
+    /// The device rejected its configuration
+    InvalidDevice { kind: String, message: String },
+    Map { kind: String, error: MapError },
+}
+
+impl fmt::Display for MachineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MachineError::Io(message) => write!(f, "{}", message),
+            MachineError::Parse(message) => write!(f, "invalid machine description: {}", message),
+            MachineError::UnknownDevice(kind) => write!(f, "unknown device `{}`", kind),
+            MachineError::InvalidDevice { kind, message } => write!(f, "{}: {}", kind, message),
+            MachineError::Map { kind, error } => write!(f, "{}: {}", kind, error),
+        }
+    }
+}
+
+impl std::error::Error for MachineError {}
+
+impl MachineDescription {
+    pub fn from_toml(text: &str) -> Result<Self, MachineError> {
+        return toml::from_str(text).map_err(|error| MachineError::Parse(error.message().to_owned()));
+    }
+
+    pub fn from_json(text: &str) -> Result<Self, MachineError> {
+        return serde_json::from_str(text).map_err(|error| MachineError::Parse(error.to_string()));
+    }
+
+    /// Reads a `.json` file as JSON and anything else as TOML
+    pub fn load(path: &Path) -> Result<Self, MachineError> {
+        let text = fs::read_to_string(path).map_err(|error| MachineError::Io(format!("can't read {}: {}", path.display(), error)))?;
+        return match path.extension().is_some_and(|extension| extension == "json") {
+            true => MachineDescription::from_json(&text),
+            false => MachineDescription::from_toml(&text),
+        };
+    }
+
+    /// Creates the devices and maps them, in the order of the description
+    pub fn build(&self, registry: &DeviceRegistry) -> Result<Machine, MachineError> {
+        let mut memory_mapper = MemoryMapper::new();
+        for config in &self.devices {
+            let device = registry.create(config)?;
+            memory_mapper.map_device(device, config.base, config.irq).map_err(|error| MachineError::Map { kind: config.kind.clone(), error })?;
+        }
+        let config = CpuConfig { branch_delay_slots: self.cpu.delay_slots, load_delay_slots: self.cpu.delay_slots };
+        return Ok(Machine { memory_mapper, config, endianness: self.cpu.endianness });
+    }
+}
+
+/// Decimal or 0x prefixed hexadecimal, with an optional k or M multiplier
+pub fn parse_number(text: &str) -> Result<u32, String> {
+    let (digits, multiplier) = match text.chars().last() {
+        Some('k') | Some('K') => (&text[..text.len() - 1], 1 << 10),
+        Some('m') | Some('M') => (&text[..text.len() - 1], 1 << 20),
+        _ => (text, 1),
+    };
+    let parsed = match digits.strip_prefix("0x").or(digits.strip_prefix("0X")) {
+        Some(digits) => u32::from_str_radix(&digits.replace('_', ""), 16),
+        None => digits.replace('_', "").parse(),
+    };
+    return parsed.ok().and_then(|number| number.checked_mul(multiplier)).ok_or(format!("invalid number `{}`", text));
+}
+
+/// Accepts numbers as integers or as strings understood by `parse_number`
+pub fn deserialize_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Number {
+        Integer(u32),
+        Text(String),
+    }
+    return match Number::deserialize(deserializer)? {
+        Number::Integer(number) => Ok(number),
+        Number::Text(text) => parse_number(&text).map_err(serde::de::Error::custom),
+    };
+}
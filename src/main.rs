@@ -1,59 +1,423 @@
-mod cpu;
-mod memory;
-mod memory_mapper;
-mod screen_device;
-pub mod fpu;
-
-use memory::Memory;
-use cpu::CPU;
-use cpu::Instruction;
-use screen_device::ScreenDevice;
-use memory_mapper::MemoryMapper;
-use vm32bits::screen_device::Command;
+#![allow(clippy::needless_return)]
+#![allow(non_camel_case_types)]
+
+use std::fs;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::process;
+
+use vm32bits::assembler::{Assembler, AssemblerOptions};
+use vm32bits::cpu::{CpuConfig, Endianness, RunLimits, StopReason, CPU};
+use vm32bits::debugger::Debugger;
+use vm32bits::device::{DeviceConfig, DeviceRegistry};
+use vm32bits::elf::{self, ElfProgram};
+use vm32bits::exception::CpuException;
+use vm32bits::gdb::GdbStub;
+use vm32bits::history::HistoryLimits;
+use vm32bits::host_io;
+use vm32bits::machine::{parse_number, Machine, MachineDescription};
+use vm32bits::memory::Memory;
+use vm32bits::memory_mapper::MemoryMapper;
+use vm32bits::snapshot::Snapshot;
+use vm32bits::symbol_table::SymbolTable;
+use vm32bits::syscall::SpimSyscalls;
+use vm32bits::trace::{BinarySink, JsonLinesSink, TextSink, TraceFilter, Tracer};
+
+const USAGE: &str = "\
+usage: vm32bits run [options] <program.elf|program.s|program.bin>
+       vm32bits debug [options] <program.elf|program.s|program.bin>
+       vm32bits gdb [options] <program.elf|program.s|program.bin>
+
+`debug` starts an interactive debugger stopped at the entry point, type help for its commands.
+`gdb` waits for gdb-multiarch to connect with `target remote` before running the program.
+
+The format is detected from the ELF magic number, files ending in .s or .asm are
+assembled and anything else is loaded as a raw binary image. Programs can use the
+SPIM/MARS syscalls for console and file I/O.
+
+options:
+  --memory <size>             RAM mapped from address 0, a multiple of 4 (default 1M, k and M
+                              suffixes allowed)
+  --load-address <address>    where a raw binary is loaded (default 0)
+  --entry <address|symbol>    start executing here instead of the program entry point
+  --endianness <big|little>   byte order of raw binaries and assembled programs (default big)
+  --delay-slots               model the branch and load delay slots
+  --device <type>@<address>[:<irq>][,<option>=<value>...]
+                              map a device, wired to interrupt line irq (0 to 5) when it's given,
+                              available devices: screen, keyboard, timer, intc, disk, uart,
+                              framebuffer. The options are the ones of machine descriptions, a
+                              disk needs an image or a size: disk@0xffff0000,image=disk.img
+  --machine <file>            build the machine from a TOML or JSON description (.json) instead
+                              of mapping --memory bytes of RAM
+  --max-instructions <n>      stop after executing n instructions
+  --save-snapshot <file>      save the machine to a file when the instruction limit is reached
+  --resume <file>             start from a saved machine instead of the entry point, the program
+                              and the memory options must be the ones it was saved with
+  --trace <file|->            write every executed instruction to a file or to stderr
+  --trace-format <format>     text (default), json (one object per line) or binary
+  --trace-range <start>:<end> only trace instructions in this address range, can be repeated
+  --trace-skip <n>            start tracing after n instructions
+  --trace-count <n>           stop tracing after n instructions
+  --listen <host:port|path>   where `gdb` waits for the debugger, a TCP address or a Unix
+                              socket path (default 127.0.0.1:1234)
+  -h, --help                  print this message
+
+exit status:
+  the code passed to the exit2 syscall (17), 0 for exit (10), 1 when the program
+  can't be loaded or raises an unhandled exception, 2 for usage errors and 124
+  when the instruction limit is reached";
+
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+/// The same status `timeout` uses
+const EXIT_LIMIT: i32 = 124;
+
+enum Command {
+    Help,
+    Start(Mode, Box<Options>),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Run,
+    Debug,
+    Gdb,
+}
+
+struct Options {
+    program: String,
+    memory_size: u32,
+    load_address: u32,
+    entry: Option<String>,
+    endianness: Endianness,
+    config: CpuConfig,
+    devices: Vec<DeviceConfig>,
+    machine: Option<String>,
+    max_instructions: Option<u64>,
+    save_snapshot: Option<String>,
+    resume: Option<String>,
+    trace: Option<String>,
+    trace_format: TraceFormat,
+    trace_filter: TraceFilter,
+    listen: String,
+}
+
+#[derive(Clone, Copy)]
+enum TraceFormat {
+    Text,
+    Json,
+    Binary,
+}
+
+/// What is needed to start a program once it is in memory
+struct Image {
+    entry: u32,
+    endianness: Endianness,
+    symbols: SymbolTable,
+    global_pointer: u32,
+}
 
 fn main() {
-    let mem = Memory::new(256 * 256);
-    let sd = ScreenDevice{};
+    let arguments: Vec<String> = std::env::args().skip(1).collect();
+    match parse_arguments(&arguments) {
+        Ok(Command::Help) => println!("{}", USAGE),
+        Ok(Command::Start(mode, options)) => process::exit(run(&options, mode)),
+        Err(message) => {
+            eprintln!("vm32bits: {}\n\n{}", message, USAGE);
+            process::exit(EXIT_USAGE);
+        },
+    }
+}
 
-    let mut memory_mapper = MemoryMapper::new();
-    memory_mapper.map(Box::new(mem), 0, 0xffff, false);
-    memory_mapper.map(Box::new(sd), 0x9000, 0x90ff, true);
+fn parse_arguments(arguments: &[String]) -> Result<Command, String> {
+    let mut arguments = arguments.iter();
+    let mode = match arguments.next().map(|argument| argument.as_str()) {
+        Some("run") => Mode::Run,
+        Some("debug") => Mode::Debug,
+        Some("gdb") => Mode::Gdb,
+        Some("-h") | Some("--help") => return Ok(Command::Help),
+        Some(command) => return Err(format!("unknown command `{}`", command)),
+        None => return Err("missing command".to_owned()),
+    };
 
-    let mut index = 0;
+    let mut options = Options {
+        program: String::new(),
+        memory_size: 0x10_0000,
+        load_address: 0,
+        entry: None,
+        endianness: Endianness::Big,
+        config: CpuConfig::default(),
+        devices: vec![],
+        machine: None,
+        max_instructions: None,
+        save_snapshot: None,
+        resume: None,
+        trace: None,
+        trace_format: TraceFormat::Text,
+        trace_filter: TraceFilter::default(),
+        listen: "127.0.0.1:1234".to_owned(),
+    };
+    let mut program = None;
+    while let Some(argument) = arguments.next() {
+        let mut value = || arguments.next().ok_or(format!("missing value for `{}`", argument));
+        match argument.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--memory" => {
+                let size = value()?;
+                options.memory_size = parse_number(size)?;
+                if !options.memory_size.is_multiple_of(4) {
+                    return Err(format!("memory size `{}` is not a multiple of 4", size));
+                }
+            },
+            "--load-address" => options.load_address = parse_number(value()?)?,
+            "--entry" => options.entry = Some(value()?.clone()),
+            "--endianness" => options.endianness = match value()?.as_str() {
+                "big" => Endianness::Big,
+                "little" => Endianness::Little,
+                other => return Err(format!("unknown endianness `{}`", other)),
+            },
+            "--delay-slots" => options.config = CpuConfig { branch_delay_slots: true, load_delay_slots: true },
+            "--device" => {
+                let mut parts = value()?.split(',');
+                let device = parts.next().unwrap_or_default();
+                let (name, address) = device.split_once('@').ok_or(format!("expected <name>@<address>[:<irq>], found `{}`", device))?;
+                let (address, irq) = match address.split_once(':') {
+                    Some((address, irq)) => (address, Some(irq.parse().map_err(|_| format!("invalid interrupt line `{}`", irq))?)),
+                    None => (address, None),
+                };
+                let mut config = DeviceConfig { irq, ..DeviceConfig::new(name, parse_number(address)?) };
+                for option in parts {
+                    let (option, value) = option.split_once('=').ok_or(format!("expected <option>=<value>, found `{}`", option))?;
+                    config.options.insert(option.to_owned(), serde_json::Value::String(value.to_owned()));
+                }
+                options.devices.push(config);
+            },
+            "--machine" => options.machine = Some(value()?.clone()),
+            "--max-instructions" => options.max_instructions = Some(parse_count(value()?)?),
+            "--save-snapshot" => options.save_snapshot = Some(value()?.clone()),
+            "--resume" => options.resume = Some(value()?.clone()),
+            "--trace" => options.trace = Some(value()?.clone()),
+            "--trace-format" => options.trace_format = match value()?.as_str() {
+                "text" => TraceFormat::Text,
+                "json" => TraceFormat::Json,
+                "binary" => TraceFormat::Binary,
+                other => return Err(format!("unknown trace format `{}`", other)),
+            },
+            "--trace-range" => {
+                let range = value()?;
+                let (start, end) = range.split_once(':').ok_or(format!("expected <start>:<end>, found `{}`", range))?;
+                options.trace_filter.ranges.push(parse_number(start)?..=parse_number(end)?);
+            },
+            "--trace-skip" => options.trace_filter.first = parse_count(value()?)?,
+            "--trace-count" => options.trace_filter.count = Some(parse_count(value()?)?),
+            "--listen" => options.listen = value()?.clone(),
+            option if option.starts_with('-') && option != "-" => return Err(format!("unknown option `{}`", option)),
+            path if program.is_none() => program = Some(path.to_owned()),
+            path => return Err(format!("unexpected argument `{}`", path)),
+        }
+    }
+    options.program = program.ok_or("missing program")?;
+    return Ok(Command::Start(mode, Box::new(options)));
+}
 
-    //print_string(&mut memory_mapper, "Hello World!".to_owned(), &mut index);
-    for i in 0..0xff {
-        print_char(&mut memory_mapper, &mut index, '*', i as u8, Some(Command::ERASE_SCREEN));
+fn parse_count(text: &str) -> Result<u64, String> {
+    return text.parse().map_err(|_| format!("invalid instruction count `{}`", text));
+}
+
+/// The memory map and the CPU options, from the machine description or from the command line
+fn build_machine(options: &Options) -> Result<Machine, String> {
+    let registry = DeviceRegistry::new();
+    let mut machine = match &options.machine {
+        Some(path) => MachineDescription::load(Path::new(path)).and_then(|description| description.build(&registry)).map_err(|error| format!("{}: {}", path, error))?,
+        None => {
+            let mut memory_mapper = MemoryMapper::new();
+            if options.memory_size > 0 {
+                memory_mapper.map(Box::new(Memory::new(options.memory_size as usize)), 0, options.memory_size - 1, false);
+            }
+            Machine { memory_mapper, config: CpuConfig::default(), endianness: None }
+        },
+    };
+    for config in &options.devices {
+        let device = registry.create(config).map_err(|error| error.to_string())?;
+        machine.memory_mapper.map_device(device, config.base, config.irq).map_err(|error| format!("{}: {}", config.kind, error))?;
+    }
+    machine.config.branch_delay_slots |= options.config.branch_delay_slots;
+    machine.config.load_delay_slots |= options.config.load_delay_slots;
+    return Ok(machine);
+}
+
+/// `endianness` applies to raw binaries and assembled programs, ELF files say what they are
+fn load(options: &Options, endianness: Endianness, memory_mapper: &mut MemoryMapper) -> Result<Image, String> {
+    let bytes = fs::read(&options.program).map_err(|error| format!("can't read {}: {}", options.program, error))?;
+    if bytes.starts_with(b"\x7fELF") {
+        let program = elf::load(&bytes, memory_mapper).map_err(|error| format!("{}: {}", options.program, error))?;
+        return Ok(Image { entry: program.entry, endianness: program.endianness, symbols: program.symbols, global_pointer: program.global_pointer });
     }
 
-    let instruction = 0b1010_001100_u32;
-    memory_mapper.write_word(index, instruction.to_be_bytes());
+    if options.program.ends_with(".s") || options.program.ends_with(".asm") {
+        let source = String::from_utf8(bytes).map_err(|_| format!("{} is not valid UTF-8", options.program))?;
+        let assembler = Assembler::new(AssemblerOptions { endianness, ..AssemblerOptions::default() });
+        let program = assembler.assemble(&source).map_err(|errors| {
+            errors.iter().map(|error| format!("{}:{}: {}", options.program, error.line, error.message)).collect::<Vec<String>>().join("\n")
+        })?;
+        for segment in &program.segments {
+            memory_mapper.map_ram(segment.base, segment.bytes.len() as u32);
+        }
+        program.load(memory_mapper).map_err(|error| format!("the program overlaps a device at {:#010x}", error.address))?;
+        let global_pointer = program.symbols.get("_gp").unwrap_or(ElfProgram::DEFAULT_GLOBAL_POINTER);
+        return Ok(Image { entry: program.entry, endianness, symbols: program.symbols, global_pointer });
+    }
+
+    if options.load_address as u64 + bytes.len() as u64 > 1 << 32 {
+        return Err(format!("{} doesn't fit between {:#010x} and the end of memory", options.program, options.load_address));
+    }
+    memory_mapper.map_ram(options.load_address, bytes.len() as u32);
+    for (i, byte) in bytes.iter().enumerate() {
+        memory_mapper.write_byte(options.load_address + i as u32, [*byte])
+            .map_err(|error| format!("the program overlaps a device at {:#010x}", error.address))?;
+    }
+    return Ok(Image { entry: options.load_address, endianness, symbols: SymbolTable::new(), global_pointer: ElfProgram::DEFAULT_GLOBAL_POINTER });
+}
 
-    let mut cpu: CPU = CPU::new(&mut memory_mapper);
-    cpu.run();
+fn open_trace(path: &str, options: &Options, symbols: &SymbolTable) -> Result<Tracer<'static>, String> {
+    let output: Box<dyn Write> = match path {
+        "-" => Box::new(io::stderr()),
+        _ => Box::new(io::BufWriter::new(fs::File::create(path).map_err(|error| format!("can't create {}: {}", path, error))?)),
+    };
+    let filter = options.trace_filter.clone();
+    return Ok(match options.trace_format {
+        TraceFormat::Text => Tracer::with_filter(TextSink::with_symbols(output, symbols.clone()), filter),
+        TraceFormat::Json => Tracer::with_filter(JsonLinesSink::new(output), filter),
+        TraceFormat::Binary => Tracer::with_filter(BinarySink::new(output), filter),
+    });
+}
 
+/// Accepts a single gdb connection and serves it until gdb detaches or disconnects
+fn serve_gdb(stub: &mut GdbStub, listen: &str) -> io::Result<()> {
+    if let Ok(address) = listen.parse::<SocketAddr>() {
+        let listener = TcpListener::bind(address)?;
+        eprintln!("vm32bits: waiting for gdb on {}", listener.local_addr()?);
+        let (mut stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        return stub.serve(&mut stream);
+    }
+    #[cfg(unix)]
+    {
+        let listener = UnixListener::bind(listen)?;
+        eprintln!("vm32bits: waiting for gdb on {}", listen);
+        let accepted = listener.accept();
+        // The socket file is only needed until gdb has connected
+        let _ = fs::remove_file(listen);
+        return stub.serve(&mut accepted?.0);
+    }
+    #[cfg(not(unix))]
+    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid address `{}`", listen)));
+}
 
-    fn print_char(memory_mapper: &mut MemoryMapper, address: &mut u32, char: char, index: u8, command: Option<Command>) {
-        let command = command.unwrap_or(Command::NO_OP);
-        let instruction = form_i_instruction(Instruction::ADDIU as u32, 0, 1, char as u32 + ((command as u32) << 8));
-    
-        memory_mapper.write_word(*address, instruction.to_be_bytes());
-        *address += 4;
+/// Runs the program to completion, under the debugger REPL or under gdb
+fn run(options: &Options, mode: Mode) -> i32 {
+    let Machine { mut memory_mapper, config, endianness } = match build_machine(options) {
+        Ok(machine) => machine,
+        Err(message) => {
+            eprintln!("vm32bits: {}", message);
+            return EXIT_FAILURE;
+        },
+    };
+    let image = match load(options, endianness.unwrap_or(options.endianness), &mut memory_mapper) {
+        Ok(image) => image,
+        Err(message) => {
+            eprintln!("vm32bits: {}", message);
+            return EXIT_FAILURE;
+        },
+    };
+    ElfProgram::map_stack(&mut memory_mapper);
 
-        let instruction = form_i_instruction(Instruction::SB as u32, 0, 1, 0x9000 + index as u32);
-        memory_mapper.write_word(*address, instruction.to_be_bytes());
+    let setup = || -> Result<(u32, Option<Tracer>), String> {
+        let entry = match &options.entry {
+            Some(entry) => image.symbols.get(entry).map(Ok).unwrap_or_else(|| parse_number(entry))?,
+            None => image.entry,
+        };
+        let trace = options.trace.as_deref().map(|path| open_trace(path, options, &image.symbols)).transpose()?;
+        return Ok((entry, trace));
+    };
+    let (entry, trace) = match setup() {
+        Ok(setup) => setup,
+        Err(message) => {
+            eprintln!("vm32bits: {}", message);
+            return EXIT_FAILURE;
+        },
+    };
 
-        *address += 4;
+    let mut cpu = CPU::with_config(&mut memory_mapper, config);
+    cpu.set_endianness(image.endianness);
+    cpu.set_pc(entry);
+    cpu.set_register_value(29, ElfProgram::STACK_POINTER);
+    cpu.set_register_value(28, image.global_pointer);
+    cpu.set_syscall_handler(Box::new(SpimSyscalls::new()));
+    if let Some(path) = &options.resume {
+        let restored = fs::File::open(path).and_then(|file| Snapshot::read(io::BufReader::new(file))).and_then(|snapshot| cpu.restore(&snapshot));
+        if let Err(error) = restored {
+            eprintln!("vm32bits: can't resume from {}: {}", path, error);
+            return EXIT_FAILURE;
+        }
+    }
+    if let Some(tracer) = trace {
+        cpu.set_tracer(tracer);
     }
 
-    fn print_string(memory_mapper: &mut MemoryMapper, s: String, address: &mut u32) {
-        for (i, c) in s.chars().into_iter().enumerate() {
-            print_char(memory_mapper, address, c, (i) as u8, Some(Command::ERASE_SCREEN));
+    if mode == Mode::Gdb {
+        let mut stub = GdbStub::new(cpu);
+        if let Err(error) = serve_gdb(&mut stub, &options.listen) {
+            eprintln!("vm32bits: {}", error);
+            return EXIT_FAILURE;
         }
-    } 
-}
+        return stub.cpu().exit_code().unwrap_or(0);
+    }
 
+    if mode == Mode::Debug {
+        cpu.enable_history(HistoryLimits::default());
+        let mut debugger = Debugger::new(cpu, image.symbols);
+        // The guest reads the console too, so the REPL must not buffer ahead
+        if let Err(error) = debugger.repl(io::BufReader::with_capacity(1, host_io::Stdin), io::stdout()) {
+            eprintln!("vm32bits: {}", error);
+            return EXIT_FAILURE;
+        }
+        return debugger.cpu().exit_code().unwrap_or(0);
+    }
 
-fn form_i_instruction(op_code: u32, rs: u32, rd: u32, immediate: u32) -> u32 {
-    return (op_code << 26) + (rs << 21) + (rd << 16) + immediate;
+    let limits = RunLimits { max_instructions: options.max_instructions, ..RunLimits::default() };
+    let stop = cpu.run_with_limits(&limits);
+    // A broken trace doesn't change the outcome of the program, but it's worth knowing about
+    if let Some(Err(error)) = cpu.take_tracer().map(|mut tracer| tracer.finish()) {
+        eprintln!("vm32bits: can't write the trace: {}", error);
+    }
+    return match stop {
+        StopReason::Exited(code) => code,
+        StopReason::InstructionLimit => {
+            eprintln!("vm32bits: stopped after {} instructions at {:#010x}", limits.max_instructions.unwrap_or(0), cpu.get_pc());
+            if let Some(path) = &options.save_snapshot {
+                if let Err(error) = fs::File::create(path).and_then(|file| cpu.snapshot().write(io::BufWriter::new(file))) {
+                    eprintln!("vm32bits: can't save the snapshot: {}", error);
+                    return EXIT_FAILURE;
+                }
+            }
+            EXIT_LIMIT
+        },
+        StopReason::Exception(CpuException::Syscall) => {
+            eprintln!("vm32bits: unsupported syscall {} at {:#010x}", cpu.get_register_value(2), cpu.get_pc());
+            EXIT_FAILURE
+        },
+        StopReason::Exception(exception) => {
+            eprintln!("vm32bits: unhandled exception at {:#010x}: {}", cpu.get_pc(), exception);
+            EXIT_FAILURE
+        },
+        // Nothing sets breakpoints, watchpoints or a time limit
+        StopReason::Breakpoint(_) | StopReason::Watchpoint(_) | StopReason::TimeLimit => {
+            eprintln!("vm32bits: stopped at {:#010x}", cpu.get_pc());
+            EXIT_FAILURE
+        },
+    };
 }
@@ -1,4 +1,8 @@
+use std::io;
+
+use crate::device::Device;
 use crate::memory_mapper::MemoryMappable;
+use crate::snapshot;
 
 #[derive(PartialEq)]
 pub struct Memory {
@@ -46,6 +50,32 @@ impl MemoryMappable for Memory {
     fn get_word(&self, index: u32) -> [u8; 4] {
         return [self.memory[index as usize], self.memory[index as usize+1], self.memory[index as usize+2], self.memory[index as usize+3]]
     }
+
+    fn is_memory(&self) -> bool {
+        return true;
+    }
+
+    fn save_state(&self) -> Option<Vec<u8>> {
+        return Some(self.memory.to_vec());
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> io::Result<()> {
+        if state.len() != self.memory.len() {
+            return Err(snapshot::invalid_snapshot("the size of a memory region doesn't match the snapshot"));
+        }
+        self.memory.copy_from_slice(state);
+        return Ok(());
+    }
+
     
-    
-}
\ No newline at end of file
+}
+
+impl Device for Memory {
+    fn name(&self) -> &str {
+        return "ram";
+    }
+
+    fn size(&self) -> u32 {
+        return self.memory.len() as u32;
+    }
+}
@@ -1,17 +1,249 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
 use std::ptr;
 
+use crate::device::{Device, DmaRequest};
+use crate::memory::Memory;
+use crate::snapshot::{self, RegionState};
+
 pub struct MemoryMapper {
     regions: Vec<Region>,
+    watchpoints: Vec<Watchpoint>,
+    /// Accesses that hit a watchpoint since the CPU last collected them, reads only borrow the mapper
+    watch_hits: RefCell<Vec<WatchHit>>,
+    /// Every access is recorded while the CPU is being traced
+    log_accesses: bool,
+    accesses: RefCell<Vec<MemoryAccess>>,
+    /// Whether something was mapped with `map_device`, plain memory doesn't need ticks
+    has_devices: bool,
+}
+
+/// Why `map_device` couldn't map a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// The device takes no address space
+    Empty,
+    /// The device would run past the end of the address space
+    OutOfRange,
+    /// The device overlaps the region from `start` to `end`
+    Overlap { start: u32, end: u32 },
+    /// There is no such interrupt line
+    InvalidIrq(u8),
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapError::Empty => write!(f, "the device has no registers"),
+            MapError::OutOfRange => write!(f, "the device runs past the end of the address space"),
+            MapError::Overlap { start, end } => write!(f, "overlaps the region from {:#010x} to {:#010x}", start, end),
+            MapError::InvalidIrq(irq) => write!(f, "there is no interrupt line {}, they go from 0 to 5", irq),
+        }
+    }
+}
+
+impl std::error::Error for MapError {}
+
+/// Returned when an access hits an address that no region is mapped on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusError {
+    pub address: u32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// Both reads and writes
+    Access,
+}
+
+/// Watches the addresses from `start` to `end` included
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub start: u32,
+    pub end: u32,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, kind: WatchKind, address: u32, size: u32) -> bool {
+        let kind_matches = self.kind == WatchKind::Access || self.kind == kind;
+        let last = address.saturating_add(size.max(1) - 1);
+        return kind_matches && address <= self.end && self.start <= last;
+    }
+}
+
+/// An access that hit a watchpoint.
+///
+/// Values are read in address order, the CPU turns them into its byte order and fills in the `pc` of the instruction
+/// that made the access. For reads the old and new values are the same
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub watchpoint: Watchpoint,
+    /// `Read` or `Write`
+    pub kind: WatchKind,
+    pub pc: u32,
+    pub address: u32,
+    pub size: u32,
+    pub old_value: u32,
+    pub new_value: u32,
+}
+
+/// A read or a write logged by the memory mapper, values are read in address order like in `WatchHit`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    /// `Read` or `Write`
+    pub kind: WatchKind,
+    pub address: u32,
+    pub size: u32,
+    pub old_value: u32,
+    pub new_value: u32,
 }
 
 impl MemoryMapper {
+    /// Granularity of the memory allocated by `map_ram`
+    pub const PAGE_SIZE: u32 = 0x1000;
+    /// The hardware interrupt lines of a MIPS I processor, IP2 to IP7
+    pub const INTERRUPT_LINES: u8 = 6;
+
     pub fn new() -> Self {
-        MemoryMapper { regions: vec![]  }
+        MemoryMapper {
+            regions: vec![], watchpoints: vec![], watch_hits: RefCell::new(vec![]), log_accesses: false, accesses: RefCell::new(vec![]),
+            has_devices: false,
+        }
     }
 
-    pub fn map(&mut self, device: Box<dyn MemoryMappable>, start: u32, end: u32, remap: bool) -> &Region {
-        self.regions.insert(0, Region{device: device, start, end, remap});
-        return self.regions.get(0).unwrap();
+    /// Maps `device` from `start` to `end` included, over whatever was mapped there. With `remap` the device sees
+    /// addresses relative to `start`
+    pub fn map(&mut self, device: Box<dyn Device>, start: u32, end: u32, remap: bool) -> &Region {
+        self.regions.insert(0, Region{device, start, end, remap, ram: false, irq: None, ticked: false});
+        return self.regions.first().unwrap();
+    }
+
+    /// Maps a device at `base` on as many bytes as it has registers, its interrupt output is wired to line `irq` (0 to
+    /// 5). Devices mapped this way are ticked and reset, and they can't overlap anything
+    pub fn map_device(&mut self, device: Box<dyn Device>, base: u32, irq: Option<u8>) -> Result<(), MapError> {
+        let size = device.size();
+        if size == 0 {
+            return Err(MapError::Empty);
+        }
+        if let Some(irq) = irq.filter(|irq| *irq >= MemoryMapper::INTERRUPT_LINES) {
+            return Err(MapError::InvalidIrq(irq));
+        }
+        let end = base.checked_add(size - 1).ok_or(MapError::OutOfRange)?;
+        if let Some(region) = self.regions.iter().find(|region| region.start <= end && base <= region.end) {
+            return Err(MapError::Overlap { start: region.start, end: region.end });
+        }
+        self.regions.insert(0, Region { device, start: base, end, remap: true, ram: false, irq, ticked: true });
+        self.has_devices = true;
+        return Ok(());
+    }
+
+    /// Called after every instruction, the DMA transfers the devices ask for are carried out right after their tick
+    pub fn tick_devices(&mut self, cycles: u64) {
+        if !self.has_devices {
+            return;
+        }
+        for i in 0..self.regions.len() {
+            if !self.regions[i].ticked {
+                continue;
+            }
+            self.regions[i].device.tick(cycles);
+            while let Some(request) = self.regions[i].device.dma_request() {
+                let result = match request {
+                    DmaRequest::ToMemory { address, bytes } => self.dma_write(address, &bytes).map(|_| vec![]),
+                    DmaRequest::FromMemory { address, length } => self.dma_read(address, length),
+                };
+                self.regions[i].device.dma_complete(result);
+            }
+        }
+    }
+
+    /// Reads for a device, DMA reads are neither logged nor seen by watchpoints
+    fn dma_read(&self, address: u32, length: u32) -> Result<Vec<u8>, BusError> {
+        let mut bytes = Vec::with_capacity(length as usize);
+        for i in 0..length {
+            let address = address.checked_add(i).ok_or(BusError { address: u32::MAX })?;
+            let region = self.find_region(address)?;
+            bytes.push(region.device.get_byte(MemoryMapper::remap_address(region, address))[0]);
+        }
+        return Ok(bytes);
+    }
+
+    /// Writes for a device a byte at a time, the writes are logged so that the history can take them back but
+    /// watchpoints don't see them
+    fn dma_write(&mut self, address: u32, bytes: &[u8]) -> Result<(), BusError> {
+        let log_accesses = self.log_accesses;
+        for (i, byte) in bytes.iter().enumerate() {
+            let address = address.checked_add(i as u32).ok_or(BusError { address: u32::MAX })?;
+            let region = self.find_mut_region(address)?;
+            let final_address = MemoryMapper::remap_address(region, address);
+            let old = log_accesses.then(|| region.device.get_byte(final_address)[0]);
+            region.device.write_byte(final_address, [*byte]);
+            if let Some(old) = old {
+                let access = MemoryAccess { kind: WatchKind::Write, address, size: 1, old_value: old as u32, new_value: *byte as u32 };
+                self.accesses.borrow_mut().push(access);
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn reset_devices(&mut self) {
+        for region in self.regions.iter_mut() {
+            region.device.reset();
+        }
+    }
+
+    /// The first device mapped with `map_device` whose name is `name`
+    pub fn device(&self, name: &str) -> Option<&dyn Device> {
+        return self.regions.iter().find(|region| region.ticked && region.device.name() == name).map(|region| region.device.as_ref());
+    }
+
+    pub fn device_mut(&mut self, name: &str) -> Option<&mut (dyn Device + 'static)> {
+        return self.regions.iter_mut().find(|region| region.ticked && region.device.name() == name).map(|region| region.device.as_mut());
+    }
+
+    /// The lines whose device asserts its interrupt, bit 0 is IP2. When an interrupt controller is mapped these are
+    /// the lines it lets through
+    pub fn interrupt_lines(&self) -> u8 {
+        if !self.has_devices {
+            return 0;
+        }
+        let lines = self.regions.iter()
+            .filter_map(|region| region.irq.filter(|_| region.device.interrupt()))
+            .fold(0, |lines, irq| lines | (1 << irq));
+        return self.regions.iter()
+            .filter(|region| region.ticked)
+            .find_map(|region| region.device.route_interrupts(lines))
+            .unwrap_or(lines);
+    }
+
+    /// Maps zeroed memory on the pages of `[start, start + size)` that aren't mapped yet, a range that runs past the
+    /// end of the address space stops there
+    pub fn map_ram(&mut self, start: u32, size: u32) {
+        if size == 0 {
+            return;
+        }
+        let last_page = start.saturating_add(size - 1) / MemoryMapper::PAGE_SIZE;
+        let mut page = start / MemoryMapper::PAGE_SIZE;
+        while page <= last_page {
+            if self.find_region(page * MemoryMapper::PAGE_SIZE).is_ok() {
+                page += 1;
+                continue;
+            }
+            let first = page;
+            while page <= last_page && self.find_region(page * MemoryMapper::PAGE_SIZE).is_err() {
+                page += 1;
+            }
+            self.map_ram_region(first * MemoryMapper::PAGE_SIZE, (page - 1) * MemoryMapper::PAGE_SIZE + (MemoryMapper::PAGE_SIZE - 1));
+        }
+    }
+
+    fn map_ram_region(&mut self, start: u32, end: u32) {
+        self.map(Box::new(Memory::new((end - start) as usize + 1)), start, end, true);
+        self.regions[0].ram = true;
     }
 
     #[allow(dead_code)]
@@ -19,12 +251,12 @@ impl MemoryMapper {
         self.regions.retain(|r| !ptr::eq(region, r));
     }
     
-    pub fn find_region(&self, address: u32) -> &Region {
-        return self.regions.iter().find(|r| r.start <= address && address <= r.end).unwrap();
+    pub fn find_region(&self, address: u32) -> Result<&Region, BusError> {
+        return self.regions.iter().find(|r| r.start <= address && address <= r.end).ok_or(BusError { address });
     }
 
-    pub fn find_mut_region(&mut self, address: u32) -> &mut Region {
-        return self.regions.iter_mut().find(|r| r.start <= address && address <= r.end).unwrap();
+    pub fn find_mut_region(&mut self, address: u32) -> Result<&mut Region, BusError> {
+        return self.regions.iter_mut().find(|r| r.start <= address && address <= r.end).ok_or(BusError { address });
     }
 
     fn remap_address(region: &Region, address: u32) -> u32 {
@@ -34,40 +266,207 @@ impl MemoryMapper {
         return address;
     }
 
-    pub fn get_byte(&self, address: u32) -> [u8; 1] {
-        let region = self.find_region(address);
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    /// Removes one watchpoint equal to `watchpoint`, returns false when there was none
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        return match self.watchpoints.iter().position(|w| w == watchpoint) {
+            Some(index) => {
+                self.watchpoints.remove(index);
+                true
+            },
+            None => false,
+        };
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        return &self.watchpoints;
+    }
+
+    /// The first watchpoint that an access of `size` bytes at `address` would hit
+    pub fn find_watchpoint(&self, kind: WatchKind, address: u32, size: u32) -> Option<Watchpoint> {
+        return self.watchpoints.iter().find(|w| w.matches(kind, address, size)).copied();
+    }
+
+    /// Returns the hits recorded since the last call and forgets them
+    pub fn take_watch_hits(&self) -> Vec<WatchHit> {
+        return self.watch_hits.take();
+    }
+
+    /// Starts or stops logging every read and write, the log is emptied with `take_accesses`
+    pub fn set_access_logging(&mut self, enabled: bool) {
+        self.log_accesses = enabled;
+        self.accesses.borrow_mut().clear();
+    }
+
+    pub fn take_accesses(&self) -> Vec<MemoryAccess> {
+        return self.accesses.take();
+    }
+
+    fn record_access(&self, kind: WatchKind, address: u32, old: &[u8], new: &[u8]) {
+        let value = |bytes: &[u8]| bytes.iter().fold(0, |value, byte| (value << 8) | *byte as u32);
+        let size = new.len() as u32;
+        if self.log_accesses {
+            self.accesses.borrow_mut().push(MemoryAccess { kind, address, size, old_value: value(old), new_value: value(new) });
+        }
+        if let Some(watchpoint) = self.find_watchpoint(kind, address, size) {
+            let hit = WatchHit { watchpoint, kind, pc: 0, address, size, old_value: value(old), new_value: value(new) };
+            self.watch_hits.borrow_mut().push(hit);
+        }
+    }
+
+    /// The regions from the most recently mapped, with the state of their devices
+    pub fn save_regions(&self) -> Vec<RegionState> {
+        return self.regions.iter().map(|region| RegionState {
+            start: region.start,
+            end: region.end,
+            remap: region.remap,
+            ram: region.ram,
+            state: region.device.save_state(),
+        }).collect();
+    }
+
+    /// Brings back the devices saved by `save_regions`, the regions must be mapped the way they were when it was called.
+    ///
+    /// RAM mapped by `map_ram` on one side only, like the heap grown by sbrk, is mapped again or unmapped
+    pub fn restore_regions(&mut self, saved: &[RegionState]) -> io::Result<()> {
+        // Regions are mapped in front of the older ones, those mapped on one side only have to be RAM
+        let (added, removed) = (saved.len().saturating_sub(self.regions.len()), self.regions.len().saturating_sub(saved.len()));
+        let same_map = saved[..added].iter().all(|saved| saved.ram)
+            && self.regions[..removed].iter().all(|region| region.ram)
+            && self.regions[removed..].iter().zip(&saved[added..]).all(|(region, saved)| {
+                region.start == saved.start && region.end == saved.end && region.remap == saved.remap && region.ram == saved.ram
+            });
+        if !same_map {
+            return Err(snapshot::invalid_snapshot("the memory map doesn't match the snapshot"));
+        }
+        self.regions.drain(..removed);
+        for saved in saved[..added].iter().rev() {
+            self.map_ram_region(saved.start, saved.end);
+        }
+        for (region, saved) in self.regions.iter_mut().zip(saved) {
+            if let Some(state) = &saved.state {
+                region.device.restore_state(state)?;
+            }
+        }
+        return Ok(());
+    }
+
+    /// Puts back the value a logged write overwrote, writes to devices other than memory have already had their
+    /// effect and are left alone
+    pub fn undo_write(&mut self, access: &MemoryAccess) {
+        let Ok(region) = self.find_mut_region(access.address) else {
+            return;
+        };
+        if !region.device.is_memory() {
+            return;
+        }
+        let address = MemoryMapper::remap_address(region, access.address);
+        let old = access.old_value.to_be_bytes();
+        match access.size {
+            1 => region.device.write_byte(address, [old[3]]),
+            2 => region.device.write_half_word(address, [old[2], old[3]]),
+            _ => region.device.write_word(address, old),
+        }
+    }
+
+    /// Reads an instruction, fetches are neither logged nor seen by watchpoints
+    pub fn fetch_word(&self, address: u32) -> Result<[u8; 4], BusError> {
+        let region = self.find_region(address)?;
+        return Ok(region.device.get_word(MemoryMapper::remap_address(region, address)));
+    }
+
+    pub fn get_byte(&self, address: u32) -> Result<[u8; 1], BusError> {
+        let region = self.find_region(address)?;
         let final_address = MemoryMapper::remap_address(region, address);
-        return region.device.get_byte(final_address);
+        let value = region.device.get_byte(final_address);
+        self.record_access(WatchKind::Read, address, &value, &value);
+        return Ok(value);
     }
 
-    pub fn get_half_word(&self, address: u32) -> [u8; 2] {
-        let region = self.find_region(address);
+    pub fn get_half_word(&self, address: u32) -> Result<[u8; 2], BusError> {
+        let region = self.find_region(address)?;
         let final_address = MemoryMapper::remap_address(region, address);
-        return region.device.get_half_word(final_address);
+        let value = region.device.get_half_word(final_address);
+        self.record_access(WatchKind::Read, address, &value, &value);
+        return Ok(value);
     }
 
-    pub fn get_word(&self, address: u32) -> [u8; 4] {
-        let region = self.find_region(address);
+    pub fn get_word(&self, address: u32) -> Result<[u8; 4], BusError> {
+        let region = self.find_region(address)?;
         let final_address = MemoryMapper::remap_address(region, address);
-        return region.device.get_word(final_address);
+        let value = region.device.get_word(final_address);
+        self.record_access(WatchKind::Read, address, &value, &value);
+        return Ok(value);
     }
 
-    pub fn write_byte(&mut self, address: u32, value:[u8; 1]) {
-        let region = self.find_mut_region(address);
+    /// Reads a byte the way a debugger does: the read isn't logged, watchpoints don't see it and devices don't react
+    /// to it
+    pub fn peek_byte(&self, address: u32) -> Result<u8, BusError> {
+        let region = self.find_region(address)?;
+        return Ok(region.device.peek(MemoryMapper::remap_address(region, address)));
+    }
+
+    /// Four bytes read by `peek_byte`
+    pub fn peek_word(&self, address: u32) -> Result<[u8; 4], BusError> {
+        let mut word = [0; 4];
+        for (i, byte) in word.iter_mut().enumerate() {
+            *byte = self.peek_byte(address.wrapping_add(i as u32))?;
+        }
+        return Ok(word);
+    }
+
+    /// What a write is about to overwrite, only read back when the write is logged or watched and peeked so that it
+    /// doesn't count as a read
+    fn old_value<const N: usize>(&self, address: u32) -> Result<Option<[u8; N]>, BusError> {
+        let watched = !self.watchpoints.is_empty() && self.find_watchpoint(WatchKind::Write, address, N as u32).is_some();
+        if !self.log_accesses && !watched {
+            return Ok(None);
+        }
+        let region = self.find_region(address)?;
+        let address = MemoryMapper::remap_address(region, address);
+        return Ok(Some(std::array::from_fn(|i| region.device.peek(address.wrapping_add(i as u32)))));
+    }
+
+    pub fn write_byte(&mut self, address: u32, value:[u8; 1]) -> Result<(), BusError> {
+        let old = self.old_value::<1>(address)?;
+        let region = self.find_mut_region(address)?;
         let final_address = MemoryMapper::remap_address(region, address);
         region.device.write_byte(final_address, value);
+        if let Some(old) = old {
+            self.record_access(WatchKind::Write, address, &old, &value);
+        }
+        return Ok(());
     }
     
-    pub fn write_half_word(&mut self, address: u32, value:[u8; 2]) {
-        let region = self.find_mut_region(address);
+    pub fn write_half_word(&mut self, address: u32, value:[u8; 2]) -> Result<(), BusError> {
+        let old = self.old_value::<2>(address)?;
+        let region = self.find_mut_region(address)?;
         let final_address = MemoryMapper::remap_address(region, address);
         region.device.write_half_word(final_address, value);
+        if let Some(old) = old {
+            self.record_access(WatchKind::Write, address, &old, &value);
+        }
+        return Ok(());
     }
 
-    pub fn write_word(&mut self, address: u32, value: [u8; 4]) {
-        let region = self.find_mut_region(address);
+    pub fn write_word(&mut self, address: u32, value: [u8; 4]) -> Result<(), BusError> {
+        let old = self.old_value::<4>(address)?;
+        let region = self.find_mut_region(address)?;
         let final_address = MemoryMapper::remap_address(region, address);
         region.device.write_word(final_address, value);
+        if let Some(old) = old {
+            self.record_access(WatchKind::Write, address, &old, &value);
+        }
+        return Ok(());
+    }
+}
+
+impl Default for MemoryMapper {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -79,12 +478,48 @@ pub trait MemoryMappable {
     fn write_byte(&mut self, address: u32, value: [u8; 1]);
     fn write_half_word(&mut self, address: u32, value: [u8; 2]);
     fn write_word(&mut self, address: u32, value: [u8; 4]);
+
+    /// Reads a byte without the side effects a read of the guest has, for debuggers. Devices whose registers change
+    /// when they are read override it
+    fn peek(&self, address: u32) -> u8 {
+        return self.get_byte(address)[0];
+    }
+
+    /// Plain memory, a write to it is taken back by writing the old value again
+    fn is_memory(&self) -> bool {
+        return false;
+    }
+
+    /// What a snapshot has to hold to bring the device back, devices without state return `None`
+    fn save_state(&self) -> Option<Vec<u8>> {
+        return None;
+    }
+
+    /// Brings back what `save_state` returned
+    fn restore_state(&mut self, _state: &[u8]) -> io::Result<()> {
+        return Ok(());
+    }
 }
 
 //#[derive(PartialEq)]
 pub struct Region {
-    device: Box<dyn MemoryMappable>,
+    device: Box<dyn Device>,
     start: u32,
     end: u32,
-    remap: bool
-}
\ No newline at end of file
+    remap: bool,
+    /// Mapped by `map_ram`
+    ram: bool,
+    irq: Option<u8>,
+    /// Mapped by `map_device`
+    ticked: bool,
+}
+
+impl Region {
+    pub fn start(&self) -> u32 {
+        return self.start;
+    }
+
+    pub fn end(&self) -> u32 {
+        return self.end;
+    }
+}
@@ -1,74 +1,504 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
 use num_derive::FromPrimitive;
 
+use crate::device::{self, Device};
 use crate::memory_mapper::MemoryMappable;
+use crate::snapshot;
+
+/// A text mode terminal keeping a buffer of character cells that renderers draw on the host.
+///
+/// The registers are big endian words from offset 0: the number of columns and rows, the cursor column and row, the
+/// attribute of the characters that are output, the output register, the command register and the control register.
+/// Writing a character to the output register puts it at the cursor and moves the cursor on like a teletype:
+/// newline, carriage return, backspace and tab move the cursor, the screen scrolls up when the cursor goes past the
+/// last row. The command register takes a `Command` in the low byte and its argument in the next one. The cells
+/// follow at 0x100 row by row, one word each with the attribute in the upper half word and the character in the low
+/// byte, the guest can read and write them directly
+pub struct ScreenDevice {
+    screen: TextScreen,
+    /// Given to the characters written to the output register
+    attribute: u16,
+    renderer: Option<Box<dyn ScreenRenderer>>,
+}
+
+#[derive(FromPrimitive)]
+pub enum Command {
+    NO_OP = 0x00,
+    SET_BOLD = 0x01,
+    /// Clears the colours and every other attribute
+    SET_REGULAR = 0x02,
+    SET_UNDERLINE = 0x03,
+    SET_REVERSE = 0x04,
+    /// The argument is a colour from 0 to 7 (black, red, green, yellow, blue, magenta, cyan, white), anything else
+    /// is the default colour
+    SET_FOREGROUND = 0x05,
+    SET_BACKGROUND = 0x06,
+    /// Fills the row of the cursor with spaces in the current attribute
+    ERASE_LINE = 0xfe,
+    /// Fills the screen with spaces in the current attribute and moves the cursor home
+    ERASE_SCREEN = 0xff,
+}
+
+/// The cells, the cursor and its visibility, as renderers see them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextScreen {
+    columns: u32,
+    rows: u32,
+    cells: Vec<u32>,
+    cursor_x: u32,
+    cursor_y: u32,
+    cursor_visible: bool,
+}
 
-fn move_to(x: u32, y: u32) {
-    print!("\x1b[{};{}H", y, x);
+impl TextScreen {
+    /// Bits 0 to 3 of an attribute are the foreground colour, 0 is the default colour and 8 to 15 the colours 0 to 7
+    pub const FOREGROUND_SHIFT: u32 = 0;
+    /// Bits 4 to 7 are the background colour, encoded like the foreground
+    pub const BACKGROUND_SHIFT: u32 = 4;
+    pub const BOLD: u16 = 1 << 8;
+    pub const UNDERLINE: u16 = 1 << 9;
+    pub const REVERSE: u16 = 1 << 10;
+
+    fn new(columns: u32, rows: u32) -> Self {
+        return TextScreen {
+            columns, rows, cells: vec![b' ' as u32; (columns * rows) as usize], cursor_x: 0, cursor_y: 0, cursor_visible: true,
+        };
+    }
+
+    pub fn columns(&self) -> u32 {
+        return self.columns;
+    }
+
+    pub fn rows(&self) -> u32 {
+        return self.rows;
+    }
+
+    pub fn character(&self, x: u32, y: u32) -> u8 {
+        return self.cells[(y * self.columns + x) as usize] as u8;
+    }
+
+    pub fn attribute(&self, x: u32, y: u32) -> u16 {
+        return (self.cells[(y * self.columns + x) as usize] >> 16) as u16;
+    }
+
+    pub fn cursor(&self) -> (u32, u32) {
+        return (self.cursor_x, self.cursor_y);
+    }
+
+    pub fn cursor_visible(&self) -> bool {
+        return self.cursor_visible;
+    }
+
+    /// The colour (0 to 7) of a foreground or background field, `None` for the default colour
+    pub fn colour(attribute: u16, shift: u32) -> Option<u8> {
+        let field = (attribute >> shift) as u8 & 0xf;
+        return (field & 0x8 != 0).then_some(field & 0x7);
+    }
+
+    /// The characters without attributes, one line per row with the trailing spaces removed
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for y in 0..self.rows {
+            let line: String = (0..self.columns).map(|x| glyph(self.character(x, y))).collect();
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        return text;
+    }
+
+    fn fill(&mut self, range: std::ops::Range<usize>, attribute: u16) {
+        self.cells[range].fill((attribute as u32) << 16 | b' ' as u32);
+    }
+
+    /// Moves every row up one and blanks the last one
+    fn scroll(&mut self, attribute: u16) {
+        let columns = self.columns as usize;
+        self.cells.copy_within(columns.., 0);
+        let length = self.cells.len();
+        self.fill(length - columns..length, attribute);
+    }
 }
 
-fn erase_screen() {
-    print!("\x1b[2J")
+/// What the host shows for a character, control characters are blank and the upper half is Latin-1
+fn glyph(character: u8) -> char {
+    return match character {
+        0x20..=0x7e | 0xa0..=0xff => character as char,
+        _ => ' ',
+    };
+}
+
+/// Draws the screen on the host as it changes
+pub trait ScreenRenderer {
+    /// The cell at `x`, `y` changed, the cursor is drawn by the `move_cursor` that follows
+    fn draw_cell(&mut self, screen: &TextScreen, x: u32, y: u32);
+
+    /// Every cell may have changed, when the screen is erased, scrolled, reset or restored
+    fn redraw(&mut self, screen: &TextScreen);
+
+    /// The cursor moved or was shown or hidden
+    fn move_cursor(&mut self, _screen: &TextScreen) {}
+}
+
+/// Draws on a terminal with ANSI escape sequences, the screen sits in the top left corner
+pub struct AnsiRenderer {
+    output: Box<dyn Write>,
+}
+
+impl AnsiRenderer {
+    pub fn new(output: Box<dyn Write>) -> Self {
+        AnsiRenderer { output }
+    }
+
+    pub fn stdout() -> Self {
+        return AnsiRenderer::new(Box::new(io::stdout()));
+    }
+
+    /// Select Graphic Rendition for an attribute, starting from the default rendition
+    fn rendition(attribute: u16) -> String {
+        let mut rendition = String::from("\x1b[0");
+        for (flag, parameter) in [(TextScreen::BOLD, ";1"), (TextScreen::UNDERLINE, ";4"), (TextScreen::REVERSE, ";7")] {
+            if attribute & flag != 0 {
+                rendition.push_str(parameter);
+            }
+        }
+        if let Some(colour) = TextScreen::colour(attribute, TextScreen::FOREGROUND_SHIFT) {
+            rendition.push_str(&format!(";3{}", colour));
+        }
+        if let Some(colour) = TextScreen::colour(attribute, TextScreen::BACKGROUND_SHIFT) {
+            rendition.push_str(&format!(";4{}", colour));
+        }
+        rendition.push('m');
+        return rendition;
+    }
+
+    fn write_cell(&mut self, screen: &TextScreen, x: u32, y: u32) -> io::Result<()> {
+        let rendition = AnsiRenderer::rendition(screen.attribute(x, y));
+        return write!(self.output, "{}{}", rendition, glyph(screen.character(x, y)));
+    }
+
+    fn write_cursor(&mut self, screen: &TextScreen) -> io::Result<()> {
+        let visibility = if screen.cursor_visible { 'h' } else { 'l' };
+        write!(self.output, "\x1b[0m\x1b[{};{}H\x1b[?25{}", screen.cursor_y + 1, screen.cursor_x + 1, visibility)?;
+        return self.output.flush();
+    }
+}
+
+/// A terminal that can't be written to has nowhere to report it, the screen keeps going
+impl ScreenRenderer for AnsiRenderer {
+    fn draw_cell(&mut self, screen: &TextScreen, x: u32, y: u32) {
+        let _ = write!(self.output, "\x1b[{};{}H", y + 1, x + 1).and_then(|_| self.write_cell(screen, x, y));
+    }
+
+    fn redraw(&mut self, screen: &TextScreen) {
+        let mut redraw = || -> io::Result<()> {
+            write!(self.output, "\x1b[0m\x1b[2J")?;
+            for y in 0..screen.rows {
+                write!(self.output, "\x1b[{};1H", y + 1)?;
+                for x in 0..screen.columns {
+                    self.write_cell(screen, x, y)?;
+                }
+            }
+            return self.write_cursor(screen);
+        };
+        let _ = redraw();
+    }
+
+    fn move_cursor(&mut self, screen: &TextScreen) {
+        let _ = self.write_cursor(screen);
+    }
+}
+
+/// Keeps a copy of the screen as it was last drawn, clones share it so tests can check what the guest displayed
+#[derive(Clone, Default)]
+pub struct TextSnapshot {
+    screen: Rc<RefCell<Option<TextScreen>>>,
+}
+
+impl TextSnapshot {
+    pub fn new() -> Self {
+        return TextSnapshot::default();
+    }
+
+    /// The screen as plain text, empty until it's first drawn
+    pub fn text(&self) -> String {
+        return self.screen.borrow().as_ref().map(TextScreen::text).unwrap_or_default();
+    }
+
+    pub fn screen(&self) -> Option<TextScreen> {
+        return self.screen.borrow().clone();
+    }
+
+    fn copy(&mut self, screen: &TextScreen) {
+        let mut copy = self.screen.borrow_mut();
+        match copy.as_mut() {
+            Some(copy) => copy.clone_from(screen),
+            None => *copy = Some(screen.clone()),
+        }
+    }
 }
 
-fn set_bold() {
-    print!("\x1b[1m");
+impl ScreenRenderer for TextSnapshot {
+    fn draw_cell(&mut self, screen: &TextScreen, _x: u32, _y: u32) {
+        self.copy(screen);
+    }
+
+    fn redraw(&mut self, screen: &TextScreen) {
+        self.copy(screen);
+    }
+
+    fn move_cursor(&mut self, screen: &TextScreen) {
+        self.copy(screen);
+    }
 }
 
-fn set_regular() {
-    print!("\x1b[0m");
+impl ScreenDevice {
+    pub const COLUMNS: u32 = 0x00;
+    pub const ROWS: u32 = 0x04;
+    pub const CURSOR_X: u32 = 0x08;
+    pub const CURSOR_Y: u32 = 0x0c;
+    pub const ATTRIBUTE: u32 = 0x10;
+    pub const OUTPUT: u32 = 0x14;
+    pub const COMMAND: u32 = 0x18;
+    pub const CONTROL: u32 = 0x1c;
+    pub const CELLS: u32 = 0x100;
+
+    pub const CONTROL_CURSOR_VISIBLE: u32 = 1 << 0;
+
+    pub const DEFAULT_COLUMNS: u32 = 32;
+    pub const DEFAULT_ROWS: u32 = 8;
+
+    /// Columns and rows are limited to 256 each
+    pub const MAX_SIZE: u32 = 256;
+
+    /// A blank screen drawn on the host's terminal
+    pub fn new(columns: u32, rows: u32) -> Result<Self, String> {
+        if !(1..=ScreenDevice::MAX_SIZE).contains(&columns) || !(1..=ScreenDevice::MAX_SIZE).contains(&rows) {
+            return Err(format!("a {}x{} screen is out of range", columns, rows));
+        }
+        return Ok(ScreenDevice { screen: TextScreen::new(columns, rows), attribute: 0, renderer: Some(Box::new(AnsiRenderer::stdout())) });
+    }
+
+    /// Replaces the renderer, `None` keeps the screen to the guest
+    pub fn set_renderer(&mut self, renderer: Option<Box<dyn ScreenRenderer>>) {
+        self.renderer = renderer;
+    }
+
+    pub fn screen(&self) -> &TextScreen {
+        return &self.screen;
+    }
+
+    fn draw_cell(&mut self, x: u32, y: u32) {
+        if let Some(renderer) = &mut self.renderer {
+            renderer.draw_cell(&self.screen, x, y);
+        }
+    }
+
+    fn redraw(&mut self) {
+        if let Some(renderer) = &mut self.renderer {
+            renderer.redraw(&self.screen);
+        }
+    }
+
+    fn move_cursor(&mut self) {
+        if let Some(renderer) = &mut self.renderer {
+            renderer.move_cursor(&self.screen);
+        }
+    }
+
+    /// Puts a character at the cursor, or moves the cursor for control characters
+    fn output(&mut self, character: u8) {
+        let (x, y) = self.screen.cursor();
+        match character {
+            b'\n' => self.new_line(),
+            b'\r' => self.screen.cursor_x = 0,
+            0x08 => self.screen.cursor_x = x.saturating_sub(1),
+            b'\t' => self.screen.cursor_x = (x + 8 - x % 8).min(self.screen.columns - 1),
+            _ => {
+                self.screen.cells[(y * self.screen.columns + x) as usize] = (self.attribute as u32) << 16 | character as u32;
+                self.draw_cell(x, y);
+                self.screen.cursor_x += 1;
+                if self.screen.cursor_x == self.screen.columns {
+                    self.new_line();
+                }
+            },
+        }
+        self.move_cursor();
+    }
+
+    fn new_line(&mut self) {
+        self.screen.cursor_x = 0;
+        if self.screen.cursor_y + 1 < self.screen.rows {
+            self.screen.cursor_y += 1;
+            return;
+        }
+        self.screen.scroll(self.attribute);
+        self.redraw();
+    }
+
+    fn command(&mut self, value: u32) {
+        let argument = (value >> 8) as u8;
+        // Colours out of range are the default colour
+        let colour = |shift: u32| if argument < 8 { (0x8 | argument as u16) << shift } else { 0 };
+        let Some(command) = num::FromPrimitive::from_u32(value & 0xff) else {
+            return;
+        };
+        match command {
+            Command::NO_OP => {},
+            Command::SET_BOLD => self.attribute |= TextScreen::BOLD,
+            Command::SET_REGULAR => self.attribute = 0,
+            Command::SET_UNDERLINE => self.attribute |= TextScreen::UNDERLINE,
+            Command::SET_REVERSE => self.attribute |= TextScreen::REVERSE,
+            Command::SET_FOREGROUND => self.attribute = self.attribute & !(0xf << TextScreen::FOREGROUND_SHIFT) | colour(TextScreen::FOREGROUND_SHIFT),
+            Command::SET_BACKGROUND => self.attribute = self.attribute & !(0xf << TextScreen::BACKGROUND_SHIFT) | colour(TextScreen::BACKGROUND_SHIFT),
+            Command::ERASE_LINE => {
+                let start = (self.screen.cursor_y * self.screen.columns) as usize;
+                self.screen.fill(start..start + self.screen.columns as usize, self.attribute);
+                self.redraw();
+            },
+            Command::ERASE_SCREEN => {
+                let length = self.screen.cells.len();
+                self.screen.fill(0..length, self.attribute);
+                (self.screen.cursor_x, self.screen.cursor_y) = (0, 0);
+                self.redraw();
+            },
+        }
+    }
+
+    fn read_register(&self, address: u32) -> u32 {
+        return match address & !3 {
+            ScreenDevice::COLUMNS => self.screen.columns,
+            ScreenDevice::ROWS => self.screen.rows,
+            ScreenDevice::CURSOR_X => self.screen.cursor_x,
+            ScreenDevice::CURSOR_Y => self.screen.cursor_y,
+            ScreenDevice::ATTRIBUTE => self.attribute as u32,
+            ScreenDevice::CONTROL => self.screen.cursor_visible as u32,
+            _ => 0,
+        };
+    }
+
+    fn write_register(&mut self, address: u32, value: u32) {
+        match address & !3 {
+            ScreenDevice::CURSOR_X => {
+                self.screen.cursor_x = value.min(self.screen.columns - 1);
+                self.move_cursor();
+            },
+            ScreenDevice::CURSOR_Y => {
+                self.screen.cursor_y = value.min(self.screen.rows - 1);
+                self.move_cursor();
+            },
+            ScreenDevice::ATTRIBUTE => self.attribute = value as u16,
+            ScreenDevice::OUTPUT => self.output(value as u8),
+            ScreenDevice::COMMAND => self.command(value),
+            ScreenDevice::CONTROL => {
+                self.screen.cursor_visible = value & ScreenDevice::CONTROL_CURSOR_VISIBLE != 0;
+                self.move_cursor();
+            },
+            _ => {},
+        }
+    }
+
+    /// The index of the cell behind `address` when there is one
+    fn cell_index(&self, address: u32) -> Option<usize> {
+        let index = (address.checked_sub(ScreenDevice::CELLS)? / 4) as usize;
+        return (index < self.screen.cells.len()).then_some(index);
+    }
+
+    fn read(&self, address: u32) -> [u8; 4] {
+        return match self.cell_index(address) {
+            Some(index) => self.screen.cells[index].to_be_bytes(),
+            None => self.read_register(address).to_be_bytes(),
+        };
+    }
+
+    /// A cell takes the bytes at their place in its big endian word. A register gets them merged into the word it
+    /// reads back, the output and command registers read back as 0 so a byte written to their last byte is a whole
+    /// character or command
+    fn write_bytes(&mut self, address: u32, bytes: &[u8]) {
+        let Some(index) = self.cell_index(address) else {
+            self.write_register(address, device::merge_register(self.read_register(address), address, bytes));
+            return;
+        };
+        self.screen.cells[index] = device::merge_register(self.screen.cells[index], address, bytes);
+        let (x, y) = (index as u32 % self.screen.columns, index as u32 / self.screen.columns);
+        self.draw_cell(x, y);
+        self.move_cursor();
+    }
 }
 
-pub struct ScreenDevice {}
+impl Default for ScreenDevice {
+    fn default() -> Self {
+        Self::new(ScreenDevice::DEFAULT_COLUMNS, ScreenDevice::DEFAULT_ROWS).unwrap()
+    }
+}
 
 impl MemoryMappable for ScreenDevice {
-    fn get_byte(&self, _: u32) -> [u8; 1] {
-        panic!("You can only write half words here")
+    fn get_byte(&self, address: u32) -> [u8; 1] {
+        return [self.read(address)[(address & 3) as usize]];
     }
 
-    fn get_half_word(&self, _: u32) -> [u8; 2] {
-        panic!("You can only write half words here")
+    fn get_half_word(&self, address: u32) -> [u8; 2] {
+        let word = self.read(address);
+        let offset = (address & 2) as usize;
+        return [word[offset], word[offset + 1]];
     }
 
-    fn get_word(&self, _: u32) -> [u8; 4] {
-        panic!("You can only write half words here")
+    fn get_word(&self, address: u32) -> [u8; 4] {
+        return self.read(address);
     }
 
     fn write_byte(&mut self, address: u32, value: [u8; 1]) {
-        self.write_half_word(address, (value[0] as u16).to_be_bytes());
+        self.write_bytes(address, &value);
     }
 
     fn write_half_word(&mut self, address: u32, value: [u8; 2]) {
-        self.write_word(address, (u16::from_be_bytes(value) as u32).to_be_bytes())
+        self.write_bytes(address, &value);
     }
 
     fn write_word(&mut self, address: u32, value: [u8; 4]) {
-        let value = u32::from_be_bytes(value);
-        let character_value = value & 0x00ff;
-        let command = (value & 0xff00) >> 8;
-        println!("{}", command);
-        let command: Command = num::FromPrimitive::from_u32(command).unwrap();
+        self.write_bytes(address, &value);
+    }
 
+    /// The cursor column and row, the attribute and the control register as little endian words, then the cells
+    fn save_state(&self) -> Option<Vec<u8>> {
+        let registers = [self.screen.cursor_x, self.screen.cursor_y, self.attribute as u32, self.screen.cursor_visible as u32];
+        return Some(registers.iter().chain(self.screen.cells.iter()).flat_map(|word| word.to_le_bytes()).collect());
+    }
 
-        match command {
-            Command::ERASE_SCREEN => erase_screen(),
-            Command::SET_BOLD => set_bold(),
-            Command::SET_REGULAR => set_regular(),
-            Command::NO_OP => {},
+    /// The host's terminal is redrawn as the snapshot shows it
+    fn restore_state(&mut self, state: &[u8]) -> io::Result<()> {
+        if state.len() != (4 + self.screen.cells.len()) * 4 {
+            return Err(snapshot::invalid_snapshot("invalid screen state"));
         }
+        let word = |i: usize| u32::from_le_bytes(state[i * 4..i * 4 + 4].try_into().unwrap());
+        if word(0) >= self.screen.columns || word(1) >= self.screen.rows || word(2) > u16::MAX as u32 {
+            return Err(snapshot::invalid_snapshot("invalid screen state"));
+        }
+        (self.screen.cursor_x, self.screen.cursor_y) = (word(0), word(1));
+        self.attribute = word(2) as u16;
+        self.screen.cursor_visible = word(3) & ScreenDevice::CONTROL_CURSOR_VISIBLE != 0;
+        for (i, cell) in self.screen.cells.iter_mut().enumerate() {
+            *cell = word(4 + i);
+        }
+        self.redraw();
+        return Ok(());
+    }
+}
 
-        let x = (address % 32) + 1;
-        let y = (address / 32) + 1;
+impl Device for ScreenDevice {
+    fn name(&self) -> &str {
+        return "screen";
+    }
 
-        move_to(x , y);
-        let character = char::from_u32(character_value).unwrap();
-        print!("{}", character)
+    fn size(&self) -> u32 {
+        return ScreenDevice::CELLS + self.screen.cells.len() as u32 * 4;
     }
-}
 
-#[derive(FromPrimitive)]
-pub enum Command {
-    ERASE_SCREEN = 0xff,
-    SET_BOLD = 0x01,
-    SET_REGULAR = 0x02,
-    NO_OP = 0x00
+    fn reset(&mut self) {
+        self.screen = TextScreen::new(self.screen.columns, self.screen.rows);
+        self.attribute = 0;
+        self.redraw();
+    }
 }
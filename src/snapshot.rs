@@ -0,0 +1,194 @@
+use std::io::{self, Read, Write};
+
+use crate::cpu::{CpuConfig, Endianness};
+
+/// Everything needed to resume a machine exactly where it was: the CPU, the content of the mapped regions and the
+/// state of the syscall handler.
+///
+/// A snapshot only carries state, restoring it needs a machine with the same memory map and devices
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub cpu: CpuState,
+    pub regions: Vec<RegionState>,
+    /// What the syscall handler returned from `save_state`
+    pub syscalls: Option<Vec<u8>>,
+}
+
+/// The registers of the CPU and its coprocessors, along with the branch and load that are still in flight
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuState {
+    pub registers: [u32; 32],
+    pub pc: u32,
+    pub hi: u32,
+    pub lo: u32,
+    pub status: u32,
+    pub cause: u32,
+    pub epc: u32,
+    pub bad_vaddr: u32,
+    pub fpu_registers: [u32; 32],
+    pub fcsr: u32,
+    pub endianness: Endianness,
+    pub config: CpuConfig,
+    pub delayed_branch: Option<u32>,
+    pub pending_load: Option<(u8, u32)>,
+    pub exit_code: Option<i32>,
+}
+
+/// A mapped region, `state` is what its device returned from `save_state`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionState {
+    pub start: u32,
+    pub end: u32,
+    pub remap: bool,
+    /// Mapped with `MemoryMapper::map_ram`, a restore maps it again when it's missing
+    pub ram: bool,
+    pub state: Option<Vec<u8>>,
+}
+
+const FLAG_LITTLE_ENDIAN: u8 = 1 << 0;
+const FLAG_BRANCH_DELAY_SLOTS: u8 = 1 << 1;
+const FLAG_LOAD_DELAY_SLOTS: u8 = 1 << 2;
+const FLAG_DELAYED_BRANCH: u8 = 1 << 3;
+const FLAG_PENDING_LOAD: u8 = 1 << 4;
+const FLAG_EXIT_CODE: u8 = 1 << 5;
+
+const REGION_REMAP: u8 = 1 << 0;
+const REGION_STATE: u8 = 1 << 1;
+const REGION_RAM: u8 = 1 << 2;
+
+impl Snapshot {
+    pub const MAGIC: [u8; 7] = *b"VM32SNP";
+    pub const VERSION: u8 = 1;
+
+    /// Writes the versioned on-disk format, the magic and version followed by little endian fields.
+    ///
+    /// The CPU is the 32 GPRs, pc, hi, lo, Status, Cause, EPC, BadVAddr, the 32 FPU registers and FCSR (u32), a flags
+    /// byte, the delayed branch target (u32), the register (u8) and value (u32) of the pending load and the exit code
+    /// (i32), the last three only mean something when their flag is set. Then comes the number of regions (u32) and each
+    /// region as its start and end (u32), a flags byte and the length (u32) and bytes of the device state. Last is the
+    /// state of the syscall handler, a presence byte then the length (u32) and the bytes
+    pub fn write(&self, mut output: impl Write) -> io::Result<()> {
+        let cpu = &self.cpu;
+        let mut bytes = Snapshot::MAGIC.to_vec();
+        bytes.push(Snapshot::VERSION);
+        for value in cpu.registers.iter().chain([cpu.pc, cpu.hi, cpu.lo, cpu.status, cpu.cause, cpu.epc, cpu.bad_vaddr].iter()) {
+            bytes.extend(value.to_le_bytes());
+        }
+        for value in cpu.fpu_registers.iter().chain([cpu.fcsr].iter()) {
+            bytes.extend(value.to_le_bytes());
+        }
+        let flag = |set: bool, flag: u8| if set { flag } else { 0 };
+        bytes.push(flag(cpu.endianness == Endianness::Little, FLAG_LITTLE_ENDIAN)
+            | flag(cpu.config.branch_delay_slots, FLAG_BRANCH_DELAY_SLOTS)
+            | flag(cpu.config.load_delay_slots, FLAG_LOAD_DELAY_SLOTS)
+            | flag(cpu.delayed_branch.is_some(), FLAG_DELAYED_BRANCH)
+            | flag(cpu.pending_load.is_some(), FLAG_PENDING_LOAD)
+            | flag(cpu.exit_code.is_some(), FLAG_EXIT_CODE));
+        bytes.extend(cpu.delayed_branch.unwrap_or(0).to_le_bytes());
+        let (load_register, load_value) = cpu.pending_load.unwrap_or((0, 0));
+        bytes.push(load_register);
+        bytes.extend(load_value.to_le_bytes());
+        bytes.extend(cpu.exit_code.unwrap_or(0).to_le_bytes());
+
+        bytes.extend((self.regions.len() as u32).to_le_bytes());
+        for region in &self.regions {
+            bytes.extend(region.start.to_le_bytes());
+            bytes.extend(region.end.to_le_bytes());
+            bytes.push(flag(region.remap, REGION_REMAP) | flag(region.state.is_some(), REGION_STATE) | flag(region.ram, REGION_RAM));
+            write_blob(&mut bytes, region.state.as_deref());
+        }
+        bytes.push(self.syscalls.is_some() as u8);
+        write_blob(&mut bytes, self.syscalls.as_deref());
+        output.write_all(&bytes)?;
+        return output.flush();
+    }
+
+    /// Decodes what `write` wrote
+    pub fn read(mut input: impl Read) -> io::Result<Snapshot> {
+        let mut bytes = vec![];
+        input.read_to_end(&mut bytes)?;
+        if bytes.len() < 8 || bytes[..7] != Snapshot::MAGIC {
+            return Err(invalid_snapshot("not a vm32bits snapshot"));
+        }
+        if bytes[7] != Snapshot::VERSION {
+            return Err(invalid_snapshot("unsupported snapshot version"));
+        }
+
+        let mut decoder = Decoder { bytes: &bytes, position: 8 };
+        let mut registers = [0; 32];
+        for register in registers.iter_mut() {
+            *register = decoder.u32()?;
+        }
+        let (pc, hi, lo) = (decoder.u32()?, decoder.u32()?, decoder.u32()?);
+        let (status, cause, epc, bad_vaddr) = (decoder.u32()?, decoder.u32()?, decoder.u32()?, decoder.u32()?);
+        let mut fpu_registers = [0; 32];
+        for register in fpu_registers.iter_mut() {
+            *register = decoder.u32()?;
+        }
+        let fcsr = decoder.u32()?;
+        let flags = decoder.u8()?;
+        let delayed_branch = decoder.u32()?;
+        let pending_load = (decoder.u8()?, decoder.u32()?);
+        let exit_code = decoder.u32()? as i32;
+        let cpu = CpuState {
+            registers, pc, hi, lo, status, cause, epc, bad_vaddr, fpu_registers, fcsr,
+            endianness: if flags & FLAG_LITTLE_ENDIAN != 0 { Endianness::Little } else { Endianness::Big },
+            config: CpuConfig { branch_delay_slots: flags & FLAG_BRANCH_DELAY_SLOTS != 0, load_delay_slots: flags & FLAG_LOAD_DELAY_SLOTS != 0 },
+            delayed_branch: (flags & FLAG_DELAYED_BRANCH != 0).then_some(delayed_branch),
+            pending_load: (flags & FLAG_PENDING_LOAD != 0).then_some(pending_load),
+            exit_code: (flags & FLAG_EXIT_CODE != 0).then_some(exit_code),
+        };
+
+        let mut regions = vec![];
+        for _ in 0..decoder.u32()? {
+            let (start, end, flags) = (decoder.u32()?, decoder.u32()?, decoder.u8()?);
+            let state = decoder.blob()?;
+            let (remap, ram) = (flags & REGION_REMAP != 0, flags & REGION_RAM != 0);
+            regions.push(RegionState { start, end, remap, ram, state: (flags & REGION_STATE != 0).then_some(state) });
+        }
+        let has_syscalls = decoder.u8()? != 0;
+        let syscalls = decoder.blob()?;
+        if decoder.position != bytes.len() {
+            return Err(invalid_snapshot("unexpected data after the snapshot"));
+        }
+        return Ok(Snapshot { cpu, regions, syscalls: has_syscalls.then_some(syscalls) });
+    }
+}
+
+fn write_blob(bytes: &mut Vec<u8>, blob: Option<&[u8]>) {
+    let blob = blob.unwrap_or(&[]);
+    bytes.extend((blob.len() as u32).to_le_bytes());
+    bytes.extend(blob);
+}
+
+pub fn invalid_snapshot(message: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, message.to_owned());
+}
+
+/// Reads the little endian fields of a snapshot
+struct Decoder<'b> {
+    bytes: &'b [u8],
+    position: usize,
+}
+
+impl<'b> Decoder<'b> {
+    fn take(&mut self, length: usize) -> io::Result<&'b [u8]> {
+        let end = self.position.checked_add(length).ok_or_else(|| invalid_snapshot("the snapshot is truncated"))?;
+        let field = self.bytes.get(self.position..end).ok_or_else(|| invalid_snapshot("the snapshot is truncated"))?;
+        self.position = end;
+        return Ok(field);
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        return Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()));
+    }
+
+    fn blob(&mut self) -> io::Result<Vec<u8>> {
+        let length = self.u32()? as usize;
+        return Ok(self.take(length)?.to_vec());
+    }
+}
@@ -0,0 +1,55 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Maps symbol names to addresses and back, shared by the assembler, the ELF loader and the debugging tools
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SymbolTable {
+    by_name: HashMap<String, u32>,
+    by_address: BTreeMap<u32, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a symbol, returns false if a symbol with the same name already exists.
+    ///
+    /// When several symbols share an address the first one inserted is used to name it
+    pub fn insert(&mut self, name: &str, address: u32) -> bool {
+        if self.by_name.contains_key(name) {
+            return false;
+        }
+        self.by_name.insert(name.to_owned(), address);
+        self.by_address.entry(address).or_insert_with(|| name.to_owned());
+        return true;
+    }
+
+    pub fn get(&self, name: &str) -> Option<u32> {
+        return self.by_name.get(name).copied();
+    }
+
+    /// The symbol defined exactly at `address`
+    pub fn name_at(&self, address: u32) -> Option<&str> {
+        return self.by_address.get(&address).map(|name| name.as_str());
+    }
+
+    /// The closest symbol at or before `address` and the distance from it
+    pub fn lookup(&self, address: u32) -> Option<(&str, u32)> {
+        return self.by_address.range(..=address).next_back().map(|(start, name)| (name.as_str(), address - start));
+    }
+
+    pub fn len(&self) -> usize {
+        return self.by_name.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.by_name.is_empty();
+    }
+
+    /// Iterates the symbols ordered by address
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u32)> {
+        let mut symbols: Vec<(&str, u32)> = self.by_name.iter().map(|(name, address)| (name.as_str(), *address)).collect();
+        symbols.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(b.0)));
+        return symbols.into_iter();
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use num_derive::FromPrimitive;
+
+use crate::cpu::CPU;
+use crate::elf::ElfProgram;
+use crate::exception::CpuException;
+use crate::host_io;
+use crate::snapshot;
+
+/// Services the SYSCALL instruction on behalf of the guest, installed with `CPU::set_syscall_handler`.
+///
+/// Returning `Err(CpuException::Syscall)` hands the syscall over to the guest's exception handler as if no handler
+/// was installed
+pub trait SyscallHandler {
+    fn syscall(&mut self, cpu: &mut CPU) -> Result<(), CpuException>;
+
+    /// What a snapshot has to hold to bring the handler back, handlers without state return `None`
+    fn save_state(&self) -> Option<Vec<u8>> {
+        return None;
+    }
+
+    /// Brings back what `save_state` returned
+    fn restore_state(&mut self, _state: &[u8]) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+/// The services of the SPIM and MARS simulators, selected by $v0 with the arguments in $a0-$a3
+#[derive(FromPrimitive)]
+pub enum Service {
+    PRINT_INT = 1,
+    PRINT_FLOAT = 2,
+    PRINT_DOUBLE = 3,
+    PRINT_STRING = 4,
+    READ_INT = 5,
+    READ_FLOAT = 6,
+    READ_DOUBLE = 7,
+    READ_STRING = 8,
+    SBRK = 9,
+    EXIT = 10,
+    PRINT_CHAR = 11,
+    READ_CHAR = 12,
+    OPEN = 13,
+    READ = 14,
+    WRITE = 15,
+    CLOSE = 16,
+    EXIT2 = 17,
+    TIME = 30,
+    SET_SEED = 40,
+    RANDOM_INT = 41,
+    RANDOM_INT_RANGE = 42,
+}
+
+const V0: usize = 2;
+const A0: usize = 4;
+const A1: usize = 5;
+const A2: usize = 6;
+
+/// SPIM/MARS compatible syscalls, the console goes through the reader and writer given to `with_io`
+pub struct SpimSyscalls<'io> {
+    input: Box<dyn BufRead + 'io>,
+    output: Box<dyn Write + 'io>,
+    /// First address past the heap, moved by sbrk
+    heap_end: u32,
+    files: HashMap<u32, File>,
+    next_descriptor: u32,
+    random_state: u64,
+}
+
+impl<'io> SpimSyscalls<'io> {
+    /// Where sbrk starts allocating, the same address MARS uses
+    pub const HEAP_START: u32 = 0x1004_0000;
+    /// The heap stops where the stack starts
+    pub const HEAP_LIMIT: u32 = ElfProgram::STACK_BOTTOM;
+
+    /// The read service moves at most this many bytes to guest memory at once
+    const READ_CHUNK: usize = 4096;
+
+    /// File descriptors that refer to the console
+    const STDIN: u32 = 0;
+    const STDOUT: u32 = 1;
+    const STDERR: u32 = 2;
+
+    /// Flags of the open service, MARS only knows these three
+    const OPEN_READ: u32 = 0;
+    const OPEN_WRITE: u32 = 1;
+    const OPEN_APPEND: u32 = 9;
+
+    /// Uses the standard input and output of the host
+    pub fn new() -> SpimSyscalls<'static> {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_nanos() as u64).unwrap_or(0);
+        // Reading a byte at a time leaves the rest of the input to the devices that share the console
+        let mut syscalls = SpimSyscalls::with_io(io::BufReader::with_capacity(1, host_io::Stdin), io::stdout());
+        syscalls.seed(seed);
+        return syscalls;
+    }
+
+    pub fn with_io(input: impl BufRead + 'io, output: impl Write + 'io) -> Self {
+        SpimSyscalls {
+            input: Box::new(input),
+            output: Box::new(output),
+            heap_end: SpimSyscalls::HEAP_START,
+            files: HashMap::new(),
+            next_descriptor: 3,
+            random_state: 1,
+        }
+    }
+
+    /// Makes the random services reproducible
+    pub fn seed(&mut self, seed: u64) {
+        // xorshift gets stuck on zero
+        self.random_state = seed | 1;
+    }
+
+    /// xorshift64*
+    fn next_random(&mut self) -> u32 {
+        self.random_state ^= self.random_state >> 12;
+        self.random_state ^= self.random_state << 25;
+        self.random_state ^= self.random_state >> 27;
+        return (self.random_state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32) as u32;
+    }
+
+    fn print(&mut self, text: &str) {
+        // The guest has no way to handle a broken console, like a real terminal it just loses the output
+        let _ = self.output.write_all(text.as_bytes());
+        let _ = self.output.flush();
+    }
+
+    fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        let _ = self.input.read_line(&mut line);
+        return line;
+    }
+
+    /// Reads a NUL terminated string out of guest memory
+    fn read_string(cpu: &CPU, address: u32) -> Result<Vec<u8>, CpuException> {
+        let mut bytes = vec![];
+        let mut address = address;
+        loop {
+            let [byte] = cpu.memory_mapper().get_byte(address).map_err(|e| CpuException::DataBusError(e.address))?;
+            if byte == 0 {
+                return Ok(bytes);
+            }
+            bytes.push(byte);
+            address = address.wrapping_add(1);
+        }
+    }
+
+    fn read_memory(cpu: &CPU, address: u32, length: u32) -> Result<Vec<u8>, CpuException> {
+        return (0..length).map(|i| {
+            let address = address.wrapping_add(i);
+            cpu.memory_mapper().get_byte(address).map(|[byte]| byte).map_err(|e| CpuException::DataBusError(e.address))
+        }).collect();
+    }
+
+    fn write_memory(cpu: &mut CPU, address: u32, bytes: &[u8]) -> Result<(), CpuException> {
+        for (i, byte) in bytes.iter().enumerate() {
+            let address = address.wrapping_add(i as u32);
+            cpu.memory_mapper_mut().write_byte(address, [*byte]).map_err(|e| CpuException::DataBusError(e.address))?;
+        }
+        return Ok(());
+    }
+
+    /// SPIM semantics: at most `length - 1` characters up to and including the newline, always NUL terminated
+    fn read_string_service(&mut self, cpu: &mut CPU) -> Result<(), CpuException> {
+        let (buffer, length) = (cpu.get_register_value(A0), cpu.get_register_value(A1));
+        if length == 0 {
+            return Ok(());
+        }
+        let mut bytes = self.read_line().into_bytes();
+        bytes.truncate(length as usize - 1);
+        bytes.push(0);
+        return SpimSyscalls::write_memory(cpu, buffer, &bytes);
+    }
+
+    /// Moves the break by $a0 bytes and returns the old one, the heap is mapped on demand. A break below `HEAP_START` or
+    /// past `HEAP_LIMIT` is refused with -1
+    fn sbrk(&mut self, cpu: &mut CPU) {
+        let increment = cpu.get_register_value(A0) as i32;
+        let old_end = self.heap_end;
+        // Keep the heap word aligned
+        let new_end = old_end.checked_add_signed(increment).and_then(|end| end.checked_next_multiple_of(4));
+        let Some(new_end) = new_end.filter(|end| (SpimSyscalls::HEAP_START..=SpimSyscalls::HEAP_LIMIT).contains(end)) else {
+            cpu.set_register_value(V0, -1_i32 as u32);
+            return;
+        };
+        if new_end > old_end {
+            cpu.memory_mapper_mut().map_ram(old_end, new_end - old_end);
+        }
+        self.heap_end = new_end;
+        cpu.set_register_value(V0, old_end);
+    }
+
+    fn open(&mut self, cpu: &mut CPU) -> Result<i32, CpuException> {
+        let name = SpimSyscalls::read_string(cpu, cpu.get_register_value(A0))?;
+        let name = String::from_utf8_lossy(&name).into_owned();
+        let mut options = OpenOptions::new();
+        match cpu.get_register_value(A1) {
+            SpimSyscalls::OPEN_READ => options.read(true),
+            SpimSyscalls::OPEN_WRITE => options.write(true).create(true).truncate(true),
+            SpimSyscalls::OPEN_APPEND => options.append(true).create(true),
+            _ => return Ok(-1),
+        };
+        return match options.open(name) {
+            Ok(file) => {
+                let descriptor = self.next_descriptor;
+                self.next_descriptor += 1;
+                self.files.insert(descriptor, file);
+                Ok(descriptor as i32)
+            },
+            Err(_) => Ok(-1),
+        };
+    }
+
+    /// Returns the number of bytes read, 0 at the end of the file and -1 on errors. The length comes from the guest, so
+    /// the bytes go to memory a chunk at a time as they arrive and a short read ends the call
+    fn read(&mut self, cpu: &mut CPU) -> Result<i32, CpuException> {
+        let (descriptor, buffer, length) = (cpu.get_register_value(A0), cpu.get_register_value(A1), cpu.get_register_value(A2));
+        if descriptor != SpimSyscalls::STDIN && !self.files.contains_key(&descriptor) {
+            return Ok(-1);
+        }
+        let mut chunk = [0; SpimSyscalls::READ_CHUNK];
+        let mut total: u32 = 0;
+        while total < length {
+            let wanted = (length - total).min(SpimSyscalls::READ_CHUNK as u32) as usize;
+            let read = match descriptor {
+                SpimSyscalls::STDIN => self.input.read(&mut chunk[..wanted]),
+                _ => match self.files.get_mut(&descriptor) {
+                    Some(file) => file.read(&mut chunk[..wanted]),
+                    None => return Ok(-1),
+                },
+            };
+            let Ok(read) = read else {
+                return Ok(-1);
+            };
+            SpimSyscalls::write_memory(cpu, buffer.wrapping_add(total), &chunk[..read])?;
+            total += read as u32;
+            if read < wanted {
+                break;
+            }
+        }
+        return Ok(total as i32);
+    }
+
+    fn write(&mut self, cpu: &mut CPU) -> Result<i32, CpuException> {
+        let (descriptor, buffer, length) = (cpu.get_register_value(A0), cpu.get_register_value(A1), cpu.get_register_value(A2));
+        let bytes = SpimSyscalls::read_memory(cpu, buffer, length)?;
+        let written = match descriptor {
+            SpimSyscalls::STDOUT | SpimSyscalls::STDERR => self.output.write_all(&bytes).and_then(|_| self.output.flush()),
+            _ => match self.files.get_mut(&descriptor) {
+                Some(file) => file.write_all(&bytes),
+                None => return Ok(-1),
+            },
+        };
+        return Ok(if written.is_ok() { length as i32 } else { -1 });
+    }
+}
+
+impl<'io> SyscallHandler for SpimSyscalls<'io> {
+    fn syscall(&mut self, cpu: &mut CPU) -> Result<(), CpuException> {
+        let service: Option<Service> = num::FromPrimitive::from_u32(cpu.get_register_value(V0));
+        let argument = cpu.get_register_value(A0);
+        match service {
+            Some(Service::PRINT_INT) => self.print(&(argument as i32).to_string()),
+            Some(Service::PRINT_FLOAT) => self.print(&format!("{:?}", cpu.fpu().read_single(12))),
+            Some(Service::PRINT_DOUBLE) => self.print(&format!("{:?}", cpu.fpu().read_double(12))),
+            Some(Service::PRINT_STRING) => {
+                let bytes = SpimSyscalls::read_string(cpu, argument)?;
+                self.print(&String::from_utf8_lossy(&bytes));
+            },
+            Some(Service::PRINT_CHAR) => self.print(&char::from(argument as u8).to_string()),
+            Some(Service::READ_INT) => {
+                let value = self.read_line().trim().parse::<i32>().unwrap_or(0);
+                cpu.set_register_value(V0, value as u32);
+            },
+            Some(Service::READ_FLOAT) => {
+                let value = self.read_line().trim().parse::<f32>().unwrap_or(0.0);
+                cpu.fpu_mut().write_single(0, value);
+            },
+            Some(Service::READ_DOUBLE) => {
+                let value = self.read_line().trim().parse::<f64>().unwrap_or(0.0);
+                cpu.fpu_mut().write_double(0, value);
+            },
+            Some(Service::READ_STRING) => self.read_string_service(cpu)?,
+            Some(Service::READ_CHAR) => {
+                let mut byte = [0];
+                let value = match self.input.read(&mut byte) {
+                    Ok(1) => byte[0] as u32,
+                    _ => 0,
+                };
+                cpu.set_register_value(V0, value);
+            },
+            Some(Service::SBRK) => self.sbrk(cpu),
+            Some(Service::EXIT) => cpu.exit(0),
+            Some(Service::EXIT2) => cpu.exit(argument as i32),
+            Some(Service::OPEN) => {
+                let descriptor = self.open(cpu)?;
+                cpu.set_register_value(V0, descriptor as u32);
+            },
+            Some(Service::READ) => {
+                let read = self.read(cpu)?;
+                cpu.set_register_value(V0, read as u32);
+            },
+            Some(Service::WRITE) => {
+                let written = self.write(cpu)?;
+                cpu.set_register_value(V0, written as u32);
+            },
+            Some(Service::CLOSE) => {
+                self.files.remove(&argument);
+            },
+            Some(Service::TIME) => {
+                let milliseconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_millis() as u64).unwrap_or(0);
+                cpu.set_register_value(A0, milliseconds as u32);
+                cpu.set_register_value(A1, (milliseconds >> 32) as u32);
+            },
+            Some(Service::SET_SEED) => self.seed(cpu.get_register_value(A1) as u64),
+            Some(Service::RANDOM_INT) => {
+                let value = self.next_random();
+                cpu.set_register_value(A0, value);
+            },
+            Some(Service::RANDOM_INT_RANGE) => {
+                let bound = cpu.get_register_value(A1);
+                if bound as i32 <= 0 {
+                    return Err(CpuException::Syscall);
+                }
+                let value = self.next_random() % bound;
+                cpu.set_register_value(A0, value);
+            },
+            None => return Err(CpuException::Syscall),
+        }
+        return Ok(());
+    }
+
+    /// The heap and the random generator, open files can't be carried over to another run
+    fn save_state(&self) -> Option<Vec<u8>> {
+        let mut state = self.heap_end.to_le_bytes().to_vec();
+        state.extend(self.random_state.to_le_bytes());
+        return Some(state);
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> io::Result<()> {
+        if state.len() != 12 {
+            return Err(snapshot::invalid_snapshot("invalid syscall handler state"));
+        }
+        self.heap_end = u32::from_le_bytes(state[..4].try_into().unwrap());
+        self.random_state = u64::from_le_bytes(state[4..].try_into().unwrap());
+        return Ok(());
+    }
+}
@@ -0,0 +1,178 @@
+use std::io;
+
+use crate::device::{self, Device};
+use crate::memory_mapper::MemoryMappable;
+use crate::snapshot;
+
+/// A programmable interval timer counting executed cycles, the CPU ticks it with one cycle per instruction.
+///
+/// The registers are big endian words: the count at offset 0, the compare value at offset 4 and the control register
+/// at offset 8. While enabled the count goes up by one every `divider` cycles, when it reaches the compare value the
+/// pending bit is set and the count either goes back to 0 (periodic mode) or stops there and disables the timer
+/// (one-shot mode). The interrupt output follows the pending bit when interrupts are enabled, writing 1 to the pending
+/// bit clears it
+pub struct TimerDevice {
+    count: u32,
+    compare: u32,
+    control: u32,
+    divider: u32,
+    /// Cycles since the count last went up
+    cycles: u32,
+}
+
+impl TimerDevice {
+    pub const CONTROL_ENABLE: u32 = 1 << 0;
+    pub const CONTROL_PERIODIC: u32 = 1 << 1;
+    pub const CONTROL_INTERRUPT_ENABLE: u32 = 1 << 2;
+    pub const CONTROL_PENDING: u32 = 1 << 3;
+
+    const COUNT: u32 = 0;
+    const COMPARE: u32 = 4;
+    const CONTROL: u32 = 8;
+
+    /// A stopped timer counting every cycle
+    pub fn new() -> Self {
+        return TimerDevice::with_divider(1);
+    }
+
+    /// A stopped timer counting every `divider` cycles, 0 is taken as 1
+    pub fn with_divider(divider: u32) -> Self {
+        TimerDevice { count: 0, compare: 0, control: 0, divider: divider.max(1), cycles: 0 }
+    }
+
+    fn read_register(&self, address: u32) -> u32 {
+        return match address & !3 {
+            TimerDevice::COUNT => self.count,
+            TimerDevice::COMPARE => self.compare,
+            TimerDevice::CONTROL => self.control,
+            _ => 0,
+        };
+    }
+
+    fn write_register(&mut self, address: u32, value: u32) {
+        match address & !3 {
+            TimerDevice::COUNT => {
+                self.count = value;
+                self.cycles = 0;
+            },
+            TimerDevice::COMPARE => self.compare = value,
+            TimerDevice::CONTROL => {
+                let pending = self.control & TimerDevice::CONTROL_PENDING & !value;
+                let writable = TimerDevice::CONTROL_ENABLE | TimerDevice::CONTROL_PERIODIC | TimerDevice::CONTROL_INTERRUPT_ENABLE;
+                self.control = value & writable | pending;
+            },
+            _ => {},
+        }
+    }
+
+    /// Merges a byte or half word write into the register it belongs to
+    fn write_bytes(&mut self, address: u32, bytes: &[u8]) {
+        let mut word = self.read_register(address);
+        if address & !3 == TimerDevice::CONTROL {
+            // Only a write to the byte with the pending bit acknowledges the interrupt
+            word &= !TimerDevice::CONTROL_PENDING;
+        }
+        self.write_register(address, device::merge_register(word, address, bytes));
+    }
+}
+
+impl Default for TimerDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryMappable for TimerDevice {
+    fn get_byte(&self, address: u32) -> [u8; 1] {
+        return [self.read_register(address).to_be_bytes()[(address & 3) as usize]];
+    }
+
+    fn get_half_word(&self, address: u32) -> [u8; 2] {
+        let word = self.read_register(address).to_be_bytes();
+        let offset = (address & 2) as usize;
+        return [word[offset], word[offset + 1]];
+    }
+
+    fn get_word(&self, address: u32) -> [u8; 4] {
+        return self.read_register(address).to_be_bytes();
+    }
+
+    fn write_byte(&mut self, address: u32, value: [u8; 1]) {
+        self.write_bytes(address, &value);
+    }
+
+    fn write_half_word(&mut self, address: u32, value: [u8; 2]) {
+        self.write_bytes(address, &value);
+    }
+
+    fn write_word(&mut self, address: u32, value: [u8; 4]) {
+        self.write_register(address, u32::from_be_bytes(value));
+    }
+
+    /// The count, compare, control, divider and cycle registers as little endian words
+    fn save_state(&self) -> Option<Vec<u8>> {
+        let registers = [self.count, self.compare, self.control, self.divider, self.cycles];
+        return Some(registers.iter().flat_map(|register| register.to_le_bytes()).collect());
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> io::Result<()> {
+        if state.len() != 20 {
+            return Err(snapshot::invalid_snapshot("invalid timer state"));
+        }
+        let word = |i: usize| u32::from_le_bytes(state[i * 4..i * 4 + 4].try_into().unwrap());
+        self.count = word(0);
+        self.compare = word(1);
+        self.control = word(2);
+        self.divider = word(3).max(1);
+        self.cycles = word(4);
+        return Ok(());
+    }
+}
+
+impl Device for TimerDevice {
+    fn name(&self) -> &str {
+        return "timer";
+    }
+
+    fn size(&self) -> u32 {
+        return 12;
+    }
+
+    fn reset(&mut self) {
+        *self = TimerDevice::with_divider(self.divider);
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        if self.control & TimerDevice::CONTROL_ENABLE == 0 {
+            return;
+        }
+        let cycles = self.cycles as u64 + cycles;
+        let mut steps = cycles / self.divider as u64;
+        self.cycles = (cycles % self.divider as u64) as u32;
+        while steps > 0 {
+            // A count equal to the compare value goes all the way around before matching again
+            let distance = match self.compare.wrapping_sub(self.count) {
+                0 => 1 << 32,
+                distance => distance as u64,
+            };
+            if steps < distance {
+                self.count = self.count.wrapping_add(steps as u32);
+                return;
+            }
+            steps -= distance;
+            self.control |= TimerDevice::CONTROL_PENDING;
+            if self.control & TimerDevice::CONTROL_PERIODIC == 0 {
+                self.count = self.compare;
+                self.control &= !TimerDevice::CONTROL_ENABLE;
+                self.cycles = 0;
+                return;
+            }
+            self.count = 0;
+        }
+    }
+
+    fn interrupt(&self) -> bool {
+        let pending = TimerDevice::CONTROL_PENDING | TimerDevice::CONTROL_INTERRUPT_ENABLE;
+        return self.control & pending == pending;
+    }
+}
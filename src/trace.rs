@@ -0,0 +1,398 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
+
+use crate::cpu::REGISTER_NAMES;
+use crate::disassembler::disassemble_with_symbols;
+use crate::exception::CpuException;
+use crate::memory_mapper::{MemoryAccess, WatchKind};
+use crate::symbol_table::SymbolTable;
+
+/// A register an instruction can write, FPU and COP0 registers aren't traced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TracedRegister {
+    Gpr(u8),
+    Hi,
+    Lo,
+}
+
+impl TracedRegister {
+    /// Numbering of the binary format, hi and lo follow the GPRs
+    fn id(&self) -> u8 {
+        return match self {
+            TracedRegister::Gpr(register) => *register,
+            TracedRegister::Hi => 32,
+            TracedRegister::Lo => 33,
+        };
+    }
+
+    fn from_id(id: u8) -> Option<TracedRegister> {
+        return match id {
+            0..=31 => Some(TracedRegister::Gpr(id)),
+            32 => Some(TracedRegister::Hi),
+            33 => Some(TracedRegister::Lo),
+            _ => None,
+        };
+    }
+}
+
+impl fmt::Display for TracedRegister {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TracedRegister::Gpr(register) => write!(f, "${}", REGISTER_NAMES[*register as usize]),
+            TracedRegister::Hi => write!(f, "hi"),
+            TracedRegister::Lo => write!(f, "lo"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterChange {
+    pub register: TracedRegister,
+    pub old_value: u32,
+    pub new_value: u32,
+}
+
+/// What one instruction did, memory values are in the byte order of the CPU
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// Number of instructions executed before this one since the tracer was installed
+    pub index: u64,
+    pub pc: u32,
+    /// `None` when the fetch itself failed
+    pub word: Option<u32>,
+    pub registers: Vec<RegisterChange>,
+    pub memory: Vec<MemoryAccess>,
+    pub exception: Option<CpuException>,
+}
+
+impl TraceRecord {
+    pub fn disassembly(&self, symbols: &SymbolTable) -> String {
+        return match self.word {
+            Some(word) => disassemble_with_symbols(word, self.pc, symbols),
+            None => "<unmapped>".to_owned(),
+        };
+    }
+}
+
+/// Where the records of a tracer go
+pub trait TraceSink {
+    fn write(&mut self, record: &TraceRecord) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Selects the instructions that are traced, the default traces everything
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    /// Only instructions whose pc is in one of the ranges, any pc when empty
+    pub ranges: Vec<RangeInclusive<u32>>,
+    /// Index of the first instruction traced
+    pub first: u64,
+    /// How many instructions are traced from `first` on, without a limit when `None`
+    pub count: Option<u64>,
+}
+
+impl TraceFilter {
+    fn accepts(&self, index: u64, pc: u32) -> bool {
+        let in_window = index >= self.first && self.count.is_none_or(|count| index - self.first < count);
+        return in_window && (self.ranges.is_empty() || self.ranges.iter().any(|range| range.contains(&pc)));
+    }
+}
+
+/// Installed with `CPU::set_tracer`, it hands a record of every instruction the filter accepts to its sink
+pub struct Tracer<'a> {
+    sink: Box<dyn TraceSink + 'a>,
+    filter: TraceFilter,
+    executed: u64,
+    /// The first error of the sink, nothing is written after it
+    error: Option<io::Error>,
+}
+
+impl<'a> Tracer<'a> {
+    pub fn new(sink: impl TraceSink + 'a) -> Self {
+        return Tracer::with_filter(sink, TraceFilter::default());
+    }
+
+    pub fn with_filter(sink: impl TraceSink + 'a, filter: TraceFilter) -> Self {
+        Tracer { sink: Box::new(sink), filter, executed: 0, error: None }
+    }
+
+    /// Index of the next instruction
+    pub fn executed(&self) -> u64 {
+        return self.executed;
+    }
+
+    /// Whether the next instruction, at `pc`, will be recorded
+    pub fn wants(&self, pc: u32) -> bool {
+        return self.error.is_none() && self.filter.accepts(self.executed, pc);
+    }
+
+    /// Called once per instruction executed, with its record when `wants` asked for one
+    pub fn trace(&mut self, record: Option<&TraceRecord>) {
+        self.executed += 1;
+        if let (Some(record), None) = (record, &self.error) {
+            self.error = self.sink.write(record).err();
+        }
+    }
+
+    /// Flushes the sink and reports the first error it returned
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        return self.sink.flush();
+    }
+}
+
+/// One line per instruction: `pc: word  disassembly` followed by what changed
+pub struct TextSink<W: Write> {
+    output: W,
+    symbols: SymbolTable,
+}
+
+impl<W: Write> TextSink<W> {
+    pub fn new(output: W) -> Self {
+        return TextSink::with_symbols(output, SymbolTable::new());
+    }
+
+    /// Branch targets are shown with the symbols of the program
+    pub fn with_symbols(output: W, symbols: SymbolTable) -> Self {
+        TextSink { output, symbols }
+    }
+}
+
+impl<W: Write> TraceSink for TextSink<W> {
+    fn write(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let word = record.word.map(|word| format!("{:08x}", word)).unwrap_or("????????".to_owned());
+        let mut effects: Vec<String> = record.registers.iter()
+            .map(|change| format!("{} {:08x} -> {:08x}", change.register, change.old_value, change.new_value))
+            .collect();
+        effects.extend(record.memory.iter().map(|access| match access.kind {
+            WatchKind::Write => format!("[{:08x}]{} {:0width$x} -> {:0width$x}", access.address, access.size, access.old_value, access.new_value, width = access.size as usize * 2),
+            _ => format!("[{:08x}]{} read {:0width$x}", access.address, access.size, access.new_value, width = access.size as usize * 2),
+        }));
+        if let Some(exception) = record.exception {
+            effects.push(format!("exception: {}", exception));
+        }
+        let line = format!("{:08x}: {}  {}", record.pc, word, record.disassembly(&self.symbols));
+        return match effects.is_empty() {
+            true => writeln!(self.output, "{}", line),
+            false => writeln!(self.output, "{:<48} ; {}", line, effects.join(", ")),
+        };
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.output.flush();
+    }
+}
+
+/// One JSON object per line, numbers are unsigned integers
+pub struct JsonLinesSink<W: Write> {
+    output: W,
+}
+
+impl<W: Write> JsonLinesSink<W> {
+    pub fn new(output: W) -> Self {
+        JsonLinesSink { output }
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut escaped = String::from("\"");
+    for character in text.chars() {
+        match character {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            character if (character as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", character as u32)),
+            character => escaped.push(character),
+        }
+    }
+    escaped.push('"');
+    return escaped;
+}
+
+impl<W: Write> TraceSink for JsonLinesSink<W> {
+    fn write(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let registers: Vec<String> = record.registers.iter().map(|change| {
+            format!("{{\"register\":{},\"old\":{},\"new\":{}}}", json_string(&change.register.to_string()), change.old_value, change.new_value)
+        }).collect();
+        let memory: Vec<String> = record.memory.iter().map(|access| {
+            let kind = if access.kind == WatchKind::Write { "write" } else { "read" };
+            format!("{{\"kind\":\"{}\",\"address\":{},\"size\":{},\"old\":{},\"new\":{}}}", kind, access.address, access.size, access.old_value, access.new_value)
+        }).collect();
+        let word = record.word.map(|word| word.to_string()).unwrap_or("null".to_owned());
+        let exception = record.exception.map(|exception| json_string(&exception.to_string())).unwrap_or("null".to_owned());
+        return writeln!(
+            self.output,
+            "{{\"index\":{},\"pc\":{},\"word\":{},\"disassembly\":{},\"registers\":[{}],\"memory\":[{}],\"exception\":{}}}",
+            record.index, record.pc, word, json_string(&record.disassembly(&SymbolTable::new())), registers.join(","), memory.join(","), exception,
+        );
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.output.flush();
+    }
+}
+
+/// Little endian records after an 8 byte header, `read_binary_trace` decodes them.
+///
+/// A record is the index (u64), pc (u32), word (u32), a flags byte (bit 0: the word is valid, bit 1: an exception
+/// follows), the number of register changes (u8) and of memory accesses (u32, syscalls log every byte they touch). Then
+/// come the register changes as the register (u8, 32 is hi and 33 lo), old and new values (u32), the accesses as a byte
+/// holding the size with bit 7 set for writes, the address, old and new values (u32), and last the exception as its
+/// ExcCode (u8) and payload (u32)
+pub struct BinarySink<W: Write> {
+    output: W,
+    header_written: bool,
+}
+
+impl<W: Write> BinarySink<W> {
+    pub const MAGIC: [u8; 7] = *b"VM32TRC";
+    pub const VERSION: u8 = 2;
+
+    pub fn new(output: W) -> Self {
+        BinarySink { output, header_written: false }
+    }
+}
+
+const FLAG_WORD: u8 = 1 << 0;
+const FLAG_EXCEPTION: u8 = 1 << 1;
+const ACCESS_WRITE: u8 = 1 << 7;
+
+/// The value carried by an exception, so that it can be rebuilt from its ExcCode
+fn exception_payload(exception: CpuException) -> u32 {
+    return match exception {
+        CpuException::ReservedInstruction(instruction) => instruction,
+        CpuException::CoprocessorUnusable(coprocessor) => coprocessor as u32,
+        CpuException::InstructionBusError(address) | CpuException::DataBusError(address) => address,
+        exception => exception.bad_address().unwrap_or(0),
+    };
+}
+
+fn exception_from_code(code: u8, payload: u32) -> Option<CpuException> {
+    return match code {
+        4 => Some(CpuException::AddressErrorLoad(payload)),
+        5 => Some(CpuException::AddressErrorStore(payload)),
+        6 => Some(CpuException::InstructionBusError(payload)),
+        7 => Some(CpuException::DataBusError(payload)),
+        8 => Some(CpuException::Syscall),
+        9 => Some(CpuException::Breakpoint),
+        10 => Some(CpuException::ReservedInstruction(payload)),
+        11 => Some(CpuException::CoprocessorUnusable(payload as u8)),
+        12 => Some(CpuException::Overflow),
+        15 => Some(CpuException::FloatingPoint),
+        _ => None,
+    };
+}
+
+impl<W: Write> TraceSink for BinarySink<W> {
+    fn write(&mut self, record: &TraceRecord) -> io::Result<()> {
+        if !self.header_written {
+            self.output.write_all(&BinarySink::<W>::MAGIC)?;
+            self.output.write_all(&[BinarySink::<W>::VERSION])?;
+            self.header_written = true;
+        }
+        let mut bytes = vec![];
+        bytes.extend(record.index.to_le_bytes());
+        bytes.extend(record.pc.to_le_bytes());
+        bytes.extend(record.word.unwrap_or(0).to_le_bytes());
+        let flags = if record.word.is_some() { FLAG_WORD } else { 0 } | if record.exception.is_some() { FLAG_EXCEPTION } else { 0 };
+        bytes.extend([flags, record.registers.len() as u8]);
+        bytes.extend((record.memory.len() as u32).to_le_bytes());
+        for change in &record.registers {
+            bytes.push(change.register.id());
+            bytes.extend(change.old_value.to_le_bytes());
+            bytes.extend(change.new_value.to_le_bytes());
+        }
+        for access in &record.memory {
+            bytes.push(access.size as u8 | if access.kind == WatchKind::Write { ACCESS_WRITE } else { 0 });
+            bytes.extend(access.address.to_le_bytes());
+            bytes.extend(access.old_value.to_le_bytes());
+            bytes.extend(access.new_value.to_le_bytes());
+        }
+        if let Some(exception) = record.exception {
+            bytes.push(exception.exc_code() as u8);
+            bytes.extend(exception_payload(exception).to_le_bytes());
+        }
+        return self.output.write_all(&bytes);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.output.flush();
+    }
+}
+
+fn invalid_trace(message: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, message.to_owned());
+}
+
+/// Reads the little endian fields of a binary trace
+struct Decoder<'b> {
+    bytes: &'b [u8],
+    position: usize,
+}
+
+impl<'b> Decoder<'b> {
+    fn take(&mut self, length: usize) -> io::Result<&'b [u8]> {
+        let field = self.bytes.get(self.position..self.position + length).ok_or_else(|| invalid_trace("the trace is truncated"))?;
+        self.position += length;
+        return Ok(field);
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        return Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()));
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        return Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()));
+    }
+
+    fn at_end(&self) -> bool {
+        return self.position == self.bytes.len();
+    }
+}
+
+/// Decodes what a `BinarySink` wrote
+pub fn read_binary_trace(mut input: impl Read) -> io::Result<Vec<TraceRecord>> {
+    let mut bytes = vec![];
+    input.read_to_end(&mut bytes)?;
+    // Nothing is written before the first record
+    if bytes.is_empty() {
+        return Ok(vec![]);
+    }
+    if bytes.len() < 8 || bytes[..7] != BinarySink::<io::Sink>::MAGIC {
+        return Err(invalid_trace("not a vm32bits trace"));
+    }
+    if bytes[7] != BinarySink::<io::Sink>::VERSION {
+        return Err(invalid_trace("unsupported trace version"));
+    }
+
+    let mut decoder = Decoder { bytes: &bytes, position: 8 };
+    let mut records = vec![];
+    while !decoder.at_end() {
+        let (index, pc, word) = (decoder.u64()?, decoder.u32()?, decoder.u32()?);
+        let (flags, register_count, access_count) = (decoder.u8()?, decoder.u8()?, decoder.u32()?);
+        let mut record = TraceRecord { index, pc, word: (flags & FLAG_WORD != 0).then_some(word), registers: vec![], memory: vec![], exception: None };
+        for _ in 0..register_count {
+            let register = TracedRegister::from_id(decoder.u8()?).ok_or_else(|| invalid_trace("invalid register"))?;
+            record.registers.push(RegisterChange { register, old_value: decoder.u32()?, new_value: decoder.u32()? });
+        }
+        for _ in 0..access_count {
+            let kind_and_size = decoder.u8()?;
+            let kind = if kind_and_size & ACCESS_WRITE != 0 { WatchKind::Write } else { WatchKind::Read };
+            let (address, old_value, new_value) = (decoder.u32()?, decoder.u32()?, decoder.u32()?);
+            record.memory.push(MemoryAccess { kind, address, size: (kind_and_size & !ACCESS_WRITE) as u32, old_value, new_value });
+        }
+        if flags & FLAG_EXCEPTION != 0 {
+            let (code, payload) = (decoder.u8()?, decoder.u32()?);
+            record.exception = Some(exception_from_code(code, payload).ok_or_else(|| invalid_trace("invalid exception"))?);
+        }
+        records.push(record);
+    }
+    return Ok(records);
+}
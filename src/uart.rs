@@ -0,0 +1,662 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+#[cfg(unix)]
+use std::ffi::CStr;
+#[cfg(unix)]
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::device::Device;
+use crate::host_io;
+#[cfg(unix)]
+use crate::host_io::RawMode;
+use crate::memory_mapper::MemoryMappable;
+use crate::snapshot;
+
+/// The other end of the serial line of a UART
+pub trait SerialBackend {
+    /// The next byte sent to the guest, `None` when there is nothing to read yet
+    fn receive(&mut self) -> Option<u8>;
+
+    /// What the guest transmitted
+    fn transmit(&mut self, bytes: &[u8]);
+
+    /// The guest accessed the UART for the first time, nothing is received before
+    fn open(&mut self) {}
+}
+
+/// A UART with the registers of a 16550: byte registers at offsets 0 to 7 with the receive and transmit FIFOs, the
+/// divisor latch behind DLAB, loopback and the four interrupt sources.
+///
+/// Half word and word accesses reach the register at their address with its value in the least significant byte.
+/// Transmitted bytes go to the backend after the instruction, received bytes are taken from it while there's room in
+/// the receive FIFO, starting once the guest has accessed the UART so that a UART the guest doesn't use leaves the
+/// console to the syscalls. The baud rate and the line format are only stored, bytes move a FIFO at a time. Received data
+/// left under the trigger level for a whole instruction raises the character timeout
+pub struct UartDevice {
+    backend: Box<dyn SerialBackend>,
+    /// Popped by reads of RBR
+    receive: RefCell<VecDeque<u8>>,
+    transmit: VecDeque<u8>,
+    interrupt_enable: u8,
+    line_control: u8,
+    modem_control: u8,
+    scratch: u8,
+    divisor: u16,
+    /// The FIFO enable and trigger level bits of FCR
+    fifo_control: u8,
+    /// Cleared by reading LSR
+    overrun: Cell<bool>,
+    /// The transmitter emptied, cleared by writing THR or by reading IIR while it's the interrupt reported
+    transmit_empty: Cell<bool>,
+    /// Received data waited under the trigger level for an instruction, cleared by reading RBR
+    timeout: Cell<bool>,
+    /// Set by the first access of the guest
+    accessed: Cell<bool>,
+    /// The backend has been told about that access
+    open: bool,
+}
+
+impl UartDevice {
+    pub const RBR: u32 = 0;
+    pub const THR: u32 = 0;
+    pub const IER: u32 = 1;
+    pub const IIR: u32 = 2;
+    pub const FCR: u32 = 2;
+    pub const LCR: u32 = 3;
+    pub const MCR: u32 = 4;
+    pub const LSR: u32 = 5;
+    pub const MSR: u32 = 6;
+    pub const SCR: u32 = 7;
+
+    pub const IER_RECEIVED_DATA: u8 = 1 << 0;
+    pub const IER_TRANSMIT_EMPTY: u8 = 1 << 1;
+    pub const IER_LINE_STATUS: u8 = 1 << 2;
+
+    pub const IIR_NONE: u8 = 0x01;
+    pub const IIR_TRANSMIT_EMPTY: u8 = 0x02;
+    pub const IIR_RECEIVED_DATA: u8 = 0x04;
+    pub const IIR_LINE_STATUS: u8 = 0x06;
+    pub const IIR_TIMEOUT: u8 = 0x0c;
+    pub const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+    pub const FCR_ENABLE: u8 = 1 << 0;
+    pub const FCR_CLEAR_RECEIVE: u8 = 1 << 1;
+    pub const FCR_CLEAR_TRANSMIT: u8 = 1 << 2;
+
+    pub const LCR_DLAB: u8 = 1 << 7;
+    pub const MCR_LOOPBACK: u8 = 1 << 4;
+
+    pub const LSR_DATA_READY: u8 = 1 << 0;
+    pub const LSR_OVERRUN: u8 = 1 << 1;
+    pub const LSR_THR_EMPTY: u8 = 1 << 5;
+    pub const LSR_TRANSMITTER_EMPTY: u8 = 1 << 6;
+
+    pub const FIFO_SIZE: usize = 16;
+
+    /// CTS, DSR and DCD, the other end is always there
+    const MODEM_STATUS: u8 = 0xb0;
+
+    pub fn new(backend: Box<dyn SerialBackend>) -> Self {
+        UartDevice {
+            backend,
+            receive: RefCell::new(VecDeque::new()),
+            transmit: VecDeque::new(),
+            interrupt_enable: 0,
+            line_control: 0,
+            modem_control: 0,
+            scratch: 0,
+            divisor: 0,
+            fifo_control: 0,
+            overrun: Cell::new(false),
+            transmit_empty: Cell::new(false),
+            timeout: Cell::new(false),
+            accessed: Cell::new(false),
+            open: false,
+        }
+    }
+
+    fn fifo_enabled(&self) -> bool {
+        return self.fifo_control & UartDevice::FCR_ENABLE != 0;
+    }
+
+    /// Without FIFOs the receiver and the transmitter hold one byte
+    fn capacity(&self) -> usize {
+        return if self.fifo_enabled() { UartDevice::FIFO_SIZE } else { 1 };
+    }
+
+    fn trigger_level(&self) -> usize {
+        if !self.fifo_enabled() {
+            return 1;
+        }
+        return [1, 4, 8, 14][(self.fifo_control >> 6) as usize];
+    }
+
+    fn divisor_latch(&self) -> bool {
+        return self.line_control & UartDevice::LCR_DLAB != 0;
+    }
+
+    fn loopback(&self) -> bool {
+        return self.modem_control & UartDevice::MCR_LOOPBACK != 0;
+    }
+
+    /// The pending interrupt with the highest priority, as IIR reports it without the FIFO bits
+    fn interrupt_id(&self) -> u8 {
+        let receive = self.receive.borrow();
+        if self.interrupt_enable & UartDevice::IER_LINE_STATUS != 0 && self.overrun.get() {
+            return UartDevice::IIR_LINE_STATUS;
+        }
+        if self.interrupt_enable & UartDevice::IER_RECEIVED_DATA != 0 {
+            if receive.len() >= self.trigger_level() {
+                return UartDevice::IIR_RECEIVED_DATA;
+            }
+            if self.timeout.get() && !receive.is_empty() {
+                return UartDevice::IIR_TIMEOUT;
+            }
+        }
+        if self.interrupt_enable & UartDevice::IER_TRANSMIT_EMPTY != 0 && self.transmit_empty.get() && self.transmit.is_empty() {
+            return UartDevice::IIR_TRANSMIT_EMPTY;
+        }
+        return UartDevice::IIR_NONE;
+    }
+
+    fn line_status(&self) -> u8 {
+        let mut status = 0;
+        if !self.receive.borrow().is_empty() {
+            status |= UartDevice::LSR_DATA_READY;
+        }
+        if self.overrun.get() {
+            status |= UartDevice::LSR_OVERRUN;
+        }
+        if self.transmit.is_empty() {
+            status |= UartDevice::LSR_THR_EMPTY | UartDevice::LSR_TRANSMITTER_EMPTY;
+        }
+        return status;
+    }
+
+    /// In loopback the modem control outputs come back as the modem status inputs
+    fn modem_status(&self) -> u8 {
+        if !self.loopback() {
+            return UartDevice::MODEM_STATUS;
+        }
+        let control = self.modem_control;
+        return ((control & 0x01) << 5) | ((control & 0x02) << 3) | ((control & 0x0c) << 4);
+    }
+
+    /// The value of a register, without the side effects of reading it
+    fn register(&self, address: u32) -> u8 {
+        return match address & 7 {
+            UartDevice::RBR if self.divisor_latch() => self.divisor as u8,
+            UartDevice::RBR => self.receive.borrow().front().copied().unwrap_or(0),
+            UartDevice::IER if self.divisor_latch() => (self.divisor >> 8) as u8,
+            UartDevice::IER => self.interrupt_enable,
+            UartDevice::IIR => {
+                let id = self.interrupt_id();
+                if self.fifo_enabled() { id | UartDevice::IIR_FIFO_ENABLED } else { id }
+            },
+            UartDevice::LCR => self.line_control,
+            UartDevice::MCR => self.modem_control,
+            UartDevice::LSR => self.line_status(),
+            UartDevice::MSR => self.modem_status(),
+            _ => self.scratch,
+        };
+    }
+
+    fn read_register(&self, address: u32) -> u8 {
+        self.accessed.set(true);
+        let value = self.register(address);
+        match address & 7 {
+            UartDevice::RBR if !self.divisor_latch() => {
+                self.timeout.set(false);
+                self.receive.borrow_mut().pop_front();
+            },
+            UartDevice::IIR if value & !UartDevice::IIR_FIFO_ENABLED == UartDevice::IIR_TRANSMIT_EMPTY => self.transmit_empty.set(false),
+            UartDevice::LSR => self.overrun.set(false),
+            _ => {},
+        }
+        return value;
+    }
+
+    fn write_register(&mut self, address: u32, value: u8) {
+        self.accessed.set(true);
+        match address & 7 {
+            UartDevice::THR if self.divisor_latch() => self.divisor = (self.divisor & 0xff00) | value as u16,
+            UartDevice::THR => {
+                if self.transmit.len() < self.capacity() {
+                    self.transmit.push_back(value);
+                }
+                self.transmit_empty.set(false);
+            },
+            UartDevice::IER if self.divisor_latch() => self.divisor = (self.divisor & 0x00ff) | (value as u16) << 8,
+            UartDevice::IER => {
+                let enabling = value & !self.interrupt_enable & UartDevice::IER_TRANSMIT_EMPTY != 0;
+                self.interrupt_enable = value & 0x0f;
+                // Enabling the interrupt while the transmitter is empty raises it right away
+                if enabling && self.transmit.is_empty() {
+                    self.transmit_empty.set(true);
+                }
+            },
+            UartDevice::FCR => {
+                if (value ^ self.fifo_control) & UartDevice::FCR_ENABLE != 0 || value & UartDevice::FCR_CLEAR_RECEIVE != 0 {
+                    self.receive.borrow_mut().clear();
+                    self.timeout.set(false);
+                }
+                if (value ^ self.fifo_control) & UartDevice::FCR_ENABLE != 0 || value & UartDevice::FCR_CLEAR_TRANSMIT != 0 {
+                    self.transmit.clear();
+                }
+                self.fifo_control = value & 0xc1;
+            },
+            UartDevice::LCR => self.line_control = value,
+            UartDevice::MCR => self.modem_control = value & 0x1f,
+            UartDevice::SCR => self.scratch = value,
+            _ => {},
+        }
+    }
+
+    /// Adds a byte to the receive FIFO, it's lost when the FIFO is full
+    fn push_received(&mut self, byte: u8) {
+        let capacity = self.capacity();
+        let mut receive = self.receive.borrow_mut();
+        if receive.len() < capacity {
+            receive.push_back(byte);
+        } else {
+            self.overrun.set(true);
+        }
+    }
+}
+
+impl MemoryMappable for UartDevice {
+    fn get_byte(&self, address: u32) -> [u8; 1] {
+        return [self.read_register(address)];
+    }
+
+    fn get_half_word(&self, address: u32) -> [u8; 2] {
+        return (self.read_register(address) as u16).to_be_bytes();
+    }
+
+    fn get_word(&self, address: u32) -> [u8; 4] {
+        return (self.read_register(address) as u32).to_be_bytes();
+    }
+
+    fn peek(&self, address: u32) -> u8 {
+        return self.register(address);
+    }
+
+    fn write_byte(&mut self, address: u32, value: [u8; 1]) {
+        self.write_register(address, value[0]);
+    }
+
+    fn write_half_word(&mut self, address: u32, value: [u8; 2]) {
+        self.write_register(address, value[1]);
+    }
+
+    fn write_word(&mut self, address: u32, value: [u8; 4]) {
+        self.write_register(address, value[3]);
+    }
+
+    /// The registers then the content of the receive FIFO, the transmit FIFO is always empty between two
+    /// instructions
+    fn save_state(&self) -> Option<Vec<u8>> {
+        let [divisor_low, divisor_high] = self.divisor.to_le_bytes();
+        let mut state = vec![
+            self.interrupt_enable, self.line_control, self.modem_control, self.scratch, divisor_low, divisor_high,
+            self.fifo_control, self.overrun.get() as u8, self.transmit_empty.get() as u8, self.timeout.get() as u8,
+        ];
+        state.extend(self.receive.borrow().iter());
+        return Some(state);
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> io::Result<()> {
+        if state.len() < 10 || state.len() > 10 + UartDevice::FIFO_SIZE {
+            return Err(snapshot::invalid_snapshot("invalid UART state"));
+        }
+        self.interrupt_enable = state[0];
+        self.line_control = state[1];
+        self.modem_control = state[2];
+        self.scratch = state[3];
+        self.divisor = u16::from_le_bytes([state[4], state[5]]);
+        self.fifo_control = state[6];
+        self.overrun.set(state[7] != 0);
+        self.transmit_empty.set(state[8] != 0);
+        self.timeout.set(state[9] != 0);
+        *self.receive.borrow_mut() = state[10..].iter().copied().collect();
+        self.transmit.clear();
+        return Ok(());
+    }
+}
+
+impl Device for UartDevice {
+    fn name(&self) -> &str {
+        return "uart";
+    }
+
+    fn size(&self) -> u32 {
+        return 8;
+    }
+
+    fn reset(&mut self) {
+        self.receive.borrow_mut().clear();
+        self.transmit.clear();
+        self.interrupt_enable = 0;
+        self.line_control = 0;
+        self.modem_control = 0;
+        self.scratch = 0;
+        self.divisor = 0;
+        self.fifo_control = 0;
+        self.overrun.set(false);
+        self.transmit_empty.set(false);
+        self.timeout.set(false);
+    }
+
+    fn tick(&mut self, _cycles: u64) {
+        let mut received = false;
+        if !self.transmit.is_empty() {
+            let bytes: Vec<u8> = self.transmit.drain(..).collect();
+            if self.loopback() {
+                for byte in bytes {
+                    self.push_received(byte);
+                }
+                received = true;
+            } else {
+                self.backend.transmit(&bytes);
+            }
+            self.transmit_empty.set(true);
+        }
+        if self.accessed.get() && !self.open {
+            self.backend.open();
+            self.open = true;
+        }
+        if self.open && !self.loopback() {
+            while self.receive.borrow().len() < self.capacity() {
+                let Some(byte) = self.backend.receive() else {
+                    break;
+                };
+                self.receive.borrow_mut().push_back(byte);
+                received = true;
+            }
+        }
+        self.timeout.set(!received && !self.receive.borrow().is_empty());
+    }
+
+    fn interrupt(&self) -> bool {
+        return self.interrupt_id() != UartDevice::IIR_NONE;
+    }
+}
+
+/// A serial line kept in memory, clones share the same buffers so tests can type input and read the output of a
+/// UART they have handed over
+#[derive(Clone, Default)]
+pub struct MemorySerial {
+    input: Rc<RefCell<VecDeque<u8>>>,
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+impl MemorySerial {
+    pub fn new() -> Self {
+        return MemorySerial::default();
+    }
+
+    /// Queues bytes for the guest to receive
+    pub fn send(&self, bytes: &[u8]) {
+        self.input.borrow_mut().extend(bytes);
+    }
+
+    /// Everything the guest transmitted so far
+    pub fn output(&self) -> Vec<u8> {
+        return self.output.borrow().clone();
+    }
+
+    pub fn take_output(&self) -> Vec<u8> {
+        return self.output.take();
+    }
+}
+
+impl SerialBackend for MemorySerial {
+    fn receive(&mut self) -> Option<u8> {
+        return self.input.borrow_mut().pop_front();
+    }
+
+    fn transmit(&mut self, bytes: &[u8]) {
+        self.output.borrow_mut().extend(bytes);
+    }
+}
+
+/// The host's standard input and output, a terminal is put in raw mode from the first access of the guest until the
+/// backend is dropped. Standard input is shared with the console syscalls and the debugger, whoever reads first gets
+/// the bytes
+pub struct StdioSerial {
+    #[cfg(unix)]
+    _raw_mode: Option<RawMode>,
+}
+
+impl StdioSerial {
+    pub fn new() -> Self {
+        StdioSerial {
+            #[cfg(unix)]
+            _raw_mode: None,
+        }
+    }
+}
+
+impl Default for StdioSerial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SerialBackend for StdioSerial {
+    fn receive(&mut self) -> Option<u8> {
+        return host_io::poll_stdin();
+    }
+
+    fn transmit(&mut self, bytes: &[u8]) {
+        let mut stdout = io::stdout().lock();
+        let _ = stdout.write_all(bytes).and_then(|_| stdout.flush());
+    }
+
+    fn open(&mut self) {
+        #[cfg(unix)]
+        {
+            self._raw_mode = RawMode::enable();
+        }
+    }
+}
+
+/// A non-blocking host stream, the host is only polled every few calls and what it can't take yet is kept for later
+struct HostStream<S: Read + Write> {
+    stream: S,
+    input: VecDeque<u8>,
+    output: VecDeque<u8>,
+    /// Calls to `receive` until the host is polled again
+    countdown: u32,
+    closed: bool,
+}
+
+impl<S: Read + Write> HostStream<S> {
+    /// Polling the host is a system call, once every this many instructions is plenty for a terminal
+    const POLL_INTERVAL: u32 = 256;
+    /// Output past this is lost, as it would be on a serial line without flow control
+    const MAX_OUTPUT: usize = 1 << 16;
+
+    fn new(stream: S) -> Self {
+        HostStream { stream, input: VecDeque::new(), output: VecDeque::new(), countdown: 0, closed: false }
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        if self.input.is_empty() {
+            if self.countdown > 0 {
+                self.countdown -= 1;
+                return None;
+            }
+            self.countdown = HostStream::<S>::POLL_INTERVAL;
+            self.flush();
+            let mut buffer = [0; 256];
+            match self.stream.read(&mut buffer) {
+                Ok(0) => self.closed = true,
+                Ok(length) => self.input.extend(&buffer[..length]),
+                Err(error) if error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::Interrupted => {},
+                Err(_) => self.closed = true,
+            }
+        }
+        return self.input.pop_front();
+    }
+
+    fn transmit(&mut self, bytes: &[u8]) {
+        let room = HostStream::<S>::MAX_OUTPUT.saturating_sub(self.output.len());
+        self.output.extend(&bytes[..bytes.len().min(room)]);
+        self.flush();
+    }
+
+    fn flush(&mut self) {
+        while !self.output.is_empty() {
+            let (front, _) = self.output.as_slices();
+            match self.stream.write(front) {
+                Ok(0) => return,
+                Ok(length) => {
+                    self.output.drain(..length);
+                },
+                Err(error) if error.kind() == ErrorKind::Interrupted => {},
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return,
+                Err(_) => {
+                    self.closed = true;
+                    self.output.clear();
+                    return;
+                },
+            }
+        }
+    }
+}
+
+/// A pseudo terminal, terminal programs such as `screen` or `minicom` connect to the path of its slave side
+#[cfg(unix)]
+pub struct PtySerial {
+    master: HostStream<File>,
+    /// Kept open so that reading the master doesn't fail before a terminal program connects
+    _slave: File,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl PtySerial {
+    pub fn open() -> io::Result<Self> {
+        // SAFETY: the file descriptor returned by posix_openpt is owned by the File, ptsname returns a C string that
+        // is copied before any other call
+        let (master, path) = unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let master = File::from_raw_fd(fd);
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let name = libc::ptsname(fd);
+            if name.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            (master, PathBuf::from(CStr::from_ptr(name).to_string_lossy().into_owned()))
+        };
+        let slave = OpenOptions::new().read(true).write(true).custom_flags(libc::O_NOCTTY).open(&path)?;
+        // Without raw mode the slave would echo the output of the guest back to it
+        // SAFETY: termios is plain data that tcgetattr fills in
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        return Ok(PtySerial { master: HostStream::new(master), _slave: slave, path });
+    }
+
+    /// The path of the slave side, like /dev/pts/3
+    pub fn path(&self) -> &Path {
+        return &self.path;
+    }
+}
+
+#[cfg(unix)]
+impl SerialBackend for PtySerial {
+    fn receive(&mut self) -> Option<u8> {
+        return self.master.receive();
+    }
+
+    fn transmit(&mut self, bytes: &[u8]) {
+        self.master.transmit(bytes);
+    }
+}
+
+/// A Unix socket the serial line is reached through, for example with `socat - UNIX-CONNECT:<path>`.
+///
+/// One client is connected at a time, what the guest transmits while there is none is lost. The socket file is
+/// removed when the backend is dropped
+#[cfg(unix)]
+pub struct UnixSocketSerial {
+    listener: UnixListener,
+    path: PathBuf,
+    client: Option<HostStream<UnixStream>>,
+    /// Calls to `receive` until a new client is looked for
+    countdown: u32,
+}
+
+#[cfg(unix)]
+impl UnixSocketSerial {
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        return Ok(UnixSocketSerial { listener, path: path.to_owned(), client: None, countdown: 0 });
+    }
+
+    pub fn path(&self) -> &Path {
+        return &self.path;
+    }
+
+    fn accept(&mut self) {
+        if self.countdown > 0 {
+            self.countdown -= 1;
+            return;
+        }
+        self.countdown = HostStream::<UnixStream>::POLL_INTERVAL;
+        if let Ok((stream, _)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_ok() {
+                self.client = Some(HostStream::new(stream));
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl SerialBackend for UnixSocketSerial {
+    fn receive(&mut self) -> Option<u8> {
+        if self.client.as_ref().is_none_or(|client| client.closed) {
+            self.client = None;
+            self.accept();
+        }
+        return self.client.as_mut()?.receive();
+    }
+
+    fn transmit(&mut self, bytes: &[u8]) {
+        if let Some(client) = self.client.as_mut() {
+            client.transmit(bytes);
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocketSerial {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
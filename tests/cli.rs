@@ -0,0 +1,36 @@
+#![allow(clippy::needless_return)]
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Reads the size of the disk in sectors and exits with it
+const DISK_SECTORS: &str = "
+main:   lui $t0, 0xffff
+        lw $a0, 0x14($t0)
+        li $v0, 17
+        syscall
+";
+
+fn write_temporary(name: &str, contents: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("vm32bits-cli-{}-{}", std::process::id(), name));
+    fs::write(&path, contents).unwrap();
+    return path;
+}
+
+fn run(device: &str, program: &PathBuf) -> Option<i32> {
+    let output = Command::new(env!("CARGO_BIN_EXE_vm32bits")).args(["run", "--device", device]).arg(program).output().unwrap();
+    return output.status.code();
+}
+
+#[test]
+fn devices_take_their_options_on_the_command_line() {
+    let program = write_temporary("disk.s", DISK_SECTORS.as_bytes());
+    let image = write_temporary("disk.img", &[0; 1024]);
+    assert_eq!(run("disk@0xffff0000,size=4k", &program), Some(8));
+    assert_eq!(run(&format!("disk@0xffff0000:1,image={}", image.display()), &program), Some(2));
+    assert_eq!(run("disk@0xffff0000", &program), Some(1));
+    assert_eq!(run("disk@0xffff0000,size", &program), Some(2));
+    fs::remove_file(program).unwrap();
+    fs::remove_file(image).unwrap();
+}
@@ -0,0 +1,158 @@
+#![allow(clippy::needless_return)]
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+
+use vm32bits::assembler::{Assembler, AssemblerOptions};
+use vm32bits::cpu::{Endianness, CPU};
+use vm32bits::gdb::GdbStub;
+use vm32bits::memory_mapper::MemoryMapper;
+use vm32bits::syscall::SpimSyscalls;
+
+const COUNTDOWN: &str = "
+main:   li $t0, 3
+loop:   addiu $t0, $t0, -1
+        sw $t0, counter
+        bnez $t0, loop
+        li $a0, 7
+        li $v0, 17
+        syscall
+        .data
+counter: .word 9
+";
+
+/// Runs a stub for the program on another thread, it returns the exit code once the client is done
+fn start_stub(source: &'static str) -> (Client, JoinHandle<Option<i32>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let stub = thread::spawn(move || {
+        let options = AssemblerOptions { text_base: 0, data_base: 0x800, endianness: Endianness::Big };
+        let program = Assembler::new(options).assemble(source).unwrap();
+        let mut memory_mapper = MemoryMapper::new();
+        memory_mapper.map_ram(0, 0x1000);
+        program.load(&mut memory_mapper).unwrap();
+        let mut cpu = CPU::new(&mut memory_mapper);
+        cpu.set_pc(program.entry);
+        cpu.set_syscall_handler(Box::new(SpimSyscalls::with_io(io::empty(), io::sink())));
+        let mut stub = GdbStub::new(cpu);
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+        stub.serve(&mut stream).unwrap();
+        return stub.cpu().exit_code();
+    });
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_nodelay(true).unwrap();
+    return (Client { stream }, stub);
+}
+
+/// Plays the part of gdb, one packet at a time
+struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    fn read_byte(&mut self) -> u8 {
+        let mut byte = [0];
+        self.stream.read_exact(&mut byte).unwrap();
+        return byte[0];
+    }
+
+    fn send(&mut self, payload: &str) {
+        let checksum = payload.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        self.stream.write_all(format!("${}#{:02x}", payload, checksum).as_bytes()).unwrap();
+        assert_eq!(self.read_byte(), b'+');
+    }
+
+    fn receive(&mut self) -> String {
+        assert_eq!(self.read_byte(), b'$');
+        let mut payload = vec![];
+        loop {
+            match self.read_byte() {
+                b'#' => break,
+                byte => payload.push(byte),
+            }
+        }
+        let checksum = [self.read_byte(), self.read_byte()];
+        let expected = payload.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        assert_eq!(u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap(), expected);
+        self.stream.write_all(b"+").unwrap();
+        return String::from_utf8(payload).unwrap();
+    }
+
+    fn request(&mut self, payload: &str) -> String {
+        self.send(payload);
+        return self.receive();
+    }
+}
+
+#[test]
+fn registers_and_memory_can_be_read_and_written() {
+    let (mut client, stub) = start_stub(COUNTDOWN);
+    assert!(client.request("qSupported:multiprocess+;swbreak+").contains("qXfer:features:read+"));
+    assert!(client.request("qXfer:features:read:target.xml:0,ffff").starts_with("l<?xml"));
+    assert_eq!(client.request("?"), "S05");
+
+    let registers = client.request("g");
+    assert_eq!(registers.len(), 72 * 8);
+    // sr comes out of reset with BEV and CU1 set
+    assert_eq!(&registers[32 * 8..33 * 8], "20400000");
+    assert_eq!(client.request("P8=0000002a"), "OK");
+    assert_eq!(client.request("p8"), "0000002a");
+    assert_eq!(client.request("P0=0000002a"), "OK");
+    assert_eq!(client.request("p0"), "00000000");
+    assert_eq!(client.request("P21=00000010"), "OK");
+    assert_eq!(client.request("p21"), "00000010");
+    assert_eq!(client.request("p22"), "00000000");
+    assert_eq!(client.request("p5a"), "xxxxxxxx");
+
+    assert_eq!(client.request("m800,4"), "00000009");
+    assert_eq!(client.request("M800,4:0000000b"), "OK");
+    assert_eq!(client.request("m800,4"), "0000000b");
+    assert_eq!(client.request("mffc,8"), "00000000");
+    assert_eq!(client.request("m80000000,4"), "E0e");
+
+    client.send("D");
+    assert_eq!(client.receive(), "OK");
+    assert_eq!(stub.join().unwrap(), None);
+}
+
+#[test]
+fn breakpoints_steps_and_watchpoints_stop_the_target() {
+    let (mut client, stub) = start_stub(COUNTDOWN);
+    assert_eq!(client.request("Z0,4,4"), "OK");
+    assert_eq!(client.request("c"), "S05");
+    assert_eq!(client.request("p25"), "00000004");
+    assert_eq!(client.request("p8"), "00000003");
+    assert_eq!(client.request("z0,4,4"), "OK");
+
+    assert_eq!(client.request("s"), "S05");
+    assert_eq!(client.request("p25"), "00000008");
+
+    // The watchpoint triggers before the store, on the instruction that makes it
+    assert_eq!(client.request("Z2,800,4"), "OK");
+    assert_eq!(client.request("c"), "T05watch:800;");
+    assert_eq!(client.request("p25"), "0000000c");
+    assert_eq!(client.request("m800,4"), "00000009");
+    assert_eq!(client.request("s"), "S05");
+    assert_eq!(client.request("m800,4"), "00000002");
+    assert_eq!(client.request("z2,800,4"), "OK");
+
+    // The program only ever stores to the counter
+    assert_eq!(client.request("Z3,800,4"), "OK");
+    assert_eq!(client.request("c"), "W07");
+    assert_eq!(client.request("?"), "W07");
+    client.send("k");
+    assert_eq!(stub.join().unwrap(), Some(7));
+}
+
+#[test]
+fn ctrl_c_interrupts_a_running_target() {
+    let (mut client, stub) = start_stub("spin: b spin\n");
+    client.send("c");
+    client.stream.write_all(&[0x03]).unwrap();
+    assert_eq!(client.receive(), "S02");
+    assert_eq!(client.request("p25"), "00000000");
+    client.send("k");
+    assert_eq!(stub.join().unwrap(), None);
+}